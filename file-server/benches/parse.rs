@@ -0,0 +1,76 @@
+//! Compares request parsing through `WireFormat::Json` (simd-json when the
+//! `simd-json` feature is on) and the borrowed `JsontpRequestRef` against
+//! plain serde_json.
+//!
+//! cargo bench --bench parse
+//! cargo bench --bench parse --features simd-json
+
+use std::{hint::black_box, time::Instant};
+
+use jsontp::{
+    protocol::{CustomHeaders, JsontpRequest, JsontpRequestRef, Validation},
+    wire::WireFormat,
+};
+
+const ITERATIONS: u32 = 20_000;
+
+fn request(content_size: usize) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({
+        "jsontp": "1.0",
+        "type": "request",
+        "method": "POST",
+        "resource": "/uploads/report.txt",
+        "headers": {
+            "content-type": "text/plain",
+            "accept": "application/json",
+            "accept-encoding": "identity",
+            "authorization": "Bearer 0123456789abcdef",
+        },
+        "body": {
+            "content": "x".repeat(content_size),
+            "encoding": "identity",
+        },
+    }))
+    .unwrap()
+}
+
+fn measure<T>(name: &str, data: &[u8], parse: impl Fn(&[u8]) -> T) {
+    let started = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(parse(black_box(data)));
+    }
+    let elapsed = started.elapsed();
+
+    println!(
+        "{:<12} {:>8} bytes  {:>10.0} requests/s  {:>8.1} MB/s",
+        name,
+        data.len(),
+        ITERATIONS as f64 / elapsed.as_secs_f64(),
+        (data.len() as f64 * ITERATIONS as f64) / elapsed.as_secs_f64() / 1e6,
+    );
+}
+
+fn main() {
+    let backend = if cfg!(feature = "simd-json") {
+        "simd-json"
+    } else {
+        "serde_json"
+    };
+
+    for size in [64, 4096, 65536] {
+        let data = request(size);
+
+        measure("serde_json", &data, |data| -> JsontpRequest {
+            serde_json::from_slice(data).unwrap()
+        });
+        measure(backend, &data, |data| -> JsontpRequest {
+            WireFormat::Json.decode(data).unwrap()
+        });
+        measure("borrowed", &data, |data| {
+            JsontpRequestRef::from_slice(data)
+                .unwrap()
+                .validate(Validation::Strict, &CustomHeaders::new())
+                .is_ok()
+        });
+    }
+}
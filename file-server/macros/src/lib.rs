@@ -0,0 +1,447 @@
+//! Procedural macros for the `jsontp` crate, re-exported from it behind the
+//! `derive` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitFloat, LitInt, LitStr};
+
+/// Implements `jsontp::validation::JsontpBody` for a struct with named
+/// fields, checking the rules in its `#[validate(...)]` attributes:
+///
+/// - `length(min = 1, max = 64)`: the number of characters of a string, or
+///   elements of a `Vec`
+/// - `range(min = 0, max = 150)`: a number
+/// - `regex = "^[a-z]+$"`: a string must match the pattern
+///
+/// Either bound may be left out. Fields that are `Option`s are only checked
+/// when present. Errors name fields as they appear in the JSON, following
+/// `#[serde(rename)]` and `#[serde(rename_all)]`.
+#[proc_macro_derive(JsontpBody, attributes(validate))]
+pub fn derive_jsontp_body(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// A `min = ..., max = ...` pair, as floats so integers and floats compare
+/// alike.
+#[derive(Default)]
+struct Bounds {
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+fn bounds(meta: &syn::meta::ParseNestedMeta) -> syn::Result<Bounds> {
+    let mut bounds = Bounds::default();
+
+    meta.parse_nested_meta(|bound| {
+        let value = bound.value()?;
+        let number = if value.peek(LitInt) {
+            value.parse::<LitInt>()?.base10_parse::<f64>()?
+        } else {
+            value.parse::<LitFloat>()?.base10_parse::<f64>()?
+        };
+
+        if bound.path.is_ident("min") {
+            bounds.min = Some(number);
+        } else if bound.path.is_ident("max") {
+            bounds.max = Some(number);
+        } else {
+            return Err(bound.error("expected `min` or `max`"));
+        }
+        Ok(())
+    })?;
+
+    Ok(bounds)
+}
+
+fn option(value: Option<impl quote::ToTokens>) -> TokenStream2 {
+    match value {
+        Some(value) => quote!(::core::option::Option::Some(#value)),
+        None => quote!(::core::option::Option::None),
+    }
+}
+
+/// The value of `#[serde(<key> = "...")]` among `attributes`, if any.
+fn serde_attribute(attributes: &[syn::Attribute], key: &str) -> syn::Result<Option<String>> {
+    let mut found = None;
+
+    for attribute in attributes.iter().filter(|a| a.path().is_ident("serde")) {
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident(key) {
+                found = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.input.peek(syn::Token![=]) {
+                // some other `key = value`, such as `default = "..."`
+                meta.value()?.parse::<syn::Expr>()?;
+            } else if meta.input.peek(syn::token::Paren) {
+                meta.parse_nested_meta(|_| Ok(()))?;
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(found)
+}
+
+fn rename(name: &str, rule: Option<&str>) -> String {
+    let words: Vec<&str> = name.split('_').filter(|word| !word.is_empty()).collect();
+
+    match rule {
+        Some("kebab-case") => words.join("-"),
+        Some("SCREAMING_SNAKE_CASE") => name.to_uppercase(),
+        Some("SCREAMING-KEBAB-CASE") => words.join("-").to_uppercase(),
+        Some("lowercase") => name.to_lowercase(),
+        Some("UPPERCASE") => name.to_uppercase(),
+        Some("camelCase" | "PascalCase") => {
+            let pascal: String = words
+                .iter()
+                .map(|word| {
+                    let mut chars = word.chars();
+                    chars
+                        .next()
+                        .map(|first| first.to_uppercase().chain(chars).collect())
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<String>>()
+                .concat();
+            if rule == Some("camelCase") {
+                let mut chars = pascal.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_lowercase().chain(chars).collect())
+                    .unwrap_or_default()
+            } else {
+                pascal
+            }
+        }
+        _ => name.to_string(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            input,
+            "JsontpBody can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new_spanned(
+            input,
+            "JsontpBody can only be derived for structs with named fields",
+        ));
+    };
+
+    let rename_all = serde_attribute(&input.attrs, "rename_all")?;
+    let mut checks = Vec::new();
+
+    for field in &fields.named {
+        let ident = field.ident.as_ref().unwrap();
+        let name = match serde_attribute(&field.attrs, "rename")? {
+            Some(name) => name,
+            None => rename(
+                ident.to_string().trim_start_matches("r#"),
+                rename_all.as_deref(),
+            ),
+        };
+
+        for attribute in field.attrs.iter().filter(|a| a.path().is_ident("validate")) {
+            attribute.parse_nested_meta(|meta| {
+                if meta.path.is_ident("length") {
+                    let Bounds { min, max } = bounds(&meta)?;
+                    let (min, max) = (
+                        option(min.map(|min| min as usize)),
+                        option(max.map(|max| max as usize)),
+                    );
+                    checks.push(quote! {
+                        ::jsontp::validation::check_length(#name, &self.#ident, #min, #max, errors);
+                    });
+                } else if meta.path.is_ident("range") {
+                    let Bounds { min, max } = bounds(&meta)?;
+                    let (min, max) = (option(min), option(max));
+                    checks.push(quote! {
+                        ::jsontp::validation::check_range(#name, &self.#ident, #min, #max, errors);
+                    });
+                } else if meta.path.is_ident("regex") {
+                    let pattern = meta.value()?.parse::<LitStr>()?;
+                    if let Err(e) = regex_lite::Regex::new(&pattern.value()) {
+                        return Err(Error::new_spanned(&pattern, format!("invalid regex: {}", e)));
+                    }
+                    checks.push(quote! {
+                        {
+                            static PATTERN: ::std::sync::OnceLock<::jsontp::validation::Pattern> =
+                                ::std::sync::OnceLock::new();
+                            let pattern =
+                                PATTERN.get_or_init(|| ::jsontp::validation::Pattern::new(#pattern));
+                            ::jsontp::validation::check_pattern(#name, &self.#ident, pattern, errors);
+                        }
+                    });
+                } else {
+                    return Err(meta.error("expected `length`, `range` or `regex`"));
+                }
+                Ok(())
+            })?;
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::jsontp::validation::JsontpBody for #name #type_generics #where_clause {
+            fn validate(&self, errors: &mut ::std::vec::Vec<::jsontp::validation::FieldError>) {
+                #(#checks)*
+            }
+        }
+    })
+}
+
+/// Turns a function into a `jsontp::router::Endpoint`, to be mounted on a
+/// `jsontp::router::Router`:
+///
+/// ```ignore
+/// /// Reads one user.
+/// #[jsontp::route(method = "GET", path = "/users/{id}")]
+/// fn get_user(request: &JsontpRequest, context: &mut Context, id: u64) -> JsontpResponse {
+///     ...
+/// }
+///
+/// let router = Router::new("users").mount(get_user);
+/// ```
+///
+/// The first two arguments are the request and its context; every other
+/// argument is a `{name}` in the path, and every `{name}` in the path must
+/// be an argument, or the function does not compile. Arguments that are not
+/// `&str` are parsed with `FromStr`, and requests whose parameters do not
+/// parse are answered with 400. The route is named after the function, and
+/// its doc comment is the route's summary in the schema.
+#[proc_macro_attribute]
+pub fn route(attribute: TokenStream, item: TokenStream) -> TokenStream {
+    let mut method = None;
+    let mut path = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("method") {
+            method = Some(meta.value()?.parse::<LitStr>()?);
+        } else if meta.path.is_ident("path") {
+            path = Some(meta.value()?.parse::<LitStr>()?);
+        } else {
+            return Err(meta.error("expected `method` or `path`"));
+        }
+        Ok(())
+    });
+    parse_macro_input!(attribute with parser);
+    let function = parse_macro_input!(item as syn::ItemFn);
+
+    expand_route(method, path, function)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+const METHODS: &[&str] = &[
+    "GET", "POST", "PUT", "DELETE", "OPTIONS", "MKCOL", "COPY", "MOVE",
+];
+
+/// The names of the `{name}` parameters in `path`, checking that it is a
+/// resource pattern the router understands.
+fn path_parameters(path: &LitStr, resource: &str) -> syn::Result<Vec<String>> {
+    let segments: Vec<&str> = resource.split('/').collect();
+    let mut names = Vec::new();
+
+    for (i, segment) in segments.iter().enumerate() {
+        let Some(name) = segment
+            .strip_prefix('{')
+            .and_then(|segment| segment.strip_suffix('}'))
+        else {
+            if segment.contains(['{', '}']) {
+                return Err(Error::new_spanned(
+                    path,
+                    format!("`{}` must be a whole segment of the path", segment),
+                ));
+            }
+            continue;
+        };
+
+        let name = match name.strip_suffix('*') {
+            Some(_) if i + 1 < segments.len() => {
+                return Err(Error::new_spanned(
+                    path,
+                    format!("only the last parameter can be `{{{}}}`", name),
+                ));
+            }
+            Some(name) => name,
+            None => name,
+        };
+        if syn::parse_str::<syn::Ident>(name).is_err() {
+            return Err(Error::new_spanned(
+                path,
+                format!("`{}` is not a valid parameter name", name),
+            ));
+        }
+        if names.iter().any(|other| other == name) {
+            return Err(Error::new_spanned(
+                path,
+                format!("`{{{}}}` appears twice in the path", name),
+            ));
+        }
+        names.push(name.to_string());
+    }
+
+    Ok(names)
+}
+
+fn is_str_reference(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Reference(reference) => {
+            matches!(&*reference.elem, syn::Type::Path(path) if path.path.is_ident("str"))
+        }
+        _ => false,
+    }
+}
+
+fn expand_route(
+    method: Option<LitStr>,
+    path: Option<LitStr>,
+    mut function: syn::ItemFn,
+) -> syn::Result<TokenStream2> {
+    let span = proc_macro2::Span::call_site();
+    let method = method.ok_or_else(|| Error::new(span, "missing `method = \"...\"`"))?;
+    let path = path.ok_or_else(|| Error::new(span, "missing `path = \"...\"`"))?;
+
+    if !METHODS.contains(&method.value().as_str()) {
+        return Err(Error::new_spanned(
+            &method,
+            format!("the method must be one of {}", METHODS.join(", ")),
+        ));
+    }
+    // resources do not start with a slash, but paths are often written so
+    let resource = path.value().trim_start_matches('/').to_string();
+    let mut unused = path_parameters(&path, &resource)?;
+
+    let signature = &function.sig;
+    if let Some(asyncness) = &signature.asyncness {
+        return Err(Error::new_spanned(
+            asyncness,
+            "routes cannot be async; wrap the handler in `jsontp::task::Async` instead",
+        ));
+    }
+    if !signature.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &signature.generics,
+            "routes cannot be generic",
+        ));
+    }
+    if signature.inputs.len() < 2 {
+        return Err(Error::new_spanned(
+            &signature.inputs,
+            "routes take the request and its context as their first two arguments",
+        ));
+    }
+
+    let mut extractions = Vec::new();
+    let mut arguments = Vec::new();
+    for input in signature.inputs.iter().skip(2) {
+        let syn::FnArg::Typed(argument) = input else {
+            return Err(Error::new_spanned(input, "routes cannot take `self`"));
+        };
+        let syn::Pat::Ident(pattern) = &*argument.pat else {
+            return Err(Error::new_spanned(
+                &argument.pat,
+                "arguments after the context must be plain names",
+            ));
+        };
+
+        let ident = &pattern.ident;
+        let name = ident.to_string().trim_start_matches("r#").to_string();
+        let Some(position) = unused.iter().position(|parameter| *parameter == name) else {
+            return Err(Error::new_spanned(
+                ident,
+                format!(
+                    "`{}` is not a parameter in the path `{}`",
+                    name,
+                    path.value()
+                ),
+            ));
+        };
+        unused.remove(position);
+
+        let variable = quote::format_ident!("__jsontp_{}", name);
+        let ty = &argument.ty;
+        extractions.push(if is_str_reference(ty) {
+            quote! {
+                let #variable: #ty = __jsontp_parameters
+                    .get(#name)
+                    .map(::std::string::String::as_str)
+                    .unwrap_or_default();
+            }
+        } else {
+            quote! {
+                let #variable: #ty = match __jsontp_parameters.get(#name).map(|value| value.parse()) {
+                    ::core::option::Option::Some(::core::result::Result::Ok(value)) => value,
+                    _ => return ::jsontp::router::invalid_parameter(__jsontp_request, #name),
+                };
+            }
+        });
+        arguments.push(variable);
+    }
+
+    if let Some(name) = unused.first() {
+        return Err(Error::new_spanned(
+            &function.sig.inputs,
+            format!(
+                "`{{{}}}` in the path has no argument named `{}`",
+                name, name
+            ),
+        ));
+    }
+
+    let mut summary = Vec::new();
+    for attribute in function.attrs.iter().filter(|a| a.path().is_ident("doc")) {
+        if let syn::Meta::NameValue(syn::MetaNameValue {
+            value:
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(line),
+                    ..
+                }),
+            ..
+        }) = &attribute.meta
+        {
+            summary.push(line.value().trim().to_string());
+        }
+    }
+    let summary = summary.join("\n").trim().to_string();
+
+    let ident = function.sig.ident.clone();
+    let name = ident.to_string().trim_start_matches("r#").replace('_', "-");
+    let vis = std::mem::replace(&mut function.vis, syn::Visibility::Inherited);
+    let (docs, attributes): (Vec<syn::Attribute>, Vec<syn::Attribute>) = function
+        .attrs
+        .drain(..)
+        .partition(|attribute| attribute.path().is_ident("doc"));
+    function.attrs = attributes;
+
+    Ok(quote! {
+        #(#docs)*
+        #[allow(non_camel_case_types)]
+        #vis struct #ident;
+
+        impl ::jsontp::router::Endpoint for #ident {
+            fn route(&self) -> ::jsontp::schema::Route {
+                ::jsontp::schema::Route::new(#name, #method, #resource).summary(#summary)
+            }
+
+            fn call(
+                &self,
+                __jsontp_request: &::jsontp::protocol::JsontpRequest,
+                __jsontp_context: &mut ::jsontp::middleware::Context,
+                __jsontp_parameters: &::jsontp::router::Parameters,
+            ) -> ::jsontp::protocol::JsontpResponse {
+                #function
+
+                #(#extractions)*
+                #ident(__jsontp_request, __jsontp_context #(, #arguments)*)
+            }
+        }
+    })
+}
@@ -0,0 +1,256 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+use crate::{
+    middleware::{Context, Middleware},
+    protocol::JsontpRequest,
+    streaming::RequestHead,
+};
+
+/// An address range in CIDR notation, e.g. `10.0.0.0/8` or `::1/128`. A bare
+/// address is a range containing only itself. A range within the
+/// IPv4-mapped block, as `::ffff:10.0.0.0/104`, is the IPv4 range it maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// Whether `address` is in the range. IPv4 peers seen through an IPv6
+    /// socket, as `::ffff:10.1.2.3`, are taken as the IPv4 address they are.
+    ///
+    /// ```
+    /// use jsontp::acl::IpRange;
+    ///
+    /// let range: IpRange = "10.0.0.0/8".parse().unwrap();
+    /// assert!(range.contains(&"10.1.2.3".parse().unwrap()));
+    /// assert!(range.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+    /// assert!(!range.contains(&"::ffff:11.1.2.3".parse().unwrap()));
+    ///
+    /// let mapped: IpRange = "::ffff:10.0.0.0/104".parse().unwrap();
+    /// assert_eq!(mapped, range);
+    /// assert!(mapped.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+    /// assert!(mapped.contains(&"10.1.2.3".parse().unwrap()));
+    /// assert_eq!(
+    ///     "::ffff:1.2.3.4".parse::<IpRange>().unwrap(),
+    ///     "1.2.3.4".parse().unwrap(),
+    /// );
+    /// ```
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, &address.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(*address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| alloc::format!("invalid address in `{}`", s))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| alloc::format!("invalid prefix length in `{}`", s))?,
+            None => max,
+        };
+
+        // peers are matched as IPv4 when they are mapped, so the range has
+        // to be too
+        if let IpAddr::V6(v6) = address {
+            if let (Some(v4), true) = (v6.to_ipv4_mapped(), prefix >= 96) {
+                return Ok(IpRange {
+                    address: IpAddr::V4(v4),
+                    prefix: prefix - 96,
+                });
+            }
+        }
+
+        Ok(IpRange { address, prefix })
+    }
+}
+
+impl<'de> Deserialize<'de> for IpRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Grants access to resources under `prefix`. A request matching the prefix
+/// (and one of `methods`, if any are listed) is only let through if its user,
+/// one of its roles, or its peer address is listed.
+///
+/// Prefixes are matched by whole segments once `.` and `..` are resolved in
+/// both, so `admin` covers `admin/x` and `public/../admin/x` but not
+/// `administrator`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    pub prefix: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub ips: Vec<IpRange>,
+}
+
+impl AclRule {
+    fn applies_to(&self, method: &str, resource: &[&str]) -> bool {
+        segments(&self.prefix).is_some_and(|prefix| resource.starts_with(&prefix))
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(method)))
+    }
+
+    fn allows(&self, context: &Context) -> bool {
+        let user = context
+            .user
+            .as_ref()
+            .is_some_and(|user| self.users.contains(user));
+        let role = context.roles.iter().any(|role| self.roles.contains(role));
+        let ip = context
+            .peer
+            .is_some_and(|peer| self.ips.iter().any(|range| range.contains(&peer)));
+
+        user || role || ip
+    }
+}
+
+/// The segments of `resource`, leaving out empty ones and `.`, with each `..`
+/// taking off the one before it, or `None` if a `..` leads above the top.
+fn segments(resource: &str) -> Option<Vec<&str>> {
+    let mut segments = Vec::new();
+    for segment in resource.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some(segments)
+}
+
+/// Checks requests against a set of [`AclRule`]s. The rule with the longest
+/// matching prefix decides; resources no rule applies to are open to everyone,
+/// and those leading above the top to no one.
+/// Register it after any authenticating middleware so `Context::user` is set.
+pub struct AclMiddleware {
+    pub rules: Vec<AclRule>,
+}
+
+impl AclMiddleware {
+    fn rule_for(&self, method: &str, resource: &[&str]) -> Option<&AclRule> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.applies_to(method, resource))
+            .max_by_key(|rule| segments(&rule.prefix).map_or(0, |prefix| prefix.len()))
+    }
+
+    /// Whether a request for `resource` with `context` may go ahead.
+    ///
+    /// ```
+    /// use jsontp::{acl::{AclMiddleware, AclRule}, middleware::Context};
+    ///
+    /// let acl = AclMiddleware {
+    ///     rules: vec![AclRule {
+    ///         prefix: "admin".to_string(),
+    ///         methods: vec![],
+    ///         users: vec!["alice".to_string()],
+    ///         roles: vec![],
+    ///         ips: vec![],
+    ///     }],
+    /// };
+    /// let anyone = Context::default();
+    ///
+    /// assert!(!acl.check("GET", "admin/x", &anyone));
+    /// assert!(!acl.check("GET", "public/../admin/x", &anyone));
+    /// assert!(!acl.check("GET", "./admin//x", &anyone));
+    /// assert!(acl.check("GET", "administrator", &anyone));
+    /// assert!(!acl.check("GET", "../admin", &anyone));
+    /// ```
+    pub fn check(&self, method: &str, resource: &str, context: &Context) -> bool {
+        segments(resource).is_some_and(|resource| {
+            self.rule_for(method, &resource)
+                .is_none_or(|rule| rule.allows(context))
+        })
+    }
+}
+
+impl Middleware for AclMiddleware {
+    /// Only rules that grant access by address alone can be decided before
+    /// the request has been authenticated.
+    fn on_head(&self, head: &RequestHead, context: &Context) -> Result<(), (String, u16)> {
+        let (Some(method), Some(resource)) = (&head.method, &head.resource) else {
+            return Ok(());
+        };
+        let Some(resource) = segments(resource) else {
+            return Err(("Forbidden".to_string(), 403));
+        };
+
+        match self.rule_for(method, &resource) {
+            Some(rule)
+                if rule.users.is_empty() && rule.roles.is_empty() && !rule.allows(context) =>
+            {
+                Err(("Forbidden".to_string(), 403))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        // COPY and MOVE also write to their destination
+        let destination = request
+            .headers
+            .get("destination")
+            .and_then(Value::as_str)
+            .filter(|_| matches!(request.method.as_str(), "COPY" | "MOVE"));
+
+        if self.check(&request.method, &request.resource, context)
+            && destination
+                .is_none_or(|destination| self.check(&request.method, destination, context))
+        {
+            Ok(())
+        } else {
+            Err(("Forbidden".to_string(), 403))
+        }
+    }
+}
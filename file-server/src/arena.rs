@@ -0,0 +1,32 @@
+use std::cell::RefCell;
+
+use bumpalo::Bump;
+
+thread_local! {
+    static ARENA: RefCell<Bump> = RefCell::new(Bump::new());
+}
+
+/// Runs `f` with the bump arena of the request being served on this thread.
+///
+/// Anything allocated in it lives until the response has been written, when
+/// the whole arena is freed at once, so handlers and middleware can use it
+/// for scratch data without going through the allocator for every piece.
+///
+/// The server itself only puts the copy of the request that simd-json parses
+/// in place there, which is why the `arena` feature turns on `simd-json`: the
+/// decoded request owns its strings and headers, so they still come from the
+/// global allocator.
+pub fn with_arena<R>(f: impl FnOnce(&Bump) -> R) -> R {
+    ARENA.with(|arena| f(&arena.borrow()))
+}
+
+/// Frees everything allocated in this thread's arena, keeping its largest
+/// chunk around for the next request.
+pub(crate) fn reset() {
+    ARENA.with(|arena| arena.borrow_mut().reset());
+}
+
+/// The number of bytes the arena of this thread currently holds.
+pub fn allocated_bytes() -> usize {
+    ARENA.with(|arena| arena.borrow().allocated_bytes())
+}
@@ -0,0 +1,267 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    idempotency::is_unsafe,
+    middleware::{Context, Middleware},
+    protocol::{JsontpRequest, JsontpResponse},
+    server::status_response,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AuditConfig {
+    /// The file entries are appended to, one JSON object per line.
+    pub path: PathBuf,
+    /// Links every entry to the one before it by hash, so that changing or
+    /// removing an entry is noticed by [`verify`].
+    #[serde(default)]
+    pub chain: bool,
+}
+
+/// One request that changed, or tried to change, something.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch.
+    pub at: u64,
+    /// The identity established by an authenticating middleware.
+    pub user: Option<String>,
+    pub peer: Option<String>,
+    pub method: String,
+    pub resource: String,
+    /// Where a `COPY` or `MOVE` wrote to.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub destination: Option<String>,
+    /// The length of the body content.
+    pub size: usize,
+    /// The SHA-256 of the body content, in hex.
+    pub checksum: String,
+    pub status: u16,
+    /// The hash of the entry before, or of nothing for the first.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub previous: Option<String>,
+    /// The SHA-256 of this entry, without `hash`, in hex.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub hash: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        request: &JsontpRequest,
+        context: &Context,
+        response: &JsontpResponse,
+    ) -> AuditEntry {
+        AuditEntry {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            user: context.user.clone(),
+            peer: context.peer.map(|peer| peer.to_string()),
+            method: request.method.clone(),
+            resource: request.resource.clone(),
+            destination: request
+                .headers
+                .get("destination")
+                .and_then(Value::as_str)
+                .filter(|_| matches!(request.method.as_str(), "COPY" | "MOVE"))
+                .map(str::to_string),
+            size: request.body.content.len(),
+            checksum: sha256_hex(request.body.content.as_bytes()),
+            status: response.status.code,
+            previous: None,
+            hash: None,
+        }
+    }
+
+    /// What `hash` should be.
+    fn digest(&self) -> String {
+        let unhashed = AuditEntry {
+            hash: None,
+            ..self.clone()
+        };
+        sha256_hex(serde_json::to_string(&unhashed).unwrap().as_bytes())
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+/// An append-only file of [`AuditEntry`]s.
+pub struct AuditLog {
+    chain: bool,
+    /// The file, and the hash of the last entry in it when chaining.
+    file: Mutex<(File, Option<String>)>,
+    /// Whether the last entry was written.
+    healthy: AtomicBool,
+}
+
+impl AuditLog {
+    /// Opens the log for appending, creating it if need be. A chained log
+    /// carries on from the hash of its last entry.
+    pub fn open(config: &AuditConfig) -> io::Result<AuditLog> {
+        let last = if config.chain {
+            match fs::read_to_string(&config.path) {
+                Ok(text) => text
+                    .lines()
+                    .last()
+                    .and_then(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                    .and_then(|entry| entry.hash),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            }
+        } else {
+            None
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+
+        Ok(AuditLog {
+            chain: config.chain,
+            file: Mutex::new((file, last)),
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    /// Whether the last entry could be written, or none has been tried.
+    pub fn healthy(&self) -> bool {
+        self.healthy.load(Ordering::Acquire)
+    }
+
+    /// Appends `entry`, chaining it to the one before if the log is chained,
+    /// and syncs it to disk. Whether it could is what [`AuditLog::healthy`]
+    /// says from then on.
+    pub fn record(&self, entry: AuditEntry) -> io::Result<()> {
+        let recorded = self.append(entry);
+        self.healthy.store(recorded.is_ok(), Ordering::Release);
+        recorded
+    }
+
+    fn append(&self, mut entry: AuditEntry) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let (file, last) = &mut *file;
+
+        if self.chain {
+            entry.previous = Some(last.clone().unwrap_or_default());
+            entry.hash = Some(entry.digest());
+        }
+
+        let mut line = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        file.sync_data()?;
+
+        if self.chain {
+            *last = entry.hash;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the hash chain of the log at `path`, giving the number of entries
+/// in it. The error names the first line that was changed, inserted or
+/// follows a removed one.
+pub fn verify(path: &Path) -> Result<usize, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+    let mut last = String::new();
+    let mut count = 0;
+    for (number, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line)) {
+        let entry: AuditEntry = serde_json::from_str(line)
+            .map_err(|e| format!("line {} is not an audit entry: {}", number, e))?;
+
+        let (Some(previous), Some(hash)) = (&entry.previous, &entry.hash) else {
+            return Err(format!("line {} is not chained", number));
+        };
+        if *previous != last {
+            return Err(format!(
+                "line {} does not follow the entry before it",
+                number
+            ));
+        }
+        if *hash != entry.digest() {
+            return Err(format!("line {} was changed", number));
+        }
+
+        last = hash.clone();
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Records every request with an unsafe method in an [`AuditLog`], along
+/// with how it was answered. Put it before any authenticating middleware so
+/// that it sees who the request was from and the final response.
+///
+/// Nothing goes unrecorded: a request whose entry cannot be written is
+/// answered with `500`, even if it was carried out, and while the log
+/// cannot be written to, unsafe requests are refused with `500` before they
+/// are. Each refusal is still offered to the log, so requests are let
+/// through again once it takes one.
+pub struct AuditMiddleware {
+    pub log: AuditLog,
+}
+
+impl Middleware for AuditMiddleware {
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        _context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        match is_unsafe(&request.method) && !self.log.healthy() {
+            true => Err((
+                "Internal Server Error: the audit log cannot be written to".to_string(),
+                500,
+            )),
+            false => Ok(()),
+        }
+    }
+
+    fn on_response(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        let Some(request) = request else {
+            return;
+        };
+        if !is_unsafe(&request.method) {
+            return;
+        }
+
+        if let Err(e) = self.log.record(AuditEntry::new(request, context, response)) {
+            eprintln!("could not write to the audit log: {}", e);
+            if response.status.code != 500 {
+                let resource = std::mem::take(&mut response.resource);
+                *response =
+                    status_response(500, "Internal Server Error", "Request could not be audited");
+                response.resource = resource;
+            }
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    client::{Client, ClientError},
+    discovery::Discovery,
+    protocol::{BatchRequest, BatchResponse, JsontpRequest, JsontpResponse},
+};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Each endpoint in turn.
+    #[default]
+    RoundRobin,
+    /// The endpoint with the fewest requests being sent to it, taking them
+    /// in turn when several have as few.
+    LeastConnections,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BalancerConfig {
+    pub strategy: Strategy,
+    /// Failures in a row after which an endpoint is ejected.
+    pub eject_after: u32,
+    /// Seconds an ejected endpoint is left out for.
+    pub eject_for: u64,
+    /// Seconds between connecting to every endpoint to see that it is up,
+    /// ejecting those that are not, or 0 not to check.
+    pub health_check: u64,
+    /// Seconds between asking the resolver for the endpoints again.
+    pub resolve_every: u64,
+}
+
+impl Default for BalancerConfig {
+    fn default() -> Self {
+        BalancerConfig {
+            strategy: Strategy::RoundRobin,
+            eject_after: 3,
+            eject_for: 10,
+            health_check: 5,
+            resolve_every: 30,
+        }
+    }
+}
+
+/// Where the servers a [`Balancer`] spreads requests over are.
+pub enum Endpoints {
+    /// The same addresses for good.
+    Static(Vec<String>),
+    /// Gives the current addresses, such as those a DNS name resolves to.
+    Resolver(Box<dyn Fn() -> Vec<String> + Send + Sync>),
+    /// Looks up the named service. When a lookup fails, the endpoints found
+    /// the last time are kept.
+    Discovered(Arc<dyn Discovery>, String),
+}
+
+struct Endpoint {
+    address: String,
+    client: Arc<Client>,
+    in_flight: usize,
+    /// Failures since the last success.
+    failures: u32,
+    ejected_until: Option<Instant>,
+    requests: u64,
+}
+
+impl Endpoint {
+    fn ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+}
+
+struct State {
+    endpoints: Vec<Endpoint>,
+    /// Where the next round-robin turn starts.
+    next: usize,
+    resolved: Instant,
+    make: Box<dyn Fn(&str) -> Client + Send + Sync>,
+}
+
+struct Inner {
+    config: BalancerConfig,
+    /// Gives the current addresses, or nothing to keep the ones known.
+    resolver: Option<Box<dyn Fn() -> Option<Vec<String>> + Send + Sync>>,
+    state: Mutex<State>,
+}
+
+impl Inner {
+    /// Replaces the endpoints with those at `addresses`, keeping what is
+    /// known of the ones still there.
+    fn set_addresses(&self, state: &mut State, addresses: Vec<String>) {
+        let mut old = std::mem::take(&mut state.endpoints);
+        state.endpoints = addresses
+            .into_iter()
+            .map(
+                |address| match old.iter().position(|e| e.address == address) {
+                    Some(known) => old.swap_remove(known),
+                    None => Endpoint {
+                        client: Arc::new((state.make)(&address)),
+                        address,
+                        in_flight: 0,
+                        failures: 0,
+                        ejected_until: None,
+                        requests: 0,
+                    },
+                },
+            )
+            .collect();
+        state.resolved = Instant::now();
+    }
+
+    /// Takes an endpoint for a request, counting it as in flight there.
+    fn pick(&self) -> Option<(String, Arc<Client>)> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(resolver) = &self.resolver {
+            if state.resolved.elapsed() >= Duration::from_secs(self.config.resolve_every) {
+                match resolver() {
+                    Some(addresses) => self.set_addresses(&mut state, addresses),
+                    None => state.resolved = Instant::now(),
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let count = state.endpoints.len();
+        // when every endpoint is ejected, trying them beats failing outright
+        let all_ejected = state.endpoints.iter().all(|e| e.ejected(now));
+        let candidates = (0..count)
+            .map(|turn| (state.next + turn) % count)
+            .filter(|&i| all_ejected || !state.endpoints[i].ejected(now));
+
+        let chosen = match self.config.strategy {
+            Strategy::RoundRobin => candidates.take(1).next(),
+            Strategy::LeastConnections => candidates.min_by_key(|&i| state.endpoints[i].in_flight),
+        }?;
+
+        state.next = (chosen + 1) % count;
+        let endpoint = &mut state.endpoints[chosen];
+        endpoint.in_flight += 1;
+        endpoint.requests += 1;
+        Some((endpoint.address.clone(), endpoint.client.clone()))
+    }
+
+    /// Counts a request to `address` as done, ejecting the endpoint if it
+    /// has failed too often in a row.
+    fn done(&self, address: &str, healthy: bool) {
+        let eject_for = Duration::from_secs(self.config.eject_for);
+        let mut state = self.state.lock().unwrap();
+        let Some(endpoint) = state.endpoints.iter_mut().find(|e| e.address == address) else {
+            return;
+        };
+
+        endpoint.in_flight = endpoint.in_flight.saturating_sub(1);
+        if healthy {
+            endpoint.failures = 0;
+        } else {
+            endpoint.failures += 1;
+            if endpoint.failures >= self.config.eject_after.max(1) {
+                endpoint.ejected_until = Some(Instant::now() + eject_for);
+                endpoint.failures = 0;
+            }
+        }
+    }
+
+    /// Connects to every endpoint, ejecting those that cannot be reached.
+    fn check(&self) {
+        let endpoints: Vec<_> = {
+            let state = self.state.lock().unwrap();
+            state
+                .endpoints
+                .iter()
+                .map(|e| (e.address.clone(), e.client.clone()))
+                .collect()
+        };
+        let down: Vec<_> = endpoints
+            .into_iter()
+            .filter(|(_, client)| !client.reachable())
+            .map(|(address, _)| address)
+            .collect();
+
+        let until = Instant::now() + Duration::from_secs(self.config.eject_for);
+        let mut state = self.state.lock().unwrap();
+        for endpoint in &mut state.endpoints {
+            if down.contains(&endpoint.address) {
+                endpoint.ejected_until = Some(until);
+            }
+        }
+    }
+}
+
+/// Spreads requests over several servers offering the same thing, leaving
+/// out for a while those that fail `eject-after` times in a row or, when
+/// `health-check` is set, cannot be connected to. The endpoints are either
+/// fixed or looked up again every `resolve-every` seconds.
+///
+/// A request is sent to one endpoint only: one that fails is not retried
+/// elsewhere, as it may have been carried out already.
+pub struct Balancer {
+    inner: Arc<Inner>,
+}
+
+impl Balancer {
+    pub fn new(endpoints: Endpoints, config: BalancerConfig) -> Balancer {
+        let addresses = match &endpoints {
+            Endpoints::Static(addresses) => addresses.clone(),
+            _ => Vec::new(),
+        };
+        let resolver: Option<Box<dyn Fn() -> Option<Vec<String>> + Send + Sync>> = match endpoints {
+            Endpoints::Static(_) => None,
+            Endpoints::Resolver(resolver) => Some(Box::new(move || Some(resolver()))),
+            Endpoints::Discovered(discovery, service) => {
+                Some(Box::new(move || discovery.resolve(&service).ok()))
+            }
+        };
+        let health_check = config.health_check;
+
+        let inner = Arc::new(Inner {
+            config,
+            resolver,
+            state: Mutex::new(State {
+                endpoints: Vec::new(),
+                next: 0,
+                resolved: Instant::now(),
+                make: Box::new(Client::new),
+            }),
+        });
+        let addresses = inner
+            .resolver
+            .as_ref()
+            .map_or(Some(addresses), |resolver| resolver())
+            .unwrap_or_default();
+        inner.set_addresses(&mut inner.state.lock().unwrap(), addresses);
+
+        if health_check > 0 {
+            let inner = Arc::downgrade(&inner);
+            thread::spawn(move || health_checks(inner, Duration::from_secs(health_check)));
+        }
+
+        Balancer { inner }
+    }
+
+    /// Builds the client for each endpoint with `make`, to set its format,
+    /// socket options and the like.
+    pub fn with_client(self, make: impl Fn(&str) -> Client + Send + Sync + 'static) -> Balancer {
+        let mut state = self.inner.state.lock().unwrap();
+        for endpoint in &mut state.endpoints {
+            endpoint.client = Arc::new(make(&endpoint.address));
+        }
+        state.make = Box::new(make);
+        drop(state);
+        self
+    }
+
+    pub fn send(&self, request: &JsontpRequest) -> Result<JsontpResponse, ClientError> {
+        self.balanced(
+            |client| client.send(request),
+            |response| response.status.code < 500,
+        )
+    }
+
+    pub fn send_batch(&self, batch: &BatchRequest) -> Result<BatchResponse, ClientError> {
+        self.balanced(|client| client.send_batch(batch), |_| true)
+    }
+
+    fn balanced<T>(
+        &self,
+        send: impl FnOnce(&Client) -> Result<T, ClientError>,
+        healthy: impl FnOnce(&T) -> bool,
+    ) -> Result<T, ClientError> {
+        let (address, client) = self.inner.pick().ok_or_else(|| {
+            ClientError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "there are no endpoints",
+            ))
+        })?;
+
+        let result = send(&client);
+        let healthy = match &result {
+            Ok(answer) => healthy(answer),
+            #[cfg(feature = "encryption")]
+            Err(ClientError::Encryption(_)) => true,
+            Err(ClientError::MissingExtensions(_)) => true,
+            Err(_) => false,
+        };
+        self.inner.done(&address, healthy);
+        result
+    }
+
+    /// Every endpoint and how it is doing, as `{"10.0.0.7:8080":
+    /// {"in-flight": 2, "requests": 120, "failures": 0, "ejected": false}}`.
+    pub fn report(&self) -> Value {
+        let now = Instant::now();
+        let state = self.inner.state.lock().unwrap();
+        let report: BTreeMap<_, _> = state
+            .endpoints
+            .iter()
+            .map(|e| {
+                (
+                    e.address.clone(),
+                    json!({
+                        "in-flight": e.in_flight,
+                        "requests": e.requests,
+                        "failures": e.failures,
+                        "ejected": e.ejected(now),
+                    }),
+                )
+            })
+            .collect();
+        json!(report)
+    }
+}
+
+/// Checks the endpoints every `interval` for as long as the balancer lives.
+fn health_checks(inner: Weak<Inner>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match inner.upgrade() {
+            Some(inner) => inner.check(),
+            None => return,
+        }
+    }
+}
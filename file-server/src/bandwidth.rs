@@ -0,0 +1,231 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    metrics::{Metrics, METRICS},
+    middleware::{Context, Middleware},
+    protocol::{JsontpRequest, JsontpResponse},
+    throttle::quota_for,
+};
+
+/// Where the middleware leaves whom a request counts against for
+/// `on_response`.
+const CLIENT_VALUE: &str = "bandwidth-client";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BandwidthConfig {
+    /// Seconds over which a client's usage is added up.
+    pub window: u64,
+    /// Bytes of content served per window to signed-in users without a quota
+    /// of their own.
+    pub bytes: u64,
+    /// Bytes per window for each address whose requests are not signed in;
+    /// `bytes` when unset.
+    pub anonymous: Option<u64>,
+    /// Quotas of particular users, by name.
+    pub users: BTreeMap<String, u64>,
+    /// Quotas of users with a role; the most generous of a user's roles
+    /// applies, unless the user has a quota by name.
+    pub roles: BTreeMap<String, u64>,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        BandwidthConfig {
+            window: 3600,
+            bytes: 1 << 30,
+            anonymous: None,
+            users: BTreeMap::new(),
+            roles: BTreeMap::new(),
+        }
+    }
+}
+
+/// What one client was served in the current window and the one before it.
+struct Window {
+    started: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl Window {
+    /// Moves the window along to `now`.
+    fn roll(&mut self, now: Instant, length: Duration) {
+        let elapsed = now.duration_since(self.started);
+        if elapsed >= length * 2 {
+            self.started = now;
+            self.previous = 0;
+            self.current = 0;
+        } else if elapsed >= length {
+            self.started += length;
+            self.previous = self.current;
+            self.current = 0;
+        }
+    }
+
+    /// Bytes served over the last `length`, counting the part of the previous
+    /// window still inside it as if its bytes were spread evenly.
+    fn used(&self, now: Instant, length: Duration) -> u64 {
+        let into = now.duration_since(self.started).as_secs_f64() / length.as_secs_f64();
+        let overlap = (1.0 - into).clamp(0.0, 1.0);
+        self.current + (self.previous as f64 * overlap) as u64
+    }
+}
+
+/// How many bytes of content each client has been served over a sliding
+/// window, shared between the middleware and whatever reports on it.
+pub struct BandwidthUsage {
+    window: Duration,
+    clients: Mutex<HashMap<String, Window>>,
+}
+
+impl BandwidthUsage {
+    /// Beyond this many clients, ones that have been served nothing for two
+    /// windows are forgotten.
+    const TRACKED: usize = 10_000;
+
+    pub fn new(window: Duration) -> BandwidthUsage {
+        BandwidthUsage {
+            window,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bytes `client` has been served over the window.
+    pub fn used(&self, client: &str) -> u64 {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let Some(window) = clients.get_mut(client) else {
+            return 0;
+        };
+
+        window.roll(now, self.window);
+        window.used(now, self.window)
+    }
+
+    /// Counts `bytes` served to `client`, giving its usage afterwards.
+    pub fn add(&self, client: &str, bytes: u64) -> u64 {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+
+        if clients.len() >= Self::TRACKED {
+            let forget = self.window * 2;
+            clients.retain(|_, window| now.duration_since(window.started) < forget);
+        }
+
+        let window = clients.entry(client.to_string()).or_insert(Window {
+            started: now,
+            current: 0,
+            previous: 0,
+        });
+        window.roll(now, self.window);
+        window.current += bytes;
+        window.used(now, self.window)
+    }
+
+    /// Every client's usage, as `{"user:alice": 1024, "ip:10.0.0.7": 0}`.
+    pub fn report(&self) -> Value {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+
+        let usage: BTreeMap<_, _> = clients
+            .iter_mut()
+            .map(|(client, window)| {
+                window.roll(now, self.window);
+                (client.clone(), window.used(now, self.window))
+            })
+            .collect();
+        json!(usage)
+    }
+}
+
+/// Limits how many bytes of content each client is served over a sliding
+/// window: signed-in users by name and everyone else by address, as with
+/// [`ThrottleMiddleware`](crate::throttle::ThrottleMiddleware). Once a
+/// client has used its quota, its requests are answered with 429 until
+/// enough of its usage has slid out of the window. Responses say where their
+/// client stands in `bandwidth-limit` and `bandwidth-used`.
+///
+/// The response that takes a client over its quota is still served in full,
+/// so a quota can be overrun by up to one response.
+pub struct BandwidthMiddleware {
+    pub config: BandwidthConfig,
+    pub usage: Arc<BandwidthUsage>,
+}
+
+impl BandwidthMiddleware {
+    pub fn new(config: BandwidthConfig) -> BandwidthMiddleware {
+        BandwidthMiddleware {
+            usage: Arc::new(BandwidthUsage::new(Duration::from_secs(config.window))),
+            config,
+        }
+    }
+}
+
+impl Middleware for BandwidthMiddleware {
+    fn on_request(
+        &self,
+        _request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        let config = &self.config;
+        let Some((client, quota)) = quota_for(
+            context,
+            config.bytes,
+            config.anonymous,
+            &config.users,
+            &config.roles,
+        ) else {
+            return Ok(());
+        };
+
+        let used = self.usage.used(&client);
+        let exceeded = used >= quota;
+        context.values.insert(
+            CLIENT_VALUE.to_string(),
+            json!({ "client": client, "limit": quota, "used": used, "exceeded": exceeded }),
+        );
+
+        if exceeded {
+            Metrics::increment(&METRICS.bandwidth_quota_exceeded);
+            return Err((
+                "Too Many Requests: bandwidth quota exceeded".to_string(),
+                429,
+            ));
+        }
+        Ok(())
+    }
+
+    fn on_response(
+        &self,
+        _request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        let Some(client) = context.values.get(CLIENT_VALUE) else {
+            return;
+        };
+
+        let mut used = client["used"].as_u64().unwrap_or_default();
+        // the echo of a turned down request is not counted against it
+        if client["exceeded"] != Value::Bool(true) {
+            if let Some(name) = client["client"].as_str() {
+                used = self.usage.add(name, response.body.content.len() as u64);
+            }
+        }
+
+        response
+            .headers
+            .insert("bandwidth-limit".to_string(), client["limit"].clone());
+        response
+            .headers
+            .insert("bandwidth-used".to_string(), Value::from(used));
+    }
+}
@@ -0,0 +1,68 @@
+use std::{collections::BTreeMap, fs, path::Path, process};
+
+use jsontp::{
+    client::Client,
+    codegen::generate,
+    headers::Headers,
+    protocol::{Body, JsontpRequest},
+    schema::{ApiSchema, SCHEMA_RESOURCE},
+};
+
+/// Reads the schema from a file, or asks the server at `source` for it.
+fn load(source: &str) -> Result<ApiSchema, String> {
+    let text = if Path::new(source).is_file() {
+        fs::read_to_string(source).map_err(|e| format!("could not read {}: {}", source, e))?
+    } else {
+        let request = JsontpRequest {
+            jsontp: "1.0".to_string(),
+            type_of_request: "request".to_string(),
+            method: "GET".to_string(),
+            resource: SCHEMA_RESOURCE.to_string(),
+            headers: Headers::new(),
+            body: Body {
+                content: "{}".to_string(),
+                encoding: "identity".to_string(),
+                other: BTreeMap::new(),
+            },
+            other: BTreeMap::new(),
+        };
+        let response = Client::new(source)
+            .send(&request)
+            .map_err(|e| format!("could not get the schema from {}: {:?}", source, e))?;
+        if response.status.code != 200 {
+            return Err(format!(
+                "{} answered {} for {}",
+                source, response.status.code, SCHEMA_RESOURCE
+            ));
+        }
+        response.body.content
+    };
+
+    serde_json::from_str(&text).map_err(|e| format!("invalid schema: {}", e))
+}
+
+fn main() {
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    let Some(source) = arguments.first() else {
+        eprintln!("usage: jsontp-codegen <schema.json | host:port> [output.rs]");
+        process::exit(2);
+    };
+
+    let code = match load(source) {
+        Ok(schema) => generate(&schema),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    match arguments.get(1) {
+        Some(output) => {
+            if let Err(e) = fs::write(output, code) {
+                eprintln!("could not write {}: {}", output, e);
+                process::exit(1);
+            }
+        }
+        None => print!("{}", code),
+    }
+}
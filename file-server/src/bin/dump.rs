@@ -0,0 +1,25 @@
+use std::process;
+
+use jsontp::dump::{proxy, DumpOptions};
+
+const USAGE: &str = "usage: jsontp-dump [--compact] <listen-address> <server-address>";
+
+fn main() {
+    let mut options = DumpOptions::default();
+    let mut addresses = Vec::new();
+    for argument in std::env::args().skip(1) {
+        match argument.as_str() {
+            "--compact" => options.compact = true,
+            _ => addresses.push(argument),
+        }
+    }
+
+    let [listen, upstream] = addresses.as_slice() else {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+    if let Err(e) = proxy(listen, upstream, options) {
+        eprintln!("Could not listen on {}: {}", listen, e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    process,
+};
+
+use jsontp::{
+    client::Client,
+    contract::Contract,
+    lint::{fix, validate_with},
+    protocol::{CustomHeaders, Validation},
+};
+use serde_json::Value;
+
+const USAGE: &str = "usage: jsontp lint [--fix] [--lenient] [--custom-header <name>] \
+                     [--custom-prefix <prefix>] [file | -]...
+       jsontp verify <server-address> <contract.json>...";
+
+/// Exit codes, for CI: every message valid or contract kept, some not, or
+/// the command could not be run.
+const VALID: i32 = 0;
+const INVALID: i32 = 1;
+const FAILED: i32 = 2;
+
+struct Lint {
+    fix: bool,
+    validation: Validation,
+    custom: CustomHeaders,
+    inputs: Vec<String>,
+}
+
+impl Lint {
+    fn parse(arguments: &[String]) -> Result<Lint, String> {
+        let mut lint = Lint {
+            fix: false,
+            validation: Validation::Strict,
+            custom: CustomHeaders::new(),
+            inputs: Vec::new(),
+        };
+
+        let mut arguments = arguments.iter();
+        while let Some(argument) = arguments.next() {
+            match argument.as_str() {
+                "--fix" => lint.fix = true,
+                "--lenient" => lint.validation = Validation::Lenient,
+                "--custom-header" | "--custom-prefix" => {
+                    let value = arguments
+                        .next()
+                        .ok_or_else(|| format!("{} needs a value", argument))?;
+                    lint.custom = match argument.as_str() {
+                        "--custom-header" => lint.custom.name(value),
+                        _ => lint.custom.prefix(value),
+                    };
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                input => lint.inputs.push(input.to_string()),
+            }
+        }
+
+        if lint.inputs.is_empty() {
+            lint.inputs.push("-".to_string());
+        }
+        if lint.fix && lint.inputs.len() > 1 {
+            return Err("--fix takes one message".to_string());
+        }
+        Ok(lint)
+    }
+
+    /// Lints every input, giving the exit code.
+    fn run(&self) -> i32 {
+        let mut code = VALID;
+        for input in &self.inputs {
+            let text = match read(input) {
+                Ok(text) => text,
+                Err(e) => {
+                    eprintln!("{}: {}", input, e);
+                    return FAILED;
+                }
+            };
+
+            let valid = match self.fix {
+                true => self.fix(input, &text),
+                false => self.check(input, &text),
+            };
+            if !valid {
+                code = INVALID;
+            }
+        }
+        code
+    }
+
+    fn check(&self, input: &str, text: &str) -> bool {
+        let report = validate_with(text, self.validation, &self.custom);
+        match (report.is_valid(), &report.message_type) {
+            (true, Some(message_type)) => println!("{}: valid {}", input, message_type),
+            _ => {
+                for violation in &report.violations {
+                    println!("{}: {}", input, violation);
+                }
+                println!(
+                    "{}: {} violation{}",
+                    input,
+                    report.violations.len(),
+                    if report.violations.len() == 1 {
+                        ""
+                    } else {
+                        "s"
+                    }
+                );
+            }
+        }
+        report.is_valid()
+    }
+
+    /// Prints the message with its defaults filled in, saying on standard
+    /// error what was filled in and what is still wrong.
+    fn fix(&self, input: &str, text: &str) -> bool {
+        let mut message: Value = match serde_json::from_str(text) {
+            Ok(message) => message,
+            // nothing to fix, and checking says why
+            Err(_) => return self.check(input, text),
+        };
+
+        for pointer in fix(&mut message) {
+            eprintln!("{}: filled in {}", input, pointer);
+        }
+        let fixed = serde_json::to_string_pretty(&message).expect("a JSON value serializes");
+        println!("{}", fixed);
+
+        let report = validate_with(&fixed, self.validation, &self.custom);
+        for violation in &report.violations {
+            eprintln!("{}: {}", input, violation);
+        }
+        report.is_valid()
+    }
+}
+
+/// Checks the server at `address` against each contract, giving the exit
+/// code.
+fn verify(address: &str, contracts: &[String]) -> i32 {
+    let client = Client::new(address);
+    let mut code = VALID;
+    for path in contracts {
+        let contract = match Contract::load(Path::new(path)) {
+            Ok(contract) => contract,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                return FAILED;
+            }
+        };
+
+        let mismatches = contract.verify_at(&client);
+        for mismatch in &mismatches {
+            println!("{}: {}", path, mismatch);
+        }
+        match mismatches.is_empty() {
+            true => println!(
+                "{}: {} interactions kept for {}",
+                path,
+                contract.interactions.len(),
+                contract.consumer
+            ),
+            false => code = INVALID,
+        }
+    }
+    code
+}
+
+/// The file `input`, or standard input for `-`.
+fn read(input: &str) -> io::Result<String> {
+    if input != "-" {
+        return fs::read_to_string(input);
+    }
+    let mut text = String::new();
+    io::stdin().read_to_string(&mut text)?;
+    Ok(text)
+}
+
+fn main() {
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    let code = match arguments.split_first() {
+        Some((command, arguments)) if command == "lint" => match Lint::parse(arguments) {
+            Ok(lint) => lint.run(),
+            Err(e) => {
+                eprintln!("{}\n{}", e, USAGE);
+                FAILED
+            }
+        },
+        Some((command, [address, contracts @ ..]))
+            if command == "verify" && !contracts.is_empty() =>
+        {
+            verify(address, contracts)
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            FAILED
+        }
+    };
+    process::exit(code);
+}
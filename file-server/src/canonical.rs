@@ -0,0 +1,78 @@
+use alloc::string::String;
+use core::fmt::Write;
+
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+
+/// Serializes `value` in canonical form: object keys sorted by their UTF-8
+/// bytes, no insignificant whitespace, and numbers written in their shortest
+/// form with integral floats printed as integers. Two implementations that
+/// agree on the data will produce byte-identical output, which makes the
+/// result suitable for signing and checksumming.
+pub fn to_canonical_string<T: Serialize>(value: &T) -> Result<String, serde_json::Error> {
+    let value = serde_json::to_value(value)?;
+    let mut output = String::new();
+    write_value(&mut output, &value);
+    Ok(output)
+}
+
+fn write_value(output: &mut String, value: &Value) {
+    match value {
+        Value::Null => output.push_str("null"),
+        Value::Bool(true) => output.push_str("true"),
+        Value::Bool(false) => output.push_str("false"),
+        Value::Number(number) => write_number(output, number),
+        Value::String(string) => write_string(output, string),
+        Value::Array(items) => {
+            output.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                write_value(output, item);
+            }
+            output.push(']');
+        }
+        Value::Object(map) => write_object(output, map),
+    }
+}
+
+fn write_object(output: &mut String, map: &Map<String, Value>) {
+    let mut entries: alloc::vec::Vec<(&String, &Value)> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+
+    output.push('{');
+    for (i, (key, value)) in entries.into_iter().enumerate() {
+        if i > 0 {
+            output.push(',');
+        }
+        write_string(output, key);
+        output.push(':');
+        write_value(output, value);
+    }
+    output.push('}');
+}
+
+fn write_number(output: &mut String, number: &Number) {
+    if let Some(n) = number.as_i64() {
+        let _ = write!(output, "{}", n);
+    } else if let Some(n) = number.as_u64() {
+        let _ = write!(output, "{}", n);
+    } else if let Some(n) = number.as_f64() {
+        // integral values inside the exactly-representable range lose their
+        // trailing `.0`, everything else keeps serde_json's shortest form
+        const LIMIT: f64 = 9_007_199_254_740_992.0;
+        if n > -LIMIT && n < LIMIT && n as i64 as f64 == n {
+            let _ = write!(output, "{}", n as i64);
+        } else {
+            let _ = write!(output, "{}", number);
+        }
+    }
+}
+
+fn write_string(output: &mut String, string: &str) {
+    // serde_json's escaping is already minimal and deterministic
+    if let Ok(escaped) = serde_json::to_string(string) {
+        output.push_str(&escaped);
+    }
+}
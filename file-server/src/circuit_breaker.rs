@@ -0,0 +1,217 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// How many of a host's latest requests its error rate is taken over.
+    pub window: usize,
+    /// Requests a host must have had in the window before its circuit can
+    /// open.
+    pub min_requests: usize,
+    /// The share of failed requests in the window that opens the circuit.
+    pub failure_ratio: f64,
+    /// Seconds an open circuit fails requests before letting one through to
+    /// see whether the host is back.
+    pub open_for: u64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            window: 20,
+            min_requests: 5,
+            failure_ratio: 0.5,
+            open_for: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests go through.
+    Closed,
+    /// Requests fail at once, until the time given.
+    Open { until: Instant },
+    /// One request has been let through to probe the host; the others fail
+    /// at once until it is answered.
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn name(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open { .. } => "open",
+            CircuitState::HalfOpen => "half-open",
+        }
+    }
+}
+
+struct Host {
+    state: CircuitState,
+    /// Whether each of the latest requests succeeded, oldest first.
+    outcomes: VecDeque<bool>,
+    successes: u64,
+    failures: u64,
+    rejected: u64,
+    opened: u64,
+}
+
+impl Host {
+    fn new() -> Host {
+        Host {
+            state: CircuitState::Closed,
+            outcomes: VecDeque::new(),
+            successes: 0,
+            failures: 0,
+            rejected: 0,
+            opened: 0,
+        }
+    }
+
+    fn open(&mut self, open_for: Duration) {
+        self.state = CircuitState::Open {
+            until: Instant::now() + open_for,
+        };
+        self.outcomes.clear();
+        self.opened += 1;
+    }
+}
+
+/// Keeps track of how requests to each host fare, and stops sending to a
+/// host that keeps failing: once `failure-ratio` of its latest `window`
+/// requests have failed, its circuit opens and requests to it fail at once
+/// for `open-for` seconds. Then a single request is let through; if it
+/// succeeds the circuit closes again, and if not it stays open for another
+/// `open-for`.
+///
+/// One breaker can be shared by every [`Client`](crate::client::Client) of
+/// an application, see [`Client::with_circuit_breaker`](crate::client::Client::with_circuit_breaker).
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    hosts: Mutex<HashMap<String, Host>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a request to `host` may be sent now; if not, how long until
+    /// one may be.
+    pub fn allow(&self, host: &str) -> Result<(), Duration> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.entry(host.to_string()).or_insert_with(Host::new);
+
+        match host.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open { until } if Instant::now() >= until => {
+                host.state = CircuitState::HalfOpen;
+                Ok(())
+            }
+            CircuitState::Open { until } => {
+                host.rejected += 1;
+                Err(until - Instant::now())
+            }
+            CircuitState::HalfOpen => {
+                host.rejected += 1;
+                Err(Duration::ZERO)
+            }
+        }
+    }
+
+    /// Counts how a request to `host` that [`CircuitBreaker::allow`] let
+    /// through went.
+    pub fn record(&self, host: &str, succeeded: bool) {
+        let open_for = Duration::from_secs(self.config.open_for);
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.entry(host.to_string()).or_insert_with(Host::new);
+
+        if succeeded {
+            host.successes += 1;
+        } else {
+            host.failures += 1;
+        }
+
+        match host.state {
+            CircuitState::HalfOpen if succeeded => {
+                host.state = CircuitState::Closed;
+                host.outcomes.clear();
+            }
+            CircuitState::HalfOpen => host.open(open_for),
+            // answers to requests sent before the circuit opened
+            CircuitState::Open { .. } => {}
+            CircuitState::Closed => {
+                host.outcomes.push_back(succeeded);
+                if host.outcomes.len() > self.config.window.max(1) {
+                    host.outcomes.pop_front();
+                }
+
+                let failed = host
+                    .outcomes
+                    .iter()
+                    .filter(|&&succeeded| !succeeded)
+                    .count();
+                if host.outcomes.len() >= self.config.min_requests
+                    && failed as f64 >= self.config.failure_ratio * host.outcomes.len() as f64
+                {
+                    host.open(open_for);
+                }
+            }
+        }
+    }
+
+    /// Ends a probe of `host` whose request failed for reasons of the
+    /// client's own, without saying anything about the host.
+    pub fn abandon(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(host) = hosts.get_mut(host) {
+            if host.state == CircuitState::HalfOpen {
+                host.state = CircuitState::Open {
+                    until: Instant::now(),
+                };
+            }
+        }
+    }
+
+    pub fn state(&self, host: &str) -> CircuitState {
+        self.hosts
+            .lock()
+            .unwrap()
+            .get(host)
+            .map_or(CircuitState::Closed, |host| host.state)
+    }
+
+    /// Every host's circuit and counts, as `{"10.0.0.7:8080": {"state":
+    /// "open", "successes": 10, "failures": 12, "rejected": 40, "opened":
+    /// 1}}`.
+    pub fn report(&self) -> Value {
+        let hosts = self.hosts.lock().unwrap();
+        let report: BTreeMap<_, _> = hosts
+            .iter()
+            .map(|(name, host)| {
+                (
+                    name.clone(),
+                    json!({
+                        "state": host.state.name(),
+                        "successes": host.successes,
+                        "failures": host.failures,
+                        "rejected": host.rejected,
+                        "opened": host.opened,
+                    }),
+                )
+            })
+            .collect();
+        json!(report)
+    }
+}
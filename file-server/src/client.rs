@@ -0,0 +1,398 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    io::{self, BufReader, Read},
+    net::Shutdown,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    circuit_breaker::CircuitBreaker,
+    contract::Recorder,
+    extensions::{self, ExtensionPolicy, EXTENSIONS_HEADER},
+    headers::Headers,
+    pagination::{next_cursor, PageRequest},
+    protocol::{
+        BatchPart, BatchRequest, BatchResponse, Body, JsontpRequest, JsontpResponse, Utf8Policy,
+        Version,
+    },
+    transport::{write_message, Flush, TcpOptions},
+    wire::WireFormat,
+};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    Decode(String),
+    /// The circuit breaker has stopped requests to the server, for the time
+    /// given.
+    CircuitOpen(Duration),
+    /// The server lacks extensions the client's [`ExtensionPolicy`]
+    /// requires, these ones.
+    MissingExtensions(Vec<String>),
+    #[cfg(feature = "encryption")]
+    Encryption(crate::encryption::EncryptionError),
+}
+
+impl From<io::Error> for ClientError {
+    fn from(error: io::Error) -> Self {
+        ClientError::Io(error)
+    }
+}
+
+/// A blocking client that sends one request per connection.
+pub struct Client {
+    address: String,
+    format: WireFormat,
+    tcp: TcpOptions,
+    breaker: Option<Arc<CircuitBreaker>>,
+    recorder: Option<Arc<Recorder>>,
+    extensions: Option<ExtensionPolicy>,
+    /// The extensions the server said it supports, once it has been asked.
+    supported: Mutex<Option<BTreeSet<String>>>,
+    /// The version the server speaks, once it has turned down a newer one.
+    peer_version: Mutex<Option<Version>>,
+    utf8: Utf8Policy,
+    #[cfg(feature = "encryption")]
+    body_key: Option<(String, crate::encryption::BodyKey)>,
+}
+
+impl Client {
+    pub fn new(address: &str) -> Client {
+        Client {
+            address: address.to_string(),
+            format: WireFormat::Json,
+            tcp: TcpOptions::default(),
+            breaker: None,
+            recorder: None,
+            extensions: None,
+            supported: Mutex::new(None),
+            peer_version: Mutex::new(None),
+            utf8: Utf8Policy::default(),
+            #[cfg(feature = "encryption")]
+            body_key: None,
+        }
+    }
+
+    /// The address of the server the client talks to.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Sets the wire format requests are sent in.
+    pub fn with_format(mut self, format: WireFormat) -> Client {
+        self.format = format;
+        self
+    }
+
+    /// Sets the socket options used for connections to the server.
+    pub fn with_tcp_options(mut self, tcp: TcpOptions) -> Client {
+        self.tcp = tcp;
+        self
+    }
+
+    /// Has `breaker` stop requests to the server while it keeps failing.
+    /// Connection errors, undecodable answers and 5xx statuses count as
+    /// failures.
+    pub fn with_circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Client {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Encrypts every request body with `key`, and transparently decrypts
+    /// response bodies that were encrypted with it.
+    #[cfg(feature = "encryption")]
+    pub fn with_body_key(mut self, key_id: &str, key: crate::encryption::BodyKey) -> Client {
+        self.body_key = Some((key_id.to_string(), key));
+        self
+    }
+
+    /// Sets what is done with a response body that turns out not to be
+    /// UTF-8 once decrypted; by default such a response fails to decrypt.
+    pub fn with_utf8_policy(mut self, policy: Utf8Policy) -> Client {
+        self.utf8 = policy;
+        self
+    }
+
+    /// Hands every request sent with [`Client::send`] and the response it
+    /// got to `recorder`, to be saved as a contract the server can be
+    /// verified against.
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Client {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Has the client ask the server which extensions it supports before
+    /// the first request [`Client::send`] sends, failing with
+    /// [`ClientError::MissingExtensions`] if it lacks one `policy` requires.
+    /// Requests then list the required ones in their `extensions` header,
+    /// so that a server at the same address without them turns them down
+    /// rather than getting them wrong.
+    pub fn with_extensions(mut self, policy: ExtensionPolicy) -> Client {
+        self.extensions = Some(policy);
+        self
+    }
+
+    /// The extensions to use with the server: those the client's policy
+    /// requires and those it prefers that the server has. The server is
+    /// asked with `OPTIONS` the first time, and its answer remembered.
+    pub fn extensions(&self) -> Result<BTreeSet<String>, ClientError> {
+        let Some(policy) = &self.extensions else {
+            return Ok(BTreeSet::new());
+        };
+
+        let mut supported = self.supported.lock().unwrap();
+        let supported = match &mut *supported {
+            Some(supported) => supported,
+            None => {
+                let request = options_request();
+                let response = self.guarded(
+                    || self.send_unguarded(&request),
+                    |response| response.status.code < 500,
+                )?;
+                supported.insert(extensions::from_headers(&response.headers))
+            }
+        };
+        policy
+            .negotiate(supported)
+            .map_err(ClientError::MissingExtensions)
+    }
+
+    /// `request` with the extensions the client requires added, once the
+    /// server is known to support them.
+    fn requiring_extensions<'r>(
+        &self,
+        request: &'r JsontpRequest,
+    ) -> Result<Cow<'r, JsontpRequest>, ClientError> {
+        let Some(policy) = self.extensions.as_ref().filter(|p| !p.required.is_empty()) else {
+            return Ok(Cow::Borrowed(request));
+        };
+        self.extensions()?;
+
+        let mut request = request.clone();
+        request.headers.insert(
+            EXTENSIONS_HEADER.to_string(),
+            extensions::to_header(&policy.required),
+        );
+        Ok(Cow::Owned(request))
+    }
+
+    /// `request` in the version the server speaks, if it is known to speak
+    /// only an older one than that of `request`.
+    fn in_peer_version<'r>(&self, request: Cow<'r, JsontpRequest>) -> Cow<'r, JsontpRequest> {
+        match *self.peer_version.lock().unwrap() {
+            Some(version) if Version::parse(&request.jsontp).is_some_and(|own| own > version) => {
+                let mut request = request.into_owned();
+                version.adapt_request(&mut request);
+                Cow::Owned(request)
+            }
+            _ => request,
+        }
+    }
+
+    /// Sends `request`. A request of 1.1 or later that the server turns down
+    /// with `505 HTTP Version Not Supported` is sent again as 1.0, and so are
+    /// those sent after it.
+    pub fn send(&self, request: &JsontpRequest) -> Result<JsontpResponse, ClientError> {
+        let mut request = self.in_peer_version(self.requiring_extensions(request)?);
+        let mut response = self.guarded(
+            || self.send_unguarded(&request),
+            |response| response.status.code < 500,
+        )?;
+
+        let newer = Version::parse(&request.jsontp).is_some_and(|own| own > Version::V1_0);
+        if response.status.code == 505 && newer {
+            *self.peer_version.lock().unwrap() = Some(Version::V1_0);
+            request = self.in_peer_version(request);
+            response = self.guarded(
+                || self.send_unguarded(&request),
+                |response| response.status.code < 500,
+            )?;
+        }
+
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&request, &response);
+        }
+        Ok(response)
+    }
+
+    fn send_unguarded(&self, request: &JsontpRequest) -> Result<JsontpResponse, ClientError> {
+        #[cfg(feature = "encryption")]
+        let request = &{
+            let mut request = request.clone();
+            if let Some((key_id, key)) = &self.body_key {
+                request
+                    .body
+                    .encrypt(key_id, key)
+                    .map_err(ClientError::Encryption)?;
+            }
+            request
+        };
+
+        let encoded = self.format.encode(request).map_err(ClientError::Decode)?;
+        let data = self.exchange(&encoded)?;
+
+        #[allow(unused_mut)]
+        let mut response: JsontpResponse = WireFormat::sniff(&data)
+            .decode(&data)
+            .map_err(|e| ClientError::Decode(e.to_string()))?;
+
+        #[cfg(feature = "encryption")]
+        if let Some((key_id, key)) = &self.body_key {
+            if response.body.encryption_key_id() == Some(key_id) {
+                let key = *key;
+                response
+                    .body
+                    .decrypt_with(&move |_: &str| Some(key), self.utf8)
+                    .map_err(ClientError::Encryption)?;
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// Sends several requests in one message. Bodies are sent as they are,
+    /// without the encryption [`Client::send`] applies.
+    pub fn send_batch(&self, batch: &BatchRequest) -> Result<BatchResponse, ClientError> {
+        let encoded = self.format.encode(batch).map_err(ClientError::Decode)?;
+        self.guarded(
+            || {
+                let data = self.exchange(&encoded)?;
+                WireFormat::sniff(&data)
+                    .decode(&data)
+                    .map_err(|e| ClientError::Decode(e.to_string()))
+            },
+            |_| true,
+        )
+    }
+
+    /// Sends several requests in one message as an unordered batch, handing
+    /// each response to `on_part` as soon as it arrives. The batch is sent
+    /// as JSON whatever the client's format, so that the parts can be told
+    /// apart as they come in.
+    pub fn send_unordered(
+        &self,
+        batch: &BatchRequest,
+        mut on_part: impl FnMut(BatchPart),
+    ) -> Result<(), ClientError> {
+        let batch = BatchRequest {
+            ordered: false,
+            ..batch.clone()
+        };
+        let encoded = serde_json::to_vec(&batch).map_err(|e| ClientError::Decode(e.to_string()))?;
+
+        self.guarded(
+            || {
+                let mut stream = self.tcp.connect(&self.address)?;
+                write_message(&mut stream, &[&encoded], Flush::EveryMessage)?;
+                stream.shutdown(Shutdown::Write)?;
+
+                for part in
+                    serde_json::Deserializer::from_reader(BufReader::new(stream)).into_iter()
+                {
+                    on_part(part.map_err(|e| ClientError::Decode(e.to_string()))?);
+                }
+                Ok(())
+            },
+            |_| true,
+        )
+    }
+
+    /// Runs `send` past the circuit breaker, if the client has one, telling
+    /// it how the exchange went.
+    fn guarded<T>(
+        &self,
+        send: impl FnOnce() -> Result<T, ClientError>,
+        healthy: impl FnOnce(&T) -> bool,
+    ) -> Result<T, ClientError> {
+        let Some(breaker) = &self.breaker else {
+            return send();
+        };
+        breaker
+            .allow(&self.address)
+            .map_err(ClientError::CircuitOpen)?;
+
+        let result = send();
+        match &result {
+            Ok(answer) => breaker.record(&self.address, healthy(answer)),
+            Err(ClientError::Io(_) | ClientError::Decode(_)) => {
+                breaker.record(&self.address, false)
+            }
+            Err(_) => breaker.abandon(&self.address),
+        }
+        result
+    }
+
+    /// Sends one encoded message on a new connection and reads the answer.
+    fn exchange(&self, encoded: &[u8]) -> Result<Vec<u8>, ClientError> {
+        let mut stream = self.tcp.connect(&self.address)?;
+        write_message(&mut stream, &[encoded], Flush::EveryMessage)?;
+        stream.shutdown(Shutdown::Write)?;
+
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Whether the server accepts connections, checked without sending it
+    /// anything.
+    pub fn reachable(&self) -> bool {
+        self.tcp.connect(&self.address).is_ok()
+    }
+
+    /// Sends `request` once per page of a paginated listing, following the
+    /// `next-cursor` of every response until the last page.
+    pub fn pages(&self, request: &JsontpRequest) -> Pages<'_> {
+        Pages {
+            client: self,
+            request: Some(request.clone()),
+        }
+    }
+}
+
+/// The request asking a server which extensions it supports.
+fn options_request() -> JsontpRequest {
+    JsontpRequest {
+        jsontp: "1.0".to_string(),
+        type_of_request: "request".to_string(),
+        method: "OPTIONS".to_string(),
+        resource: "*".to_string(),
+        headers: Headers::new(),
+        body: Body {
+            content: "{}".to_string(),
+            encoding: "identity".to_string(),
+            other: BTreeMap::new(),
+        },
+        other: BTreeMap::new(),
+    }
+}
+
+/// The responses of a paginated listing, see [`Client::pages`].
+pub struct Pages<'c> {
+    client: &'c Client,
+    /// The request for the next page, until the last one has been fetched.
+    request: Option<JsontpRequest>,
+}
+
+impl Iterator for Pages<'_> {
+    type Item = Result<JsontpResponse, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut request = self.request.take()?;
+        let response = self.client.send(&request);
+
+        if let Ok(response) = &response {
+            if let Some(cursor) = next_cursor(&response.headers) {
+                let page = PageRequest {
+                    cursor: Some(cursor.to_string()),
+                    limit: PageRequest::from_headers(&request.headers).limit,
+                };
+                page.apply(&mut request.headers);
+                self.request = Some(request);
+            }
+        }
+
+        Some(response)
+    }
+}
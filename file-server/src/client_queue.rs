@@ -0,0 +1,184 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    client::{Client, ClientError},
+    protocol::{JsontpRequest, JsontpResponse},
+};
+
+/// A request waiting for a connection.
+struct Job {
+    client: Arc<Client>,
+    request: JsontpRequest,
+    reply: mpsc::Sender<Result<JsontpResponse, ClientError>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// By priority, highest first, then in the order they were submitted.
+    queued: BTreeMap<(Reverse<i32>, u64), Job>,
+    submitted: u64,
+    /// Requests being sent to each host.
+    in_flight: HashMap<String, usize>,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar,
+    per_host: usize,
+}
+
+impl Shared {
+    /// Waits for the most urgent request whose host has room for another,
+    /// or for the queue to be closed and empty.
+    fn next(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let runnable = state
+                .queued
+                .iter()
+                .find(|(_, job)| {
+                    state
+                        .in_flight
+                        .get(job.client.address())
+                        .is_none_or(|&in_flight| in_flight < self.per_host)
+                })
+                .map(|(key, _)| *key);
+
+            if let Some(key) = runnable {
+                let job = state.queued.remove(&key).unwrap();
+                *state
+                    .in_flight
+                    .entry(job.client.address().to_string())
+                    .or_default() += 1;
+                return Some(job);
+            }
+            if state.closed && state.queued.is_empty() {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    fn done(&self, host: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(in_flight) = state.in_flight.get_mut(host) {
+            *in_flight -= 1;
+            if *in_flight == 0 {
+                state.in_flight.remove(host);
+            }
+        }
+        drop(state);
+        self.ready.notify_all();
+    }
+}
+
+/// Sends requests from many callers over a bounded number of connections:
+/// at most `max_connections` at once, and at most `max_per_host` to any
+/// one server. Requests wait in a queue until there is room, the ones with
+/// the highest priority first and otherwise in the order they came.
+///
+/// Dropping the queue waits for every request already submitted to be sent.
+pub struct RequestQueue {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl RequestQueue {
+    pub fn new(max_connections: usize, max_per_host: usize) -> RequestQueue {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            ready: Condvar::new(),
+            per_host: max_per_host.max(1),
+        });
+
+        let workers = (0..max_connections.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    while let Some(job) = shared.next() {
+                        let response = job.client.send(&job.request);
+                        shared.done(job.client.address());
+                        let _ = job.reply.send(response);
+                    }
+                })
+            })
+            .collect();
+
+        RequestQueue { shared, workers }
+    }
+
+    /// Queues `request` for the server `client` talks to. Of the requests
+    /// waiting, those with a higher `priority` are sent first.
+    pub fn submit(&self, client: &Arc<Client>, request: JsontpRequest, priority: i32) -> Pending {
+        let (reply, response) = mpsc::channel();
+        let mut state = self.shared.state.lock().unwrap();
+        let key = (Reverse(priority), state.submitted);
+        state.submitted += 1;
+        state.queued.insert(
+            key,
+            Job {
+                client: client.clone(),
+                request,
+                reply,
+            },
+        );
+        drop(state);
+
+        self.shared.ready.notify_one();
+        Pending { response }
+    }
+
+    /// How many requests are waiting for a connection.
+    pub fn queued(&self) -> usize {
+        self.shared.state.lock().unwrap().queued.len()
+    }
+
+    /// How many requests are being sent right now.
+    pub fn in_flight(&self) -> usize {
+        self.shared.state.lock().unwrap().in_flight.values().sum()
+    }
+}
+
+impl Drop for RequestQueue {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.ready.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The response to a request submitted to a [`RequestQueue`], once it has
+/// been sent.
+pub struct Pending {
+    response: mpsc::Receiver<Result<JsontpResponse, ClientError>>,
+}
+
+impl Pending {
+    /// Waits for the response.
+    pub fn wait(self) -> Result<JsontpResponse, ClientError> {
+        self.response.recv().unwrap_or_else(|_| Err(lost()))
+    }
+
+    /// The response, if it has arrived.
+    pub fn try_wait(&self) -> Option<Result<JsontpResponse, ClientError>> {
+        match self.response.try_recv() {
+            Ok(response) => Some(response),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(lost())),
+        }
+    }
+}
+
+/// The error for a request whose worker went away without answering.
+fn lost() -> ClientError {
+    ClientError::Io(io::Error::other("the request was lost"))
+}
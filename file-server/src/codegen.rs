@@ -0,0 +1,376 @@
+use std::fmt::Write;
+
+use serde_json::Value;
+
+use crate::schema::{ApiSchema, Route};
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+fn words(name: &str) -> impl Iterator<Item = String> + '_ {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+}
+
+/// `name` as a Rust field, variable or method name.
+pub fn snake_case(name: &str) -> String {
+    let mut snake = words(name).collect::<Vec<_>>().join("_");
+
+    if snake.is_empty() || snake.starts_with(|c: char| c.is_ascii_digit()) {
+        snake.insert(0, '_');
+    }
+    match snake.as_str() {
+        // these cannot be raw identifiers
+        "self" | "crate" | "super" => snake + "_",
+        keyword if KEYWORDS.contains(&keyword) => format!("r#{}", snake),
+        _ => snake,
+    }
+}
+
+/// `name` as a Rust type name.
+pub fn pascal_case(name: &str) -> String {
+    let mut pascal: String = words(name)
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect();
+
+    if pascal.is_empty() || pascal.starts_with(|c: char| c.is_ascii_digit()) {
+        pascal.insert(0, 'T');
+    }
+    pascal
+}
+
+/// Writes `text` as a doc comment, one `///` line per line.
+fn doc(output: &mut String, indent: &str, text: &str) {
+    for line in text.lines() {
+        if line.is_empty() {
+            let _ = writeln!(output, "{}///", indent);
+        } else {
+            let _ = writeln!(output, "{}/// {}", indent, line);
+        }
+    }
+}
+
+struct Generator {
+    /// The structs generated so far, each complete.
+    types: String,
+}
+
+impl Generator {
+    /// The Rust type for values matching `schema`, generating a struct
+    /// called `name` if it describes an object with known properties.
+    fn rust_type(&mut self, schema: &Value, name: &str) -> String {
+        let is_string_enum = schema
+            .get("enum")
+            .and_then(Value::as_array)
+            .is_some_and(|values| values.iter().all(Value::is_string));
+
+        match schema.get("type").and_then(Value::as_str) {
+            Some("string") => "String".to_string(),
+            Some("integer") => "i64".to_string(),
+            Some("number") => "f64".to_string(),
+            Some("boolean") => "bool".to_string(),
+            Some("array") => {
+                let items = schema.get("items").unwrap_or(&Value::Null);
+                format!("Vec<{}>", self.rust_type(items, name))
+            }
+            Some("object") if schema.get("properties").is_some_and(Value::is_object) => {
+                self.structure(schema, name);
+                name.to_string()
+            }
+            None if is_string_enum => "String".to_string(),
+            _ => "Value".to_string(),
+        }
+    }
+
+    fn structure(&mut self, schema: &Value, name: &str) {
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|names| names.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let properties = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten();
+
+        let mut fields = String::new();
+        for (property, property_schema) in properties {
+            let field_type = self.rust_type(
+                property_schema,
+                &(name.to_string() + &pascal_case(property)),
+            );
+
+            if let Some(description) = property_schema.get("description").and_then(Value::as_str) {
+                doc(&mut fields, "    ", description);
+            }
+            if required.contains(&property.as_str()) {
+                let _ = writeln!(fields, "    #[serde(rename = {:?})]", property);
+                let _ = writeln!(fields, "    pub {}: {},", snake_case(property), field_type);
+            } else {
+                let _ = writeln!(
+                    fields,
+                    "    #[serde(rename = {:?}, default, skip_serializing_if = \"Option::is_none\")]",
+                    property
+                );
+                let _ = writeln!(
+                    fields,
+                    "    pub {}: Option<{}>,",
+                    snake_case(property),
+                    field_type
+                );
+            }
+        }
+
+        if let Some(description) = schema.get("description").and_then(Value::as_str) {
+            doc(&mut self.types, "", description);
+        }
+        let _ = writeln!(
+            self.types,
+            "#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]"
+        );
+        let _ = writeln!(self.types, "pub struct {} {{\n{}}}\n", name, fields);
+    }
+}
+
+/// The Rust type of a header parameter, and how to turn it into a `Value`.
+fn header_type(schema: &Value) -> (&'static str, &'static str) {
+    match schema.get("type").and_then(Value::as_str) {
+        Some("string") => ("&str", "Value::from"),
+        Some("integer") => ("i64", "Value::from"),
+        Some("boolean") => ("bool", "Value::from"),
+        _ => ("Value", ""),
+    }
+}
+
+/// A `format!` call building the resource of `route` from its parameters.
+fn resource_expression(route: &Route) -> String {
+    let mut pattern = String::new();
+    let mut arguments = String::new();
+
+    for (i, segment) in route.resource.split('/').enumerate() {
+        if i > 0 {
+            pattern.push('/');
+        }
+        match segment
+            .strip_prefix('{')
+            .and_then(|segment| segment.strip_suffix('}'))
+        {
+            Some(name) => {
+                pattern.push_str("{}");
+                let _ = write!(arguments, ", {}", snake_case(name.trim_end_matches('*')));
+            }
+            None => pattern.push_str(&segment.replace('{', "{{").replace('}', "}}")),
+        }
+    }
+
+    format!("format!({:?}{})", pattern, arguments)
+}
+
+fn method(generator: &mut Generator, route: &Route) -> String {
+    let name = pascal_case(&route.name);
+    let mut output = String::new();
+    let mut parameters = String::new();
+    let mut body = String::new();
+
+    for parameter in route.parameters() {
+        let _ = write!(parameters, ", {}: &str", snake_case(parameter));
+    }
+
+    for header in route.headers.iter().filter(|header| header.required) {
+        let (rust_type, conversion) = header_type(&header.schema);
+        let variable = snake_case(&header.name);
+        let _ = write!(parameters, ", {}: {}", variable, rust_type);
+        let _ = writeln!(
+            body,
+            "        headers.insert({:?}.to_string(), {}({}));",
+            header.name, conversion, variable
+        );
+    }
+
+    let content = match &route.request {
+        Some(schema) => {
+            let rust_type = generator.rust_type(schema, &format!("{}Request", name));
+            let _ = write!(parameters, ", content: &{}", rust_type);
+            "serde_json::to_string(content).map_err(|e| ClientError::Decode(e.to_string()))?"
+                .to_string()
+        }
+        None if matches!(route.method.as_str(), "PUT" | "POST") => {
+            let _ = write!(parameters, ", content: &str");
+            "content.to_string()".to_string()
+        }
+        // the protocol wants some content in every request
+        None => "\"{}\".to_string()".to_string(),
+    };
+
+    let returns = match &route.response {
+        Some(schema) => {
+            let rust_type = generator.rust_type(schema, &format!("{}Response", name));
+            format!("Reply<{}>", rust_type)
+        }
+        None => "JsontpResponse".to_string(),
+    };
+
+    if !route.summary.is_empty() {
+        doc(&mut output, "    ", &route.summary);
+        output.push_str("    ///\n");
+    }
+    let _ = writeln!(output, "    /// `{} {}`", route.method, route.resource);
+    let optional: Vec<String> = route
+        .headers
+        .iter()
+        .filter(|header| !header.required)
+        .map(|header| format!("`{}`", header.name))
+        .collect();
+    if !optional.is_empty() {
+        let _ = writeln!(
+            output,
+            "    ///\n    /// Optional headers: {}.",
+            optional.join(", ")
+        );
+    }
+    if !route.statuses.is_empty() {
+        let statuses: Vec<String> = route
+            .statuses
+            .iter()
+            .map(|(code, meaning)| format!("{} ({})", code, meaning))
+            .collect();
+        let _ = writeln!(
+            output,
+            "    ///\n    /// Answers with {}.",
+            statuses.join(", ")
+        );
+    }
+
+    let _ = writeln!(
+        output,
+        "    pub fn {}(&self{}, mut headers: Headers) -> Result<{}, ClientError> {{",
+        snake_case(&route.name),
+        parameters,
+        returns
+    );
+    output.push_str(&body);
+    let _ = writeln!(
+        output,
+        "        let response = self.request({:?}, {}, headers, {})?;",
+        route.method,
+        resource_expression(route),
+        content
+    );
+    if route.response.is_some() {
+        let _ = writeln!(output, "        Reply::new(response)");
+    } else {
+        let _ = writeln!(output, "        Ok(response)");
+    }
+    let _ = writeln!(output, "    }}");
+
+    output
+}
+
+/// A Rust module with a typed client for the API `schema` describes: a
+/// method per route, taking the resource parameters and required headers as
+/// arguments, and structs for the JSON content of requests and responses.
+pub fn generate(schema: &ApiSchema) -> String {
+    let client = format!("{}Client", pascal_case(&schema.title));
+    let mut generator = Generator {
+        types: String::new(),
+    };
+
+    let methods: Vec<String> = schema
+        .routes
+        .iter()
+        .map(|route| method(&mut generator, route))
+        .collect();
+
+    let mut output = String::new();
+    let _ = writeln!(
+        output,
+        "//! A client for the {} API, generated by jsontp-codegen from its\n\
+         //! schema. Regenerate it rather than editing it.\n\
+         #![allow(dead_code, unused_imports, unused_mut, clippy::all)]\n\n\
+         use std::collections::BTreeMap;\n\n\
+         use jsontp::{{\n    \
+             client::{{Client, ClientError}},\n    \
+             headers::Headers,\n    \
+             protocol::{{Body, JsontpRequest, JsontpResponse}},\n\
+         }};\n\
+         use serde::{{de::DeserializeOwned, Deserialize, Serialize}};\n\
+         use serde_json::Value;\n",
+        schema.title
+    );
+
+    output.push_str(
+        "/// A response, and its content parsed if it was successful.\n\
+         #[derive(Debug, Clone)]\n\
+         pub struct Reply<T> {\n    \
+             pub response: JsontpResponse,\n    \
+             pub content: Option<T>,\n\
+         }\n\n\
+         impl<T: DeserializeOwned> Reply<T> {\n    \
+             fn new(response: JsontpResponse) -> Result<Reply<T>, ClientError> {\n        \
+                 let content = if response.status.code < 300 {\n            \
+                     let content = serde_json::from_str(&response.body.content)\n                \
+                         .map_err(|e| ClientError::Decode(e.to_string()))?;\n            \
+                     Some(content)\n        \
+                 } else {\n            \
+                     None\n        \
+                 };\n        \
+                 Ok(Reply { response, content })\n    \
+             }\n\
+         }\n\n",
+    );
+
+    output.push_str(&generator.types);
+
+    let _ = writeln!(
+        output,
+        "pub struct {client} {{\n    \
+             pub client: Client,\n\
+         }}\n\n\
+         impl {client} {{\n    \
+             pub fn new(client: Client) -> {client} {{\n        \
+                 {client} {{ client }}\n    \
+             }}\n\n    \
+             fn request(\n        \
+                 &self,\n        \
+                 method: &str,\n        \
+                 resource: String,\n        \
+                 headers: Headers,\n        \
+                 content: String,\n    \
+             ) -> Result<JsontpResponse, ClientError> {{\n        \
+                 self.client.send(&JsontpRequest {{\n            \
+                     jsontp: \"1.0\".to_string(),\n            \
+                     type_of_request: \"request\".to_string(),\n            \
+                     method: method.to_string(),\n            \
+                     resource,\n            \
+                     headers,\n            \
+                     body: Body {{\n                \
+                         content,\n                \
+                         encoding: \"identity\".to_string(),\n                \
+                         other: BTreeMap::new(),\n            \
+                     }},\n            \
+                     other: BTreeMap::new(),\n        \
+                 }})\n    \
+             }}"
+    );
+
+    for method in methods {
+        output.push('\n');
+        output.push_str(&method);
+    }
+    output.push_str("}\n");
+
+    output
+}
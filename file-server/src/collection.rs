@@ -0,0 +1,233 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::pagination::{paginate, Page, PageRequest};
+
+/// Where in `root` `resource` is, or `None` if it would be outside it: a
+/// resource with a `..`, or with a root or drive of its own after any
+/// leading `/` is taken off, is turned down rather than resolved.
+pub fn resolve(root: &Path, resource: &str) -> Option<PathBuf> {
+    let relative = Path::new(resource.trim_start_matches('/'));
+    let inside = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    inside.then(|| root.join(relative))
+}
+
+/// How much of a collection an operation covers, from the `depth` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    /// The resource itself; for a collection, without its members.
+    Zero,
+    Infinity,
+}
+
+impl Depth {
+    /// Parses `0` or `infinity`.
+    pub fn from_name(name: &str) -> Option<Depth> {
+        match name {
+            "0" => Some(Depth::Zero),
+            "infinity" => Some(Depth::Infinity),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CollectionError {
+    NotFound,
+    /// The destination exists and overwriting was not allowed.
+    Exists,
+    /// The collection the new resource would go in does not exist.
+    MissingParent,
+    /// The destination is inside the source.
+    IntoItself,
+    Io(io::Error),
+}
+
+impl From<io::Error> for CollectionError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => CollectionError::NotFound,
+            io::ErrorKind::AlreadyExists => CollectionError::Exists,
+            _ => CollectionError::Io(error),
+        }
+    }
+}
+
+fn parent_exists(path: &Path) -> bool {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.is_dir(),
+        _ => true,
+    }
+}
+
+/// A member of a collection, as listed by [`list`].
+#[derive(Serialize, Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub size: u64,
+}
+
+/// One page of the members of the collection `path`, in name order. Hidden
+/// entries, such as partial uploads, are left out.
+pub fn list(path: &Path, page: &PageRequest, max_limit: usize) -> io::Result<Page<Entry>> {
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        entries.push(Entry {
+            name,
+            kind: if metadata.is_dir() {
+                "directory"
+            } else {
+                "file"
+            },
+            size: metadata.len(),
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(paginate(
+        entries,
+        |entry| entry.name.clone(),
+        page,
+        max_limit,
+    ))
+}
+
+/// Creates the collection (directory) `path`. Its parent must exist already.
+pub fn make_collection(path: &Path) -> Result<(), CollectionError> {
+    if !parent_exists(path) {
+        return Err(CollectionError::MissingParent);
+    }
+
+    Ok(fs::create_dir(path)?)
+}
+
+/// Whether [`make_collection`] would create `path`, without creating it.
+pub fn check_make_collection(path: &Path) -> Result<(), CollectionError> {
+    if !parent_exists(path) {
+        return Err(CollectionError::MissingParent);
+    }
+    match fs::symlink_metadata(path) {
+        Ok(_) => Err(CollectionError::Exists),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Removes a resource, and everything in it if it is a collection.
+pub fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Checks that `source` may be copied or moved to `destination`, clearing the
+/// destination out of the way if `overwrite` allows it. Returns whether there
+/// was something at the destination.
+fn prepare_destination(
+    source: &Path,
+    destination: &Path,
+    overwrite: bool,
+) -> Result<bool, CollectionError> {
+    let existed = check_destination(source, destination, overwrite)?;
+    if existed {
+        remove(destination)?;
+    }
+    Ok(existed)
+}
+
+/// Whether [`copy`] and [`move_to`] would take `source` to `destination`,
+/// without changing anything. Returns whether something would be replaced.
+pub fn check_destination(
+    source: &Path,
+    destination: &Path,
+    overwrite: bool,
+) -> Result<bool, CollectionError> {
+    if !source.exists() {
+        return Err(CollectionError::NotFound);
+    }
+
+    let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    if absolute(destination).starts_with(absolute(source)) {
+        return Err(CollectionError::IntoItself);
+    }
+
+    if !parent_exists(destination) {
+        return Err(CollectionError::MissingParent);
+    }
+
+    let existed = destination.exists();
+    if existed && !overwrite {
+        return Err(CollectionError::Exists);
+    }
+    Ok(existed)
+}
+
+fn copy_tree(source: &Path, destination: &Path, depth: Depth) -> io::Result<()> {
+    if !source.is_dir() {
+        return fs::copy(source, destination).map(|_| ());
+    }
+
+    fs::create_dir(destination)?;
+    if depth == Depth::Zero {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let target = destination.join(entry.file_name());
+        copy_tree(&entry.path(), &target, depth)?;
+    }
+
+    Ok(())
+}
+
+/// Copies `source` to `destination`; with [`Depth::Zero`] a collection is
+/// copied without its members. Returns whether something was replaced.
+pub fn copy(
+    source: &Path,
+    destination: &Path,
+    depth: Depth,
+    overwrite: bool,
+) -> Result<bool, CollectionError> {
+    let existed = prepare_destination(source, destination, overwrite)?;
+    copy_tree(source, destination, depth)?;
+    Ok(existed)
+}
+
+/// Moves `source` to `destination`, members and all. Returns whether
+/// something was replaced.
+pub fn move_to(
+    source: &Path,
+    destination: &Path,
+    overwrite: bool,
+) -> Result<bool, CollectionError> {
+    let existed = prepare_destination(source, destination, overwrite)?;
+
+    if fs::rename(source, destination).is_err() {
+        // renaming fails across filesystems, where copying does not
+        copy_tree(source, destination, Depth::Infinity)?;
+        remove(source)?;
+    }
+
+    Ok(existed)
+}
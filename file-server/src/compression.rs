@@ -0,0 +1,1195 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use base64::{
+    engine::general_purpose::STANDARD, read::DecoderReader, write::EncoderStringWriter, Engine,
+};
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+    Compress, Compression, Decompress, FlushCompress, FlushDecompress,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    middleware::{Context, Middleware},
+    protocol::{Body, JsontpRequest, JsontpResponse, Utf8Policy, BINARY_ENCODING},
+    tenants::TENANT_VALUE,
+    transform::BodyTransform,
+};
+
+/// The body field naming the dictionary `content` was compressed with.
+pub const DICTIONARY_FIELD: &str = "dictionary";
+
+/// Dictionaries by the names they are negotiated with.
+pub type Dictionaries = BTreeMap<String, Vec<u8>>;
+
+/// A body encoding other than `identity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    /// zlib-wrapped deflate, as HTTP's `deflate` is.
+    Deflate,
+    Br,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "br" => Some(Encoding::Br),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Br => "br",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Whether the encoding makes use of a dictionary; the others ignore one.
+    pub fn takes_dictionary(self) -> bool {
+        #[cfg(feature = "zstd")]
+        return self == Encoding::Zstd;
+        #[cfg(not(feature = "zstd"))]
+        false
+    }
+
+    /// Compresses whatever is written to it into `writer`, a piece at a
+    /// time. `level` is clamped to what the encoding supports: 0 to 9 for
+    /// gzip and deflate, 0 to 11 for br and 1 to 22 for zstd.
+    pub fn encoder<W: Write>(
+        self,
+        writer: W,
+        level: u32,
+        dictionary: Option<&[u8]>,
+    ) -> io::Result<Encoder<W>> {
+        let _ = dictionary;
+        Ok(match self {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(writer, Compression::new(level.min(9)))),
+            Encoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(writer, Compression::new(level.min(9))))
+            }
+            Encoding::Br => Encoder::Br(Box::new(brotli::CompressorWriter::new(
+                writer,
+                BUFFER_SIZE,
+                level.min(11),
+                22,
+            ))),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::with_dictionary(
+                writer,
+                level.clamp(1, 22) as i32,
+                dictionary.unwrap_or_default(),
+            )?),
+        })
+    }
+
+    /// Reads `reader` decompressed, a piece at a time, with the dictionary
+    /// it was compressed with if any.
+    pub fn decoder<R: Read>(self, reader: R, dictionary: Option<&[u8]>) -> io::Result<Decoder<R>> {
+        let _ = dictionary;
+        Ok(match self {
+            Encoding::Gzip => Decoder::Gzip(GzDecoder::new(reader)),
+            Encoding::Deflate => Decoder::Deflate(ZlibDecoder::new(reader)),
+            Encoding::Br => Decoder::Br(Box::new(brotli::Decompressor::new(reader, BUFFER_SIZE))),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Decoder::Zstd(zstd::stream::read::Decoder::with_dictionary(
+                io::BufReader::new(reader),
+                dictionary.unwrap_or_default(),
+            )?),
+        })
+    }
+
+    /// `data` compressed at `level`.
+    pub fn compress(self, data: &[u8], level: u32) -> Vec<u8> {
+        // writing into a vector cannot fail
+        let mut encoder = self.encoder(Vec::new(), level, None).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    pub fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        self.decoder(data, None)?.read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+/// How much the codecs take in or give out at once.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Compresses what is written to it as it arrives, so a body can be
+/// compressed while it is read from a file or produced, never being whole in
+/// memory uncompressed. Nothing is complete until [`Encoder::finish`].
+pub enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Deflate(ZlibEncoder<W>),
+    Br(Box<brotli::CompressorWriter<W>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes out the end of the compressed stream and gives the writer
+    /// back.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+            Encoder::Br(mut encoder) => {
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Deflate(encoder) => encoder.write(buf),
+            Encoder::Br(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Deflate(encoder) => encoder.flush(),
+            Encoder::Br(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Decompresses a reader as it is read from, so a compressed body can be
+/// written out a piece at a time.
+pub enum Decoder<R: Read> {
+    Gzip(GzDecoder<R>),
+    Deflate(ZlibDecoder<R>),
+    Br(Box<brotli::Decompressor<R>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, io::BufReader<R>>),
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.read(buf),
+            Decoder::Deflate(decoder) => decoder.read(buf),
+            Decoder::Br(decoder) => decoder.read(buf),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CompressionError {
+    /// The body is in an encoding that is not known.
+    UnknownEncoding,
+    /// The body names a dictionary that is not known.
+    UnknownDictionary,
+    /// A message of a compression context is not the one expected next.
+    OutOfSequence,
+    /// The content is not base64, or not what its encoding says.
+    Malformed,
+    /// The decompressed content is not UTF-8 text.
+    NotText,
+    /// The decompressed content is longer than it was allowed to be.
+    TooLarge,
+}
+
+impl CompressionError {
+    /// What a server answers a body turned down for this with.
+    pub fn response(&self) -> (String, u16) {
+        match self {
+            CompressionError::UnknownEncoding => (
+                "Unsupported Media Type: unknown body encoding".to_string(),
+                415,
+            ),
+            CompressionError::UnknownDictionary => (
+                "Bad Request: unknown compression dictionary".to_string(),
+                400,
+            ),
+            CompressionError::OutOfSequence => {
+                ("Bad Request: message out of sequence".to_string(), 400)
+            }
+            CompressionError::Malformed => (
+                "Bad Request: content is not what its encoding says".to_string(),
+                400,
+            ),
+            CompressionError::NotText => (
+                "Unsupported Media Type: content is not UTF-8 text".to_string(),
+                415,
+            ),
+            CompressionError::TooLarge => ("Payload Too Large".to_string(), 413),
+        }
+    }
+}
+
+impl Body {
+    /// Replaces `content` with its compressed bytes, base64 encoded, and
+    /// `encoding` with the name of `with`.
+    pub fn compress(&mut self, with: Encoding, level: u32) {
+        let compressed = with.compress(self.content.as_bytes(), level);
+        self.content = STANDARD.encode(compressed);
+        self.encoding = with.name().to_string();
+    }
+
+    /// Compresses everything `reader` gives into the content, a piece at a
+    /// time, so that only the compressed bytes are ever held whole. A
+    /// `(name, dictionary)` is used when `with` takes one, and its name put
+    /// in the body's `dictionary` field.
+    pub fn compress_from(
+        &mut self,
+        reader: &mut impl Read,
+        with: Encoding,
+        level: u32,
+        dictionary: Option<(&str, &[u8])>,
+    ) -> io::Result<()> {
+        let dictionary = dictionary.filter(|_| with.takes_dictionary());
+        let mut encoder = with.encoder(
+            EncoderStringWriter::new(&STANDARD),
+            level,
+            dictionary.map(|(_, bytes)| bytes),
+        )?;
+        io::copy(reader, &mut encoder)?;
+
+        self.content = encoder.finish()?.into_inner();
+        self.encoding = with.name().to_string();
+        match dictionary {
+            Some((name, _)) => self
+                .other
+                .insert(DICTIONARY_FIELD.to_string(), Value::from(name)),
+            None => self.other.remove(DICTIONARY_FIELD),
+        };
+        Ok(())
+    }
+
+    /// The content as it was before it was compressed, decompressed as it is
+    /// read rather than all at once; for an `identity` body, the content
+    /// itself, and for a [`BINARY_ENCODING`] one, the bytes it encodes. A
+    /// body naming a dictionary is read with the one of that name in
+    /// `dictionaries`.
+    pub fn content_reader(
+        &self,
+        dictionaries: &Dictionaries,
+    ) -> Result<Box<dyn Read + '_>, CompressionError> {
+        if self.encoding == "identity" || self.encoding.is_empty() {
+            return Ok(Box::new(self.content.as_bytes()));
+        }
+        if self.encoding == BINARY_ENCODING {
+            return Ok(Box::new(DecoderReader::new(
+                self.content.as_bytes(),
+                &STANDARD,
+            )));
+        }
+        let encoding =
+            Encoding::from_name(&self.encoding).ok_or(CompressionError::UnknownEncoding)?;
+        let dictionary = match self.other.get(DICTIONARY_FIELD) {
+            Some(name) => Some(
+                name.as_str()
+                    .and_then(|name| dictionaries.get(name))
+                    .ok_or(CompressionError::UnknownDictionary)?
+                    .as_slice(),
+            ),
+            None => None,
+        };
+
+        let compressed = DecoderReader::new(self.content.as_bytes(), &STANDARD);
+        let decoder = encoding
+            .decoder(compressed, dictionary)
+            .map_err(|_| CompressionError::Malformed)?;
+        Ok(Box::new(decoder))
+    }
+
+    /// Turns a compressed body back into `identity`; one that is not
+    /// compressed is left as it is. Content that turns out not to be text
+    /// fails with [`CompressionError::NotText`], and content that comes to
+    /// more than `max_size` bytes with [`CompressionError::TooLarge`], having
+    /// read no further than that.
+    ///
+    /// ```
+    /// use jsontp::{
+    ///     compression::{CompressionError, Dictionaries, Encoding},
+    ///     protocol::Body,
+    /// };
+    ///
+    /// let mut body = Body {
+    ///     content: "a".repeat(100_000),
+    ///     encoding: "identity".to_string(),
+    ///     other: Default::default(),
+    /// };
+    /// body.compress(Encoding::Gzip, 6);
+    ///
+    /// let dictionaries = Dictionaries::new();
+    /// assert_eq!(
+    ///     body.clone().decompress(&dictionaries, 1024),
+    ///     Err(CompressionError::TooLarge)
+    /// );
+    /// assert_eq!(body.decompress(&dictionaries, 100_000), Ok(()));
+    /// assert_eq!(body.content.len(), 100_000);
+    /// ```
+    pub fn decompress(
+        &mut self,
+        dictionaries: &Dictionaries,
+        max_size: usize,
+    ) -> Result<(), CompressionError> {
+        self.decompress_with(dictionaries, Utf8Policy::Reject, max_size)
+    }
+
+    /// Like [`Body::decompress`], but content that is not text is dealt
+    /// with as `policy` says, which may leave the body in
+    /// [`BINARY_ENCODING`].
+    pub fn decompress_with(
+        &mut self,
+        dictionaries: &Dictionaries,
+        policy: Utf8Policy,
+        max_size: usize,
+    ) -> Result<(), CompressionError> {
+        if matches!(self.encoding.as_str(), "identity" | "" | BINARY_ENCODING) {
+            return Ok(());
+        }
+        let mut decompressed = Vec::new();
+        self.content_reader(dictionaries)?
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|_| CompressionError::Malformed)?;
+        if decompressed.len() > max_size {
+            return Err(CompressionError::TooLarge);
+        }
+
+        self.set_bytes(decompressed, policy)
+            .map_err(|_| CompressionError::NotText)?;
+        self.other.remove(DICTIONARY_FIELD);
+        Ok(())
+    }
+}
+
+/// How hard each encoding works.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Levels {
+    pub gzip: u32,
+    pub deflate: u32,
+    pub br: u32,
+    #[cfg(feature = "zstd")]
+    pub zstd: u32,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Levels {
+            gzip: 6,
+            deflate: 6,
+            br: 4,
+            #[cfg(feature = "zstd")]
+            zstd: 3,
+        }
+    }
+}
+
+impl Levels {
+    pub fn of(&self, encoding: Encoding) -> u32 {
+        match encoding {
+            Encoding::Gzip => self.gzip,
+            Encoding::Deflate => self.deflate,
+            Encoding::Br => self.br,
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => self.zstd,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// The encodings offered, most preferred first, among those a request
+    /// accepts.
+    pub encodings: Vec<String>,
+    pub levels: Levels,
+    /// Content shorter than this many bytes is sent as it is.
+    pub min_size: usize,
+    /// The content types compressed, as `text/html` or `text/*`; every type
+    /// when empty. Content without a `content-type` is compressed only when
+    /// this is empty.
+    pub types: Vec<String>,
+    /// Content types never compressed, which win over `types`; by default
+    /// the ones that are compressed already.
+    pub skip_types: Vec<String>,
+    /// Compression contexts kept between messages; off when unset.
+    pub contexts: Option<ContextsConfig>,
+    /// How many compressed files are kept, so that each version of a file
+    /// is compressed once per encoding rather than for every request; 0 to
+    /// keep none.
+    pub cache: usize,
+    /// What else the files in `cache` are kept apart by.
+    pub cache_key: CacheKeyConfig,
+    /// zstd dictionaries, by the names requests ask for them with
+    /// `zstd;dictionary=<name>` in `accept-encoding`, read from the files
+    /// given.
+    #[cfg(feature = "zstd")]
+    #[serde(deserialize_with = "read_dictionaries")]
+    pub dictionaries: Dictionaries,
+}
+
+#[cfg(feature = "zstd")]
+fn read_dictionaries<'de, D>(deserializer: D) -> Result<Dictionaries, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    BTreeMap::<String, std::path::PathBuf>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, path)| match std::fs::read(&path) {
+            Ok(dictionary) => Ok((name, dictionary)),
+            Err(e) => Err(serde::de::Error::custom(format!(
+                "could not read dictionary {}: {}",
+                path.display(),
+                e
+            ))),
+        })
+        .collect()
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            encodings: [
+                #[cfg(feature = "zstd")]
+                "zstd",
+                "br",
+                "gzip",
+                "deflate",
+            ]
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+            levels: Levels::default(),
+            min_size: 1024,
+            types: Vec::new(),
+            skip_types: [
+                "image/*",
+                "video/*",
+                "audio/*",
+                "font/woff",
+                "font/woff2",
+                "application/gzip",
+                "application/zip",
+                "application/zstd",
+                "application/x-7z-compressed",
+                "application/x-bzip2",
+                "application/x-xz",
+            ]
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+            contexts: None,
+            cache: 0,
+            cache_key: CacheKeyConfig::default(),
+            #[cfg(feature = "zstd")]
+            dictionaries: Dictionaries::new(),
+        }
+    }
+}
+
+/// What a compressed file is kept by besides its resource, the ETag of its
+/// version and how it was compressed, for content that is not the same for
+/// every request, or should not be shared between users.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CacheKeyConfig {
+    /// Request headers whose values are part of the key, as
+    /// `accept-language`. A header given more than once counts with every
+    /// value, in order, and one not given is a variant of its own.
+    pub headers: Vec<String>,
+    /// Whether the signed-in user is part of the key, so that no one is
+    /// sent a file compressed for someone else.
+    pub user: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ContextsConfig {
+    /// How many contexts are kept at most.
+    pub max: usize,
+    /// Seconds after which an unused context is dropped when room is
+    /// needed; if none has been idle that long, the one used least recently
+    /// is dropped instead.
+    pub idle: u64,
+    /// The most bytes a request body compressed with a context may come to
+    /// once decompressed.
+    pub max_size: usize,
+}
+
+impl Default for ContextsConfig {
+    fn default() -> Self {
+        ContextsConfig {
+            max: 256,
+            idle: 300,
+            max_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Whether `content_type`, parameters and all, is one of `patterns`.
+fn matches_type(patterns: &[String], content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix("/*") {
+            Some(prefix) => media_type
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/')),
+            None => media_type == pattern.to_ascii_lowercase(),
+        })
+}
+
+/// The encodings `accept-encoding` lists, as a comma separated string or an
+/// array, each with the dictionary it names if any, leaving out those
+/// refused with `;q=0`.
+fn accepted(request: &JsontpRequest) -> Vec<(String, Option<String>)> {
+    let names: Vec<&str> = match request.headers.get("accept-encoding") {
+        Some(Value::String(list)) => list.split(',').collect(),
+        Some(Value::Array(list)) => list.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let mut parts = name.split(';').map(str::trim);
+            let name = parts.next()?.to_ascii_lowercase();
+            let mut dictionary = None;
+            let mut refused = false;
+            for parameter in parts {
+                match parameter.split_once('=') {
+                    Some(("q", q)) => refused |= q.parse::<f64>().is_ok_and(|q| q == 0.0),
+                    Some(("dictionary", value)) => dictionary = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            (!name.is_empty() && !refused).then_some((name, dictionary))
+        })
+        .collect()
+}
+
+/// How to compress a response, as agreed with its request.
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated<'a> {
+    pub encoding: Encoding,
+    pub level: u32,
+    /// The name and bytes of the dictionary to compress with.
+    pub dictionary: Option<(&'a str, &'a [u8])>,
+}
+
+impl CompressionConfig {
+    /// How to compress the response to `request`, if at all.
+    pub fn negotiate(&self, request: &JsontpRequest) -> Option<Negotiated<'_>> {
+        let accepted = accepted(request);
+
+        self.encodings.iter().find_map(|name| {
+            let encoding = Encoding::from_name(name)?;
+            let mut offers = accepted
+                .iter()
+                .filter(|(accepted, _)| accepted == name || accepted == "*")
+                .peekable();
+            offers.peek()?;
+
+            let dictionary = offers
+                .filter_map(|(_, dictionary)| self.dictionary(dictionary.as_deref()?))
+                .find(|_| encoding.takes_dictionary());
+            Some(Negotiated {
+                encoding,
+                level: self.levels.of(encoding),
+                dictionary,
+            })
+        })
+    }
+
+    /// The dictionary called `name`, if there is one.
+    #[cfg(feature = "zstd")]
+    fn dictionary(&self, name: &str) -> Option<(&str, &[u8])> {
+        self.dictionaries
+            .get_key_value(name)
+            .map(|(name, dictionary)| (name.as_str(), dictionary.as_slice()))
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn dictionary(&self, _name: &str) -> Option<(&str, &[u8])> {
+        None
+    }
+
+    /// Whether the response to `request` is for its compression context
+    /// rather than for negotiation.
+    pub fn uses_context(&self, request: &JsontpRequest) -> bool {
+        self.contexts.is_some() && context_id(request).is_some()
+    }
+
+    /// The dictionaries compressed request bodies may name.
+    pub fn dictionaries(&self) -> Dictionaries {
+        #[cfg(feature = "zstd")]
+        return self.dictionaries.clone();
+        #[cfg(not(feature = "zstd"))]
+        Dictionaries::new()
+    }
+
+    /// Whether content of `content_type` and `size` bytes is worth
+    /// compressing.
+    pub fn compresses(&self, content_type: Option<&str>, size: usize) -> bool {
+        size >= self.min_size && self.compresses_type(content_type)
+    }
+
+    /// Whether content of `content_type` is compressed, whatever its size.
+    pub fn compresses_type(&self, content_type: Option<&str>) -> bool {
+        match content_type {
+            Some(content_type) => {
+                !matches_type(&self.skip_types, content_type)
+                    && (self.types.is_empty() || matches_type(&self.types, content_type))
+            }
+            None => self.types.is_empty(),
+        }
+    }
+}
+
+/// The encoding of bodies compressed with a context: raw deflate, each
+/// message sync flushed, with the history of earlier messages.
+pub const CONTEXT_ENCODING: &str = "deflate-stream";
+
+/// The request header naming the context to use.
+pub const CONTEXT_HEADER: &str = "compression-context";
+
+/// The body fields saying which context compressed `content`, and which of
+/// its messages it is, counting from 0.
+pub const CONTEXT_FIELD: &str = "context";
+pub const SEQUENCE_FIELD: &str = "sequence";
+
+/// The context `request` names, if it names a reasonable one.
+fn context_id(request: &JsontpRequest) -> Option<&str> {
+    request
+        .headers
+        .get(CONTEXT_HEADER)
+        .and_then(Value::as_str)
+        .filter(|id| (1..=64).contains(&id.len()))
+}
+
+/// The key the context a client calls `id` is kept under. Clients only ever
+/// get their own contexts, as another's would let them learn what it was
+/// sent from how well theirs compress, or drop it by sending it a message
+/// out of sequence, so the key has the tenant, user and address of the
+/// client too.
+fn context_key(context: &Context, id: &str) -> String {
+    let tenant = context.values.get(TENANT_VALUE).and_then(Value::as_str);
+    format!(
+        "{}\0{}\0{}\0{}",
+        tenant.unwrap_or(""),
+        context.user.as_deref().unwrap_or(""),
+        context
+            .peer
+            .map(|peer| peer.to_canonical().to_string())
+            .unwrap_or_default(),
+        id
+    )
+}
+
+/// A compression context: one deflate stream each way, which only ever go
+/// forward, so that a message can refer back to the ones before it.
+pub struct CompressionContext {
+    compress: Compress,
+    decompress: Decompress,
+    /// Messages compressed and decompressed so far.
+    sent: u64,
+    received: u64,
+    used: Instant,
+}
+
+impl CompressionContext {
+    pub fn new(level: u32) -> CompressionContext {
+        CompressionContext {
+            compress: Compress::new(Compression::new(level.min(9)), false),
+            decompress: Decompress::new(false),
+            sent: 0,
+            received: 0,
+            used: Instant::now(),
+        }
+    }
+
+    /// `data` compressed after everything compressed before it, and its
+    /// number in the stream.
+    pub fn compress(&mut self, data: &[u8]) -> (Vec<u8>, u64) {
+        let mut compressed = Vec::with_capacity(data.len() / 2 + 64);
+        let mut input = data;
+        loop {
+            if compressed.capacity() - compressed.len() < 64 {
+                compressed.reserve(compressed.capacity());
+            }
+            let before = self.compress.total_in();
+            // only fails on a corrupted stream, which one we made cannot be
+            self.compress
+                .compress_vec(input, &mut compressed, FlushCompress::Sync)
+                .unwrap();
+            input = &input[(self.compress.total_in() - before) as usize..];
+
+            // a flush is done once it leaves room to spare
+            if input.is_empty() && compressed.len() < compressed.capacity() {
+                break;
+            }
+        }
+
+        self.sent += 1;
+        (compressed, self.sent - 1)
+    }
+
+    /// Decompresses the next message of the other side's stream, which
+    /// must be number `sequence`, failing with
+    /// [`CompressionError::TooLarge`] once it comes to more than `max_size`
+    /// bytes. The stream cannot be carried on after that.
+    ///
+    /// ```
+    /// use jsontp::compression::{CompressionContext, CompressionError};
+    ///
+    /// let (mut client, mut server) = (CompressionContext::new(6), CompressionContext::new(6));
+    /// let (small, sequence) = client.compress(b"hello");
+    /// assert_eq!(server.decompress(&small, sequence, 1024).unwrap(), b"hello");
+    ///
+    /// let (large, sequence) = client.compress(&[b'a'; 100_000]);
+    /// assert_eq!(
+    ///     server.decompress(&large, sequence, 1024),
+    ///     Err(CompressionError::TooLarge)
+    /// );
+    /// ```
+    pub fn decompress(
+        &mut self,
+        data: &[u8],
+        sequence: u64,
+        max_size: usize,
+    ) -> Result<Vec<u8>, CompressionError> {
+        if sequence != self.received {
+            return Err(CompressionError::OutOfSequence);
+        }
+
+        let mut decompressed = Vec::with_capacity(data.len() * 4 + 64);
+        let mut input = data;
+        loop {
+            if decompressed.capacity() - decompressed.len() < 64 {
+                decompressed.reserve(decompressed.capacity());
+            }
+            let before = self.decompress.total_in();
+            self.decompress
+                .decompress_vec(input, &mut decompressed, FlushDecompress::Sync)
+                .map_err(|_| CompressionError::Malformed)?;
+            input = &input[(self.decompress.total_in() - before) as usize..];
+            if decompressed.len() > max_size {
+                return Err(CompressionError::TooLarge);
+            }
+
+            if input.is_empty() && decompressed.len() < decompressed.capacity() {
+                break;
+            }
+        }
+
+        self.received += 1;
+        Ok(decompressed)
+    }
+}
+
+/// The compression contexts of clients, by the ids they chose and who they
+/// are.
+pub struct CompressionContexts {
+    config: ContextsConfig,
+    level: u32,
+    contexts: Mutex<HashMap<String, Arc<Mutex<CompressionContext>>>>,
+}
+
+impl CompressionContexts {
+    pub fn new(config: ContextsConfig, level: u32) -> CompressionContexts {
+        CompressionContexts {
+            config,
+            level,
+            contexts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The context kept under `key`, made if there is none.
+    pub fn get(&self, key: &str) -> Arc<Mutex<CompressionContext>> {
+        let mut contexts = self.contexts.lock().unwrap();
+        if let Some(context) = contexts.get(key) {
+            return context.clone();
+        }
+
+        if contexts.len() >= self.config.max {
+            let idle = Duration::from_secs(self.config.idle);
+            // a context in use is locked, and is not idle anyway
+            let used = |context: &Arc<Mutex<CompressionContext>>| {
+                context.try_lock().map(|context| context.used).ok()
+            };
+            contexts.retain(|_, context| used(context).is_none_or(|used| used.elapsed() < idle));
+
+            if contexts.len() >= self.config.max {
+                let oldest = contexts
+                    .iter()
+                    .filter_map(|(id, context)| Some((used(context)?, id.clone())))
+                    .min();
+                if let Some((_, id)) = oldest {
+                    contexts.remove(&id);
+                }
+            }
+        }
+
+        let context = Arc::new(Mutex::new(CompressionContext::new(self.level)));
+        contexts.insert(key.to_string(), context.clone());
+        context
+    }
+
+    /// Forgets the context kept under `key`, once the two sides no longer
+    /// agree on it.
+    pub fn drop_context(&self, key: &str) {
+        self.contexts.lock().unwrap().remove(key);
+    }
+}
+
+/// What a compressed body was made from and how, and the parts of the
+/// request [`CacheKeyConfig`] names.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    resource: String,
+    etag: String,
+    encoding: Encoding,
+    level: u32,
+    dictionary: Option<String>,
+    /// The values of each of the configured headers.
+    headers: Vec<Vec<String>>,
+    user: Option<String>,
+}
+
+impl CacheKey {
+    fn new(
+        config: &CacheKeyConfig,
+        request: &JsontpRequest,
+        context: &Context,
+        etag: &str,
+        negotiated: &Negotiated,
+    ) -> CacheKey {
+        CacheKey {
+            resource: request.resource.clone(),
+            etag: etag.to_string(),
+            encoding: negotiated.encoding,
+            level: negotiated.level,
+            dictionary: negotiated.dictionary.map(|(name, _)| name.to_string()),
+            headers: config
+                .headers
+                .iter()
+                .map(|name| {
+                    request
+                        .headers
+                        .get_all(name)
+                        .map(Value::to_string)
+                        .collect()
+                })
+                .collect(),
+            user: context.user.clone().filter(|_| config.user),
+        }
+    }
+}
+
+#[derive(Default)]
+struct CachedBodies {
+    bodies: HashMap<CacheKey, Body>,
+    /// Oldest first.
+    order: VecDeque<CacheKey>,
+}
+
+/// Compressed bodies of resources, by the ETag of the version compressed,
+/// how it was compressed and what `key` adds, the oldest dropped first when
+/// more than `max` are kept.
+///
+/// ```
+/// use jsontp::{
+///     compression::{CacheKeyConfig, CompressedCache, Encoding, Negotiated},
+///     middleware::Context,
+///     protocol::JsontpRequest,
+/// };
+///
+/// let request = |language: &str| -> JsontpRequest {
+///     serde_json::from_value(serde_json::json!({
+///         "jsontp": "1.0",
+///         "type": "request",
+///         "method": "GET",
+///         "resource": "index.html",
+///         "headers": { "accept-language": language },
+///         "body": { "content": "{}", "encoding": "identity" },
+///     }))
+///     .unwrap()
+/// };
+/// let key = CacheKeyConfig {
+///     headers: vec!["accept-language".to_string()],
+///     user: false,
+/// };
+/// let cache = CompressedCache::new(16, key);
+/// let gzip = Negotiated { encoding: Encoding::Gzip, level: 6, dictionary: None };
+/// let context = Context::default();
+///
+/// let english = request("en");
+/// cache.insert(&english, &context, "\"1\"", &gzip, english.body.clone());
+/// assert!(cache.get(&english, &context, "\"1\"", &gzip).is_some());
+/// assert!(cache.get(&request("fr"), &context, "\"1\"", &gzip).is_none());
+/// ```
+pub struct CompressedCache {
+    max: usize,
+    key: CacheKeyConfig,
+    cached: Mutex<CachedBodies>,
+}
+
+impl CompressedCache {
+    pub fn new(max: usize, key: CacheKeyConfig) -> CompressedCache {
+        CompressedCache {
+            max,
+            key,
+            cached: Mutex::new(CachedBodies::default()),
+        }
+    }
+
+    /// The body of version `etag` of the resource of `request` compressed as
+    /// `negotiated`, if one is kept for requests like it.
+    pub fn get(
+        &self,
+        request: &JsontpRequest,
+        context: &Context,
+        etag: &str,
+        negotiated: &Negotiated,
+    ) -> Option<Body> {
+        let key = CacheKey::new(&self.key, request, context, etag, negotiated);
+        self.cached.lock().unwrap().bodies.get(&key).cloned()
+    }
+
+    pub fn insert(
+        &self,
+        request: &JsontpRequest,
+        context: &Context,
+        etag: &str,
+        negotiated: &Negotiated,
+        body: Body,
+    ) {
+        let key = CacheKey::new(&self.key, request, context, etag, negotiated);
+        let mut cached = self.cached.lock().unwrap();
+        if cached.bodies.insert(key.clone(), body).is_some() {
+            return;
+        }
+        cached.order.push_back(key);
+        while cached.order.len() > self.max {
+            if let Some(oldest) = cached.order.pop_front() {
+                cached.bodies.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Compresses response bodies with the most preferred of the configured
+/// encodings that the request's `accept-encoding` lists, unless the content
+/// is too small, of a type not to compress, or would not get any smaller.
+///
+/// With `contexts` configured, a client may instead name a context of its
+/// own in a `compression-context` header. Every response to it is then
+/// compressed as the next message of one deflate stream, so that repeated
+/// content costs next to nothing, and its request bodies may be compressed
+/// the same way with a stream of the client's. Such bodies are in
+/// [`CONTEXT_ENCODING`], with `context` and `sequence` fields; a message out
+/// of sequence is turned down with 400 and the context dropped, after which
+/// the client starts a new one.
+///
+/// Register it after middleware that sign or encrypt bodies, so that it
+/// compresses the content before they see it.
+pub struct CompressionMiddleware {
+    pub config: CompressionConfig,
+    pub contexts: Option<CompressionContexts>,
+}
+
+impl CompressionMiddleware {
+    pub fn new(config: CompressionConfig) -> CompressionMiddleware {
+        let contexts = config.contexts.clone().map(|contexts| {
+            CompressionContexts::new(contexts, config.levels.of(Encoding::Deflate))
+        });
+        CompressionMiddleware { config, contexts }
+    }
+
+    /// Decompresses a request body compressed with the client's context
+    /// `id`, kept under `key`.
+    fn decompress_request(
+        &self,
+        contexts: &CompressionContexts,
+        id: &str,
+        key: &str,
+        body: &mut Body,
+        policy: Utf8Policy,
+    ) -> Result<(), (String, u16)> {
+        let bad_request = |message: &str| Err((format!("Bad Request: {}", message), 400));
+        if body.other.get(CONTEXT_FIELD).and_then(Value::as_str) != Some(id) {
+            return bad_request("body is not of the request's compression context");
+        }
+        let Some(sequence) = body.other.get(SEQUENCE_FIELD).and_then(Value::as_u64) else {
+            return bad_request("body has no sequence");
+        };
+        let Ok(compressed) = STANDARD.decode(&body.content) else {
+            return bad_request("content is not base64");
+        };
+
+        let context = contexts.get(key);
+        let mut context = context.lock().unwrap();
+        context.used = Instant::now();
+        let max_size = contexts.config.max_size;
+        let decompressed = match context.decompress(&compressed, sequence, max_size) {
+            // the stream is intact, so the context is kept
+            Ok(data) => match body.set_bytes(data, policy) {
+                Ok(()) => Ok(()),
+                Err(e) => return Err(e.response()),
+            },
+            Err(e) => Err(e),
+        };
+
+        match decompressed {
+            Ok(()) => {
+                body.other.remove(CONTEXT_FIELD);
+                body.other.remove(SEQUENCE_FIELD);
+                Ok(())
+            }
+            Err(e) => {
+                drop(context);
+                contexts.drop_context(key);
+                if e == CompressionError::TooLarge {
+                    return Err(e.response());
+                }
+                bad_request(match e {
+                    CompressionError::OutOfSequence => "message out of sequence",
+                    _ => "content is not what its compression context says",
+                })
+            }
+        }
+    }
+}
+
+impl Middleware for CompressionMiddleware {
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        if request.body.encoding != CONTEXT_ENCODING {
+            return Ok(());
+        }
+        let (Some(contexts), Some(id)) = (&self.contexts, context_id(request)) else {
+            return Err((
+                "Bad Request: body names no known compression context".to_string(),
+                400,
+            ));
+        };
+
+        let id = id.to_string();
+        let key = context_key(context, &id);
+        self.decompress_request(contexts, &id, &key, &mut request.body, context.utf8)
+    }
+
+    fn on_response(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        self.transform(request, context, response)
+    }
+}
+
+impl BodyTransform for CompressionMiddleware {
+    fn transform(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        let Some(request) = request else {
+            return;
+        };
+        if response.body.encoding != "identity" {
+            return;
+        }
+
+        let content_type = response.headers.get("content-type").and_then(Value::as_str);
+        // small messages are what a context is for, so any size will do
+        if let (Some(contexts), Some(id)) = (&self.contexts, context_id(request)) {
+            if !self.config.compresses_type(content_type) {
+                return;
+            }
+
+            let context = contexts.get(&context_key(context, id));
+            let mut context = context.lock().unwrap();
+            context.used = Instant::now();
+
+            let (compressed, sequence) = context.compress(response.body.content.as_bytes());
+            response.body.content = STANDARD.encode(compressed);
+            response.body.encoding = CONTEXT_ENCODING.to_string();
+            response
+                .body
+                .other
+                .insert(CONTEXT_FIELD.to_string(), Value::from(id));
+            response
+                .body
+                .other
+                .insert(SEQUENCE_FIELD.to_string(), Value::from(sequence));
+            return;
+        }
+
+        if !self
+            .config
+            .compresses(content_type, response.body.content.len())
+        {
+            return;
+        }
+
+        let Some(negotiated) = self.config.negotiate(request) else {
+            return;
+        };
+
+        let mut compressed = response.body.clone();
+        // reading from a string and writing into one cannot fail
+        compressed
+            .compress_from(
+                &mut response.body.content.as_bytes(),
+                negotiated.encoding,
+                negotiated.level,
+                negotiated.dictionary,
+            )
+            .unwrap();
+        // base64 costs a third, which small or random content does not make
+        // up for
+        if compressed.content.len() < response.body.content.len() {
+            response.body = compressed;
+        }
+    }
+}
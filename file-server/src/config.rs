@@ -0,0 +1,812 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use toml::{
+    de::{DeArray, DeString, DeTable, DeValue, Deserializer},
+    Spanned,
+};
+
+use crate::{
+    acl::AclRule,
+    bandwidth::BandwidthConfig,
+    cors::CorsPolicy,
+    file_metadata::MetadataCacheConfig,
+    header_table::HeaderTableConfig,
+    idempotency::IdempotencyConfig,
+    ip_filter::IpFilter,
+    minify::MinifyConfig,
+    overrides::RouteOverride,
+    protocol::{CustomHeaders, Utf8Policy, Validation},
+    response_hooks::ResponseHeaders,
+    search::SearchConfig,
+    security_headers::SecurityHeaders,
+    tenants::TenantConfig,
+    throttle::ThrottleConfig,
+    transport::{Limits, TcpOptions},
+    upload::Durability,
+    warm_up::WarmUpConfig,
+};
+
+/// The server configuration, read from a TOML file.
+#[derive(Deserialize, Debug)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub address: String,
+    /// Number of worker processes sharing the port through `SO_REUSEPORT`.
+    /// One (the default) serves from a single process.
+    pub workers: usize,
+    /// Serve from this many mio event loops instead of a thread per
+    /// connection.
+    #[cfg(feature = "mio")]
+    pub event_loop_threads: Option<usize>,
+    /// Run handlers on a pool of threads rather than on the event loops.
+    #[cfg(feature = "mio")]
+    pub blocking_pool: Option<crate::task::PoolConfig>,
+    pub acl: Vec<AclRule>,
+    pub cors: Option<CorsPolicy>,
+    #[cfg(feature = "csrf")]
+    pub csrf: Option<crate::csrf::CsrfConfig>,
+    /// Compress response bodies; off unless configured.
+    #[cfg(feature = "compression")]
+    pub compression: Option<crate::compression::CompressionConfig>,
+    /// Record requests that change something; off unless configured.
+    #[cfg(feature = "audit")]
+    pub audit: Option<crate::audit::AuditConfig>,
+    /// A file of users who can sign in with Basic credentials.
+    #[cfg(feature = "users")]
+    pub users_file: Option<std::path::PathBuf>,
+    /// Hand out bearer tokens to the users in `users-file`; off unless
+    /// configured.
+    #[cfg(feature = "tokens")]
+    pub tokens: Option<crate::tokens::TokenConfig>,
+    /// Accept JWTs from an OpenID Connect or OAuth2 provider; off unless
+    /// configured.
+    #[cfg(feature = "oidc")]
+    pub oidc: Option<crate::oidc::OidcConfig>,
+    pub security_headers: Option<SecurityHeaders>,
+    /// Headers put on every response, even ones the middleware never see;
+    /// none unless configured.
+    pub response_headers: Option<ResponseHeaders>,
+    /// Translations of `human-message` by language and then status code, as
+    /// `[messages.nl] 404 = "..."`, used besides the built-in ones for
+    /// requests whose `accept-language` prefers them.
+    pub messages: BTreeMap<String, BTreeMap<u16, String>>,
+    /// The language of files whose names give none, sent as their `language`
+    /// header; `en-GB` unless configured. `index.de.html` is served for
+    /// `index.html` to requests preferring `de`, with `language: de`.
+    pub language: String,
+    /// Checked when a connection is accepted; reloaded whenever the config
+    /// file changes.
+    pub ip_filter: IpFilter,
+    pub limits: Limits,
+    pub tcp: TcpOptions,
+    /// Cache file metadata for the file server; off unless configured.
+    pub metadata_cache: Option<MetadataCacheConfig>,
+    /// How much syncing `PUT` does before answering.
+    pub durability: Durability,
+    /// Answer searches on a resource of their own; off unless configured.
+    pub search: Option<SearchConfig>,
+    /// Replay responses to retried unsafe requests with the same
+    /// `idempotency-key`; off unless configured.
+    pub idempotency: Option<IdempotencyConfig>,
+    /// Whether requests with headers or fields the server does not know are
+    /// turned down (`strict`, the default) or accepted (`lenient`).
+    pub validation: Validation,
+    /// Headers accepted besides the ones the server knows, by name or by
+    /// prefix.
+    pub custom_headers: CustomHeaders,
+    /// What is done with files and request bodies that are not UTF-8 text:
+    /// they are turned down with `415` (`reject`, the default), have what
+    /// is not text replaced (`replace`), or are sent base64 encoded
+    /// (`binary`).
+    pub utf8: Utf8Policy,
+    /// Limits, encodings and authentication that differ for some resources,
+    /// given as `[[overrides]]` tables.
+    pub overrides: Vec<RouteOverride>,
+    /// Limit how many requests each user or address makes; off unless
+    /// configured.
+    pub throttle: Option<ThrottleConfig>,
+    /// Limit how many bytes each user or address is served; off unless
+    /// configured.
+    pub bandwidth: Option<BandwidthConfig>,
+    /// Serve the server's counters at `.well-known/jsontp-metrics`.
+    pub metrics: bool,
+    /// Number header names, so that clients can send and receive numbers
+    /// in their place; off unless configured.
+    pub header_table: Option<HeaderTableConfig>,
+    /// Resources requested at startup to fill the caches; none unless
+    /// configured.
+    pub warm_up: Option<WarmUpConfig>,
+    /// Minify files of the types listed before they are served; off unless
+    /// configured.
+    pub minify: Option<MinifyConfig>,
+    /// The directory whose files are served; the working directory unless
+    /// configured. Resources that would lead outside it are answered with
+    /// `403`.
+    pub root: PathBuf,
+    /// Carry out `PUT`, `DELETE`, `MKCOL`, `COPY`, `MOVE` and uploads,
+    /// which are otherwise answered with `405`; off unless configured.
+    pub writable: bool,
+    /// The most bytes a resumable upload may come to, counted after chunks
+    /// are decompressed; chunks that would take it further are answered
+    /// with `413`.
+    pub max_upload_size: u64,
+    /// Check and log writes, answering them with what would have happened,
+    /// without carrying them out.
+    pub dry_run: bool,
+    /// Sites served from this process with their own root, users, limits
+    /// and audit log, picked by `host` header or resource prefix. Requests
+    /// for none of them are served as configured above.
+    pub tenants: Vec<TenantConfig>,
+    /// Who to run as once the listener is bound; the user started as unless
+    /// configured.
+    #[cfg(unix)]
+    pub privileges: Option<crate::privileges::PrivilegesConfig>,
+    /// Detach from the terminal and run in the background; off unless
+    /// configured.
+    #[cfg(unix)]
+    pub daemon: Option<crate::daemon::DaemonConfig>,
+    /// JSON logs, draining on `SIGTERM` and a health endpoint, for running
+    /// in a container; off unless configured.
+    #[cfg(unix)]
+    pub container: Option<crate::container::ContainerConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "localhost:8080".to_string(),
+            workers: 1,
+            #[cfg(feature = "mio")]
+            event_loop_threads: None,
+            #[cfg(feature = "mio")]
+            blocking_pool: None,
+            acl: Vec::new(),
+            cors: None,
+            #[cfg(feature = "csrf")]
+            csrf: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "audit")]
+            audit: None,
+            #[cfg(feature = "users")]
+            users_file: None,
+            #[cfg(feature = "tokens")]
+            tokens: None,
+            #[cfg(feature = "oidc")]
+            oidc: None,
+            security_headers: None,
+            response_headers: None,
+            messages: BTreeMap::new(),
+            language: "en-GB".to_string(),
+            ip_filter: IpFilter::default(),
+            limits: Limits::default(),
+            tcp: TcpOptions::default(),
+            metadata_cache: None,
+            durability: Durability::default(),
+            search: None,
+            idempotency: None,
+            validation: Validation::default(),
+            custom_headers: CustomHeaders::new(),
+            utf8: Utf8Policy::default(),
+            overrides: Vec::new(),
+            throttle: None,
+            bandwidth: None,
+            metrics: false,
+            header_table: None,
+            warm_up: None,
+            minify: None,
+            root: PathBuf::from("."),
+            writable: false,
+            max_upload_size: 1024 * 1024 * 1024,
+            dry_run: false,
+            tenants: Vec::new(),
+            #[cfg(unix)]
+            privileges: None,
+            #[cfg(unix)]
+            daemon: None,
+            #[cfg(unix)]
+            container: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        Config::check(path).map_err(|problems| problems.join("\n"))
+    }
+
+    /// Reads the config at `path`, finding every problem with it rather than
+    /// stopping at the first: syntax errors, unknown keys, values of the
+    /// wrong type, unset environment variables and options that conflict,
+    /// each with the file, line and column it is at.
+    ///
+    /// `${NAME}` in a string is replaced by the environment variable `NAME`,
+    /// or by `default` for `${NAME:-default}` when it is unset, and `$${` is
+    /// a plain `${`. A top-level `include`, a file or a list of them relative
+    /// to the one naming them, is read first, later files and then the
+    /// including one taking precedence over what they set.
+    pub fn check(path: &Path) -> Result<Config, Vec<String>> {
+        let mut sources = Vec::new();
+        let mut problems = Vec::new();
+        read_sources(path, None, &mut sources, &mut Vec::new(), &mut problems);
+
+        // in the order they are in the files
+        let report = |mut problems: Vec<(Option<Range<usize>>, String)>| {
+            problems.sort_by_key(|(span, _)| span.as_ref().map(|span| span.start));
+            problems
+                .into_iter()
+                .map(|(span, message)| match span {
+                    Some(span) => {
+                        let (path, line, column) = position(&sources, span.start);
+                        format!("{}:{}:{}: {}", path.display(), line, column, message)
+                    }
+                    None => format!("{}: {}", path.display(), message),
+                })
+                .collect()
+        };
+        if sources.is_empty() {
+            return Err(report(problems));
+        }
+
+        // outlives the document, which borrows numbers and booleans from it
+        let mut whole = Vec::new();
+        // what follows syntax errors is not worth reading
+        let found = problems.len();
+        let mut document = document(&sources, 0, &mut problems);
+        if problems.len() > found {
+            return Err(report(problems));
+        }
+        interpolate_table(&mut document, &mut whole, &mut problems);
+        retype_table(&mut document, &whole);
+        let end = sources.last().map_or(0, |source| source.end());
+        let mut document = Spanned::new(0..end, document);
+
+        // each key in error is taken out and the rest read again, until
+        // what is left reads
+        // errors in a table a key was taken out of, or in a value that could
+        // not be interpolated, follow from a problem already found
+        let mut explained: Vec<Range<usize>> = problems
+            .iter()
+            .filter_map(|(span, _)| span.clone())
+            .collect();
+        let config = loop {
+            let error = match Config::deserialize(Deserializer::from(document.clone())) {
+                Ok(config) => break config,
+                Err(e) => e,
+            };
+            let Some(span) = error.span() else {
+                problems.push((None, error.message().to_string()));
+                return Err(report(problems));
+            };
+
+            if !explained.iter().any(|explained| contains(&span, explained)) {
+                problems.push((Some(span.clone()), error.message().to_string()));
+            }
+            let whole = document.span();
+            match remove(document.get_mut(), whole, &span) {
+                Some(table) => explained.push(table),
+                None => return Err(report(problems)),
+            }
+        };
+
+        for (key, message) in config.conflicts() {
+            problems.push((find(document.get_ref(), &key), message));
+        }
+        match problems.is_empty() {
+            true => Ok(config),
+            false => Err(report(problems)),
+        }
+    }
+
+    /// Options that read but do not work together, each with the dotted
+    /// key, array indexes included, it is best reported at.
+    fn conflicts(&self) -> Vec<(String, String)> {
+        let mut conflicts = Vec::new();
+
+        #[cfg(unix)]
+        if self.privileges.as_ref().is_some_and(|p| p.chroot) && self.root.is_absolute() {
+            conflicts.push((
+                "root".to_string(),
+                "an absolute root is outside the chroot".to_string(),
+            ));
+        }
+
+        for (i, tenant) in self.tenants.iter().enumerate() {
+            if self.tenants[..i]
+                .iter()
+                .any(|other| other.name == tenant.name)
+            {
+                conflicts.push((
+                    format!("tenants.{}.name", i),
+                    format!("there is already a tenant called {:?}", tenant.name),
+                ));
+            }
+            if tenant.hosts.is_empty() && tenant.prefix.is_none() {
+                conflicts.push((
+                    format!("tenants.{}", i),
+                    format!(
+                        "tenant {:?} has neither hosts nor a prefix, so no request is for it",
+                        tenant.name
+                    ),
+                ));
+            }
+            #[cfg(unix)]
+            if self.privileges.as_ref().is_some_and(|p| p.chroot) && tenant.root.is_absolute() {
+                conflicts.push((
+                    format!("tenants.{}.root", i),
+                    "an absolute root is outside the chroot".to_string(),
+                ));
+            }
+        }
+
+        #[cfg(feature = "mio")]
+        if self.blocking_pool.is_some() && self.event_loop_threads.is_none() {
+            conflicts.push((
+                "blocking-pool".to_string(),
+                "there is no blocking pool without event-loop-threads".to_string(),
+            ));
+        }
+
+        #[cfg(feature = "tokens")]
+        if self.tokens.is_some() && self.users_file.is_none() {
+            conflicts.push((
+                "tokens".to_string(),
+                "tokens are issued to users, and there is no users-file".to_string(),
+            ));
+        }
+
+        #[cfg(unix)]
+        if let Some(container) = &self.container {
+            if container.health_address.as_ref() == Some(&self.address) {
+                conflicts.push((
+                    "container.health-address".to_string(),
+                    "health checks cannot be answered at the address the server listens on"
+                        .to_string(),
+                ));
+            }
+            if self.daemon.is_some() {
+                conflicts.push((
+                    "daemon".to_string(),
+                    "a container stops when the process it started exits, so the server cannot \
+                     run as a daemon in one"
+                        .to_string(),
+                ));
+            }
+        }
+
+        conflicts
+    }
+
+    /// Takes the address from the environment where it is set, as container
+    /// platforms set it: `BIND` is the host, or host and port, to listen on,
+    /// and `PORT` the port, over either.
+    pub fn apply_environment(&mut self) {
+        let variable = |name| env::var(name).ok().filter(|value| !value.is_empty());
+        let (bind, port) = (variable("BIND"), variable("PORT"));
+        if bind.is_none() && port.is_none() {
+            return;
+        }
+
+        let (mut host, mut address_port) = split_address(&self.address);
+        if let Some(bind) = &bind {
+            let (bind_host, bind_port) = split_address(bind);
+            host = bind_host;
+            address_port = bind_port.or(address_port);
+        }
+        let port = port.as_deref().or(address_port).unwrap_or("8080");
+
+        self.address = match host.contains(':') && !host.starts_with('[') {
+            true => format!("[{}]:{}", host, port),
+            false => format!("{}:{}", host, port),
+        };
+    }
+}
+
+/// `address`'s host, and its port if it has one.
+fn split_address(address: &str) -> (&str, Option<&str>) {
+    match address.rsplit_once(':') {
+        // an IPv6 address has colons of its own, and brackets when a port follows
+        Some((host, port))
+            if !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit())
+                && (host.starts_with('[') || !host.contains(':')) =>
+        {
+            (host, Some(port))
+        }
+        _ => (address, None),
+    }
+}
+
+/// A config file, read whole, and where its spans start among those of
+/// the files read with it, so that a span says which file it is in.
+struct Source {
+    path: PathBuf,
+    text: String,
+    start: usize,
+    /// The sources for the files it includes, in order.
+    includes: Vec<usize>,
+}
+
+impl Source {
+    fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+}
+
+/// Reads `path` into `sources`, and then the files it includes, giving its
+/// index. `named_at` is where an including file names it.
+fn read_sources(
+    path: &Path,
+    named_at: Option<Range<usize>>,
+    sources: &mut Vec<Source>,
+    including: &mut Vec<PathBuf>,
+    problems: &mut Vec<(Option<Range<usize>>, String)>,
+) -> Option<usize> {
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if including.contains(&canonical) {
+        problems.push((named_at, format!("{} includes itself", path.display())));
+        return None;
+    }
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            problems.push((
+                named_at,
+                format!("could not read {}: {}", path.display(), e),
+            ));
+            return None;
+        }
+    };
+
+    // a byte apart, so that the end of one is not the start of the next
+    let start = sources.last().map_or(0, |source| source.end() + 1);
+    let index = sources.len();
+    sources.push(Source {
+        path: path.to_path_buf(),
+        text,
+        start,
+        includes: Vec::new(),
+    });
+
+    // syntax errors are reported once every file is read
+    let Ok(document) = DeTable::parse(&sources[index].text) else {
+        return Some(index);
+    };
+    let mut names = Vec::new();
+    match document.get_ref().get("include") {
+        None => {}
+        Some(include) => match include.get_ref() {
+            DeValue::String(name) => names.push((name.to_string(), include.span())),
+            DeValue::Array(items) => {
+                for item in items.iter() {
+                    match item.get_ref().as_str() {
+                        Some(name) => names.push((name.to_string(), item.span())),
+                        None => problems.push((
+                            shift(Some(item.span()), start),
+                            "include is a list of files".to_string(),
+                        )),
+                    }
+                }
+            }
+            _ => problems.push((
+                shift(Some(include.span()), start),
+                "include is a file or a list of files".to_string(),
+            )),
+        },
+    }
+    drop(document);
+
+    including.push(canonical);
+    let directory = path.parent().unwrap_or(Path::new(""));
+    for (name, span) in names {
+        let named_at = shift(Some(span), start);
+        let included = read_sources(
+            &directory.join(name),
+            named_at,
+            sources,
+            including,
+            problems,
+        );
+        if let Some(included) = included {
+            sources[index].includes.push(included);
+        }
+    }
+    including.pop();
+    Some(index)
+}
+
+fn shift(span: Option<Range<usize>>, by: usize) -> Option<Range<usize>> {
+    span.map(|span| span.start + by..span.end + by)
+}
+
+/// The document in `sources[index]`, with what the files it includes set
+/// beneath it, and every span made unique to its file.
+fn document<'a>(
+    sources: &'a [Source],
+    index: usize,
+    problems: &mut Vec<(Option<Range<usize>>, String)>,
+) -> DeTable<'a> {
+    let source = &sources[index];
+    let (own, errors) = DeTable::parse_recoverable(&source.text);
+    problems.extend(
+        errors
+            .iter()
+            .map(|e| (shift(e.span(), source.start), e.message().to_string())),
+    );
+    let mut own = shift_table(own.into_inner(), source.start);
+    own.remove("include");
+
+    let mut document = DeTable::new();
+    for &included in &source.includes {
+        merge(&mut document, self::document(sources, included, problems));
+    }
+    merge(&mut document, own);
+    document
+}
+
+fn shift_table(table: DeTable, by: usize) -> DeTable {
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let span = key.span();
+            (
+                Spanned::new(span.start + by..span.end + by, key.into_inner()),
+                shift_value(value, by),
+            )
+        })
+        .collect()
+}
+
+fn shift_value(value: Spanned<DeValue>, by: usize) -> Spanned<DeValue> {
+    let span = value.span();
+    let value = match value.into_inner() {
+        DeValue::Table(table) => DeValue::Table(shift_table(table, by)),
+        DeValue::Array(items) => {
+            let mut shifted = DeArray::new();
+            for item in items.iter() {
+                shifted.push(shift_value(item.clone(), by));
+            }
+            DeValue::Array(shifted)
+        }
+        value => value,
+    };
+    Spanned::new(span.start + by..span.end + by, value)
+}
+
+/// Sets what `over` sets in `under`, key by key in tables both have.
+fn merge<'a>(under: &mut DeTable<'a>, over: DeTable<'a>) {
+    for (key, value) in over {
+        let value = match (under.remove(key.get_ref().as_ref()), value) {
+            (Some(below), value) => match (below.into_inner(), value.get_ref()) {
+                (DeValue::Table(mut below), DeValue::Table(_)) => {
+                    let span = value.span();
+                    let DeValue::Table(above) = value.into_inner() else {
+                        unreachable!()
+                    };
+                    merge(&mut below, above);
+                    Spanned::new(span, DeValue::Table(below))
+                }
+                _ => value,
+            },
+            (None, value) => value,
+        };
+        under.insert(key, value);
+    }
+}
+
+fn interpolate_table(
+    table: &mut DeTable,
+    whole: &mut Vec<(Range<usize>, String)>,
+    problems: &mut Vec<(Option<Range<usize>>, String)>,
+) {
+    for (_, value) in table.iter_mut() {
+        interpolate_value(value, whole, problems);
+    }
+}
+
+/// Interpolates the strings in `value`, adding to `whole` those that were
+/// one `${...}` and nothing else, which may be other than strings.
+fn interpolate_value(
+    value: &mut Spanned<DeValue>,
+    whole: &mut Vec<(Range<usize>, String)>,
+    problems: &mut Vec<(Option<Range<usize>>, String)>,
+) {
+    let span = value.span();
+    match value.get_mut() {
+        DeValue::String(text) if text.contains('$') => match interpolate(text) {
+            Ok(interpolated) => {
+                if text.starts_with("${") && text.find('}') == Some(text.len() - 1) {
+                    whole.push((span, interpolated.clone()));
+                }
+                *text = interpolated.into();
+            }
+            Err(message) => problems.push((Some(span), message)),
+        },
+        DeValue::Table(table) => interpolate_table(table, whole, problems),
+        DeValue::Array(items) => {
+            for item in items.iter_mut() {
+                interpolate_value(item, whole, problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Makes the strings that were one `${...}` numbers or booleans, where
+/// what they were replaced with reads as one, so that
+/// `workers = "${WORKERS}"` is a number.
+fn retype_table<'a>(table: &mut DeTable<'a>, whole: &'a [(Range<usize>, String)]) {
+    for (_, value) in table.iter_mut() {
+        retype_value(value, whole);
+    }
+}
+
+fn retype_value<'a>(value: &mut Spanned<DeValue<'a>>, whole: &'a [(Range<usize>, String)]) {
+    let span = value.span();
+    match value.get_mut() {
+        DeValue::String(_) => {
+            let Some((_, text)) = whole.iter().find(|(whole, _)| *whole == span) else {
+                return;
+            };
+            if let Ok(parsed) = DeValue::parse(text) {
+                if matches!(
+                    parsed.get_ref(),
+                    DeValue::Integer(_) | DeValue::Float(_) | DeValue::Boolean(_)
+                ) {
+                    *value = Spanned::new(span, parsed.into_inner());
+                }
+            }
+        }
+        DeValue::Table(table) => retype_table(table, whole),
+        DeValue::Array(items) => {
+            for item in items.iter_mut() {
+                retype_value(item, whole);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `text` with `${NAME}` and `${NAME:-default}` replaced from the
+/// environment, and `$${` with `${`.
+fn interpolate(text: &str) -> Result<String, String> {
+    let mut interpolated = String::new();
+    let mut rest = text;
+    while let Some(at) = rest.find('$') {
+        interpolated.push_str(&rest[..at]);
+        rest = &rest[at..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            interpolated.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            interpolated.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let Some((reference, after)) = after.split_once('}') else {
+            return Err(format!("{:?} has a ${{ that is not closed", text));
+        };
+
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        match (env::var(name), default) {
+            (Ok(value), _) => interpolated.push_str(&value),
+            (Err(_), Some(default)) => interpolated.push_str(default),
+            (Err(_), None) => {
+                return Err(format!("environment variable {} is not set", name));
+            }
+        }
+        rest = after;
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+/// The file `offset` is in, and the line and column, from 1, it is at.
+fn position(sources: &[Source], offset: usize) -> (&Path, usize, usize) {
+    let source = sources
+        .iter()
+        .rev()
+        .find(|source| source.start <= offset)
+        .unwrap_or(&sources[0]);
+    let text = &source.text;
+    let before = &text[..(offset - source.start).min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        &source.path,
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+fn contains(outer: &Range<usize>, inner: &Range<usize>) -> bool {
+    outer.start <= inner.start && inner.end <= outer.end
+}
+
+/// Whether `error` is in the key or value of an entry, or in an entry
+/// nested in it. Tables set across files span several ranges, so this
+/// looks at each key.
+fn holds(key: &Spanned<DeString>, value: &Spanned<DeValue>, error: &Range<usize>) -> bool {
+    if contains(&key.span(), error) || contains(&value.span(), error) {
+        return true;
+    }
+    match value.get_ref() {
+        DeValue::Table(table) => table.iter().any(|(key, value)| holds(key, value, error)),
+        DeValue::Array(items) => items.iter().any(|item| match item.get_ref() {
+            DeValue::Table(table) => table.iter().any(|(key, value)| holds(key, value, error)),
+            _ => contains(&item.span(), error),
+        }),
+        _ => false,
+    }
+}
+
+/// Takes out of `table`, which spans `span`, the innermost key whose entry
+/// holds `error`, giving the span of the table it was taken from, or `None`
+/// if no key holds it.
+fn remove(table: &mut DeTable, span: Range<usize>, error: &Range<usize>) -> Option<Range<usize>> {
+    let mut found = None;
+    for (key, value) in table.iter_mut() {
+        if !holds(key, value, error) {
+            continue;
+        }
+
+        let value_span = value.span();
+        let inner = match value.get_mut() {
+            DeValue::Table(inner) => remove(inner, value_span, error),
+            DeValue::Array(items) => items.iter_mut().find_map(|item| {
+                let item_span = item.span();
+                match item.get_mut() {
+                    DeValue::Table(inner) => remove(inner, item_span, error),
+                    _ => None,
+                }
+            }),
+            _ => None,
+        };
+        if inner.is_some() {
+            return inner;
+        }
+        found = Some(key.get_ref().to_string());
+        break;
+    }
+
+    table.remove(found?.as_str());
+    Some(span)
+}
+
+/// The span of the innermost part of the dotted `key` that `table` has.
+fn find(table: &DeTable, key: &str) -> Option<Range<usize>> {
+    let mut span = None;
+    let mut value: Option<&DeValue> = None;
+    for part in key.split('.') {
+        let next = match value {
+            None => table.get_key_value(part),
+            Some(DeValue::Table(inner)) => inner.get_key_value(part),
+            Some(DeValue::Array(items)) => {
+                let Some(item) = part.parse().ok().and_then(|i: usize| items.get(i)) else {
+                    break;
+                };
+                span = Some(item.span());
+                value = Some(item.get_ref());
+                continue;
+            }
+            Some(_) => None,
+        };
+        let Some((name, next)) = next else {
+            break;
+        };
+        span = Some(name.span());
+        value = Some(next.get_ref());
+    }
+    span
+}
@@ -0,0 +1,231 @@
+use std::{
+    io::{self, Read, Write},
+    mem,
+    net::{SocketAddr, TcpListener, TcpStream},
+    process, ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    embedded::wake,
+    events::{Event, Subscriber},
+    metrics::METRICS,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ContainerConfig {
+    /// Log connections and requests to standard output as JSON lines,
+    /// rather than as plain text.
+    pub json_logs: bool,
+    /// Seconds to let connections being served finish after `SIGTERM` or
+    /// `SIGINT`, before exiting anyway.
+    pub drain_timeout: u64,
+    /// Where to answer health checks, such as `0.0.0.0:8081`; nowhere unless
+    /// set.
+    pub health_address: Option<String>,
+}
+
+impl Default for ContainerConfig {
+    fn default() -> Self {
+        ContainerConfig {
+            json_logs: true,
+            drain_timeout: 10,
+            health_address: None,
+        }
+    }
+}
+
+/// Prints a JSON line to standard output for each event, for log collectors
+/// that read a container's output.
+pub struct JsonLog;
+
+impl Subscriber for JsonLog {
+    fn on_event(&self, event: &Event) {
+        let line = match *event {
+            Event::Accepted { connection, peer } => json!({
+                "event": "accepted",
+                "connection": connection,
+                "peer": peer.to_string(),
+            }),
+            Event::RequestStart { .. } => return,
+            Event::RequestEnd {
+                connection,
+                request,
+                response,
+                elapsed,
+            } => json!({
+                "event": "request",
+                "connection": connection,
+                "method": request.map(|request| &request.method),
+                "resource": request.map(|request| &request.resource),
+                "status": response.status.code,
+                "elapsed-ms": elapsed.as_secs_f64() * 1000.0,
+            }),
+            Event::Errored {
+                connection,
+                peer,
+                error,
+            } => json!({
+                "event": "error",
+                "connection": connection,
+                "peer": peer.to_string(),
+                "error": error,
+            }),
+            Event::Closed {
+                connection,
+                peer,
+                bytes_read,
+                bytes_written,
+                open_for,
+            } => json!({
+                "event": "closed",
+                "connection": connection,
+                "peer": peer.to_string(),
+                "bytes-read": bytes_read,
+                "bytes-written": bytes_written,
+                "open-ms": open_for.as_secs_f64() * 1000.0,
+            }),
+        };
+        log(line);
+    }
+}
+
+/// Prints `line` as [`JsonLog`] does, with the time added.
+pub fn log(mut line: Value) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    line["time"] = json!(time.as_secs_f64());
+    println!("{}", line);
+}
+
+/// `SIGTERM` and `SIGINT`, blocked so that a thread can wait for them rather
+/// than have them end the process there and then.
+pub struct Termination {
+    signals: libc::sigset_t,
+}
+
+impl Termination {
+    /// Blocks `SIGTERM` and `SIGINT` on the calling thread and every thread
+    /// it starts after, so this has to be called before any other thread is
+    /// started.
+    pub fn block() -> io::Result<Termination> {
+        // SAFETY: the set is initialised by `sigemptyset` before it is used,
+        // and holds only valid signal numbers
+        unsafe {
+            let mut signals = mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            libc::sigaddset(&mut signals, libc::SIGTERM);
+            libc::sigaddset(&mut signals, libc::SIGINT);
+
+            match libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut()) {
+                0 => Ok(Termination { signals }),
+                error => Err(io::Error::from_raw_os_error(error)),
+            }
+        }
+    }
+
+    /// Waits for one of the signals, giving its number.
+    pub fn wait(&self) -> io::Result<libc::c_int> {
+        let mut signal = 0;
+        // SAFETY: both pointers are to live values of the right type
+        match unsafe { libc::sigwait(&self.signals, &mut signal) } {
+            0 => Ok(signal),
+            error => Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+
+    /// Waits on a thread of its own for a signal, then sets `stopping` and
+    /// wakes the listener at `address`, so that no more connections are
+    /// accepted, and exits once those open have been served or `timeout`
+    /// has passed. A second signal exits straight away.
+    pub fn drain_on_signal(
+        self,
+        stopping: Arc<AtomicBool>,
+        address: SocketAddr,
+        timeout: Duration,
+    ) {
+        thread::spawn(move || {
+            let signal = self.wait();
+            stopping.store(true, Ordering::Release);
+            wake(address);
+            log(json!({ "event": "draining", "signal": signal.ok() }));
+
+            thread::spawn(move || {
+                let _ = self.wait();
+                log(json!({ "event": "stopped", "drained": false }));
+                process::exit(1);
+            });
+
+            let deadline = Instant::now() + timeout;
+            let drained = loop {
+                let open = METRICS.connections_open.load() + METRICS.requests_in_flight.load();
+                if open == 0 || Instant::now() >= deadline {
+                    break open == 0;
+                }
+                thread::sleep(Duration::from_millis(50));
+            };
+            log(json!({ "event": "stopped", "drained": drained }));
+            process::exit(0);
+        });
+    }
+}
+
+/// Answers health checks at `address` on a thread of its own: any HTTP
+/// request gets `200` while the server serves, and `503` once `stopping` is
+/// set, so that load balancers stop sending it connections while it drains.
+pub fn serve_health(address: &str, stopping: Arc<AtomicBool>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let local = listener.local_addr()?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = answer_health(stream, stopping.load(Ordering::Acquire));
+        }
+    });
+    Ok(local)
+}
+
+fn answer_health(mut stream: TcpStream, stopping: bool) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    // the request says nothing the answer depends on, but is read so that
+    // closing does not reset the connection under the client
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request);
+
+    let (status, body) = match stopping {
+        false => ("200 OK", r#"{"status":"ok"}"#),
+        true => ("503 Service Unavailable", r#"{"status":"draining"}"#),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Asks the health endpoint at `address` whether the server is healthy,
+/// for a container's `HEALTHCHECK` in images without an HTTP client.
+pub fn check_health(address: &str) -> io::Result<bool> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response
+        .split(' ')
+        .nth(1)
+        .is_some_and(|status| status == "200"))
+}
@@ -0,0 +1,241 @@
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    client::{Client, ClientError},
+    protocol::{JsontpRequest, JsontpResponse},
+    server::{Arrival, Server},
+    wire::WireFormat,
+};
+
+/// Response headers that differ from one run or deployment to the next,
+/// such as an `etag` made from a file's inode, left out of recordings so
+/// that recording the same interactions twice gives the same file, and so
+/// never checked.
+pub const VOLATILE_HEADERS: &[&str] = &[
+    "date",
+    "etag",
+    "last-modified",
+    "request-id",
+    "server-timing",
+    "trace-id",
+    "traceparent",
+    "x-request-id",
+];
+
+/// A request a consumer sends and the response it relies on getting.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Interaction {
+    pub request: JsontpRequest,
+    pub response: JsontpResponse,
+}
+
+/// What a consumer expects of a server: the interactions it recorded, to be
+/// checked against the server by [`Contract::verify`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Contract {
+    /// Who relies on these interactions, for the reports of failing ones.
+    pub consumer: String,
+    pub interactions: Vec<Interaction>,
+}
+
+impl Contract {
+    pub fn new(consumer: &str) -> Contract {
+        Contract {
+            consumer: consumer.to_string(),
+            interactions: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Contract> {
+        let text = fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the contract as indented JSON with its keys sorted, so that
+    /// the same interactions always give the same file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let value = serde_json::to_value(self).map_err(io::Error::other)?;
+        let text = serde_json::to_string_pretty(&value).map_err(io::Error::other)?;
+        fs::write(path, text + "\n")
+    }
+
+    /// Checks every interaction against `server` in this process, without
+    /// opening a connection, giving each way the server falls short.
+    pub fn verify(&self, server: &Server) -> Vec<Mismatch> {
+        let arrival = Arrival::new(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        self.verify_with(|request| {
+            let message = serde_json::to_vec(request).map_err(|e| e.to_string())?;
+            let answer = server.handle_message(&message, &arrival);
+            WireFormat::sniff(&answer)
+                .decode(&answer)
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    /// Checks every interaction against the server `client` talks to.
+    pub fn verify_at(&self, client: &Client) -> Vec<Mismatch> {
+        self.verify_with(|request| {
+            client.send(request).map_err(|e| match e {
+                ClientError::Io(e) => e.to_string(),
+                e => format!("{:?}", e),
+            })
+        })
+    }
+
+    fn verify_with(
+        &self,
+        mut send: impl FnMut(&JsontpRequest) -> Result<JsontpResponse, String>,
+    ) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        for (index, interaction) in self.interactions.iter().enumerate() {
+            let mismatch = |field: &str, expected: String, actual: String| Mismatch {
+                interaction: index,
+                request: format!(
+                    "{} {}",
+                    interaction.request.method, interaction.request.resource
+                ),
+                field: field.to_string(),
+                expected,
+                actual,
+            };
+
+            let actual = match send(&interaction.request) {
+                Ok(actual) => actual,
+                Err(e) => {
+                    mismatches.push(mismatch("response", "a response".to_string(), e));
+                    continue;
+                }
+            };
+            let expected = &interaction.response;
+
+            if actual.status.code != expected.status.code {
+                mismatches.push(mismatch(
+                    "status",
+                    expected.status.code.to_string(),
+                    actual.status.code.to_string(),
+                ));
+            }
+            for (name, value) in expected.headers.iter() {
+                if VOLATILE_HEADERS.contains(&name) || actual.headers.get(name) == Some(value) {
+                    continue;
+                }
+                mismatches.push(mismatch(
+                    &format!("headers.{}", name),
+                    value.to_string(),
+                    actual
+                        .headers
+                        .get(name)
+                        .map_or("nothing".to_string(), Value::to_string),
+                ));
+            }
+            if actual.body.encoding != expected.body.encoding {
+                mismatches.push(mismatch(
+                    "body.encoding",
+                    quoted(&expected.body.encoding),
+                    quoted(&actual.body.encoding),
+                ));
+            }
+            if !same_content(&expected.body.content, &actual.body.content) {
+                mismatches.push(mismatch(
+                    "body.content",
+                    quoted(&expected.body.content),
+                    quoted(&actual.body.content),
+                ));
+            }
+        }
+        mismatches
+    }
+}
+
+/// Whether two bodies say the same: as JSON if both are, so that the order
+/// of keys and spacing do not matter, and byte for byte otherwise.
+fn same_content(expected: &str, actual: &str) -> bool {
+    match (
+        serde_json::from_str::<Value>(expected),
+        serde_json::from_str::<Value>(actual),
+    ) {
+        (Ok(expected), Ok(actual)) => expected == actual,
+        _ => expected == actual,
+    }
+}
+
+/// `text` as a JSON string, so that a body with line breaks in it still
+/// reads as one value.
+fn quoted(text: &str) -> String {
+    Value::from(text).to_string()
+}
+
+/// One way a server's response differs from the one recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The interaction's place in [`Contract::interactions`].
+    pub interaction: usize,
+    /// The method and resource of its request.
+    pub request: String,
+    /// Which part of the response differs, such as `status` or
+    /// `headers.etag`.
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "interaction {} ({}): {} should be {} but was {}",
+            self.interaction, self.request, self.field, self.expected, self.actual
+        )
+    }
+}
+
+/// Collects the interactions of a [`Client`] made with
+/// [`Client::with_recorder`] into a [`Contract`], saved to `path` by
+/// [`Recorder::save`].
+pub struct Recorder {
+    path: PathBuf,
+    contract: Mutex<Contract>,
+}
+
+impl Recorder {
+    pub fn new(consumer: &str, path: impl Into<PathBuf>) -> Recorder {
+        Recorder {
+            path: path.into(),
+            contract: Mutex::new(Contract::new(consumer)),
+        }
+    }
+
+    /// Adds an interaction, leaving out the [`VOLATILE_HEADERS`] of its
+    /// response.
+    pub fn record(&self, request: &JsontpRequest, response: &JsontpResponse) {
+        let mut response = response.clone();
+        for name in VOLATILE_HEADERS {
+            response.headers.remove(name);
+        }
+        self.contract
+            .lock()
+            .unwrap()
+            .interactions
+            .push(Interaction {
+                request: request.clone(),
+                response,
+            });
+    }
+
+    /// The interactions recorded so far.
+    pub fn contract(&self) -> Contract {
+        self.contract.lock().unwrap().clone()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        self.contract().save(&self.path)
+    }
+}
@@ -0,0 +1,173 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    headers::Headers,
+    middleware::{Context, Middleware},
+    protocol::{Body, JsontpRequest, JsontpResponse},
+    streaming::RequestHead,
+};
+
+/// Which origins may talk to the server when requests are relayed on behalf
+/// of a browser page, e.g. through a gateway. Requests without an `origin`
+/// header are not affected.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CorsPolicy {
+    /// Allowed origins; `"*"` allows any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// How long, in seconds, a preflight result may be cached.
+    pub max_age: u64,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "DELETE", "OPTIONS"]
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            allowed_headers: Vec::new(),
+            max_age: 600,
+        }
+    }
+}
+
+impl CorsPolicy {
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+}
+
+/// Enforces a [`CorsPolicy`].
+///
+/// A request carrying an `origin` header is rejected with a 403 unless the
+/// origin is allowed. An `OPTIONS` request that also carries
+/// `access-control-request-method` is a preflight: it is answered directly
+/// with a 204 listing what the origin may do. Every other allowed request gets
+/// an `access-control-allow-origin` header on its response.
+pub struct CorsMiddleware {
+    pub policy: CorsPolicy,
+}
+
+fn header<'a>(request: &'a JsontpRequest, name: &str) -> Option<&'a str> {
+    request.headers.get(name).and_then(Value::as_str)
+}
+
+/// Removes from `response` the headers that are still as `request` sent
+/// them.
+fn remove_echoed(request: &Headers, response: &mut Headers) {
+    for name in request.keys() {
+        if response.get_all(name).eq(request.get_all(name)) {
+            response.remove(name);
+        }
+    }
+}
+
+impl Middleware for CorsMiddleware {
+    fn on_head(&self, head: &RequestHead, _context: &Context) -> Result<(), (String, u16)> {
+        let origin = head
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.get("origin"))
+            .and_then(Value::as_str);
+
+        match origin {
+            Some(origin) if !self.policy.allows_origin(origin) => {
+                Err(("Forbidden".to_string(), 403))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        let Some(origin) = header(request, "origin") else {
+            return Ok(());
+        };
+
+        if !self.policy.allows_origin(origin) {
+            return Err(("Forbidden".to_string(), 403));
+        }
+
+        context
+            .values
+            .insert("cors-origin".to_string(), Value::String(origin.to_string()));
+
+        if request.method == "OPTIONS" {
+            if let Some(method) = header(request, "access-control-request-method") {
+                if !self.policy.allows_method(method) {
+                    return Err(("Forbidden".to_string(), 403));
+                }
+
+                context
+                    .values
+                    .insert("cors-preflight".to_string(), Value::Bool(true));
+                return Err(("No Content".to_string(), 204));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_response(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        let Some(origin) = context.values.get("cors-origin").cloned() else {
+            return;
+        };
+
+        if context.values.contains_key("cors-preflight") {
+            // preflights go through the error path, which echoes the
+            // request; take out what was echoed, keeping the headers the
+            // server and its response hooks have added since
+            if let Some(request) = request {
+                remove_echoed(&request.headers, &mut response.headers);
+            }
+            response.body = Body {
+                content: String::new(),
+                encoding: "identity".to_string(),
+                other: BTreeMap::new(),
+            };
+            response.headers.insert(
+                "access-control-allow-methods".to_string(),
+                Value::String(self.policy.allowed_methods.join(", ")),
+            );
+            response.headers.insert(
+                "access-control-allow-headers".to_string(),
+                Value::String(self.policy.allowed_headers.join(", ")),
+            );
+            response.headers.insert(
+                "access-control-max-age".to_string(),
+                Value::from(self.policy.max_age),
+            );
+        }
+
+        response
+            .headers
+            .insert("access-control-allow-origin".to_string(), origin);
+    }
+}
@@ -0,0 +1,159 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{
+    headers::Headers,
+    middleware::{Context, Middleware},
+    protocol::{JsontpRequest, JsontpResponse},
+    streaming::RequestHead,
+};
+
+pub const CSRF_HEADER: &str = "csrf-token";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CsrfConfig {
+    pub secret: String,
+    /// The cookie holding the session id tokens are bound to.
+    #[serde(default = "default_session_cookie")]
+    pub session_cookie: String,
+}
+
+fn default_session_cookie() -> String {
+    "session".to_string()
+}
+
+/// Issues and checks CSRF tokens. A token is an HMAC of the session id, so
+/// nothing needs to be stored server side and a token is only valid for the
+/// session it was issued to.
+pub struct CsrfProtection {
+    secret: Vec<u8>,
+    session_cookie: String,
+}
+
+impl CsrfProtection {
+    pub fn new(config: &CsrfConfig) -> CsrfProtection {
+        CsrfProtection {
+            secret: config.secret.as_bytes().to_vec(),
+            session_cookie: config.session_cookie.clone(),
+        }
+    }
+
+    fn mac(&self, session: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("hmac accepts any key length");
+        mac.update(session.as_bytes());
+        mac
+    }
+
+    pub fn issue(&self, session: &str) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(session).finalize().into_bytes())
+    }
+
+    pub fn verify(&self, session: &str, token: &str) -> bool {
+        match URL_SAFE_NO_PAD.decode(token) {
+            Ok(token) => self.mac(session).verify_slice(&token).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Finds the session id in the `cookies` header, which may either be an
+    /// object of cookie names to values or a `name=value; name=value` string.
+    pub fn session(&self, request: &JsontpRequest) -> Option<String> {
+        self.session_from(&request.headers)
+    }
+
+    fn session_from(&self, headers: &Headers) -> Option<String> {
+        match headers.get("cookies")? {
+            Value::Object(cookies) => cookies
+                .get(&self.session_cookie)
+                .and_then(Value::as_str)
+                .map(ToString::to_string),
+            Value::String(cookies) => cookies
+                .split(';')
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(name, _)| *name == self.session_cookie)
+                .map(|(_, value)| value.to_string()),
+            _ => None,
+        }
+    }
+}
+
+pub fn is_state_changing(method: &str) -> bool {
+    matches!(
+        method,
+        "POST" | "PUT" | "DELETE" | "PATCH" | "MKCOL" | "COPY" | "MOVE"
+    )
+}
+
+/// Rejects state-changing requests that do not carry a valid `csrf-token`
+/// for their session with a 403, and hands out a token on every other
+/// request that has a session.
+pub struct CsrfMiddleware {
+    pub protection: CsrfProtection,
+}
+
+impl CsrfMiddleware {
+    fn check(&self, method: &str, headers: &Headers) -> Result<(), (String, u16)> {
+        if !is_state_changing(method) {
+            return Ok(());
+        }
+
+        let session = self.protection.session_from(headers);
+        let token = headers.get(CSRF_HEADER).and_then(Value::as_str);
+
+        match (session, token) {
+            (Some(session), Some(token)) if self.protection.verify(&session, token) => Ok(()),
+            _ => Err(("Forbidden".to_string(), 403)),
+        }
+    }
+}
+
+impl Middleware for CsrfMiddleware {
+    fn on_head(&self, head: &RequestHead, _context: &Context) -> Result<(), (String, u16)> {
+        match (&head.method, &head.headers) {
+            (Some(method), Some(headers)) => self.check(method, headers),
+            _ => Ok(()),
+        }
+    }
+
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        _context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        self.check(&request.method, &request.headers)
+    }
+
+    fn on_response(
+        &self,
+        request: Option<&JsontpRequest>,
+        _context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        let Some(request) = request else {
+            return;
+        };
+
+        if is_state_changing(&request.method) {
+            return;
+        }
+
+        if let Some(session) = self.protection.session(request) {
+            response.headers.insert(
+                CSRF_HEADER.to_string(),
+                Value::String(self.protection.issue(&session)),
+            );
+        }
+    }
+}
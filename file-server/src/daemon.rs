@@ -0,0 +1,85 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+};
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Where to write the daemon's process id. A server whose pid file
+    /// names a process that is still running does not start.
+    pub pid_file: Option<PathBuf>,
+    /// Where standard output and error go, appended to; nowhere when unset.
+    pub log_file: Option<PathBuf>,
+}
+
+/// Detaches from the terminal and the session it was started from, as
+/// daemons do: forks twice, so the process that carries on is not a session
+/// leader and cannot take a terminal again, with standard input from
+/// `/dev/null` and output to the `log-file`. The process that called this
+/// exits once the daemon is on its way.
+///
+/// The working directory is kept, as resources are resolved against it.
+/// This has to be called before any other thread is started.
+pub fn daemonize(config: &DaemonConfig) -> io::Result<()> {
+    if let Some(pid) = config.pid_file.as_deref().and_then(running) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("already running as process {}", pid),
+        ));
+    }
+
+    // opened before detaching, so a bad path is still reported
+    let null = File::open("/dev/null")?;
+    let log = match &config.log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+
+    fork()?;
+    // SAFETY: takes no arguments, and fails only for a process group leader,
+    // which the child of a fork is not
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    fork()?;
+
+    for (from, to) in [(&null, 0), (&log, 1), (&log, 2)] {
+        // SAFETY: both descriptors are open, and standard input and output
+        // are meant to be replaced here
+        if unsafe { libc::dup2(from.as_raw_fd(), to) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if let Some(path) = &config.pid_file {
+        fs::write(path, format!("{}\n", process::id()))?;
+    }
+    Ok(())
+}
+
+/// Forks, carrying on in the child and leaving the parent to exit.
+fn fork() -> io::Result<()> {
+    // SAFETY: there is only the one thread, so the child gets a consistent
+    // copy of everything
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
+
+/// The process `pid_file` names, if it is still running.
+fn running(pid_file: &Path) -> Option<libc::pid_t> {
+    let pid: libc::pid_t = fs::read_to_string(pid_file).ok()?.trim().parse().ok()?;
+    // SAFETY: signal 0 only checks that the process exists
+    let alive = pid > 0
+        && (unsafe { libc::kill(pid, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM));
+    alive.then_some(pid)
+}
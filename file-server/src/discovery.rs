@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+/// Finds the addresses a logical service, such as `files` or
+/// `_jsontp._tcp.example.com`, can be reached at, so that clients need not
+/// hard-code them. Callers ask again from time to time, as a
+/// [`Balancer`](crate::balancer::Balancer) does every `resolve-every`
+/// seconds, so a discovery need not cache anything.
+///
+/// Any `Fn(&str) -> io::Result<Vec<String>>` is a discovery, for lookups in
+/// a registry of the application's own.
+pub trait Discovery: Send + Sync {
+    /// The `host:port` addresses of `service`.
+    fn resolve(&self, service: &str) -> io::Result<Vec<String>>;
+}
+
+impl<F> Discovery for F
+where
+    F: Fn(&str) -> io::Result<Vec<String>> + Send + Sync,
+{
+    fn resolve(&self, service: &str) -> io::Result<Vec<String>> {
+        self(service)
+    }
+}
+
+/// Reads the services from a TOML file of `service = ["host:port", ...]`
+/// lines, again on every lookup, so that the file can be edited (or
+/// written by a deployment tool) while clients run.
+pub struct StaticFile {
+    path: PathBuf,
+}
+
+impl StaticFile {
+    pub fn new(path: impl Into<PathBuf>) -> StaticFile {
+        StaticFile { path: path.into() }
+    }
+}
+
+impl Discovery for StaticFile {
+    fn resolve(&self, service: &str) -> io::Result<Vec<String>> {
+        let text = fs::read_to_string(&self.path)?;
+        let mut services: HashMap<String, Vec<String>> = toml::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        services.remove(service).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not list {}", self.path.display(), service),
+            )
+        })
+    }
+}
+
+/// Looks services up as DNS SRV records, such as
+/// `_jsontp._tcp.example.com`, giving their targets most preferred first:
+/// by priority, then by weight. Only UDP is spoken, so an answer too large
+/// for one datagram is an error.
+pub struct DnsSrv {
+    nameserver: SocketAddr,
+    timeout: Duration,
+}
+
+/// The SRV record type.
+const SRV: u16 = 33;
+
+impl DnsSrv {
+    pub fn new(nameserver: SocketAddr) -> DnsSrv {
+        DnsSrv {
+            nameserver,
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    /// Asks the first nameserver of `/etc/resolv.conf`.
+    pub fn from_system() -> io::Result<DnsSrv> {
+        let conf = fs::read_to_string("/etc/resolv.conf")?;
+        let nameserver = conf
+            .lines()
+            .filter_map(|line| line.strip_prefix("nameserver"))
+            .find_map(|address| address.trim().parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameserver configured"))?;
+
+        Ok(DnsSrv::new(SocketAddr::new(nameserver, 53)))
+    }
+
+    /// Sets how long to wait for an answer, 2 seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> DnsSrv {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Discovery for DnsSrv {
+    fn resolve(&self, service: &str) -> io::Result<Vec<String>> {
+        let id = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |now| now.subsec_nanos() as u16);
+
+        let socket = UdpSocket::bind(match self.nameserver {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        })?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.connect(self.nameserver)?;
+        socket.send(&srv_query(id, service)?)?;
+
+        let mut answer = [0; 512];
+        let len = socket.recv(&mut answer)?;
+        let mut records = parse_srv_answer(id, &answer[..len])?;
+
+        records.sort_by_key(|&(priority, weight, _)| (priority, std::cmp::Reverse(weight)));
+        Ok(records.into_iter().map(|(_, _, target)| target).collect())
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// A recursive query for the SRV records of `name`.
+fn srv_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a domain name", name),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    query.extend_from_slice(&SRV.to_be_bytes());
+    // class IN
+    query.extend_from_slice(&[0, 1]);
+    Ok(query)
+}
+
+/// The (priority, weight, `target:port`) of every SRV record answering
+/// query `id`.
+fn parse_srv_answer(id: u16, packet: &[u8]) -> io::Result<Vec<(u16, u16, String)>> {
+    let u16_at = |at: usize| {
+        packet
+            .get(at..at + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(|| invalid("truncated DNS answer"))
+    };
+
+    if u16_at(0)? != id {
+        return Err(invalid("DNS answer to another query"));
+    }
+    let flags = u16_at(2)?;
+    if flags & 0x0200 != 0 {
+        return Err(invalid("DNS answer too large for UDP"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Ok(Vec::new()),
+        rcode => return Err(invalid(&format!("DNS error {}", rcode))),
+    }
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut at = 12;
+    for _ in 0..questions {
+        at = read_name(packet, at)?.1 + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        at = read_name(packet, at)?.1;
+        let kind = u16_at(at)?;
+        let data = at + 10;
+        let next = data + u16_at(at + 8)? as usize;
+
+        if kind == SRV {
+            let priority = u16_at(data)?;
+            let weight = u16_at(data + 2)?;
+            let port = u16_at(data + 4)?;
+            let (target, _) = read_name(packet, data + 6)?;
+            // a target of "." means the service is not offered
+            if !target.is_empty() {
+                records.push((priority, weight, format!("{}:{}", target, port)));
+            }
+        }
+        at = next;
+    }
+    Ok(records)
+}
+
+/// The name at `at`, following compression pointers, and where what comes
+/// after it starts.
+fn read_name(packet: &[u8], mut at: usize) -> io::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+
+    // no name takes more jumps than the packet has bytes, short of a loop
+    let mut jumps = 0;
+    loop {
+        let len = *packet
+            .get(at)
+            .ok_or_else(|| invalid("truncated DNS name"))? as usize;
+        match len {
+            0 => break,
+            len if len & 0xc0 == 0xc0 => {
+                let low = *packet
+                    .get(at + 1)
+                    .ok_or_else(|| invalid("truncated DNS name"))?;
+                end.get_or_insert(at + 2);
+                at = (len & 0x3f) << 8 | low as usize;
+                jumps += 1;
+                if jumps > packet.len() {
+                    return Err(invalid("looping DNS name"));
+                }
+            }
+            len => {
+                let label = packet
+                    .get(at + 1..at + 1 + len)
+                    .ok_or_else(|| invalid("truncated DNS name"))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + len;
+            }
+        }
+    }
+
+    Ok((labels.join("."), end.unwrap_or(at + 1)))
+}
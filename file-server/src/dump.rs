@@ -0,0 +1,292 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use serde_json::Value;
+
+use crate::{
+    metrics::Counter,
+    wire::{MessageState, WireFormat},
+};
+
+/// One message as it crossed the wire.
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Message {
+        format: WireFormat,
+        /// How many bytes it took on the wire.
+        size: usize,
+        message: Value,
+    },
+    /// Bytes that do not start a message in any format compiled in, given
+    /// up on as far as the end of what had arrived.
+    Garbage(Vec<u8>),
+}
+
+/// Splits the bytes going one way on a connection into the messages they
+/// carry, however the reads happen to cut them.
+#[derive(Default)]
+pub struct Frames {
+    data: Vec<u8>,
+}
+
+impl Frames {
+    pub fn new() -> Frames {
+        Frames::default()
+    }
+
+    /// Adds `bytes` that have arrived, giving the frames they complete.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Frame> {
+        self.data.extend_from_slice(bytes);
+
+        let mut frames = Vec::new();
+        loop {
+            let start = self
+                .data
+                .iter()
+                .position(|byte| !byte.is_ascii_whitespace())
+                .unwrap_or(self.data.len());
+            self.data.drain(..start);
+            if self.data.is_empty() {
+                return frames;
+            }
+
+            let format = WireFormat::sniff(&self.data);
+            match next_message(format, &self.data) {
+                Some((message, size)) => {
+                    frames.push(Frame::Message {
+                        format,
+                        size,
+                        message,
+                    });
+                    self.data.drain(..size);
+                }
+                None if format.message_state(&self.data) == MessageState::Invalid => {
+                    frames.push(Frame::Garbage(std::mem::take(&mut self.data)));
+                }
+                None => return frames,
+            }
+        }
+    }
+
+    /// Bytes left over that never made up a whole message, once the side
+    /// sending them has closed.
+    pub fn rest(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// The first message in `data` and the bytes it took, unless it has not all
+/// arrived or cannot be read.
+fn next_message(format: WireFormat, data: &[u8]) -> Option<(Value, usize)> {
+    match format {
+        WireFormat::Json | WireFormat::CanonicalJson => {
+            let mut messages = serde_json::Deserializer::from_slice(data).into_iter::<Value>();
+            let message = messages.next()?.ok()?;
+            Some((message, messages.byte_offset()))
+        }
+        #[cfg(feature = "cbor")]
+        WireFormat::Cbor => {
+            let mut rest = data;
+            let message = ciborium::from_reader(&mut rest).ok()?;
+            Some((message, data.len() - rest.len()))
+        }
+        #[cfg(feature = "msgpack")]
+        WireFormat::MessagePack => {
+            let mut rest = data;
+            let message = rmp_serde::from_read(&mut rest).ok()?;
+            Some((message, data.len() - rest.len()))
+        }
+    }
+}
+
+/// How [`proxy`] prints what it sees.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DumpOptions {
+    /// One line per message rather than indented JSON.
+    pub compact: bool,
+}
+
+/// Which way a message went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ToServer,
+    ToClient,
+}
+
+/// What the two halves of a proxied connection share.
+struct Connection {
+    id: u64,
+    opened: Instant,
+    /// When the latest message to the server arrived, for the latency of
+    /// the answers to it.
+    requested: Mutex<Option<Instant>>,
+    options: DumpOptions,
+}
+
+impl Connection {
+    fn print(&self, direction: Direction, frame: &Frame) {
+        let now = Instant::now();
+        let arrow = match direction {
+            Direction::ToServer => "->",
+            Direction::ToClient => "<-",
+        };
+        let at = now.duration_since(self.opened).as_secs_f64();
+
+        let mut latency = String::new();
+        let mut requested = self.requested.lock().unwrap();
+        match direction {
+            Direction::ToServer => *requested = Some(now),
+            Direction::ToClient => {
+                if let Some(requested) = *requested {
+                    latency = format!(
+                        ", {:.1} ms after the request",
+                        now.duration_since(requested).as_secs_f64() * 1000.0
+                    );
+                }
+            }
+        }
+        drop(requested);
+
+        // one lock for the whole frame, so that connections do not
+        // interleave their output
+        let mut out = io::stdout().lock();
+        let _ = match frame {
+            Frame::Message {
+                format,
+                size,
+                message,
+            } => {
+                let kind = message["type"].as_str().unwrap_or("message");
+                let text = match self.options.compact {
+                    true => serde_json::to_string(message),
+                    false => serde_json::to_string_pretty(message),
+                }
+                .unwrap_or_default();
+                writeln!(
+                    out,
+                    "#{} {} {} ({}, {} bytes) at +{:.3}s{}\n{}",
+                    self.id,
+                    arrow,
+                    kind,
+                    format.name(),
+                    size,
+                    at,
+                    latency,
+                    text
+                )
+            }
+            Frame::Garbage(bytes) => writeln!(
+                out,
+                "#{} {} {} bytes that are not a message at +{:.3}s\n{}",
+                self.id,
+                arrow,
+                bytes.len(),
+                at,
+                String::from_utf8_lossy(bytes)
+            ),
+        };
+    }
+
+    fn note(&self, text: &str) {
+        let at = self.opened.elapsed().as_secs_f64();
+        let _ = writeln!(io::stdout().lock(), "#{} {} at +{:.3}s", self.id, text, at);
+    }
+}
+
+/// Accepts connections at `listen`, passes each through to `upstream`
+/// untouched, and prints the messages going either way with when they went,
+/// for seeing what a client and server actually said to each other.
+pub fn proxy(listen: &str, upstream: &str, options: DumpOptions) -> io::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    let ids = Counter::new(1);
+    eprintln!("Passing {} through to {}", listener.local_addr()?, upstream);
+
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Could not accept a connection: {}", e);
+                continue;
+            }
+        };
+        let connection = Arc::new(Connection {
+            id: ids.fetch_add(1),
+            opened: Instant::now(),
+            requested: Mutex::new(None),
+            options,
+        });
+
+        let peer = client
+            .peer_addr()
+            .map(|peer| peer.to_string())
+            .unwrap_or_default();
+        let server = match TcpStream::connect(upstream) {
+            Ok(server) => server,
+            Err(e) => {
+                connection.note(&format!("{} could not reach {}: {}", peer, upstream, e));
+                continue;
+            }
+        };
+        connection.note(&format!("{} connected", peer));
+
+        let (Ok(client_reader), Ok(server_reader)) = (client.try_clone(), server.try_clone())
+        else {
+            continue;
+        };
+        let to_server = {
+            let connection = connection.clone();
+            thread::spawn(move || pump(client_reader, server, Direction::ToServer, &connection))
+        };
+        thread::spawn(move || {
+            let to_client = pump(server_reader, client, Direction::ToClient, &connection);
+            let to_server = to_server.join().unwrap_or(0);
+            connection.note(&format!(
+                "closed, {} bytes sent and {} received",
+                to_server, to_client
+            ));
+        });
+    }
+    Ok(())
+}
+
+/// Copies `from` to `to` until `from` closes, printing each message as it
+/// completes, then closes `to` for writing so the other side sees the end
+/// too. Gives the bytes copied.
+fn pump(
+    mut from: TcpStream,
+    mut to: TcpStream,
+    direction: Direction,
+    connection: &Connection,
+) -> u64 {
+    let mut frames = Frames::new();
+    let mut buffer = [0; 8192];
+    let mut copied = 0;
+
+    loop {
+        let bytes_read = match from.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        if to.write_all(&buffer[..bytes_read]).is_err() {
+            break;
+        }
+        copied += bytes_read as u64;
+
+        for frame in frames.push(&buffer[..bytes_read]) {
+            connection.print(direction, &frame);
+        }
+    }
+
+    if !frames.rest().is_empty() {
+        connection.print(direction, &Frame::Garbage(frames.rest().to_vec()));
+    }
+    let _ = to.shutdown(Shutdown::Write);
+    copied
+}
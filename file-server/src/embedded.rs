@@ -0,0 +1,204 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::server::Server;
+
+/// A [`Server`] an application runs itself, alongside whatever else it
+/// does, rather than as the standalone binary:
+///
+/// ```no_run
+/// use jsontp::{embedded::JsontpServer, router::Router, server::Server};
+///
+/// let router = Router::new("Example");
+/// let server = JsontpServer::bind(Server::new(router), "127.0.0.1:0").unwrap();
+/// println!("listening on {}", server.local_addr());
+///
+/// server.start().unwrap();
+/// // ...
+/// server.stop();
+/// ```
+///
+/// Applications with connections of their own, such as those accepted by
+/// another runtime, can pass them to [`server`](JsontpServer::server)'s
+/// `handle_connection`, or their messages to its `handle_message`.
+pub struct JsontpServer {
+    server: Arc<Server>,
+    /// Closed once the server is stopped, along with the clones accepting
+    /// threads have.
+    listener: Mutex<Option<TcpListener>>,
+    address: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    accepting: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl JsontpServer {
+    /// Binds `address` with `server.tcp`'s socket options. A port of 0
+    /// binds any free one, which [`local_addr`](JsontpServer::local_addr)
+    /// gives.
+    pub fn bind(server: Server, address: &str) -> io::Result<JsontpServer> {
+        let listener = server.tcp.bind(address)?;
+        let address = listener.local_addr()?;
+
+        Ok(JsontpServer {
+            server: Arc::new(server),
+            listener: Mutex::new(Some(listener)),
+            address,
+            stopping: Arc::new(AtomicBool::new(false)),
+            accepting: Mutex::new(None),
+        })
+    }
+
+    /// The address the server is bound to, with the port it was given.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn server(&self) -> &Arc<Server> {
+        &self.server
+    }
+
+    /// Starts accepting connections on a thread of its own, unless that
+    /// thread is running already.
+    pub fn start(&self) -> io::Result<()> {
+        let mut accepting = self.accepting.lock().unwrap();
+        if accepting.is_some() {
+            return Ok(());
+        }
+
+        let listener = self.accepting_listener()?;
+        let server = self.server.clone();
+        let stopping = self.stopping.clone();
+        let address = self.address;
+        *accepting = Some(
+            thread::Builder::new()
+                .name(format!("jsontp {}", address))
+                .spawn(move || {
+                    server.run_until(&listener, &stopping);
+                    wake(address);
+                })?,
+        );
+        Ok(())
+    }
+
+    /// Accepts connections on the calling thread until another calls
+    /// [`stop`](JsontpServer::stop).
+    pub fn run(&self) -> io::Result<()> {
+        let listener = self.accepting_listener()?;
+        self.server.run_until(&listener, &self.stopping);
+        wake(self.address);
+        Ok(())
+    }
+
+    /// Stops accepting connections, waiting for [`start`](JsontpServer::start)'s
+    /// thread to end. Connections already accepted are served to the end on
+    /// their own threads. A stopped server cannot be started again.
+    pub fn stop(&self) {
+        if !self.stopping.swap(true, Ordering::AcqRel) {
+            wake(self.address);
+        }
+        self.listener.lock().unwrap().take();
+        self.join();
+    }
+
+    /// Waits for [`start`](JsontpServer::start)'s thread to end, once the
+    /// server is stopped from another thread.
+    pub fn join(&self) {
+        // held while joining, so that others joining wait too
+        let mut accepting = self.accepting.lock().unwrap();
+        if let Some(accepting) = accepting.take() {
+            let _ = accepting.join();
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
+
+    fn accepting_listener(&self) -> io::Result<TcpListener> {
+        match &*self.listener.lock().unwrap() {
+            Some(listener) if !self.is_stopped() => listener.try_clone(),
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the server was stopped",
+            )),
+        }
+    }
+}
+
+/// Binds `address` and serves it on a thread of its own, as
+/// [`JsontpServer`] does, giving a handle to the running server:
+///
+/// ```no_run
+/// # use jsontp::{embedded::serve, router::Router, server::Server};
+/// let handle = serve(Server::new(Router::new("Example")), "127.0.0.1:0").unwrap();
+/// let client = jsontp::client::Client::new(&handle.local_addr().to_string());
+/// // ...
+/// handle.shutdown();
+/// ```
+pub fn serve(server: Server, address: &str) -> io::Result<ServerHandle> {
+    let server = JsontpServer::bind(server, address)?;
+    server.start()?;
+    Ok(ServerHandle {
+        server: Arc::new(server),
+    })
+}
+
+/// A server [`serve`] started. Clones are handles to the same server, which
+/// is shut down when the last of them is dropped, if not before.
+#[derive(Clone)]
+pub struct ServerHandle {
+    server: Arc<JsontpServer>,
+}
+
+impl ServerHandle {
+    /// The address the server is bound to, with the port it was given.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    pub fn server(&self) -> &Arc<Server> {
+        self.server.server()
+    }
+
+    /// Stops accepting connections and closes the listener, returning once
+    /// the server's thread has ended. Connections already accepted are
+    /// served to the end.
+    pub fn shutdown(&self) {
+        self.server.stop();
+    }
+
+    /// Waits until the server is shut down, through another handle.
+    pub fn join(&self) {
+        self.server.join();
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.server.is_stopped()
+    }
+}
+
+/// Connects to the listener at `address`, so that an accept waiting on it
+/// returns and sees that the server is stopping. Each accepting thread that
+/// stops wakes the next, should there be several.
+pub(crate) fn wake(mut address: SocketAddr) {
+    if address.ip().is_unspecified() {
+        address.set_ip(match address {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let _ = TcpStream::connect(address);
+}
+
+impl Drop for JsontpServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
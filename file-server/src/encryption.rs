@@ -0,0 +1,209 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+
+use crate::{
+    middleware::{Context, Middleware},
+    protocol::{Body, JsontpRequest, JsontpResponse, Utf8Policy},
+    transform::BodyTransform,
+};
+
+/// The body attribute describing how `content` was encrypted.
+pub const ENCRYPTION_ATTRIBUTE: &str = "encryption";
+
+pub type BodyKey = [u8; 32];
+
+/// Finds the key for a `key-id` named in an encrypted body. Any
+/// `Fn(&str) -> Option<BodyKey>` closure can be used as a lookup.
+pub trait BodyKeyLookup: Send + Sync {
+    fn lookup(&self, key_id: &str) -> Option<BodyKey>;
+}
+
+impl<F> BodyKeyLookup for F
+where
+    F: Fn(&str) -> Option<BodyKey> + Send + Sync,
+{
+    fn lookup(&self, key_id: &str) -> Option<BodyKey> {
+        self(key_id)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncryptionError {
+    Malformed,
+    UnsupportedAlgorithm,
+    UnknownKey,
+    Failed,
+    /// The plaintext is not UTF-8 text, and the [`Utf8Policy`] turns such
+    /// content down.
+    NotText,
+}
+
+impl Body {
+    pub fn is_encrypted(&self) -> bool {
+        self.other.contains_key(ENCRYPTION_ATTRIBUTE)
+    }
+
+    /// The `key-id` the body was encrypted with, if it is encrypted.
+    pub fn encryption_key_id(&self) -> Option<&str> {
+        self.other
+            .get(ENCRYPTION_ATTRIBUTE)
+            .and_then(|encryption| encryption.get("key-id"))
+            .and_then(Value::as_str)
+    }
+
+    /// Replaces `content` with its AES-256-GCM ciphertext, base64 encoded, and
+    /// records the parameters needed to decrypt it in the `encryption`
+    /// attribute.
+    pub fn encrypt(&mut self, key_id: &str, key: &BodyKey) -> Result<(), EncryptionError> {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let ciphertext = cipher
+            .encrypt(&nonce, self.content.as_bytes())
+            .map_err(|_| EncryptionError::Failed)?;
+
+        self.content = STANDARD.encode(ciphertext);
+        self.other.insert(
+            ENCRYPTION_ATTRIBUTE.to_string(),
+            json!({
+                "algorithm": "aes-256-gcm",
+                "key-id": key_id,
+                "nonce": STANDARD.encode(nonce),
+            }),
+        );
+
+        Ok(())
+    }
+
+    /// Reverses [`Body::encrypt`], looking the key up by the recorded
+    /// `key-id`. Bodies without an `encryption` attribute are left alone.
+    pub fn decrypt(&mut self, keys: &dyn BodyKeyLookup) -> Result<(), EncryptionError> {
+        self.decrypt_with(keys, Utf8Policy::Reject)
+    }
+
+    /// Like [`Body::decrypt`], but a plaintext that is not text is dealt
+    /// with as `policy` says.
+    pub fn decrypt_with(
+        &mut self,
+        keys: &dyn BodyKeyLookup,
+        policy: Utf8Policy,
+    ) -> Result<(), EncryptionError> {
+        let Some(encryption) = self.other.get(ENCRYPTION_ATTRIBUTE) else {
+            return Ok(());
+        };
+
+        let field = |name: &str| {
+            encryption
+                .get(name)
+                .and_then(Value::as_str)
+                .ok_or(EncryptionError::Malformed)
+        };
+
+        if field("algorithm")? != "aes-256-gcm" {
+            return Err(EncryptionError::UnsupportedAlgorithm);
+        }
+
+        let key = keys
+            .lookup(field("key-id")?)
+            .ok_or(EncryptionError::UnknownKey)?;
+        let nonce = STANDARD
+            .decode(field("nonce")?)
+            .map_err(|_| EncryptionError::Malformed)?;
+        if nonce.len() != 12 {
+            return Err(EncryptionError::Malformed);
+        }
+        let ciphertext = STANDARD
+            .decode(&self.content)
+            .map_err(|_| EncryptionError::Malformed)?;
+
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| EncryptionError::Failed)?;
+
+        // the encoding stays what it was under the encryption, unless the
+        // plaintext is bytes the policy sends as such
+        match String::from_utf8(plaintext) {
+            Ok(content) => self.content = content,
+            Err(e) => self
+                .set_bytes(e.into_bytes(), policy)
+                .map_err(|_| EncryptionError::NotText)?,
+        }
+        self.other.remove(ENCRYPTION_ATTRIBUTE);
+
+        Ok(())
+    }
+}
+
+/// Decrypts encrypted request bodies before they reach the handler, and
+/// encrypts the response body with the same key when the request was
+/// encrypted.
+pub struct EncryptionMiddleware {
+    pub keys: Box<dyn BodyKeyLookup>,
+}
+
+impl Middleware for EncryptionMiddleware {
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        if !request.body.is_encrypted() {
+            return Ok(());
+        }
+
+        // the key id is kept so the response can be encrypted with it
+        let key_id = request.body.encryption_key_id().map(str::to_string);
+
+        match request.body.decrypt_with(self.keys.as_ref(), context.utf8) {
+            Ok(()) => {
+                if let Some(key_id) = key_id {
+                    context
+                        .values
+                        .insert("encryption-key-id".to_string(), Value::String(key_id));
+                }
+                Ok(())
+            }
+            Err(EncryptionError::UnknownKey) => Err(("Forbidden".to_string(), 403)),
+            Err(EncryptionError::NotText) => Err((
+                "Unsupported Media Type: content is not UTF-8 text".to_string(),
+                415,
+            )),
+            Err(_) => Err(("Bad Request".to_string(), 400)),
+        }
+    }
+
+    fn on_response(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        self.transform(request, context, response)
+    }
+}
+
+impl BodyTransform for EncryptionMiddleware {
+    fn transform(
+        &self,
+        _request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        let Some(key_id) = context
+            .values
+            .get("encryption-key-id")
+            .and_then(Value::as_str)
+        else {
+            return;
+        };
+
+        if let Some(key) = self.keys.lookup(key_id) {
+            let _ = response.body.encrypt(key_id, &key);
+        }
+    }
+}
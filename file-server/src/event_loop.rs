@@ -0,0 +1,556 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    mem,
+    net::{Shutdown, SocketAddr},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Token, Waker,
+};
+
+use crate::{
+    metrics::{Metrics, METRICS},
+    server::{Arrival, Server},
+    streaming::HeadScanner,
+    task::{urgency, BlockingPool, DEFAULT_URGENCY},
+    transport::{Limits, TokenBucket, PACED_CHUNK},
+    wire::{MessageState, WireFormat},
+};
+
+const LISTENER: Token = Token(0);
+/// Raised by the pool when a handler has finished.
+const HANDLED: Token = Token(usize::MAX);
+
+struct Connection {
+    /// The number [`Server::opened`] gave it.
+    number: u64,
+    stream: TcpStream,
+    peer: SocketAddr,
+    started: Instant,
+    input: Vec<u8>,
+    /// The size of the request, kept once `input` has gone to the pool.
+    bytes_read: usize,
+    output: Vec<u8>,
+    written: usize,
+    /// Present until the head of a JSON request has been checked.
+    scanner: Option<HeadScanner>,
+    /// The server's limits, narrowed to the resource once the head is known.
+    limits: Limits,
+    /// Still waiting for the request; once false the response is being sent.
+    reading: bool,
+    /// When the request was handed to the pool, until its response is back.
+    handling: Option<Instant>,
+    /// Why sending the response failed, if it did.
+    error: Option<String>,
+    /// Holds the response back to the limits' `max-send-rate`.
+    pacer: Option<TokenBucket>,
+    /// When the pacer lets more of the response go, while it is holding it
+    /// back.
+    paused_until: Option<Instant>,
+    /// How urgent the request says it is, once its head has been read.
+    urgency: u8,
+}
+
+/// What an event loop needs to hand requests to a [`BlockingPool`] and get
+/// the responses back.
+struct Offload {
+    pool: Arc<BlockingPool>,
+    sender: Sender<(Token, Vec<u8>)>,
+    receiver: Receiver<(Token, Vec<u8>)>,
+    waker: Arc<Waker>,
+}
+
+enum Progress {
+    Continue,
+    Close,
+}
+
+/// Serves `listener` from `threads` event loops instead of a thread per
+/// connection. Each loop polls the shared listener and drives its own
+/// connections without blocking. The handler runs inline on the loop thread,
+/// or on `pool` if given, so that slow handlers hold up only their own
+/// requests. Returns once every loop has stopped.
+pub fn run(
+    server: Arc<Server>,
+    listener: std::net::TcpListener,
+    threads: usize,
+    pool: Option<BlockingPool>,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let pool = pool.map(Arc::new);
+
+    let mut loops = Vec::new();
+    for _ in 0..threads.max(1) {
+        let listener = TcpListener::from_std(listener.try_clone()?);
+        let server = server.clone();
+        let pool = pool.clone();
+        loops.push(thread::spawn(move || event_loop(server, listener, pool)));
+    }
+
+    for handle in loops {
+        match handle.join() {
+            Ok(Err(e)) => eprintln!("event loop stopped: {}", e),
+            Err(_) => eprintln!("event loop panicked"),
+            Ok(Ok(())) => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn event_loop(
+    server: Arc<Server>,
+    mut listener: TcpListener,
+    pool: Option<Arc<BlockingPool>>,
+) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(1024);
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = 1;
+
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+
+    let offload = match pool {
+        Some(pool) => {
+            let (sender, receiver) = mpsc::channel();
+            Some(Offload {
+                pool,
+                sender,
+                receiver,
+                waker: Arc::new(Waker::new(poll.registry(), HANDLED)?),
+            })
+        }
+        None => None,
+    };
+
+    loop {
+        // wake up regularly so slow clients are noticed even when idle, and
+        // whenever a paced response may carry on
+        let now = Instant::now();
+        let timeout = connections
+            .values()
+            .filter_map(|connection| connection.paused_until)
+            .map(|until| until.saturating_duration_since(now))
+            .fold(Duration::from_secs(1), Duration::min);
+        if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                loop {
+                    let (mut stream, peer) = match listener.accept() {
+                        Ok(accepted) => accepted,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            server.log(format_args!("Failed to accept: {}", e));
+                            break;
+                        }
+                    };
+
+                    if !server.accepts(&peer) {
+                        continue;
+                    }
+
+                    if let Err(e) = server.tcp.apply(&stream) {
+                        server.log(format_args!("Could not set socket options: {}", e));
+                    }
+
+                    let token = Token(next_token);
+                    next_token += 1;
+
+                    poll.registry()
+                        .register(&mut stream, token, Interest::READABLE)?;
+                    server.log(format_args!("Handling connection from {}", peer));
+
+                    connections.insert(
+                        token,
+                        Connection {
+                            number: server.opened(peer),
+                            stream,
+                            peer,
+                            started: Instant::now(),
+                            input: server.buffers.take(),
+                            bytes_read: 0,
+                            output: server.buffers.take(),
+                            written: 0,
+                            scanner: Some(HeadScanner::with_limits(
+                                server.read_limits().header_limits(),
+                            )),
+                            limits: server.read_limits(),
+                            reading: true,
+                            handling: None,
+                            error: None,
+                            pacer: None,
+                            paused_until: None,
+                            urgency: DEFAULT_URGENCY,
+                        },
+                    );
+                }
+                continue;
+            }
+
+            if event.token() == HANDLED {
+                let Some(offload) = &offload else {
+                    continue;
+                };
+
+                for (token, output) in offload.receiver.try_iter() {
+                    // the connection may have timed out or gone away meanwhile
+                    let Some(connection) = connections.get_mut(&token) else {
+                        continue;
+                    };
+                    if connection.handling.take().is_none() {
+                        continue;
+                    }
+
+                    server
+                        .buffers
+                        .give(mem::replace(&mut connection.output, output));
+                    if let Progress::Close = respond(&server, &poll, token, connection) {
+                        close(&server, &poll, &mut connections, token);
+                    }
+                }
+                continue;
+            }
+
+            let Some(connection) = connections.get_mut(&event.token()) else {
+                continue;
+            };
+
+            let progress = if connection.handling.is_some() {
+                Progress::Continue
+            } else if connection.reading {
+                read(&server, offload.as_ref(), &poll, event.token(), connection)
+            } else {
+                write(&server, connection)
+            };
+
+            if let Progress::Close = progress {
+                close(&server, &poll, &mut connections, event.token());
+            }
+        }
+
+        let now = Instant::now();
+        let resumed: Vec<Token> = connections
+            .iter()
+            .filter(|(_, connection)| connection.paused_until.is_some_and(|until| until <= now))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in resumed {
+            let connection = connections.get_mut(&token).unwrap();
+            connection.paused_until = None;
+            if let Progress::Close = write(&server, connection) {
+                close(&server, &poll, &mut connections, token);
+            }
+        }
+
+        connections.retain(|_, connection| {
+            let too_slow = connection.reading
+                && connection.handling.is_none()
+                && connection
+                    .limits
+                    .too_slow(connection.started, connection.input.len(), now);
+
+            if too_slow {
+                Metrics::increment(&METRICS.slow_clients_dropped);
+                server.log(format_args!(
+                    "Dropped slow connection from {}",
+                    connection.peer
+                ));
+                let _ = poll.registry().deregister(&mut connection.stream);
+                server.errored(
+                    connection.number,
+                    connection.peer,
+                    "the client was too slow",
+                );
+                server.closed(
+                    connection.number,
+                    connection.peer,
+                    connection.input.len(),
+                    0,
+                    connection.started,
+                );
+            }
+
+            !too_slow
+        });
+
+        if let Some(offload) = &offload {
+            let timed_out: Vec<Token> = connections
+                .iter()
+                .filter(|(_, connection)| {
+                    connection
+                        .handling
+                        .is_some_and(|since| now.duration_since(since) >= offload.pool.timeout())
+                })
+                .map(|(token, _)| *token)
+                .collect();
+
+            for token in timed_out {
+                let connection = connections.get_mut(&token).unwrap();
+                connection.handling = None;
+                connection.output = server.rejected_response(
+                    "Service Unavailable: the request took too long to handle",
+                    503,
+                );
+                if let Progress::Close = respond(&server, &poll, token, connection) {
+                    close(&server, &poll, &mut connections, token);
+                }
+            }
+        }
+    }
+}
+
+fn close(server: &Server, poll: &Poll, connections: &mut HashMap<Token, Connection>, token: Token) {
+    if let Some(mut connection) = connections.remove(&token) {
+        let _ = poll.registry().deregister(&mut connection.stream);
+        let _ = connection.stream.shutdown(Shutdown::Both);
+        if let Some(error) = &connection.error {
+            server.errored(connection.number, connection.peer, error);
+        }
+        server.closed(
+            connection.number,
+            connection.peer,
+            connection.bytes_read.max(connection.input.len()),
+            connection.written,
+            connection.started,
+        );
+        server.buffers.give(connection.input);
+        server.buffers.give(connection.output);
+    }
+}
+
+fn read(
+    server: &Arc<Server>,
+    offload: Option<&Offload>,
+    poll: &Poll,
+    token: Token,
+    connection: &mut Connection,
+) -> Progress {
+    let mut buffer = [0; 8192];
+    let mut finished = false;
+
+    loop {
+        match connection.stream.read(&mut buffer) {
+            Ok(0) => {
+                finished = true;
+                break;
+            }
+            Ok(bytes_read) => connection.input.extend_from_slice(&buffer[..bytes_read]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                server.log(format_args!(
+                    "Failed to read from {}: {}",
+                    connection.peer, e
+                ));
+                server.errored(connection.number, connection.peer, &e.to_string());
+                return Progress::Close;
+            }
+        }
+
+        if connection.input.len() > connection.limits.max_message_size {
+            connection.output = server.oversized_response();
+            return respond(server, poll, token, connection);
+        }
+    }
+
+    let input = &connection.input;
+    let format = WireFormat::sniff(input);
+
+    if let (WireFormat::Json, Some(scanner)) = (format, &mut connection.scanner) {
+        let head = match scanner.feed(input) {
+            Ok(head) => head,
+            Err((message, code)) => {
+                connection.output = server.rejected_response(&message, code);
+                return respond(server, poll, token, connection);
+            }
+        };
+        if head.is_complete() {
+            if let Some(headers) = &scanner.head().headers {
+                connection.urgency = urgency(headers);
+            }
+            match server.check_head(scanner.head(), connection.peer.ip()) {
+                Ok(limits) => connection.limits = limits,
+                Err((message, code)) => {
+                    connection.output = server.rejected_response(&message, code);
+                    return respond(server, poll, token, connection);
+                }
+            }
+            connection.scanner = None;
+            if input.len() > connection.limits.max_message_size {
+                connection.output = server.oversized_response();
+                return respond(server, poll, token, connection);
+            }
+        }
+    }
+
+    if !finished && format.message_state(input) == MessageState::Incomplete {
+        return Progress::Continue;
+    }
+
+    if let Some(offload) = offload {
+        return hand_off(server, offload, poll, token, connection);
+    }
+
+    connection.bytes_read = input.len();
+    let arrival = Arrival {
+        connection: connection.number,
+        received: connection.started,
+        ..Arrival::new(connection.peer.ip())
+    };
+    handle_isolated(server, input, &arrival, &mut connection.output);
+
+    // the response is already encoded into its own buffer, and this thread
+    // moves on to other connections while it is sent
+    #[cfg(feature = "arena")]
+    crate::arena::reset();
+
+    respond(server, poll, token, connection)
+}
+
+/// Handles `input` onto `output`, or answers it with 500 if that panics, so
+/// that neither the loop's other connections nor the one waiting for the
+/// pool are dropped with it. Handler panics are answered in
+/// [`Server::handle_message_into`] already; this catches the rest.
+fn handle_isolated(server: &Server, input: &[u8], arrival: &Arrival, output: &mut Vec<u8>) {
+    let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+        server.handle_message_into(input, arrival, output)
+    }));
+
+    if handled.is_err() {
+        Metrics::increment(&METRICS.handler_panics);
+        output.clear();
+        output.extend(server.rejected_response("Internal Server Error", 500));
+    }
+}
+
+/// Has the pool handle the request the connection has finished reading.
+fn hand_off(
+    server: &Arc<Server>,
+    offload: &Offload,
+    poll: &Poll,
+    token: Token,
+    connection: &mut Connection,
+) -> Progress {
+    let input = mem::take(&mut connection.input);
+    connection.bytes_read = input.len();
+    let queued = Instant::now();
+    let timeout = offload.pool.timeout();
+    let arrival = Arrival {
+        connection: connection.number,
+        received: connection.started,
+        deadline: Some(queued + timeout),
+        ..Arrival::new(connection.peer.ip())
+    };
+
+    let job = {
+        let server = server.clone();
+        let sender = offload.sender.clone();
+        let waker = offload.waker.clone();
+
+        move || {
+            // already answered by the event loop
+            if queued.elapsed() >= timeout {
+                return;
+            }
+
+            let mut output = server.buffers.take();
+            handle_isolated(&server, &input, &arrival, &mut output);
+            server.buffers.give(input);
+            #[cfg(feature = "arena")]
+            crate::arena::reset();
+
+            if sender.send((token, output)).is_ok() {
+                let _ = waker.wake();
+            }
+        }
+    };
+
+    let peer = connection.peer.ip();
+    match offload.pool.execute_as(job, &peer, connection.urgency) {
+        Ok(()) => {
+            connection.handling = Some(queued);
+            Progress::Continue
+        }
+        Err(_) => {
+            connection.output = server.rejected_response(
+                "Service Unavailable: too many requests are waiting to be handled",
+                503,
+            );
+            respond(server, poll, token, connection)
+        }
+    }
+}
+
+fn respond(server: &Server, poll: &Poll, token: Token, connection: &mut Connection) -> Progress {
+    connection.reading = false;
+    connection.pacer = connection.limits.pacer();
+
+    if poll
+        .registry()
+        .reregister(&mut connection.stream, token, Interest::WRITABLE)
+        .is_err()
+    {
+        return Progress::Close;
+    }
+
+    // the socket is usually writable straight away
+    write(server, connection)
+}
+
+fn write(server: &Server, connection: &mut Connection) -> Progress {
+    while connection.written < connection.output.len() {
+        let mut end = connection.output.len();
+        if let Some(pacer) = &mut connection.pacer {
+            let now = Instant::now();
+            let wanted = (end - connection.written).min(PACED_CHUNK);
+            let allowed = pacer.available(now).min(wanted);
+            if allowed == 0 {
+                connection.paused_until = Some(now + pacer.wait(wanted, now));
+                return Progress::Continue;
+            }
+            end = connection.written + allowed;
+        }
+
+        match connection
+            .stream
+            .write(&connection.output[connection.written..end])
+        {
+            Ok(0) => return Progress::Close,
+            Ok(bytes_written) => {
+                connection.written += bytes_written;
+                if let Some(pacer) = &mut connection.pacer {
+                    pacer.spend(bytes_written);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Progress::Continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                server.log(format_args!(
+                    "Failed to write to {}: {}",
+                    connection.peer, e
+                ));
+                connection.error = Some(e.to_string());
+                return Progress::Close;
+            }
+        }
+    }
+
+    server.log(format_args!("handled connection from {}", connection.peer));
+    Progress::Close
+}
@@ -0,0 +1,68 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::{
+    metrics::Counter,
+    protocol::{JsontpRequest, JsontpResponse},
+};
+
+static NEXT_CONNECTION: Counter = Counter::new(1);
+
+/// A number for a newly accepted connection, unique within the process.
+pub fn next_connection() -> u64 {
+    NEXT_CONNECTION.fetch_add(1)
+}
+
+/// Something that happened to a connection. Every event names the
+/// connection it belongs to by the number the server gave it, which is also
+/// [`Context::connection`](crate::middleware::Context::connection) for its
+/// requests.
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// The connection was accepted and is about to be read from.
+    Accepted { connection: u64, peer: SocketAddr },
+    /// A request was decoded and is about to be validated and handled. A
+    /// batch starts one for each of its requests.
+    RequestStart {
+        connection: u64,
+        request: &'a JsontpRequest,
+    },
+    /// The response to a request is ready to be encoded. `request` is `None`
+    /// when the message could not be decoded.
+    RequestEnd {
+        connection: u64,
+        request: Option<&'a JsontpRequest>,
+        response: &'a JsontpResponse,
+        elapsed: Duration,
+    },
+    /// Serving the connection went wrong: the client was too slow, or
+    /// reading or writing failed. It is closed straight after.
+    Errored {
+        connection: u64,
+        peer: SocketAddr,
+        error: &'a str,
+    },
+    /// The connection was closed, whether or not it was answered.
+    Closed {
+        connection: u64,
+        peer: SocketAddr,
+        bytes_read: usize,
+        bytes_written: usize,
+        open_for: Duration,
+    },
+}
+
+/// Told about every [`Event`] on a server, from whichever thread it
+/// happens on, so that embedders can keep their own accounting, quotas or
+/// audit trails. Subscribers are called inline and should return quickly.
+pub trait Subscriber: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+impl<F> Subscriber for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
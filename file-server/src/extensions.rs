@@ -0,0 +1,88 @@
+use alloc::{
+    collections::BTreeSet,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use serde_json::Value;
+
+use crate::headers::Headers;
+
+/// The header a response to `OPTIONS` lists the extensions the server
+/// supports in, and a request lists the extensions it cannot do without in.
+pub const EXTENSIONS_HEADER: &str = "extensions";
+
+/// Several messages in flight on one connection at once.
+pub const MULTIPLEXING: &str = "multiplexing";
+/// Responses the server sends without being asked.
+pub const PUSH: &str = "push";
+/// Signed requests and responses, see `jsontp::signing`.
+pub const SIGNING: &str = "signing";
+/// Bodies compressed with zstd, see `jsontp::compression`.
+pub const ZSTD: &str = "zstd";
+
+/// The extensions named in the `extensions` header of `headers`, as a
+/// string or an array of strings, in lower case.
+pub fn from_headers(headers: &Headers) -> BTreeSet<String> {
+    match headers.get(EXTENSIONS_HEADER) {
+        Some(Value::String(name)) => [name.to_lowercase()].into(),
+        Some(Value::Array(names)) => names
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_lowercase)
+            .collect(),
+        _ => BTreeSet::new(),
+    }
+}
+
+/// `extensions` as the value of an `extensions` header.
+pub fn to_header(extensions: &BTreeSet<String>) -> Value {
+    extensions.iter().cloned().map(Value::String).collect()
+}
+
+/// Those of `required` that are not among `supported`.
+pub fn missing(required: &BTreeSet<String>, supported: &BTreeSet<String>) -> Vec<String> {
+    required.difference(supported).cloned().collect()
+}
+
+/// What a client wants of a server's extensions: some it cannot work
+/// without, and some it uses when the server has them and does without
+/// otherwise.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionPolicy {
+    pub required: BTreeSet<String>,
+    pub preferred: BTreeSet<String>,
+}
+
+impl ExtensionPolicy {
+    pub fn new() -> ExtensionPolicy {
+        ExtensionPolicy::default()
+    }
+
+    pub fn require(mut self, extension: &str) -> ExtensionPolicy {
+        self.required.insert(extension.to_lowercase());
+        self
+    }
+
+    pub fn prefer(mut self, extension: &str) -> ExtensionPolicy {
+        self.preferred.insert(extension.to_lowercase());
+        self
+    }
+
+    /// The extensions to use with a server supporting `supported`: every
+    /// required one and the preferred ones it has. Gives the required ones
+    /// it lacks if there are any.
+    pub fn negotiate(&self, supported: &BTreeSet<String>) -> Result<BTreeSet<String>, Vec<String>> {
+        let missing = missing(&self.required, supported);
+        if !missing.is_empty() {
+            return Err(missing);
+        }
+
+        Ok(self
+            .required
+            .iter()
+            .chain(self.preferred.intersection(supported))
+            .map(ToString::to_string)
+            .collect())
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{self, Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+/// What the file server needs to know about a file besides its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub size: u64,
+    pub modified: SystemTime,
+    pub mime_type: &'static str,
+    pub etag: String,
+}
+
+impl FileMetadata {
+    /// Reads the metadata of `path` from the filesystem.
+    pub fn read(path: &Path) -> io::Result<FileMetadata> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?;
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        // writes replace the file, so the inode tells apart two versions
+        // written within the resolution of the modification time
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        Ok(FileMetadata {
+            size: metadata.len(),
+            modified,
+            mime_type: mime_type(path),
+            etag: format!(
+                "\"{:x}-{:x}-{:x}.{:x}\"",
+                inode,
+                metadata.len(),
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos()
+            ),
+        })
+    }
+}
+
+/// Whether a write may go ahead given the `if-match` and `if-none-match`
+/// preconditions of the request, where `current` is the metadata of the
+/// resource as it is now, or `None` if it does not exist.
+///
+/// Both take `*` or a comma separated list of ETags, so `if-match` with the
+/// ETag last read only lets the write through if nobody changed the file in
+/// between, and `if-none-match: *` only lets it create a new file.
+pub fn preconditions_hold(
+    current: Option<&FileMetadata>,
+    if_match: Option<&str>,
+    if_none_match: Option<&str>,
+) -> bool {
+    let matches = |condition: &str| {
+        current.is_some_and(|current| {
+            condition
+                .split(',')
+                .map(str::trim)
+                .any(|etag| etag == "*" || etag == current.etag)
+        })
+    };
+
+    if_match.is_none_or(matches) && !if_none_match.is_some_and(matches)
+}
+
+/// Guesses the MIME type of a file from its extension.
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js" | "mjs") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt" | "md" | "toml" | "rs") => "text/plain",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MetadataCacheConfig {
+    /// Seconds an entry is trusted before the file is looked at again.
+    pub ttl: u64,
+    /// The most paths remembered at once.
+    pub capacity: usize,
+    /// Seconds past its `ttl` an entry is still served while the file is
+    /// looked at again in the background.
+    pub stale_while_revalidate: u64,
+    /// Seconds past its `ttl` an entry is still served when looking at the
+    /// file fails other than by it not existing.
+    pub stale_if_error: u64,
+}
+
+impl Default for MetadataCacheConfig {
+    fn default() -> Self {
+        MetadataCacheConfig {
+            ttl: 5,
+            capacity: 1024,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    cached_at: Instant,
+    metadata: FileMetadata,
+    /// Whether the file is being looked at again in the background.
+    refreshing: bool,
+}
+
+type Entries = RwLock<BTreeMap<PathBuf, Entry>>;
+
+/// A lookup under way, which other lookups of the same path wait for.
+#[derive(Debug, Default)]
+struct Flight {
+    /// Set once the lookup is done. Errors are kept apart, as an
+    /// `io::Error` cannot be cloned.
+    result: Mutex<Option<Result<FileMetadata, (io::ErrorKind, String)>>>,
+    done: Condvar,
+}
+
+/// Remembers the metadata of recently served files for `ttl` seconds, so
+/// serving the same file again does not need another `stat`. Past that, an
+/// entry may still be served for `stale-while-revalidate` seconds while a
+/// background thread refreshes it, or for `stale-if-error` seconds when the
+/// filesystem fails. Requests missing the cache for the same path at once
+/// share a single lookup.
+#[derive(Debug)]
+pub struct MetadataCache {
+    pub config: MetadataCacheConfig,
+    entries: Arc<Entries>,
+    in_flight: Mutex<BTreeMap<PathBuf, Arc<Flight>>>,
+}
+
+impl MetadataCache {
+    pub fn new(config: MetadataCacheConfig) -> MetadataCache {
+        MetadataCache {
+            config,
+            entries: Arc::new(RwLock::new(BTreeMap::new())),
+            in_flight: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The metadata of `path`, from the cache when a fresh entry exists.
+    /// Failed lookups are not cached.
+    pub fn get(&self, path: &Path) -> io::Result<FileMetadata> {
+        let ttl = Duration::from_secs(self.config.ttl);
+        let while_revalidate = ttl + Duration::from_secs(self.config.stale_while_revalidate);
+        let now = Instant::now();
+
+        let stale = match self.entries.read().unwrap().get(path) {
+            Some(entry) if now.duration_since(entry.cached_at) < ttl => {
+                return Ok(entry.metadata.clone())
+            }
+            Some(entry) => Some((entry.cached_at, entry.metadata.clone())),
+            None => None,
+        };
+
+        if let Some((cached_at, metadata)) = &stale {
+            if now.duration_since(*cached_at) < while_revalidate {
+                self.revalidate(path);
+                return Ok(metadata.clone());
+            }
+        }
+
+        self.read_once(path).or_else(|error| {
+            let if_error = ttl + Duration::from_secs(self.config.stale_if_error);
+            match stale {
+                Some((cached_at, metadata))
+                    if error.kind() != io::ErrorKind::NotFound
+                        && now.duration_since(cached_at) < if_error =>
+                {
+                    Ok(metadata)
+                }
+                _ => Err(error),
+            }
+        })
+    }
+
+    /// Reads the metadata of `path` and caches it, or waits for the lookup
+    /// of another request for the same path if one is under way.
+    fn read_once(&self, path: &Path) -> io::Result<FileMetadata> {
+        let (flight, leading) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(path) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    in_flight.insert(path.to_path_buf(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if !leading {
+            let mut result = flight.result.lock().unwrap();
+            while result.is_none() {
+                result = flight.done.wait(result).unwrap();
+            }
+            return match result.as_ref().unwrap() {
+                Ok(metadata) => Ok(metadata.clone()),
+                Err((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+            };
+        }
+
+        let read = FileMetadata::read(path);
+        // cached first, so that lookups from now on find it there
+        if let Ok(metadata) = &read {
+            insert(&self.entries, &self.config, path, metadata.clone());
+        }
+        self.in_flight.lock().unwrap().remove(path);
+
+        *flight.result.lock().unwrap() = Some(match &read {
+            Ok(metadata) => Ok(metadata.clone()),
+            Err(error) => Err((error.kind(), error.to_string())),
+        });
+        flight.done.notify_all();
+        read
+    }
+
+    /// Looks at `path` again on a thread of its own, unless that is already
+    /// under way.
+    fn revalidate(&self, path: &Path) {
+        match self.entries.write().unwrap().get_mut(path) {
+            Some(entry) if !entry.refreshing => entry.refreshing = true,
+            _ => return,
+        }
+
+        let entries = self.entries.clone();
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            let metadata = FileMetadata::read(&path);
+            // an entry invalidated meanwhile stays gone, as the file may
+            // have changed since it was read
+            if let Some(entry) = entries.write().unwrap().get_mut(&path) {
+                match metadata {
+                    Ok(metadata) => {
+                        *entry = Entry {
+                            cached_at: Instant::now(),
+                            metadata,
+                            refreshing: false,
+                        }
+                    }
+                    // left to be served stale, or looked up again once too old
+                    Err(_) => entry.refreshing = false,
+                }
+            }
+        });
+    }
+
+    /// Forgets `path`, and everything under it if it is a directory, so the
+    /// next lookup goes to the filesystem. Relative and absolute spellings of
+    /// the same path are treated alike.
+    pub fn invalidate(&self, path: &Path) {
+        let absolute = |path: &Path| path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        let changed = absolute(path);
+
+        self.entries
+            .write()
+            .unwrap()
+            .retain(|cached, _| !absolute(cached).starts_with(&changed));
+    }
+
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+}
+
+/// Caches `metadata` as just read, making room if the cache is full.
+fn insert(entries: &Entries, config: &MetadataCacheConfig, path: &Path, metadata: FileMetadata) {
+    let now = Instant::now();
+    // entries that may still be served, if only stale
+    let kept =
+        Duration::from_secs(config.ttl + config.stale_while_revalidate.max(config.stale_if_error));
+
+    let mut entries = entries.write().unwrap();
+    if entries.len() >= config.capacity && !entries.contains_key(path) {
+        entries.retain(|_, entry| now.duration_since(entry.cached_at) < kept);
+
+        if entries.len() >= config.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.cached_at)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+    }
+    if config.capacity > 0 {
+        entries.insert(
+            path.to_path_buf(),
+            Entry {
+                cached_at: now,
+                metadata,
+                refreshing: false,
+            },
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::headers::Headers;
+
+/// Where the table is served, for clients to fetch before they use it.
+pub const HEADER_TABLE_RESOURCE: &str = ".well-known/jsontp-header-table";
+
+/// The names every table starts with. Entries are only ever added to the
+/// end, so that indexes a client has learned keep their meaning.
+const BUILT_IN: &[&str] = &[
+    "content-type",
+    "accept",
+    "accept-encoding",
+    "accept-language",
+    "authorization",
+    "cookies",
+    "etag",
+    "if-match",
+    "if-none-match",
+    "if-modified-since",
+    "if-unmodified-since",
+    "wire-format",
+    "upload-id",
+    "upload-offset",
+    "upload-complete",
+    "cursor",
+    "limit",
+    "next-cursor",
+    "idempotency-key",
+    "compression-context",
+    "signature",
+    "csrf-token",
+    "origin",
+    "destination",
+    "overwrite",
+    "depth",
+    "expect",
+    "date",
+    "language",
+    "quota-limit",
+    "quota-remaining",
+    "quota-reset",
+    "bandwidth-limit",
+    "bandwidth-used",
+    "access-control-allow-origin",
+    "x-content-type-options",
+    "x-frame-options",
+    "priority",
+];
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HeaderTableConfig {
+    /// Names added after the built-in ones, such as the application's own
+    /// headers. Only add to the end of the list, or clients holding the old
+    /// table will send the wrong names.
+    pub names: Vec<String>,
+}
+
+/// Header names numbered so that a client sending many small messages can
+/// give `"0"` for `content-type` rather than spelling it out. A client
+/// fetches the table from [`HEADER_TABLE_RESOURCE`] once; after that any
+/// header key of its requests that is a number stands for that entry, and
+/// the responses to requests doing so name their headers the same way,
+/// where the table has them.
+#[derive(Debug, Clone)]
+pub struct HeaderTable {
+    names: Vec<String>,
+}
+
+impl HeaderTable {
+    pub fn new(config: &HeaderTableConfig) -> HeaderTable {
+        let mut names: Vec<String> = BUILT_IN.iter().map(ToString::to_string).collect();
+        for name in &config.names {
+            let name = name.to_ascii_lowercase();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        HeaderTable { names }
+    }
+
+    /// Identifies the table, so that a client can tell whether the one it
+    /// holds is still current. It is the FNV-1a hash of the names, each
+    /// ended by a zero byte, so the same names give the same id whatever
+    /// built the server.
+    pub fn id(&self) -> String {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in self.names.iter().flat_map(|name| name.bytes().chain([0])) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        format!("{:016x}", hash)
+    }
+
+    /// The name of entry `index`.
+    pub fn name(&self, index: usize) -> Option<&str> {
+        self.names.get(index).map(String::as_str)
+    }
+
+    /// The index of `name`, in any case.
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .position(|known| known.eq_ignore_ascii_case(name))
+    }
+
+    /// Replaces numbered keys with the names they stand for, giving whether
+    /// there were any. A number past the end of the table is turned down,
+    /// as the client's table is not this one.
+    pub fn expand(&self, headers: &mut Headers) -> Result<bool, (String, u16)> {
+        if !headers.keys().any(is_index) {
+            return Ok(false);
+        }
+
+        // built apart, so that a request turned down is echoed as it came
+        let expanded = headers
+            .iter()
+            .map(|(key, value)| {
+                if !is_index(key) {
+                    return Ok((key, value.clone()));
+                }
+                let name = key
+                    .parse()
+                    .ok()
+                    .and_then(|index| self.name(index))
+                    .ok_or_else(|| (format!("Bad Request: no header is numbered {}", key), 400))?;
+                Ok((name, value.clone()))
+            })
+            .collect::<Result<Headers, _>>()?;
+
+        *headers = expanded;
+        Ok(true)
+    }
+
+    /// Replaces the names the table has with their numbers.
+    pub fn intern(&self, headers: &mut Headers) {
+        *headers = std::mem::take(headers)
+            .into_iter()
+            .map(|(key, value)| match self.index(&key) {
+                Some(index) => (index.to_string(), value),
+                None => (key, value),
+            })
+            .collect();
+    }
+
+    /// The table as it is served: `{"id": "...", "names": [...]}`, the
+    /// index of a name being its place in `names`.
+    pub fn to_json(&self) -> Value {
+        json!({ "id": self.id(), "names": self.names })
+    }
+}
+
+fn is_index(key: &str) -> bool {
+    !key.is_empty() && key.bytes().all(|byte| byte.is_ascii_digit())
+}
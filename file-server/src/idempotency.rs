@@ -0,0 +1,516 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::protocol::{JsontpRequest, JsontpResponse};
+
+/// The request header carrying the key a client picked for an operation, the
+/// same on every retry of it.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// A response remembered for an idempotency key, with a fingerprint of the
+/// request it answered so a different request reusing the key is caught.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredResponse {
+    pub fingerprint: u64,
+    pub response: JsontpResponse,
+}
+
+/// What [`IdempotencyStore::claim`] found for a key.
+#[derive(Debug, Clone)]
+pub enum Claim {
+    /// The request was answered already, with this.
+    Stored(Box<StoredResponse>),
+    /// Another request with the key is being handled.
+    Pending,
+    /// The key is now the caller's, until it is given up with
+    /// [`IdempotencyStore::release`].
+    Claimed,
+}
+
+/// Where responses are remembered. Any store will do as long as entries live
+/// at least as long as clients keep retrying.
+pub trait IdempotencyStore: Send + Sync {
+    fn get(&self, key: &str) -> Option<StoredResponse>;
+    fn put(&self, key: &str, stored: StoredResponse);
+    /// Looks `key` up and, if nothing is stored for it and no one else holds
+    /// it, takes it, all in one step so two requests cannot both go ahead.
+    fn claim(&self, key: &str) -> Claim;
+    /// Gives up a key taken with [`IdempotencyStore::claim`], after its
+    /// response is [put](IdempotencyStore::put), or when there is none to
+    /// keep.
+    fn release(&self, key: &str);
+    /// Forgets the response put for `key`, as when the transaction it was
+    /// part of is rolled back and a retry has to carry the request out again.
+    fn remove(&self, key: &str);
+}
+
+/// Gives up a claimed key when dropped.
+pub(crate) struct Released<'a> {
+    pub store: &'a dyn IdempotencyStore,
+    pub key: &'a str,
+}
+
+impl Drop for Released<'_> {
+    fn drop(&mut self) {
+        self.store.release(self.key);
+    }
+}
+
+/// Whether requests with `method` change something, so a retry could do it
+/// twice.
+pub fn is_unsafe(method: &str) -> bool {
+    !matches!(method, "GET" | "OPTIONS")
+}
+
+/// The headers that change what a request does, and so are part of its
+/// [`fingerprint`].
+pub const FINGERPRINT_HEADERS: &[&str] = &[
+    "destination",
+    "overwrite",
+    "depth",
+    "upload-id",
+    "upload-offset",
+    "upload-complete",
+    "if-match",
+    "if-none-match",
+];
+
+/// FNV-1a, which unlike `DefaultHasher` is the same in every build and
+/// process, as hashes kept on disk and shared between workers need to be.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// What makes two requests the same operation.
+pub fn fingerprint(request: &JsontpRequest) -> u64 {
+    let mut hasher = Fnv::default();
+    request.method.hash(&mut hasher);
+    request.resource.hash(&mut hasher);
+    for name in FINGERPRINT_HEADERS {
+        request
+            .headers
+            .get(name)
+            .map(Value::to_string)
+            .hash(&mut hasher);
+    }
+    request.body.content.hash(&mut hasher);
+    request.body.encoding.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A store and the resources it is used for.
+pub struct Idempotency {
+    pub store: Arc<dyn IdempotencyStore>,
+    /// Resource prefixes requests must be under to be deduplicated; every
+    /// resource when empty.
+    pub routes: Vec<String>,
+}
+
+impl Idempotency {
+    pub fn new(store: impl IdempotencyStore + 'static) -> Idempotency {
+        Idempotency {
+            store: Arc::new(store),
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn from_config(config: &IdempotencyConfig) -> io::Result<Idempotency> {
+        let ttl = Duration::from_secs(config.ttl);
+
+        let store: Arc<dyn IdempotencyStore> = match &config.store {
+            StoreKind::Memory => Arc::new(MemoryStore::new(ttl, config.capacity)),
+            StoreKind::File { path } => {
+                Arc::new(FileStore::new(path.clone(), ttl, config.capacity)?)
+            }
+        };
+
+        Ok(Idempotency {
+            store,
+            routes: config.routes.clone(),
+        })
+    }
+
+    pub fn applies_to(&self, resource: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|route| resource.starts_with(route))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum StoreKind {
+    Memory,
+    /// One file per key in the `path` directory, so responses survive a
+    /// restart and are shared between worker processes.
+    File {
+        path: PathBuf,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    /// Seconds a response is replayed for.
+    pub ttl: u64,
+    /// The most responses kept; the least recently used go first.
+    pub capacity: usize,
+    pub store: StoreKind,
+    /// Only deduplicate requests for resources under these prefixes.
+    pub routes: Vec<String>,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            ttl: 24 * 60 * 60,
+            capacity: 10_000,
+            store: StoreKind::Memory,
+            routes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    stored_at: Instant,
+    used_at: Instant,
+    stored: StoredResponse,
+}
+
+/// Keeps up to `capacity` responses in memory for `ttl`, forgetting the least
+/// recently used first.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use jsontp::{
+///     idempotency::{Claim, IdempotencyStore, MemoryStore, StoredResponse},
+///     server::status_response,
+/// };
+///
+/// let store = MemoryStore::new(Duration::from_secs(60), 10);
+/// assert!(matches!(store.claim("key"), Claim::Claimed));
+/// assert!(matches!(store.claim("key"), Claim::Pending));
+///
+/// let response = status_response(201, "Created", "Resource was created");
+/// store.put("key", StoredResponse { fingerprint: 1, response });
+/// store.release("key");
+/// assert!(matches!(store.claim("key"), Claim::Stored(_)));
+/// ```
+#[derive(Debug)]
+pub struct MemoryStore {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<BTreeMap<String, MemoryEntry>>,
+    /// Keys claimed by requests being handled; only changed with `entries`
+    /// locked, so a claim sees what was stored before it.
+    claimed: Mutex<BTreeSet<String>>,
+}
+
+impl MemoryStore {
+    pub fn new(ttl: Duration, capacity: usize) -> MemoryStore {
+        MemoryStore {
+            ttl,
+            capacity,
+            entries: Mutex::new(BTreeMap::new()),
+            claimed: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    fn lookup(
+        &self,
+        entries: &mut BTreeMap<String, MemoryEntry>,
+        key: &str,
+    ) -> Option<StoredResponse> {
+        let entry = entries.get_mut(key)?;
+
+        if entry.stored_at.elapsed() >= self.ttl {
+            entries.remove(key);
+            return None;
+        }
+
+        entry.used_at = Instant::now();
+        Some(entry.stored.clone())
+    }
+}
+
+impl IdempotencyStore for MemoryStore {
+    fn get(&self, key: &str) -> Option<StoredResponse> {
+        self.lookup(&mut self.entries.lock().unwrap(), key)
+    }
+
+    fn put(&self, key: &str, stored: StoredResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.capacity && !entries.contains_key(key) {
+            entries.retain(|_, entry| now.duration_since(entry.stored_at) < self.ttl);
+
+            if entries.len() >= self.capacity {
+                let least_recent = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.used_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(least_recent) = least_recent {
+                    entries.remove(&least_recent);
+                }
+            }
+        }
+
+        entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                stored_at: now,
+                used_at: now,
+                stored,
+            },
+        );
+    }
+
+    fn claim(&self, key: &str) -> Claim {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(stored) = self.lookup(&mut entries, key) {
+            return Claim::Stored(Box::new(stored));
+        }
+        match self.claimed.lock().unwrap().insert(key.to_string()) {
+            true => Claim::Claimed,
+            false => Claim::Pending,
+        }
+    }
+
+    fn release(&self, key: &str) {
+        let _entries = self.entries.lock().unwrap();
+        self.claimed.lock().unwrap().remove(key);
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+/// How long a key claimed in a [`FileStore`] stays claimed, after which the
+/// process that claimed it is taken to have stopped without giving it up.
+const ABANDONED_CLAIM: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize)]
+struct FileEntry {
+    key: String,
+    stored: StoredResponse,
+}
+
+/// Keeps responses as files in a directory, named after a hash of their key.
+/// Expiry goes by the modification time of the files. A key is claimed by
+/// creating a hidden file next to where its response goes, which other
+/// processes sharing the directory see too.
+///
+/// The directory is only looked through when the store is opened and when
+/// it fills up, which then makes room for a tenth of `capacity` at once.
+/// Each process counts the files it writes, so several sharing a directory
+/// may take it past `capacity` until one of them fills up.
+#[derive(Debug)]
+pub struct FileStore {
+    directory: PathBuf,
+    ttl: Duration,
+    capacity: usize,
+    /// About how many responses are in the directory: those there when it
+    /// was last looked through, and those put since.
+    count: Mutex<usize>,
+}
+
+impl FileStore {
+    pub fn new(directory: PathBuf, ttl: Duration, capacity: usize) -> io::Result<FileStore> {
+        fs::create_dir_all(&directory)?;
+        let mut store = FileStore {
+            directory,
+            ttl,
+            capacity,
+            count: Mutex::new(0),
+        };
+        *store.count.get_mut().unwrap() = store.make_room(capacity)?;
+        Ok(store)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory
+            .join(format!("{:016x}.json", Self::hash(key)))
+    }
+
+    fn claim_path(&self, key: &str) -> PathBuf {
+        self.directory
+            .join(format!(".{:016x}.claim", Self::hash(key)))
+    }
+
+    fn hash(key: &str) -> u64 {
+        let mut hasher = Fnv::default();
+        hasher.write(key.as_bytes());
+        hasher.finish()
+    }
+
+    /// Creates the file claiming `key`, replacing one left by a process that
+    /// stopped before giving it up.
+    fn create_claim(&self, key: &str) -> io::Result<()> {
+        let path = self.claim_path(key);
+        let create = || {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+        };
+
+        match create() {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let abandoned = fs::metadata(&path)?
+                    .modified()
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .is_some_and(|age| age >= ABANDONED_CLAIM);
+                if !abandoned {
+                    return Err(e);
+                }
+                fs::remove_file(&path)?;
+                create().map(drop)
+            }
+            created => created.map(drop),
+        }
+    }
+
+    fn is_expired(&self, metadata: &fs::Metadata) -> bool {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age >= self.ttl)
+    }
+
+    /// Removes expired entries, then the oldest ones until at most `keep`
+    /// are left, giving how many are.
+    fn make_room(&self, keep: usize) -> io::Result<usize> {
+        let mut entries = Vec::new();
+
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            // leave files being written alone
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+
+            if self.is_expired(&metadata) {
+                let _ = fs::remove_file(entry.path());
+            } else {
+                entries.push((metadata.modified()?, entry.path()));
+            }
+        }
+
+        entries.sort();
+        let excess = entries.len().saturating_sub(keep);
+        for (_, path) in entries.drain(..excess) {
+            let _ = fs::remove_file(path);
+        }
+
+        Ok(entries.len())
+    }
+}
+
+impl IdempotencyStore for FileStore {
+    fn get(&self, key: &str) -> Option<StoredResponse> {
+        let path = self.path(key);
+
+        if self.is_expired(&fs::metadata(&path).ok()?) {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+
+        let entry: FileEntry = serde_json::from_slice(&fs::read(&path).ok()?).ok()?;
+        // two keys could hash alike
+        (entry.key == key).then_some(entry.stored)
+    }
+
+    fn claim(&self, key: &str) -> Claim {
+        if let Some(stored) = self.get(key) {
+            return Claim::Stored(Box::new(stored));
+        }
+        if self.create_claim(key).is_err() {
+            return Claim::Pending;
+        }
+        // the request holding the key before could have stored its response
+        // and given the key up in between
+        match self.get(key) {
+            Some(stored) => {
+                self.release(key);
+                Claim::Stored(Box::new(stored))
+            }
+            None => Claim::Claimed,
+        }
+    }
+
+    fn release(&self, key: &str) {
+        let _ = fs::remove_file(self.claim_path(key));
+    }
+
+    fn remove(&self, key: &str) {
+        // only if it is this key's, not another's that hashed alike
+        if self.get(key).is_some() {
+            let _ = fs::remove_file(self.path(key));
+        }
+    }
+
+    fn put(&self, key: &str, stored: StoredResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut count = self.count.lock().unwrap();
+        if *count >= self.capacity {
+            let keep = self.capacity - 1 - (self.capacity - 1) / 10;
+            match self.make_room(keep) {
+                Ok(left) => *count = left,
+                Err(_) => return,
+            }
+        }
+        *count += 1;
+        drop(count);
+
+        let entry = FileEntry {
+            key: key.to_string(),
+            stored,
+        };
+
+        if let Ok(data) = serde_json::to_vec(&entry) {
+            let _ = crate::upload::write_atomically(
+                &self.path(key),
+                &data,
+                crate::upload::Durability::None,
+            );
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use alloc::vec::Vec;
+use core::net::IpAddr;
+
+use serde::Deserialize;
+
+use crate::acl::IpRange;
+
+/// Decides which peers may connect at all. Deny entries win over allow
+/// entries; when `allow` is empty every address not denied is let in.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpFilter {
+    pub allow: Vec<IpRange>,
+    pub deny: Vec<IpRange>,
+}
+
+impl IpFilter {
+    pub fn permits(&self, address: &IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(address)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(address))
+    }
+}
@@ -0,0 +1,118 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "derive")]
+pub use jsontp_macros::route;
+pub use lint::{validate, ValidationReport};
+
+pub mod acl;
+#[cfg(feature = "arena")]
+pub mod arena;
+#[cfg(feature = "audit")]
+pub mod audit;
+#[cfg(feature = "std")]
+pub mod balancer;
+#[cfg(feature = "std")]
+pub mod bandwidth;
+pub mod canonical;
+#[cfg(feature = "std")]
+pub mod circuit_breaker;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "std")]
+pub mod client_queue;
+#[cfg(feature = "std")]
+pub mod codegen;
+#[cfg(feature = "std")]
+pub mod collection;
+#[cfg(feature = "compression")]
+pub mod compression;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(all(feature = "std", unix))]
+pub mod container;
+#[cfg(feature = "std")]
+pub mod contract;
+pub mod cors;
+#[cfg(feature = "csrf")]
+pub mod csrf;
+#[cfg(all(feature = "std", unix))]
+pub mod daemon;
+#[cfg(feature = "std")]
+pub mod discovery;
+#[cfg(feature = "std")]
+pub mod dump;
+#[cfg(feature = "std")]
+pub mod embedded;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "mio")]
+pub mod event_loop;
+#[cfg(feature = "std")]
+pub mod events;
+pub mod extensions;
+#[cfg(feature = "std")]
+pub mod file_metadata;
+#[cfg(feature = "std")]
+pub mod header_table;
+pub mod headers;
+#[cfg(feature = "std")]
+pub mod idempotency;
+pub mod ip_filter;
+pub mod lint;
+pub mod messages;
+pub mod metrics;
+pub mod middleware;
+#[cfg(feature = "std")]
+pub mod minify;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+#[cfg(feature = "std")]
+pub mod overrides;
+pub mod pagination;
+#[cfg(all(feature = "std", unix))]
+pub mod privileges;
+pub mod protocol;
+#[cfg(feature = "std")]
+pub mod response_hooks;
+#[cfg(feature = "std")]
+pub mod router;
+pub mod schema;
+#[cfg(feature = "std")]
+pub mod search;
+pub mod security_headers;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "signing")]
+pub mod signing;
+pub mod streaming;
+#[cfg(all(feature = "std", unix))]
+pub mod supervisor;
+#[cfg(all(feature = "std", unix))]
+pub mod systemd;
+#[cfg(feature = "std")]
+pub mod task;
+#[cfg(feature = "std")]
+pub mod template;
+#[cfg(feature = "std")]
+pub mod tenants;
+#[cfg(feature = "std")]
+pub mod throttle;
+#[cfg(feature = "tokens")]
+pub mod tokens;
+pub mod transform;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod upload;
+#[cfg(feature = "users")]
+pub mod users;
+#[cfg(feature = "std")]
+pub mod validation;
+#[cfg(feature = "std")]
+pub mod warm_up;
+#[cfg(feature = "watch")]
+pub mod watch;
+#[cfg(feature = "std")]
+pub mod wire;
@@ -0,0 +1,566 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+use serde_json::{Map, Value};
+
+use crate::protocol::{
+    header_type, is_known_encoding, is_known_method, CustomHeaders, Validation, Version,
+    KNOWN_BODY_FIELDS, VALIDATION_HEADER,
+};
+
+/// Everything wrong with a message, as [`validate`] found it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ValidationReport {
+    /// The message's `type`, when it has one this protocol defines:
+    /// `request`, `response`, `batch` or `batch-part`.
+    pub message_type: Option<String>,
+    /// Field by field, each message in a batch in turn.
+    pub violations: Vec<Violation>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for violation in &self.violations {
+            writeln!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+/// One thing wrong with a message, at the field `pointer` names, as a JSON
+/// pointer (RFC 6901): `/headers/limit`, or the empty string for the
+/// message as a whole.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub pointer: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pointer.is_empty() {
+            true => write!(f, "(message): {}", self.message),
+            false => write!(f, "{}: {}", self.pointer, self.message),
+        }
+    }
+}
+
+/// Checks a jsontp message, a request, response, batch or batch part, as
+/// [`Validation::Strict`] does, and reports every violation rather than
+/// the first, so that tools and tests can lint messages.
+///
+/// ```
+/// let report = jsontp::validate(r#"{"jsontp": "1.0", "type": "request"}"#);
+/// assert!(report.violations.iter().any(|v| v.pointer == "/method"));
+/// ```
+pub fn validate(message: &str) -> ValidationReport {
+    validate_with(message, Validation::Strict, &CustomHeaders::new())
+}
+
+/// [`validate`], with unknown headers and fields treated as `validation`
+/// says, and the headers `custom` permits known.
+pub fn validate_with(
+    message: &str,
+    validation: Validation,
+    custom: &CustomHeaders,
+) -> ValidationReport {
+    let mut lint = Lint {
+        validation,
+        custom,
+        violations: Vec::new(),
+        version: Version::V1_0,
+    };
+
+    let message = match serde_json::from_str::<Value>(message) {
+        Ok(message) => message,
+        Err(e) => {
+            lint.violation("", format!("not JSON: {}", e));
+            return lint.report(None);
+        }
+    };
+    let message_type = lint.message("", &message);
+    lint.report(message_type)
+}
+
+/// Fills in what `message` is missing that has a default, so that only
+/// what needs a person is left to [`validate`]: `jsontp` of 1.0, a `type`
+/// its fields make plain, empty `headers`, a body in `identity` encoding
+/// (empty for a response) and status messages that go with the code, in
+/// each message of a batch too. Gives the pointers of the fields filled in.
+pub fn fix(message: &mut Value) -> Vec<String> {
+    let mut fixed = Vec::new();
+    fix_message("", message, &mut fixed);
+    fixed
+}
+
+fn fix_message(pointer: &str, message: &mut Value, fixed: &mut Vec<String>) {
+    let Some(object) = message.as_object_mut() else {
+        return;
+    };
+    let mut default = |object: &mut Map<String, Value>, field: &str, value: Value| {
+        if !object.contains_key(field) {
+            object.insert(field.to_string(), value);
+            fixed.push(child(pointer, field));
+        }
+    };
+
+    default(object, "jsontp", Value::from("1.0"));
+    let inferred = if object.contains_key("requests") {
+        Some("batch")
+    } else if object.contains_key("index") && object.contains_key("response") {
+        Some("batch-part")
+    } else if object.contains_key("status") {
+        Some("response")
+    } else if object.contains_key("method") {
+        Some("request")
+    } else {
+        None
+    };
+    if let Some(inferred) = inferred {
+        default(object, "type", Value::from(inferred));
+    }
+
+    match object.get("type").and_then(Value::as_str) {
+        Some(message_type @ ("request" | "response")) => {
+            let response = message_type == "response";
+            default(object, "headers", Value::Object(Map::new()));
+            default(object, "body", Value::Object(Map::new()));
+            if let Some(body) = object.get_mut("body").and_then(Value::as_object_mut) {
+                let body_pointer = child(pointer, "body");
+                for (field, value) in [("content", ""), ("encoding", "identity")] {
+                    if !body.contains_key(field) && (response || field == "encoding") {
+                        body.insert(field.to_string(), Value::from(value));
+                        fixed.push(child(&body_pointer, field));
+                    }
+                }
+            }
+
+            if let Some(status) = object.get_mut("status").and_then(Value::as_object_mut) {
+                let status_pointer = child(pointer, "status");
+                let reason = status
+                    .get("code")
+                    .and_then(Value::as_u64)
+                    .and_then(reason_phrase);
+                for field in ["formal-message", "human-message"] {
+                    if let (false, Some(reason)) = (status.contains_key(field), reason) {
+                        status.insert(field.to_string(), Value::from(reason));
+                        fixed.push(child(&status_pointer, field));
+                    }
+                }
+            }
+        }
+        Some("batch") => {
+            if let Some(requests) = object.get_mut("requests").and_then(Value::as_array_mut) {
+                let requests_pointer = child(pointer, "requests");
+                for (i, request) in requests.iter_mut().enumerate() {
+                    fix_message(&child(&requests_pointer, &i.to_string()), request, fixed);
+                }
+            }
+        }
+        Some("batch-part") => {
+            if let Some(response) = object.get_mut("response") {
+                fix_message(&child(pointer, "response"), response, fixed);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The formal message for the status codes this server answers with.
+fn reason_phrase(code: u64) -> Option<&'static str> {
+    Some(match code {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        424 => "Failed Dependency",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => return None,
+    })
+}
+
+struct Lint<'a> {
+    validation: Validation,
+    custom: &'a CustomHeaders,
+    violations: Vec<Violation>,
+    /// The version of the message being checked, for the fields it may
+    /// have; 1.0 when it names none spoken.
+    version: Version,
+}
+
+impl Lint<'_> {
+    fn report(self, message_type: Option<&str>) -> ValidationReport {
+        ValidationReport {
+            message_type: message_type.map(ToString::to_string),
+            violations: self.violations,
+        }
+    }
+
+    fn violation(&mut self, pointer: &str, message: String) {
+        self.violations.push(Violation {
+            pointer: pointer.to_string(),
+            message,
+        });
+    }
+
+    /// Checks any message, giving its type if it is a known one.
+    fn message<'m>(&mut self, pointer: &str, message: &'m Value) -> Option<&'m str> {
+        let Some(object) = message.as_object() else {
+            self.violation(pointer, "a message is a JSON object".to_string());
+            return None;
+        };
+
+        self.version = Version::V1_0;
+        if let Some(version) = self.string(pointer, object, "jsontp") {
+            match Version::parse(version) {
+                Some(version) => self.version = version,
+                None => self.violation(
+                    &child(pointer, "jsontp"),
+                    format!("version {:?} is not supported, only 1.0 and 1.1", version),
+                ),
+            }
+        }
+
+        let message_type = self.string(pointer, object, "type")?;
+        let fields: &[&str] = match message_type {
+            "request" => {
+                self.request(pointer, object);
+                &["jsontp", "type", "method", "resource", "headers", "body"]
+            }
+            "response" => {
+                self.response(pointer, object);
+                &["jsontp", "type", "status", "resource", "headers", "body"]
+            }
+            "batch" => {
+                self.batch(pointer, object);
+                &[
+                    "jsontp",
+                    "type",
+                    "parallel",
+                    "transactional",
+                    "ordered",
+                    "requests",
+                ]
+            }
+            "batch-part" => {
+                self.batch_part(pointer, object);
+                &["jsontp", "type", "index", "response"]
+            }
+            other => {
+                self.violation(
+                    &child(pointer, "type"),
+                    format!(
+                        "{:?} is not a type of message; it is request, response, batch or batch-part",
+                        other
+                    ),
+                );
+                return None;
+            }
+        };
+
+        if self.strict(object) {
+            let mut fields = fields.to_vec();
+            if matches!(message_type, "request" | "response") {
+                fields.extend(self.version.fields());
+            }
+            self.unknown_fields(pointer, object, &fields);
+        }
+        Some(message_type)
+    }
+
+    /// A field that has to be a string, if it is one.
+    fn string<'m>(
+        &mut self,
+        pointer: &str,
+        object: &'m Map<String, Value>,
+        field: &str,
+    ) -> Option<&'m str> {
+        match object.get(field) {
+            None => {
+                self.violation(&child(pointer, field), "is missing".to_string());
+                None
+            }
+            Some(Value::String(value)) => Some(value),
+            Some(_) => {
+                self.violation(&child(pointer, field), "must be a string".to_string());
+                None
+            }
+        }
+    }
+
+    /// A field that has to be a non-empty string, if it is one.
+    fn non_empty<'m>(
+        &mut self,
+        pointer: &str,
+        object: &'m Map<String, Value>,
+        field: &str,
+    ) -> Option<&'m str> {
+        let value = self.string(pointer, object, field)?;
+        if value.is_empty() {
+            self.violation(&child(pointer, field), "must not be empty".to_string());
+            return None;
+        }
+        Some(value)
+    }
+
+    /// A field that has to be an object, if it is one.
+    fn object<'m>(
+        &mut self,
+        pointer: &str,
+        object: &'m Map<String, Value>,
+        field: &str,
+    ) -> Option<&'m Map<String, Value>> {
+        match object.get(field) {
+            None => {
+                self.violation(&child(pointer, field), "is missing".to_string());
+                None
+            }
+            Some(Value::Object(value)) => Some(value),
+            Some(_) => {
+                self.violation(&child(pointer, field), "must be an object".to_string());
+                None
+            }
+        }
+    }
+
+    fn optional_bool(&mut self, pointer: &str, object: &Map<String, Value>, field: &str) {
+        if object.get(field).is_some_and(|value| !value.is_boolean()) {
+            self.violation(&child(pointer, field), "must be true or false".to_string());
+        }
+    }
+
+    /// Whether unknown headers and fields in `message` are violations, as
+    /// its `validation` header may say.
+    fn strict(&self, message: &Map<String, Value>) -> bool {
+        let requested = message
+            .get("headers")
+            .and_then(|headers| headers.get(VALIDATION_HEADER))
+            .and_then(Value::as_str)
+            .and_then(Validation::from_name);
+        requested.unwrap_or(self.validation) == Validation::Strict
+    }
+
+    fn unknown_fields(&mut self, pointer: &str, object: &Map<String, Value>, known: &[&str]) {
+        for field in object.keys() {
+            if !known.contains(&field.as_str()) {
+                self.violation(
+                    &child(pointer, field),
+                    "is not a field of this message".to_string(),
+                );
+            }
+        }
+    }
+
+    fn request(&mut self, pointer: &str, request: &Map<String, Value>) {
+        if let Some(method) = self.non_empty(pointer, request, "method") {
+            if !is_known_method(method) {
+                self.violation(
+                    &child(pointer, "method"),
+                    format!("{:?} is not a method", method),
+                );
+            }
+        }
+        self.non_empty(pointer, request, "resource");
+
+        let strict = self.strict(request);
+        if let Some(headers) = self.object(pointer, request, "headers") {
+            let pointer = child(pointer, "headers");
+            for (name, value) in headers {
+                match header_type(name) {
+                    Some(header_type) if header_type.normalize(value).is_none() => self.violation(
+                        &child(&pointer, name),
+                        format!("must be {}", header_type.expected()),
+                    ),
+                    Some(_) => {}
+                    None if strict && !self.custom.permits(name) => {
+                        self.violation(&child(&pointer, name), "is not a known header".to_string())
+                    }
+                    None => {}
+                }
+            }
+            if let Some(value) = headers.get(VALIDATION_HEADER) {
+                if value.as_str().and_then(Validation::from_name).is_none() {
+                    self.violation(
+                        &child(&pointer, VALIDATION_HEADER),
+                        "must be \"strict\" or \"lenient\"".to_string(),
+                    );
+                }
+            }
+        }
+
+        self.body(pointer, request, true, strict);
+    }
+
+    /// Checks the `body`, whose `content` a request has to have something
+    /// in.
+    fn body(
+        &mut self,
+        pointer: &str,
+        message: &Map<String, Value>,
+        needs_content: bool,
+        strict: bool,
+    ) {
+        let Some(body) = self.object(pointer, message, "body") else {
+            return;
+        };
+        let pointer = child(pointer, "body");
+
+        match needs_content {
+            true => self.non_empty(&pointer, body, "content"),
+            false => self.string(&pointer, body, "content"),
+        };
+        if let Some(encoding) = self.non_empty(&pointer, body, "encoding") {
+            if !is_known_encoding(encoding) {
+                self.violation(
+                    &child(&pointer, "encoding"),
+                    format!("{:?} is not an encoding", encoding),
+                );
+            }
+        }
+
+        if strict {
+            for field in body.keys() {
+                let known = ["content", "encoding"].contains(&field.as_str())
+                    || KNOWN_BODY_FIELDS.contains(&field.as_str());
+                if !known {
+                    self.violation(
+                        &child(&pointer, field),
+                        "is not a field of a body".to_string(),
+                    );
+                }
+            }
+        }
+    }
+
+    fn response(&mut self, pointer: &str, response: &Map<String, Value>) {
+        if let Some(status) = self.object(pointer, response, "status") {
+            let pointer = child(pointer, "status");
+            match status.get("code") {
+                None => self.violation(&child(&pointer, "code"), "is missing".to_string()),
+                Some(code) if !code.as_u64().is_some_and(|code| (100..600).contains(&code)) => self
+                    .violation(
+                        &child(&pointer, "code"),
+                        "must be a status code, from 100 to 599".to_string(),
+                    ),
+                Some(_) => {}
+            }
+            self.string(&pointer, status, "formal-message");
+            self.string(&pointer, status, "human-message");
+            if let Some(retryable) = status.get("retryable") {
+                if !retryable.is_boolean() {
+                    self.violation(
+                        &child(&pointer, "retryable"),
+                        "must be a boolean".to_string(),
+                    );
+                }
+            }
+            let known = ["code", "formal-message", "human-message", "retryable"];
+            let known = match self.version {
+                Version::V1_0 => &known[..3],
+                Version::V1_1 => &known[..],
+            };
+            self.unknown_fields(&pointer, status, known);
+        }
+
+        self.string(pointer, response, "resource");
+        self.object(pointer, response, "headers");
+        self.body(pointer, response, false, false);
+    }
+
+    fn batch(&mut self, pointer: &str, batch: &Map<String, Value>) {
+        for field in ["parallel", "transactional", "ordered"] {
+            self.optional_bool(pointer, batch, field);
+        }
+        let flag = |field: &str| batch.get(field).and_then(Value::as_bool);
+        if flag("transactional") == Some(true)
+            && (flag("parallel") == Some(true) || flag("ordered") == Some(false))
+        {
+            self.violation(
+                &child(pointer, "transactional"),
+                "a transactional batch can be neither parallel nor unordered".to_string(),
+            );
+        }
+
+        match batch.get("requests") {
+            None => self.violation(&child(pointer, "requests"), "is missing".to_string()),
+            Some(Value::Array(requests)) if requests.is_empty() => self.violation(
+                &child(pointer, "requests"),
+                "must have at least one request".to_string(),
+            ),
+            Some(Value::Array(requests)) => {
+                let pointer = child(pointer, "requests");
+                for (i, request) in requests.iter().enumerate() {
+                    let pointer = child(&pointer, &i.to_string());
+                    match self.message(&pointer, request) {
+                        Some("request") | None => {}
+                        Some(other) => self.violation(
+                            &child(&pointer, "type"),
+                            format!("a batch holds requests, not a {}", other),
+                        ),
+                    }
+                }
+            }
+            Some(_) => self.violation(
+                &child(pointer, "requests"),
+                "must be an array of requests".to_string(),
+            ),
+        }
+    }
+
+    fn batch_part(&mut self, pointer: &str, part: &Map<String, Value>) {
+        match part.get("index") {
+            None => self.violation(&child(pointer, "index"), "is missing".to_string()),
+            Some(index) if index.as_u64().is_none() => self.violation(
+                &child(pointer, "index"),
+                "must be a non-negative integer".to_string(),
+            ),
+            Some(_) => {}
+        }
+
+        let Some(response) = part.get("response") else {
+            self.violation(&child(pointer, "response"), "is missing".to_string());
+            return;
+        };
+        let pointer = child(pointer, "response");
+        match self.message(&pointer, response) {
+            Some("response") | None => {}
+            Some(other) => self.violation(
+                &child(&pointer, "type"),
+                format!("a batch part holds a response, not a {}", other),
+            ),
+        }
+    }
+}
+
+/// The JSON pointer to `field` in what `pointer` points to.
+fn child(pointer: &str, field: &str) -> String {
+    format!(
+        "{}/{}",
+        pointer,
+        field.replace('~', "~0").replace('/', "~1")
+    )
+}
@@ -1,203 +1,1865 @@
-use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap, io::{Read, Write}
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc, Mutex, RwLock},
+    time::Duration,
 };
 
-use serde_json::Value;
+use jsontp::{
+    acl::AclMiddleware,
+    bandwidth::BandwidthMiddleware,
+    collection::{self, CollectionError, Depth},
+    config::Config,
+    cors::CorsMiddleware,
+    file_metadata::{preconditions_hold, FileMetadata, MetadataCache},
+    header_table::{HeaderTable, HEADER_TABLE_RESOURCE},
+    headers::Headers,
+    idempotency::Idempotency,
+    ip_filter::IpFilter,
+    messages::{accepted_languages, Messages, MessagesMiddleware},
+    metrics::METRICS_RESOURCE,
+    middleware::{Context, Middleware},
+    minify::MinifyConfig,
+    pagination::{PageRequest, CURSOR_HEADER, LIMIT_HEADER, NEXT_CURSOR_HEADER},
+    protocol::{Body, JsontpRequest, JsontpResponse, Status},
+    schema::{ApiSchema, Route},
+    search::{self, SearchConfig, SearchQuery},
+    security_headers::SecurityHeadersMiddleware,
+    server::{status_response, Server},
+    tenants::{TenantConfig, TenantMiddleware, TENANT_VALUE},
+    throttle::ThrottleMiddleware,
+    upload::{write_atomically, Durability, Upload, UploadError},
+};
+use serde::Serialize;
+use serde_json::{json, Value};
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Body {
-    content: String,
-    encoding: String,
-    #[serde(flatten)]
-    other: HashMap<String, Value>,
-}
+/// The resumable upload flow: `GET` with an `upload-id` header asks how many
+/// bytes the server has, `PUT` sends the chunk starting at `upload-offset`,
+/// and the chunk with `upload-complete: true` moves the file into place.
+fn upload(
+    request: &JsontpRequest,
+    id: &str,
+    files: &FileServer,
+    durability: Durability,
+) -> JsontpResponse {
+    let respond = |code: u16, formal_message: &str, human_message: &str, offset: Option<u64>| {
+        let mut response = status_response(code, formal_message, human_message);
+        response.resource = request.resource.clone();
+        if let Some(offset) = offset {
+            response
+                .headers
+                .insert("upload-offset".to_string(), Value::from(offset));
+        }
+        response
+    };
+
+    let upload = match Upload::new(Path::new(&request.resource), id) {
+        Ok(upload) => upload.max_size(files.max_upload_size),
+        Err(_) => return respond(400, "Bad Request", "Invalid upload id", None),
+    };
+
+    if request.method != "PUT" {
+        return match upload.offset() {
+            Ok(offset) => respond(200, "OK", "Request was successful", Some(offset)),
+            Err(_) => respond(500, "Internal Server Error", "Could not read upload", None),
+        };
+    }
+
+    let Some(offset) = request.headers.get("upload-offset").and_then(Value::as_u64) else {
+        return respond(400, "Bad Request", "Missing upload-offset", None);
+    };
+
+    // held from checking the offset to moving the file into place, so that
+    // two chunks for the same offset cannot both be appended, and no chunk
+    // is appended to a file being completed
+    let _guard = WRITES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    // compressed chunks are decompressed on their way to the file
+    #[cfg(feature = "compression")]
+    let appended = {
+        let dictionaries = files
+            .compression
+            .as_ref()
+            .map(jsontp::compression::CompressionConfig::dictionaries)
+            .unwrap_or_default();
+        match request.body.content_reader(&dictionaries) {
+            Ok(mut content) => upload.append_from(offset, &mut content, durability),
+            Err(jsontp::compression::CompressionError::UnknownDictionary) => {
+                return respond(400, "Bad Request", "Unknown compression dictionary", None)
+            }
+            Err(_) => return respond(415, "Unsupported Media Type", "Unknown body encoding", None),
+        }
+    };
+    #[cfg(not(feature = "compression"))]
+    let appended = {
+        let _ = files;
+        let Some(content) = request.body.bytes() else {
+            return respond(415, "Unsupported Media Type", "Unknown body encoding", None);
+        };
+        upload.append(offset, &content, durability)
+    };
+
+    let offset = match appended {
+        Ok(offset) => offset,
+        Err(UploadError::WrongOffset { expected }) => {
+            return respond(
+                409,
+                "Conflict",
+                "Upload is at a different offset",
+                Some(expected),
+            )
+        }
+        Err(UploadError::InvalidId) => {
+            return respond(400, "Bad Request", "Invalid upload id", None)
+        }
+        Err(UploadError::TooLarge { .. }) => {
+            return respond(
+                413,
+                "Payload Too Large",
+                "Upload is larger than allowed",
+                None,
+            )
+        }
+        Err(UploadError::Io(e))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::InvalidData
+                    | io::ErrorKind::InvalidInput
+                    | io::ErrorKind::UnexpectedEof
+            ) =>
+        {
+            return respond(
+                400,
+                "Bad Request",
+                "Chunk is not what its encoding says",
+                None,
+            )
+        }
+        Err(UploadError::Io(_)) => {
+            return respond(500, "Internal Server Error", "Could not write upload", None)
+        }
+    };
+
+    if request.headers.get("upload-complete") != Some(&Value::Bool(true)) {
+        return respond(200, "OK", "Chunk received", Some(offset));
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct JsontpRequest {
-    jsontp: String,
-    #[serde(rename = "type")]
-    type_of_request: String,
-    method: String,
-    resource: String,
-    headers: HashMap<String, Value>,
-    body: Body,
+    match upload.complete(durability) {
+        Ok(()) => respond(201, "Created", "Upload complete", Some(offset)),
+        Err(_) => respond(500, "Internal Server Error", "Could not store upload", None),
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Status {
-    code: u16,
-    #[serde(rename = "formal-message")]
-    formal_message: String,
-    #[serde(rename = "human-message")]
-    human_message: String,
+/// Serialises every write to the document root, so no other write can get in
+/// between checking the preconditions of a request and carrying it out.
+static WRITES: Mutex<()> = Mutex::new(());
+
+/// Registers `undo` to be run, under the write lock, if the transaction the
+/// request is part of fails.
+fn on_rollback(context: &mut Context, undo: impl FnOnce() + Send + 'static) {
+    context.on_rollback(move || {
+        let _guard = WRITES
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        undo();
+    });
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct JsontpResponse {
-    jsontp: String,
-    #[serde(rename = "type")]
-    type_of_response: String,
-    status: Status,
-    resource: String,
-    headers: HashMap<String, Value>,
-    body: Body,
+/// Carries out a `PUT` or `DELETE` on the resource, if the `if-match` and
+/// `if-none-match` preconditions allow it. `PUT` stores the body in one step.
+///
+/// In a transaction the previous contents of a file are kept so the write can
+/// be undone; deleting a directory cannot be.
+fn write(request: &JsontpRequest, context: &mut Context, durability: Durability) -> JsontpResponse {
+    let path = Path::new(&request.resource);
+    let precondition = |name: &str| request.headers.get(name).and_then(Value::as_str);
+
+    let _guard = WRITES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let current = FileMetadata::read(path).ok();
+
+    let backup = match context.in_transaction && !path.is_dir() {
+        true => Some(fs::read(path).ok()),
+        false => None,
+    };
+
+    let mut response = if !preconditions_hold(
+        current.as_ref(),
+        precondition("if-match"),
+        precondition("if-none-match"),
+    ) {
+        status_response(
+            412,
+            "Precondition Failed",
+            "Resource was changed by someone else",
+        )
+    } else if request.method == "DELETE" {
+        match collection::remove(path) {
+            Ok(()) => status_response(200, "OK", "Resource was deleted"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                status_response(404, "Not Found", "Resource not found")
+            }
+            Err(_) => status_response(500, "Internal Server Error", "Could not delete resource"),
+        }
+    } else {
+        // a binary body is stored as the bytes it encodes, and one in an
+        // encoding that cannot be undone here is not stored at all
+        match request.body.bytes() {
+            None => status_response(415, "Unsupported Media Type", "Unknown body encoding"),
+            Some(content) => match write_atomically(path, &content, durability) {
+                Ok(()) if current.is_some() => status_response(200, "OK", "Resource was replaced"),
+                Ok(()) => status_response(201, "Created", "Resource was created"),
+                Err(_) => status_response(500, "Internal Server Error", "Could not store resource"),
+            },
+        }
+    };
+
+    if let (Some(backup), true) = (backup, response.status.code < 300) {
+        let path = path.to_path_buf();
+        on_rollback(context, move || {
+            let _ = match backup {
+                Some(contents) => write_atomically(&path, &contents, durability),
+                None => fs::remove_file(&path),
+            };
+        });
+    }
+
+    // the ETag the resource has now, so a client that lost can retry with it
+    if let Ok(metadata) = FileMetadata::read(path) {
+        response
+            .headers
+            .insert("etag".to_string(), Value::String(metadata.etag));
+    }
+
+    response.resource = request.resource.clone();
+    response
 }
 
-impl JsontpRequest {
-    fn validate(&self) -> Result<(), (String, u16)> {
-        if self.jsontp.get(..3) != Some("1.0") {
-            return Err(("HTTP Version Not Supported".to_string(), 505));
+/// `MKCOL` creates a directory; `COPY` and `MOVE` take the `destination`
+/// header, replace what is there unless `overwrite` is false, and `COPY` of a
+/// directory with `depth: "0"` copies it without its contents.
+///
+/// In a transaction these are undone by removing what was created or moving
+/// it back, except when something was replaced.
+fn collection_operation(request: &JsontpRequest, context: &mut Context) -> JsontpResponse {
+    let source = Path::new(&request.resource);
+    let (destination, overwrite, depth) = collection_arguments(request);
+
+    let _guard = WRITES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let result = match (request.method.as_str(), destination, depth) {
+        ("MKCOL", _, _) => collection::make_collection(source).map(|_| false),
+        ("COPY", Some(destination), Some(depth)) => {
+            collection::copy(source, destination, depth, overwrite)
         }
-        if self.type_of_request != "request" {
-            return Err(("Bad Request".to_string(), 400));
+        ("MOVE", Some(destination), Some(Depth::Infinity)) => {
+            collection::move_to(source, destination, overwrite)
         }
-        if self.resource.is_empty() {
-            return Err(("Bad Request".to_string(), 400));
+        _ => {
+            let mut response = status_response(400, "Bad Request", "Invalid destination or depth");
+            response.resource = request.resource.clone();
+            return response;
         }
-        if self.body.content.is_empty() {
-            return Err(("Bad Request".to_string(), 400));
+    };
+
+    if let (Ok(false), true) = (&result, context.in_transaction) {
+        let source = source.to_path_buf();
+        let destination = destination.map(Path::to_path_buf);
+
+        let moved = request.method == "MOVE";
+
+        on_rollback(context, move || {
+            let _ = match destination {
+                Some(destination) if moved => fs::rename(&destination, &source),
+                Some(destination) => collection::remove(&destination),
+                None => fs::remove_dir(&source),
+            };
+        });
+    }
+
+    let mut response = collection_response(&request.method, result);
+    response.resource = request.resource.clone();
+    response
+}
+
+/// The `destination`, `overwrite` and `depth` headers of a collection
+/// operation, with their defaults; a depth is `None` if it is not one.
+fn collection_arguments(request: &JsontpRequest) -> (Option<&Path>, bool, Option<Depth>) {
+    let destination = request
+        .headers
+        .get("destination")
+        .and_then(Value::as_str)
+        .map(Path::new);
+    let overwrite = request.headers.get("overwrite") != Some(&Value::Bool(false));
+    let depth = match request.headers.get("depth") {
+        None => Some(Depth::Infinity),
+        Some(Value::String(depth)) => Depth::from_name(depth),
+        Some(depth) => (depth.as_u64() == Some(0)).then_some(Depth::Zero),
+    };
+    (destination, overwrite, depth)
+}
+
+/// The answer to a collection operation that replaced something or not,
+/// or failed.
+fn collection_response(method: &str, result: Result<bool, CollectionError>) -> JsontpResponse {
+    match result {
+        Ok(false) => status_response(201, "Created", "Resource was created"),
+        Ok(true) => status_response(204, "No Content", "Resource was replaced"),
+        Err(CollectionError::NotFound) => status_response(404, "Not Found", "Resource not found"),
+        Err(CollectionError::Exists) if method == "MKCOL" => {
+            status_response(405, "Method Not Allowed", "Resource already exists")
         }
-        if self.body.encoding.is_empty() {
-            return Err(("Bad Request".to_string(), 400));
+        Err(CollectionError::Exists) => {
+            status_response(412, "Precondition Failed", "Destination already exists")
         }
-        if self.method.is_empty() {
-            return Err(("Bad Request".to_string(), 400));
+        Err(CollectionError::MissingParent) => {
+            status_response(409, "Conflict", "Parent collection does not exist")
         }
+        Err(CollectionError::IntoItself) => {
+            status_response(403, "Forbidden", "Destination is inside the resource")
+        }
+        Err(CollectionError::Io(_)) => status_response(
+            500,
+            "Internal Server Error",
+            "Could not complete the operation",
+        ),
+    }
+}
 
-        match self.body.encoding.as_str() {
-            "gzip" | "deflate" | "br" | "identity" => {}
+/// What a write would do, found without doing it: the answer it would get,
+/// with its preconditions, destination and upload offset checked as they
+/// would be, marked `dry-run` and saying what would have happened.
+fn dry_run(request: &JsontpRequest, upload_id: Option<&str>) -> JsontpResponse {
+    let path = Path::new(&request.resource);
+    let precondition = |name: &str| request.headers.get(name).and_then(Value::as_str);
+    let _guard = WRITES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
 
-            _ => return Err(("Bad Request".to_string(), 400)),
+    let mut response = match (upload_id, request.method.as_str()) {
+        (Some(id), _) => {
+            let offset = request.headers.get("upload-offset").and_then(Value::as_u64);
+            match (Upload::new(path, id), offset) {
+                (Err(_), _) => status_response(400, "Bad Request", "Invalid upload id"),
+                (_, None) => status_response(400, "Bad Request", "Missing upload-offset"),
+                (Ok(upload), Some(offset)) => match upload.offset() {
+                    Ok(expected) if expected != offset => {
+                        let mut response =
+                            status_response(409, "Conflict", "Upload is at a different offset");
+                        response
+                            .headers
+                            .insert("upload-offset".to_string(), Value::from(expected));
+                        response
+                    }
+                    Ok(_) if request.headers.get("upload-complete") == Some(&Value::Bool(true)) => {
+                        status_response(201, "Created", "Upload would be complete")
+                    }
+                    Ok(_) => status_response(200, "OK", "Chunk would be received"),
+                    Err(_) => {
+                        status_response(500, "Internal Server Error", "Could not read upload")
+                    }
+                },
+            }
+        }
+        (None, "PUT" | "DELETE") => {
+            let current = FileMetadata::read(path).ok();
+            if !preconditions_hold(
+                current.as_ref(),
+                precondition("if-match"),
+                precondition("if-none-match"),
+            ) {
+                status_response(
+                    412,
+                    "Precondition Failed",
+                    "Resource was changed by someone else",
+                )
+            } else if request.method == "DELETE" {
+                match fs::symlink_metadata(path) {
+                    Ok(_) => status_response(200, "OK", "Resource would be deleted"),
+                    Err(_) => status_response(404, "Not Found", "Resource not found"),
+                }
+            } else if path.is_dir() {
+                status_response(500, "Internal Server Error", "Could not store resource")
+            } else if current.is_some() {
+                status_response(200, "OK", "Resource would be replaced")
+            } else if path
+                .parent()
+                .is_none_or(|parent| parent.as_os_str().is_empty() || parent.is_dir())
+            {
+                status_response(201, "Created", "Resource would be created")
+            } else {
+                status_response(500, "Internal Server Error", "Could not store resource")
+            }
+        }
+        (None, method) => {
+            let (destination, overwrite, depth) = collection_arguments(request);
+            let result = match (method, destination, depth) {
+                ("MKCOL", _, _) => Some(collection::check_make_collection(path).map(|_| false)),
+                ("COPY", Some(destination), Some(_))
+                | ("MOVE", Some(destination), Some(Depth::Infinity)) => {
+                    Some(collection::check_destination(path, destination, overwrite))
+                }
+                _ => None,
+            };
+            match result.map(|result| collection_response(method, result)) {
+                Some(mut response) => {
+                    response.status.human_message = match response.status.code {
+                        201 => "Resource would be created".to_string(),
+                        204 => "Resource would be replaced".to_string(),
+                        _ => response.status.human_message,
+                    };
+                    response
+                }
+                None => status_response(400, "Bad Request", "Invalid destination or depth"),
+            }
         }
+    };
+
+    eprintln!(
+        "Dry run: {} {} would be answered {} {}",
+        request.method, request.resource, response.status.code, response.status.formal_message
+    );
+    response
+        .headers
+        .insert("dry-run".to_string(), Value::Bool(true));
+    response.resource = request.resource.clone();
+    response
+}
+
+/// A `200` carrying one page of a listing as JSON content, with `next` as
+/// its `next-cursor`.
+fn json_page(
+    request: &JsontpRequest,
+    value: &impl Serialize,
+    next: Option<&str>,
+) -> JsontpResponse {
+    let mut response = status_response(200, "OK", "Request was successful");
+    response.headers.insert(
+        "content-type".to_string(),
+        Value::String("application/json".to_string()),
+    );
+    if let Some(next) = next {
+        response.headers.insert(
+            NEXT_CURSOR_HEADER.to_string(),
+            Value::String(next.to_string()),
+        );
+    }
+    response.resource = request.resource.clone();
+    response.body.content = serde_json::to_string(value).unwrap();
+    response.body.encoding = "identity".to_string();
+    response
+}
+
+/// Answers a search request, whose content is a JSON [`SearchQuery`], with a
+/// page of results as JSON content. Only files the client may `GET` are
+/// found, and searching under a directory it may not `GET` is forbidden.
+fn search_files(
+    request: &JsontpRequest,
+    context: &Context,
+    files: &FileServer,
+    config: &SearchConfig,
+    root: &Path,
+) -> JsontpResponse {
+    let Ok(query) = serde_json::from_str::<SearchQuery>(&request.body.content) else {
+        return status_response(400, "Bad Request", "Invalid search query");
+    };
+    let page = PageRequest::from_headers(&request.headers);
 
-        match self.method.as_str() {
-            "GET" | "POST" | "PUT" | "DELETE" | "OPTIONS" => {}
-            _ => return Err(("Bad Request".to_string(), 400)),
+    // the ACL sees resources as requested, with the tenant's prefix if any
+    let prefix = files
+        .tenant
+        .as_ref()
+        .and_then(|tenant| tenant.prefix.as_deref())
+        .map(|prefix| prefix.trim_matches('/'));
+    let gettable = |path: &str| {
+        let resource = match prefix {
+            Some(prefix) => format!("{}/{}", prefix, path),
+            None => path.to_string(),
+        };
+        files.acl.check("GET", &resource, context)
+    };
+
+    if query.under.as_deref().is_some_and(|under| !gettable(under)) {
+        return status_response(403, "Forbidden", "Searching there is not allowed");
+    }
+
+    match search::search(root, &query, &page, config.max_limit, gettable) {
+        Ok(page) => json_page(request, &page, page.next.as_deref()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            status_response(404, "Not Found", "Directory not found")
         }
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            status_response(400, "Bad Request", "Invalid search query")
+        }
+        Err(_) => status_response(500, "Internal Server Error", "Could not search"),
+    }
+}
 
-        let mut bad_headers = false;
+/// The most entries in one page of a directory listing.
+const LISTING_MAX_LIMIT: usize = 1000;
 
-        for (key, _) in &self.headers {
-            match key.to_lowercase().as_str() {
-                "content-type"
-                | "accept"
-                | "accept-encoding"
-                | "accept-language"
-                | "authorization"
-                | "cookies"
-                | "if-modified-since"
-                | "if-unmodified-since"
-                | "expect" => {}
-                _ => {
-                    bad_headers = true;
-                    break;
-                }
+/// Lists a directory as JSON content: `{"entries": [...], "next": ...}`.
+fn list_directory(request: &JsontpRequest) -> JsontpResponse {
+    let page = PageRequest::from_headers(&request.headers);
+
+    match collection::list(Path::new(&request.resource), &page, LISTING_MAX_LIMIT) {
+        Ok(page) => {
+            let mut listing = serde_json::json!({ "entries": page.items });
+            if let Some(next) = &page.next {
+                listing["next"] = Value::String(next.clone());
             }
+            json_page(request, &listing, page.next.as_deref())
         }
+        Err(_) => status_response(500, "Internal Server Error", "Could not list directory"),
+    }
+}
 
-        if bad_headers
-            && self
-                .headers
-                .get("ignore-invalid-headers")
-                .unwrap_or(&Value::Bool(false))
-                != &Value::Bool(true)
-        {
-            return Err(("Bad Request hea".to_string(), 400));
+/// How the file server is set up, from the config file.
+struct FileServer {
+    metadata_cache: Option<Arc<MetadataCache>>,
+    durability: Durability,
+    search: Option<SearchConfig>,
+    /// The rules the ACL middleware checks requests for these files with,
+    /// for searches to leave out what they forbid.
+    acl: AclMiddleware,
+    #[cfg(feature = "tokens")]
+    tokens: Option<jsontp::tokens::TokenService>,
+    #[cfg(feature = "compression")]
+    compression: Option<jsontp::compression::CompressionConfig>,
+    #[cfg(feature = "compression")]
+    compressed_cache: Option<Arc<jsontp::compression::CompressedCache>>,
+    minify: Option<MinifyConfig>,
+    /// The directory resources are resolved against, unless a tenant is
+    /// served.
+    root: PathBuf,
+    /// The tenant served, whose root resources are resolved against rather
+    /// than `root`.
+    tenant: Option<TenantConfig>,
+    /// Whether writes are carried out, rather than answered with `405`.
+    writable: bool,
+    /// The most bytes an upload may come to.
+    max_upload_size: u64,
+    /// Writes are checked and logged, and answered with what would have
+    /// happened, but not carried out.
+    dry_run: bool,
+    /// The language of files whose names give none.
+    language: String,
+}
+
+fn serve(request: &JsontpRequest, context: &mut Context, files: &FileServer) -> JsontpResponse {
+    let (resource, root) = match &files.tenant {
+        Some(tenant) => (tenant.relative(&request.resource), tenant.root.as_path()),
+        None => (request.resource.as_str(), files.root.as_path()),
+    };
+
+    if let Some(search) = &files.search {
+        if resource == search.resource && request.method == "GET" {
+            return search_files(request, context, files, search, root);
         }
+    }
 
-        Ok(())
+    #[cfg(feature = "tokens")]
+    if let Some(tokens) = &files.tokens {
+        if resource == tokens.resource {
+            return tokens.login(request);
+        }
     }
+
+    let confined = match &files.tenant {
+        Some(tenant) => in_root(request, |resource| tenant.path(resource)),
+        None => in_root(request, |resource| collection::resolve(root, resource)),
+    };
+    let mut response = match confined {
+        Some(request) if writes_root(&request, root) => status_response(
+            403,
+            "Forbidden",
+            "The root of the site cannot be written to",
+        ),
+        Some(request) => serve_file(&request, context, files),
+        None => status_response(403, "Forbidden", "Resource is outside the site"),
+    };
+    response.resource = request.resource.clone();
+    response
 }
 
-fn main() {
-    let stream = std::net::TcpListener::bind("localhost:8080").unwrap();
+/// `request` with its resource, and its `destination` if it has one, made
+/// paths by `resolve`, or `None` if either is outside the root it resolves
+/// them in.
+fn in_root(
+    request: &JsontpRequest,
+    resolve: impl Fn(&str) -> Option<PathBuf>,
+) -> Option<JsontpRequest> {
+    let path = |resource: &str| resolve(resource)?.into_os_string().into_string().ok();
+
+    let mut request = request.clone();
+    request.resource = path(&request.resource)?;
+    if let Some(destination) = request.headers.get_mut("destination") {
+        if let Some(resource) = destination.as_str() {
+            *destination = Value::String(path(resource)?);
+        }
+    }
+    Some(request)
+}
 
-    for stream in stream.incoming() {
-        let mut stream = stream.unwrap();
+/// Whether `request`, made paths by [`in_root`], would write to `root`
+/// itself, rather than to something in it. The partial file of an upload
+/// goes next to its destination, so an upload to the root would be kept
+/// outside it, and deleting the root, moving it, or copying or moving
+/// something over it would take the whole site with it.
+fn writes_root(request: &JsontpRequest, root: &Path) -> bool {
+    let is_root = |resource: &str| Path::new(resource) == root;
+    let writes = request.headers.get("upload-id").is_some()
+        || matches!(request.method.as_str(), "DELETE" | "MOVE");
+    let destination = request.headers.get("destination").and_then(Value::as_str);
 
-        std::thread::spawn(move || {
+    (writes && is_root(&request.resource))
+        || (matches!(request.method.as_str(), "COPY" | "MOVE") && destination.is_some_and(is_root))
+}
 
-            println!("Handling connection from {}", stream.peer_addr().unwrap());
-            let mut buffer = [0; 2048];
-            let bytes_read = stream.read(&mut buffer).unwrap();
-            let client_data = String::from_utf8_lossy(&buffer[..bytes_read]);
+/// Serves a file, directory or upload at the path `request` names.
+fn serve_file(
+    request: &JsontpRequest,
+    context: &mut Context,
+    files: &FileServer,
+) -> JsontpResponse {
+    let durability = files.durability;
+    let metadata_cache = files.metadata_cache.as_deref();
 
-            let request: Option<JsontpRequest> = serde_json::from_str(&client_data).ok();
+    let upload_id = request.headers.get("upload-id").and_then(Value::as_str);
 
-            let response = match request {
-                Some(request) => match request.validate() {
-                    Ok(_) => {
-                        let file = std::fs::read_to_string(&request.resource);
+    let writes = matches!(
+        request.method.as_str(),
+        "PUT" | "DELETE" | "MKCOL" | "COPY" | "MOVE"
+    );
 
-                        let mut headers = HashMap::new();
+    if (writes || upload_id.is_some()) && !files.writable {
+        return status_response(405, "Method Not Allowed", "Writes are not enabled");
+    }
 
-                        headers.insert("date".to_string(), Value::String("".to_string()));
-                        headers.insert("language".to_string(), Value::String("en-GB".to_string()));
+    // preflights are answered by the CORS middleware, and files have no
+    // options of their own to tell
+    if request.method == "OPTIONS" {
+        return status_response(405, "Method Not Allowed", "Files have no options");
+    }
 
-                        JsontpResponse {
-                            jsontp: "1.0".to_string(),
-                            type_of_response: "response".to_string(),
-                            status: match file {
-                                Ok(_) => Status {
-                                    code: 200,
-                                    formal_message: "OK".to_string(),
-                                    human_message: "Request was successful".to_string(),
-                                },
-                                Err(_) => Status {
-                                    code: 404,
-                                    formal_message: "Not Found".to_string(),
-                                    human_message: "Resource not found".to_string(),
-                                },
-                            },
-                            resource: request.resource,
-                            headers: headers,
-                            body: Body {
-                                content: match file {
-                                    Ok(content) => content,
-                                    Err(_) => "".to_string(),
-                                },
-                                encoding: "identity".to_string(),
-                                other: HashMap::new(),
-                            },
-                        }
+    if files.dry_run && writes {
+        return dry_run(request, upload_id);
+    }
+
+    if upload_id.is_some() || writes {
+        let response = match (upload_id, request.method.as_str()) {
+            (Some(id), _) => upload(request, id, files, durability),
+            (None, "PUT" | "DELETE") => write(request, context, durability),
+            (None, _) => collection_operation(request, context),
+        };
+
+        if let Some(cache) = metadata_cache {
+            cache.invalidate(Path::new(&request.resource));
+            if let Some(destination) = request.headers.get("destination").and_then(Value::as_str) {
+                cache.invalidate(Path::new(destination));
+            }
+        }
+        return response;
+    }
+
+    if request.method == "GET" && Path::new(&request.resource).is_dir() {
+        return list_directory(request);
+    }
+
+    let languages = accepted_languages(request);
+    let (served, language) = match language_variant(&request.resource, &languages, &files.language)
+    {
+        Some((resource, language)) => (
+            Cow::Owned(JsontpRequest {
+                resource,
+                ..request.clone()
+            }),
+            language,
+        ),
+        None => (Cow::Borrowed(request), files.language.clone()),
+    };
+
+    let path = Path::new(&served.resource);
+    let metadata = match metadata_cache {
+        Some(cache) => cache.get(path),
+        None => FileMetadata::read(path),
+    };
+    let file = read_file(&served, context, files, metadata.as_ref().ok());
+
+    let mut headers = Headers::new();
+
+    headers.insert("date".to_string(), Value::String("".to_string()));
+
+    if let (Ok(_), Ok(metadata)) = (&file, metadata) {
+        headers.insert("language".to_string(), Value::String(language));
+        headers.insert(
+            "content-type".to_string(),
+            Value::String(metadata.mime_type.to_string()),
+        );
+        headers.insert("etag".to_string(), Value::String(metadata.etag));
+    }
+
+    JsontpResponse {
+        jsontp: "1.0".to_string(),
+        type_of_response: "response".to_string(),
+        status: match &file {
+            Ok(_) => Status {
+                code: 200,
+                formal_message: "OK".to_string(),
+                human_message: "Request was successful".to_string(),
+                retryable: None,
+            },
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Status {
+                code: 415,
+                formal_message: "Unsupported Media Type".to_string(),
+                human_message: format!("Unsupported Media Type: {}", e),
+                retryable: None,
+            },
+            Err(_) => Status {
+                code: 404,
+                formal_message: "Not Found".to_string(),
+                human_message: "Resource not found".to_string(),
+                retryable: None,
+            },
+        },
+        resource: request.resource.clone(),
+        headers,
+        body: file.unwrap_or_else(|_| Body {
+            content: "".to_string(),
+            encoding: "identity".to_string(),
+            other: BTreeMap::new(),
+        }),
+        id: None,
+    }
+}
+
+/// The file to serve for `resource` in the first of `languages` there is a
+/// variant of it in, as `index.de.html` for `index.html` and `de`, with that
+/// language. A tag with a region, as `de-AT`, falls back to its primary
+/// language. `None` once a language of the file itself comes up, `default`,
+/// so that the file is served as it is.
+fn language_variant(
+    resource: &str,
+    languages: &[String],
+    default: &str,
+) -> Option<(String, String)> {
+    let path = Path::new(resource);
+    let stem = path.file_stem()?.to_str()?;
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let primary = |tag: &str| tag.split('-').next().unwrap_or_default().to_string();
+    let default = primary(&default.to_ascii_lowercase());
+
+    let variant = |tag: &str| {
+        let name = match extension {
+            Some(extension) => format!("{}.{}.{}", stem, tag, extension),
+            None => format!("{}.{}", stem, tag),
+        };
+        let variant = path.with_file_name(name);
+        match variant.is_file() {
+            true => Some((
+                variant.into_os_string().into_string().ok()?,
+                tag.to_string(),
+            )),
+            false => None,
+        }
+    };
+
+    // tags end up in file names, so nothing that could leave the directory
+    let tags = languages.iter().filter(|tag| {
+        tag.bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+    });
+    for tag in tags {
+        if let Some(found) = variant(tag) {
+            return Some(found);
+        }
+        let base = primary(tag);
+        if base == default {
+            return None;
+        }
+        if base != *tag {
+            if let Some(found) = variant(&base) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+
+/// The body of a file. When responses are compressed and this one should
+/// be, the file is compressed as it is read, so that it is never in memory
+/// whole; otherwise it is read as it is, and a file that is not text is
+/// sent as the context's `utf8` says, or fails with `InvalidData`. Files of
+/// the types `[minify]` lists are minified first, and so read whole.
+fn read_file(
+    request: &JsontpRequest,
+    context: &Context,
+    files: &FileServer,
+    metadata: Option<&FileMetadata>,
+) -> io::Result<Body> {
+    let mut body = Body {
+        content: String::new(),
+        encoding: "identity".to_string(),
+        other: BTreeMap::new(),
+    };
+
+    // a file that is not text is not minified, but sent as `utf8` says
+    let minified = match (&files.minify, metadata) {
+        (Some(minify), Some(metadata)) => match fs::read_to_string(&request.resource) {
+            Ok(text) => minify.minify(metadata.mime_type, &text),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => None,
+            Err(e) => return Err(e),
+        },
+        _ => None,
+    };
+
+    #[cfg(feature = "compression")]
+    if let (Some(compression), Some(metadata)) = (&files.compression, metadata) {
+        // the middleware compresses it with the client's context instead
+        let negotiated = compression
+            .negotiate(request)
+            .filter(|_| !compression.uses_context(request))
+            .filter(|_| compression.compresses(Some(metadata.mime_type), metadata.size as usize));
+        if let Some(negotiated) = negotiated {
+            let cache = files.compressed_cache.as_deref();
+            let cached = cache.and_then(|c| c.get(request, context, &metadata.etag, &negotiated));
+            if let Some(cached) = cached {
+                return Ok(cached);
+            }
+
+            let size = match &minified {
+                Some(minified) => {
+                    body.compress_from(
+                        &mut minified.as_bytes(),
+                        negotiated.encoding,
+                        negotiated.level,
+                        negotiated.dictionary,
+                    )?;
+                    minified.len() as u64
+                }
+                None => {
+                    let mut file = fs::File::open(&request.resource)?;
+                    body.compress_from(
+                        &mut file,
+                        negotiated.encoding,
+                        negotiated.level,
+                        negotiated.dictionary,
+                    )?;
+                    metadata.size
+                }
+            };
+            if (body.content.len() as u64) < size {
+                if let Some(cache) = cache {
+                    cache.insert(request, context, &metadata.etag, &negotiated, body.clone());
+                }
+                return Ok(body);
+            }
+            // like the middleware, send content that did not get any
+            // smaller as it is
+            body.encoding = "identity".to_string();
+            body.other.clear();
+        }
+    }
+
+    match minified {
+        Some(minified) => body.content = minified,
+        None => body
+            .set_bytes(fs::read(&request.resource)?, context.utf8)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+    }
+    Ok(body)
+}
+
+/// What the file server answers, as served at `.well-known/jsontp-schema`.
+fn api_schema(config: &Config) -> ApiSchema {
+    let string = || json!({ "type": "string" });
+    let etags =
+        || json!({ "type": "string", "description": "* or a comma separated list of ETags" });
+    let page = |route: Route| {
+        route
+            .header(
+                LIMIT_HEADER,
+                false,
+                json!({ "type": "integer", "minimum": 1 }),
+            )
+            .header(CURSOR_HEADER, false, string())
+    };
+    let entries = json!({
+        "type": "object",
+        "properties": {
+            "entries": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "type": { "enum": ["file", "directory"] },
+                        "size": { "type": "integer" }
+                    },
+                    "required": ["name", "type", "size"]
+                }
+            },
+            "next": { "type": "string" }
+        },
+        "required": ["entries"]
+    });
+    let copy_or_move = |name: &str, method: &str, summary: &str| {
+        Route::new(name, method, "{path*}")
+            .summary(summary)
+            .header("destination", true, string())
+            .header("overwrite", false, json!({ "type": "boolean" }))
+            .status(201, "Created")
+            .status(204, "Replaced what was at the destination")
+            .status(404, "Not found")
+            .status(409, "The parent of the destination does not exist")
+            .status(412, "The destination exists and overwrite is false")
+    };
+
+    let mut schema = ApiSchema::new("jsontp reference file server")
+        .route(
+            Route::new("read-file", "GET", "{path*}")
+                .summary("The contents of a file, with its content-type and etag")
+                .status(200, "OK")
+                .status(404, "Not found"),
+        )
+        .route(
+            page(Route::new("list-directory", "GET", "{path*}"))
+                .summary("One page of the entries of a directory")
+                .response(entries)
+                .status(200, "OK"),
+        )
+        .route(
+            Route::new("write-file", "PUT", "{path*}")
+                .summary("Stores the body content as the file")
+                .header("if-match", false, etags())
+                .header("if-none-match", false, etags())
+                .status(200, "Replaced")
+                .status(201, "Created")
+                .status(412, "A precondition failed"),
+        )
+        .route(
+            Route::new("delete", "DELETE", "{path*}")
+                .summary("Removes a file, or a directory with everything in it")
+                .header("if-match", false, etags())
+                .header("if-none-match", false, etags())
+                .status(200, "Deleted")
+                .status(404, "Not found")
+                .status(412, "A precondition failed"),
+        )
+        .route(
+            Route::new("make-collection", "MKCOL", "{path*}")
+                .summary("Creates a directory")
+                .status(201, "Created")
+                .status(405, "Already exists")
+                .status(409, "The parent directory does not exist"),
+        )
+        .route(
+            copy_or_move(
+                "copy",
+                "COPY",
+                "Copies a file or directory to the destination",
+            )
+            .header("depth", false, json!({ "enum": ["0", "infinity"] })),
+        )
+        .route(copy_or_move(
+            "move",
+            "MOVE",
+            "Moves a file or directory to the destination",
+        ))
+        .route(
+            Route::new("upload-chunk", "PUT", "{path*}")
+                .summary("Appends a chunk to a resumable upload")
+                .header("upload-id", true, string())
+                .header("upload-offset", true, json!({ "type": "integer" }))
+                .header("upload-complete", false, json!({ "type": "boolean" }))
+                .status(200, "Chunk received")
+                .status(201, "Upload complete")
+                .status(409, "The upload is at a different offset"),
+        );
+
+    // ahead of reading files, which the search resource would otherwise be
+    if let Some(search) = &config.search {
+        schema.routes.insert(
+            0,
+            page(Route::new("search", "GET", &search.resource))
+                .summary("One page of the files matching a query")
+                .request(json!({
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "under": { "type": "string" },
+                        "min-size": { "type": "integer" },
+                        "max-size": { "type": "integer" },
+                        "modified-after": { "type": "integer" },
+                        "modified-before": { "type": "integer" }
                     }
-                    Err((message, code)) => JsontpResponse {
-                        jsontp: "1.0".to_string(),
-                        type_of_response: "response".to_string(),
-                        status: Status {
-                            code,
-                            formal_message: message.clone(),
-                            human_message: message,
+                }))
+                .response(json!({
+                    "type": "object",
+                    "properties": {
+                        "results": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "path": { "type": "string" },
+                                    "size": { "type": "integer" },
+                                    "modified": { "type": "integer" }
+                                },
+                                "required": ["path", "size", "modified"]
+                            }
                         },
-                        resource: request.resource,
-                        headers: request.headers,
-                        body: request.body,
-                    },
-                },
-                None => JsontpResponse {
-                    jsontp: "1.0".to_string(),
-                    type_of_response: "response".to_string(),
-                    status: Status {
-                        code: 400,
-                        formal_message: "Bad Request".to_string(),
-                        human_message: "Request was not a valid JSONTP request".to_string(),
-                    },
-                    resource: "".to_string(),
-                    headers: HashMap::new(),
-                    body: Body {
-                        content: "".to_string(),
-                        encoding: "".to_string(),
-                        other: HashMap::new(),
+                        "next": { "type": "string" }
                     },
+                    "required": ["results"]
+                }))
+                .status(200, "OK")
+                .status(400, "Invalid query"),
+        );
+    }
+
+    #[cfg(feature = "tokens")]
+    if let Some(tokens) = &config.tokens {
+        schema = schema.route(jsontp::tokens::token_route(&tokens.resource));
+    }
+
+    if config.metrics {
+        schema = schema.route(
+            Route::new("metrics", "GET", METRICS_RESOURCE)
+                .summary("The server's counters, and the usage of each client")
+                .response(json!({ "type": "object" }))
+                .status(200, "OK"),
+        );
+    }
+
+    if config.header_table.is_some() {
+        schema = schema.route(
+            Route::new("header-table", "GET", HEADER_TABLE_RESOURCE)
+                .summary("The numbers header names may be sent as")
+                .response(json!({
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "string" },
+                        "names": { "type": "array", "items": { "type": "string" } }
+                    }
+                }))
+                .status(200, "OK"),
+        );
+    }
+
+    schema
+}
+
+/// Sets up the middleware `config` asks for on `server`, along with the
+/// reports some of them add to its metrics.
+fn middleware(config: &Config, server: &mut Server) {
+    let mut middleware: Vec<Box<dyn Middleware>> = Vec::new();
+
+    // first, so that their response hooks run last: the response is signed
+    // and encrypted as it is sent, with every header the others add
+    #[cfg(feature = "signing")]
+    if let Ok(secret) = std::env::var("JSONTP_HMAC_KEY") {
+        use jsontp::signing::{SignatureMiddleware, SigningKey, VerifyingKey};
+
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            "default".to_string(),
+            VerifyingKey::HmacSha256(secret.clone().into_bytes()),
+        );
+
+        let mut signatures = SignatureMiddleware::new(
+            Box::new(keys),
+            Some((
+                "default".to_string(),
+                SigningKey::HmacSha256(secret.into_bytes()),
+            )),
+        );
+        // unsigned preflights are only let through to be answered by CORS
+        signatures.preflights = config.cors.is_some();
+        middleware.push(Box::new(signatures));
+    }
+
+    #[cfg(feature = "encryption")]
+    if let Ok(key) = std::env::var("JSONTP_BODY_KEY") {
+        use jsontp::encryption::{BodyKey, EncryptionMiddleware};
+
+        // every character is checked, so that a mistyped key is refused
+        // rather than read as some other key
+        if key.len() != 64 || !key.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            eprintln!("JSONTP_BODY_KEY must be 64 hex characters");
+            std::process::exit(1);
+        }
+        let mut bytes: BodyKey = [0; 32];
+        for (byte, hex) in bytes.iter_mut().zip(key.as_bytes().chunks(2)) {
+            // two hex digits, as checked above
+            *byte = u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap();
+        }
+
+        middleware.push(Box::new(EncryptionMiddleware {
+            keys: Box::new(move |key_id: &str| (key_id == "default").then_some(bytes)),
+        }));
+    }
+
+    // next, so its response hook runs after every other but those above, and
+    // sees who was authenticated and how the request was answered
+    middleware.extend(audit_middleware(config));
+
+    if let Some(headers) = &config.security_headers {
+        middleware.push(Box::new(SecurityHeadersMiddleware {
+            headers: headers.clone(),
+            tls: false,
+        }));
+    }
+
+    if let Some(policy) = &config.cors {
+        middleware.push(Box::new(CorsMiddleware {
+            policy: policy.clone(),
+        }));
+    }
+
+    #[cfg(feature = "csrf")]
+    if let Some(csrf) = &config.csrf {
+        use jsontp::csrf::{CsrfMiddleware, CsrfProtection};
+
+        middleware.push(Box::new(CsrfMiddleware {
+            protection: CsrfProtection::new(csrf),
+        }));
+    }
+
+    let access = access_middleware(config, server, "bandwidth-usage");
+    if config.tenants.is_empty() {
+        middleware.extend(access);
+    } else {
+        let tenants = config
+            .tenants
+            .iter()
+            .map(|tenant| {
+                let report = format!("bandwidth-usage-{}", tenant.name);
+                let tenant_config = tenant_config(tenant);
+                let mut tenant_middleware: Vec<_> =
+                    audit_middleware(&tenant_config).into_iter().collect();
+                tenant_middleware.extend(access_middleware(&tenant_config, server, &report));
+                (tenant.clone(), tenant_middleware)
+            })
+            .collect();
+        middleware.push(Box::new(TenantMiddleware {
+            tenants,
+            default: access,
+        }));
+    }
+
+    // last, so that it compresses responses before anything signs or
+    // encrypts them, and bandwidth is counted in compressed bytes
+    #[cfg(feature = "compression")]
+    if let Some(compression) = &config.compression {
+        middleware.push(Box::new(jsontp::compression::CompressionMiddleware::new(
+            compression.clone(),
+        )));
+    }
+
+    // after everything, so that statuses are translated before they are
+    // signed or logged
+    let mut messages = Messages::new();
+    for (language, catalog) in &config.messages {
+        for (code, message) in catalog {
+            messages.register(language, *code, message);
+        }
+    }
+    middleware.push(Box::new(MessagesMiddleware { messages }));
+
+    server.middleware = middleware;
+}
+
+/// Sets `server` up as `config` says, from its middleware to its
+/// idempotency store.
+fn set_up(config: &Config, server: &mut Server) {
+    middleware(config, server);
+    server.metrics = config.metrics;
+    server.header_table = config.header_table.as_ref().map(HeaderTable::new);
+    server.limits = config.limits.clone();
+    server.ip_filter = Arc::new(RwLock::new(config.ip_filter.clone()));
+    server.schema = Some(api_schema(config));
+    server.validation = config.validation;
+    server.custom_headers = config.custom_headers.clone();
+    server.utf8 = config.utf8;
+    if let Some(headers) = &config.response_headers {
+        server.response_hooks.push(Box::new(headers.clone()));
+    }
+    server.overrides = config.overrides.clone();
+    server.extensions = extensions(config);
+    #[cfg(unix)]
+    if config
+        .container
+        .as_ref()
+        .is_some_and(|container| container.json_logs)
+    {
+        server.plain_logs = false;
+        server
+            .subscribers
+            .push(Box::new(jsontp::container::JsonLog));
+    }
+    if let Some(idempotency) = &config.idempotency {
+        match Idempotency::from_config(idempotency) {
+            Ok(idempotency) => server.idempotency = Some(idempotency),
+            Err(e) => {
+                eprintln!("could not open the idempotency store: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// The protocol extensions `config` sets the server up for, for `OPTIONS`
+/// to list.
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+#[cfg_attr(not(any(feature = "signing", feature = "zstd")), allow(unused_mut))]
+fn extensions(config: &Config) -> BTreeSet<String> {
+    let mut extensions = BTreeSet::new();
+
+    #[cfg(feature = "signing")]
+    if std::env::var("JSONTP_HMAC_KEY").is_ok() {
+        extensions.insert(jsontp::extensions::SIGNING.to_string());
+    }
+
+    #[cfg(feature = "zstd")]
+    if config.compression.as_ref().is_some_and(|compression| {
+        compression
+            .encodings
+            .iter()
+            .any(|encoding| encoding == "zstd")
+    }) {
+        extensions.insert(jsontp::extensions::ZSTD.to_string());
+    }
+
+    extensions
+}
+
+/// The audit log middleware, if `config` asks for one.
+fn audit_middleware(config: &Config) -> Option<Box<dyn Middleware>> {
+    #[cfg(feature = "audit")]
+    if let Some(audit) = &config.audit {
+        use jsontp::audit::{AuditLog, AuditMiddleware};
+
+        return match AuditLog::open(audit) {
+            Ok(log) => Some(Box::new(AuditMiddleware { log })),
+            Err(e) => {
+                eprintln!(
+                    "could not open the audit log {}: {}",
+                    audit.path.display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        };
+    }
+    #[cfg(not(feature = "audit"))]
+    let _ = config;
+    None
+}
+
+/// The middleware that signs users in and limits and authorizes what they
+/// do, as `config` asks, reporting bandwidth used under `report`.
+fn access_middleware(
+    config: &Config,
+    server: &mut Server,
+    report: &str,
+) -> Vec<Box<dyn Middleware>> {
+    let mut middleware: Vec<Box<dyn Middleware>> = Vec::new();
+
+    #[cfg(feature = "users")]
+    if config.users_file.is_some() {
+        middleware.push(Box::new(jsontp::users::BasicAuthMiddleware {
+            users: load_users(config),
+        }));
+    }
+
+    #[cfg(feature = "tokens")]
+    if let Some(tokens) = &config.tokens {
+        use jsontp::tokens::{BearerMiddleware, TokenSigner};
+
+        middleware.push(Box::new(BearerMiddleware {
+            signer: TokenSigner::new(tokens.secret.as_bytes()),
+        }));
+    }
+
+    #[cfg(feature = "oidc")]
+    if let Some(oidc) = &config.oidc {
+        use jsontp::oidc::{JwtValidator, OidcMiddleware};
+
+        middleware.push(Box::new(OidcMiddleware {
+            validator: JwtValidator::new(oidc.clone()),
+        }));
+    }
+
+    if let Some(throttle) = &config.throttle {
+        middleware.push(Box::new(ThrottleMiddleware::new(throttle.clone())));
+    }
+
+    if let Some(bandwidth) = &config.bandwidth {
+        let bandwidth = BandwidthMiddleware::new(bandwidth.clone());
+        let usage = bandwidth.usage.clone();
+        server
+            .reports
+            .insert(report.to_string(), Box::new(move || usage.report()));
+        middleware.push(Box::new(bandwidth));
+    }
+
+    if !config.acl.is_empty() {
+        middleware.push(Box::new(AclMiddleware {
+            rules: config.acl.clone(),
+        }));
+    }
+
+    middleware
+}
+
+/// A config with the sections a tenant sets for itself taken from `tenant`,
+/// to set up its users and middleware as the whole server's are.
+fn tenant_config(tenant: &TenantConfig) -> Config {
+    Config {
+        #[cfg(feature = "users")]
+        users_file: tenant.users_file.clone(),
+        #[cfg(feature = "tokens")]
+        tokens: tenant.tokens.clone(),
+        #[cfg(feature = "oidc")]
+        oidc: tenant.oidc.clone(),
+        acl: tenant.acl.clone(),
+        throttle: tenant.throttle.clone(),
+        bandwidth: tenant.bandwidth.clone(),
+        #[cfg(feature = "audit")]
+        audit: tenant.audit.clone(),
+        ..Config::default()
+    }
+}
+
+/// Reloads the IP filter whenever the config file is modified, so addresses
+/// can be banned without a restart.
+fn watch_ip_filter(path: &Path, ip_filter: &RwLock<IpFilter>) {
+    let modified = |path: &Path| {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    let mut last_modified = modified(path);
+
+    loop {
+        std::thread::sleep(Duration::from_secs(2));
+
+        let current = modified(path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        match Config::load(path) {
+            Ok(config) => {
+                *ip_filter.write().unwrap() = config.ip_filter;
+                println!("Reloaded IP filter from {}", path.display());
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+/// Runs a maintenance command given instead of a config file, returning the
+/// exit code, or `None` if the arguments are not one.
+fn command(args: &[String]) -> Option<i32> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["--check-config", path] => Config::check(Path::new(path))
+            .map(|_| println!("{} is valid", path))
+            .map_err(|problems| {
+                format!(
+                    "{}\n{} problem{} found",
+                    problems.join("\n"),
+                    problems.len(),
+                    if problems.len() == 1 { "" } else { "s" }
+                )
+            }),
+        #[cfg(feature = "audit")]
+        ["--verify-audit", path] => jsontp::audit::verify(Path::new(path))
+            .map(|count| println!("{} entries, chain intact", count)),
+        #[cfg(feature = "users")]
+        ["--set-user", path, name, roles @ ..] => set_user(Path::new(path), name, roles),
+        #[cfg(feature = "users")]
+        ["--remove-user", path, name] => {
+            use jsontp::users::Users;
+
+            Users::load(Path::new(path)).and_then(|mut users| {
+                users
+                    .remove(name)
+                    .ok_or_else(|| format!("there is no user called {}", name))?;
+                users.save(Path::new(path)).map_err(|e| e.to_string())
+            })
+        }
+        #[cfg(unix)]
+        ["--health-check", address] => match jsontp::container::check_health(address) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!("{} is not healthy", address)),
+            Err(e) => Err(format!("could not check {}: {}", address, e)),
+        },
+        [flag, ..] if flag.starts_with("--") => Err(format!("unknown command {}", flag)),
+        _ => return None,
+    };
+
+    match result {
+        Ok(()) => Some(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            Some(1)
+        }
+    }
+}
+
+/// The users in `users-file`, or none if there is no such file.
+#[cfg(feature = "users")]
+fn load_users(config: &Config) -> jsontp::users::Users {
+    let Some(path) = &config.users_file else {
+        return jsontp::users::Users::new();
+    };
+
+    jsontp::users::Users::load(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Adds or updates a user in a users file, reading the password from the
+/// first line of standard input.
+#[cfg(feature = "users")]
+fn set_user(path: &Path, name: &str, roles: &[&str]) -> Result<(), String> {
+    use jsontp::users::Users;
+
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .map_err(|e| e.to_string())?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("no password was given on standard input".to_string());
+    }
+
+    let roles: Vec<String> = roles
+        .iter()
+        .flat_map(|roles| roles.split(','))
+        .filter(|role| !role.is_empty())
+        .map(ToString::to_string)
+        .collect();
+
+    let mut users = Users::load(path)?;
+    users.set(name, password, &roles)?;
+    users.save(path).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = command(&args) {
+        std::process::exit(code);
+    }
+
+    let config_path = std::env::args().nth(1).map(PathBuf::from);
+    let mut config = match &config_path {
+        Some(path) => Config::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        None => Config::default(),
+    };
+    config.apply_environment();
+
+    // before any thread is started, and only once: workers are started
+    // by the daemon, and stay with it
+    #[cfg(unix)]
+    if let (Some(daemon), false) = (&config.daemon, jsontp::supervisor::is_worker()) {
+        if let Err(e) = jsontp::daemon::daemonize(daemon) {
+            eprintln!("could not run as a daemon: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut tcp = config.tcp.clone();
+
+    // started through a .socket unit, systemd has bound the address for us
+    #[cfg(unix)]
+    let mut activated = jsontp::systemd::listeners().unwrap_or_else(|e| {
+        eprintln!("could not take the sockets systemd passed: {}", e);
+        std::process::exit(1);
+    });
+    #[cfg(not(unix))]
+    let mut activated: Vec<std::net::TcpListener> = Vec::new();
+    if activated.len() > 1 {
+        eprintln!(
+            "systemd passed {} sockets, serving the first",
+            activated.len()
+        );
+    }
+
+    if config.workers > 1 && !activated.is_empty() {
+        eprintln!("serving the socket systemd passed from one process");
+    } else if config.workers > 1 {
+        #[cfg(unix)]
+        {
+            use jsontp::supervisor;
+
+            if !supervisor::is_worker() {
+                supervisor::supervise(config.workers);
+            }
+            tcp.reuse_port = true;
+        }
+        #[cfg(not(unix))]
+        eprintln!("multiple workers are only supported on unix, serving from one process");
+    }
+
+    let listener = if activated.is_empty() {
+        tcp.bind(&config.address).unwrap_or_else(|e| {
+            eprintln!("could not bind {}: {}", config.address, e);
+            std::process::exit(1);
+        })
+    } else {
+        activated.swap_remove(0)
+    };
+
+    // blocked before the threads that follow are started, so that they
+    // leave the signals to the one waiting for them
+    #[cfg(unix)]
+    let termination = config.container.as_ref().map(|_| {
+        jsontp::container::Termination::block().unwrap_or_else(|e| {
+            eprintln!("could not block termination signals: {}", e);
+            std::process::exit(1);
+        })
+    });
+
+    let metadata_cache = config
+        .metadata_cache
+        .clone()
+        .map(|config| Arc::new(MetadataCache::new(config)));
+
+    // resources are resolved against the root, or a tenant's
+    // root, so those are the trees whose changes make cached metadata stale
+    #[cfg(feature = "watch")]
+    let _watchers: Vec<_> = std::iter::once(config.root.as_path())
+        .chain(config.tenants.iter().map(|tenant| tenant.root.as_path()))
+        .filter_map(|root| {
+            let cache = metadata_cache.clone()?;
+            jsontp::watch::watch(root, move |changed| match changed {
+                Some(path) => cache.invalidate(path),
+                None => cache.clear(),
+            })
+            .map_err(|e| eprintln!("not watching {} for changes: {}", root.display(), e))
+            .ok()
+        })
+        .collect();
+
+    let files = FileServer {
+        metadata_cache,
+        durability: config.durability,
+        search: config.search.clone(),
+        acl: AclMiddleware {
+            rules: config.acl.clone(),
+        },
+        #[cfg(feature = "tokens")]
+        tokens: config
+            .tokens
+            .as_ref()
+            .map(|tokens| jsontp::tokens::TokenService::new(tokens, load_users(&config))),
+        #[cfg(feature = "compression")]
+        compression: config.compression.clone(),
+        #[cfg(feature = "compression")]
+        compressed_cache: config
+            .compression
+            .as_ref()
+            .filter(|compression| compression.cache > 0)
+            .map(|compression| {
+                Arc::new(jsontp::compression::CompressedCache::new(
+                    compression.cache,
+                    compression.cache_key.clone(),
+                ))
+            }),
+        minify: config.minify.clone(),
+        root: config.root.clone(),
+        tenant: None,
+        writable: config.writable,
+        max_upload_size: config.max_upload_size,
+        dry_run: config.dry_run,
+        language: config.language.clone(),
+    };
+
+    // tenants sign their own users in, and share everything else
+    let tenants: BTreeMap<String, FileServer> = config
+        .tenants
+        .iter()
+        .map(|tenant| {
+            let tenant_files = FileServer {
+                metadata_cache: files.metadata_cache.clone(),
+                durability: files.durability,
+                search: files.search.clone(),
+                acl: AclMiddleware {
+                    rules: tenant.acl.clone(),
                 },
+                #[cfg(feature = "tokens")]
+                tokens: tenant.tokens.as_ref().map(|tokens| {
+                    jsontp::tokens::TokenService::new(tokens, load_users(&tenant_config(tenant)))
+                }),
+                #[cfg(feature = "compression")]
+                compression: files.compression.clone(),
+                #[cfg(feature = "compression")]
+                compressed_cache: files.compressed_cache.clone(),
+                minify: files.minify.clone(),
+                root: files.root.clone(),
+                tenant: Some(tenant.clone()),
+                writable: files.writable,
+                max_upload_size: files.max_upload_size,
+                dry_run: files.dry_run,
+                language: files.language.clone(),
             };
+            (tenant.name.clone(), tenant_files)
+        })
+        .collect();
+
+    let mut server = Server::new(move |request: &JsontpRequest, context: &mut Context| {
+        let files = context
+            .values
+            .get(TENANT_VALUE)
+            .and_then(Value::as_str)
+            .and_then(|name| tenants.get(name))
+            .unwrap_or(&files);
+        serve(request, context, files)
+    });
+    set_up(&config, &mut server);
+    server.tcp = tcp;
+    let server = Arc::new(server);
+
+    // with the port bound and the logs open, root is no longer needed
+    #[cfg(unix)]
+    if let Some(privileges) = &config.privileges {
+        if let Err(e) = jsontp::privileges::drop_privileges(privileges) {
+            eprintln!("could not drop privileges: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(warm_up) = &config.warm_up {
+        #[cfg(feature = "compression")]
+        let encodings = config
+            .compression
+            .as_ref()
+            .map(|compression| compression.encodings.clone())
+            .unwrap_or_default();
+        #[cfg(not(feature = "compression"))]
+        let encodings = Vec::new();
 
-            let str_response = serde_json::to_string(&response).unwrap();
+        let resources = jsontp::warm_up::resources(warm_up, &config.root);
+        let warmed = jsontp::warm_up::warm_up(&server, &resources, &encodings);
+        eprintln!("warmed up {} of {} resources", warmed, resources.len());
+    }
+
+    if let Some(path) = config_path {
+        let ip_filter = server.ip_filter.clone();
+        std::thread::spawn(move || watch_ip_filter(&path, &ip_filter));
+    }
+
+    let stopping = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if let (Some(container), Some(termination)) = (&config.container, termination) {
+        if let Some(address) = &container.health_address {
+            if let Err(e) = jsontp::container::serve_health(address, stopping.clone()) {
+                eprintln!("could not serve health checks at {}: {}", address, e);
+                std::process::exit(1);
+            }
+        }
+        match listener.local_addr() {
+            Ok(address) => termination.drain_on_signal(
+                stopping.clone(),
+                address,
+                Duration::from_secs(container.drain_timeout),
+            ),
+            Err(e) => eprintln!("not draining on SIGTERM: {}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    if let Err(e) = jsontp::systemd::notify("READY=1") {
+        eprintln!("could not tell systemd the server is ready: {}", e);
+    }
+
+    #[cfg(feature = "mio")]
+    if let Some(threads) = config.event_loop_threads {
+        let pool = config
+            .blocking_pool
+            .as_ref()
+            .map(jsontp::task::BlockingPool::new);
+        if let Err(e) = jsontp::event_loop::run(server, listener, threads, pool) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    server.run_until(&listener, &stopping);
+    // stopped by a signal, whose thread exits once connections are drained
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(all(test, feature = "signing"))]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use jsontp::{
+        config::Config,
+        cors::CorsPolicy,
+        middleware::Context,
+        protocol::{JsontpRequest, JsontpResponse},
+        response_hooks::ResponseHeaders,
+        security_headers::SecurityHeaders,
+        server::{status_response, Arrival, Server},
+        signing::{unix_time, SigningKey, VerifyingKey},
+    };
+    use serde_json::json;
+
+    use super::set_up;
+
+    /// A server set up from `config` as `main` sets one up, signing with the
+    /// key `secret`, and answering every request with `200`.
+    fn signing_server(config: &Config) -> Server {
+        std::env::set_var("JSONTP_HMAC_KEY", "secret");
+        let mut server = Server::new(|request: &JsontpRequest, _: &mut Context| {
+            let mut response = status_response(200, "OK", "Request was successful");
+            response.resource = request.resource.clone();
+            response
+        });
+        set_up(config, &mut server);
+        server
+    }
+
+    /// The response `server` sends to a `method` request with `headers`,
+    /// signed unless `method` is `OPTIONS`.
+    fn exchange(server: &Server, method: &str, headers: serde_json::Value) -> JsontpResponse {
+        let mut request: JsontpRequest = serde_json::from_value(json!({
+            "jsontp": "1.0",
+            "type": "request",
+            "method": method,
+            "resource": "index.html",
+            "headers": headers,
+            "body": { "content": "{}", "encoding": "identity" },
+        }))
+        .unwrap();
+        if method != "OPTIONS" {
+            request.sign(
+                "default",
+                &SigningKey::HmacSha256(b"secret".to_vec()),
+                unix_time(),
+            );
+        }
+
+        let message = serde_json::to_vec(&request).unwrap();
+        let response = server.handle_message(&message, &Arrival::new([127, 0, 0, 1].into()));
+        serde_json::from_slice(&response).unwrap()
+    }
+
+    fn verifies(response: &JsontpResponse) -> bool {
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            "default".to_string(),
+            VerifyingKey::HmacSha256(b"secret".to_vec()),
+        );
+        response.verify_signature(&keys, unix_time(), 300).is_ok()
+    }
+
+    fn config() -> Config {
+        Config {
+            security_headers: Some(SecurityHeaders::default()),
+            cors: Some(CorsPolicy {
+                allowed_origins: vec!["https://example.com".to_string()],
+                ..CorsPolicy::default()
+            }),
+            response_headers: Some(ResponseHeaders {
+                headers: [("server".to_string(), "jsontp".to_string())].into(),
+                request_id: true,
+            }),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn responses_verify_after_every_middleware() {
+        let server = signing_server(&config());
+
+        let response = exchange(&server, "GET", json!({ "origin": "https://example.com" }));
+        assert_eq!(response.status.code, 200, "{:?}", response);
+        assert!(response.headers.contains_key("x-content-type-options"));
+        assert!(response.headers.contains_key("access-control-allow-origin"));
+        assert!(response.headers.contains_key("request-id"));
+        assert!(verifies(&response));
+    }
+
+    #[test]
+    fn preflights_go_through_unsigned_and_are_answered_signed() {
+        let server = signing_server(&config());
 
-            stream.write(str_response.as_bytes()).unwrap();
+        let response = exchange(
+            &server,
+            "OPTIONS",
+            json!({
+                "origin": "https://example.com",
+                "access-control-request-method": "PUT",
+            }),
+        );
+        assert_eq!(response.status.code, 204, "{:?}", response);
+        assert!(response
+            .headers
+            .contains_key("access-control-allow-methods"));
+        assert!(verifies(&response));
+    }
 
-            println!("handled connection from {}", stream.peer_addr().unwrap());
+    #[test]
+    fn preflights_are_not_let_through_unsigned_without_cors() {
+        let server = signing_server(&Config {
+            cors: None,
+            ..config()
         });
+
+        let response = exchange(
+            &server,
+            "OPTIONS",
+            json!({
+                "origin": "https://example.com",
+                "access-control-request-method": "GET",
+            }),
+        );
+        assert_eq!(response.status.code, 401, "{:?}", response);
+    }
+
+    #[test]
+    fn preflights_keep_the_headers_added_for_every_response() {
+        let server = signing_server(&config());
+
+        let response = exchange(
+            &server,
+            "OPTIONS",
+            json!({
+                "origin": "https://example.com",
+                "access-control-request-method": "PUT",
+            }),
+        );
+        assert_eq!(response.status.code, 204, "{:?}", response);
+        assert_eq!(response.headers.get("server"), Some(&json!("jsontp")));
+        assert!(response.headers.contains_key("request-id"));
+        assert!(response.headers.contains_key("extensions"));
+        assert!(!response.headers.contains_key("origin"));
+        assert!(!response
+            .headers
+            .contains_key("access-control-request-method"));
+        assert!(verifies(&response));
     }
 }
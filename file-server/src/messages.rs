@@ -0,0 +1,210 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use serde_json::Value;
+
+use crate::{
+    middleware::{Context, Middleware},
+    protocol::{JsontpRequest, JsontpResponse},
+};
+
+/// The language `human-message`s are written in by the server and its
+/// handlers, which a request preferring it gets them in unchanged.
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Translations of `human-message`, by language and then status code, which
+/// [`Messages::translate`] picks from with a request's `accept-language`.
+///
+/// German and French are built in for the statuses this server answers
+/// with; applications [`register`](Messages::register) their own languages
+/// and replace the built-in messages the same way. A message is replaced
+/// whatever it said, so one a handler wrote to explain a particular failure
+/// loses that detail for a client that prefers another language.
+///
+/// ```
+/// use jsontp::messages::Messages;
+/// use jsontp::protocol::JsontpRequest;
+/// use jsontp::server::status_response;
+///
+/// let request: JsontpRequest = serde_json::from_value(serde_json::json!({
+///     "jsontp": "1.0", "type": "request", "method": "GET", "resource": "a",
+///     "headers": {"accept-language": "nl-BE, de;q=0.8, en;q=0.5"},
+///     "body": {"content": "{}", "encoding": "identity"},
+/// }))
+/// .unwrap();
+///
+/// let mut messages = Messages::new();
+/// let mut response = status_response(404, "Not Found", "Resource not found");
+/// messages.translate(&request, &mut response);
+/// assert_eq!(response.status.human_message, "Ressource nicht gefunden");
+///
+/// messages.register("nl", 404, "Bron niet gevonden");
+/// let mut response = status_response(404, "Not Found", "Resource not found");
+/// messages.translate(&request, &mut response);
+/// assert_eq!(response.status.human_message, "Bron niet gevonden");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Messages {
+    catalogs: BTreeMap<String, BTreeMap<u16, String>>,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        let mut messages = Messages::empty();
+        for (language, catalog) in [("de", GERMAN), ("fr", FRENCH)] {
+            for (code, message) in catalog {
+                messages.register(language, *code, message);
+            }
+        }
+        messages
+    }
+}
+
+impl Messages {
+    /// The built-in translations.
+    pub fn new() -> Messages {
+        Messages::default()
+    }
+
+    /// No translations at all.
+    pub fn empty() -> Messages {
+        Messages {
+            catalogs: BTreeMap::new(),
+        }
+    }
+
+    /// Has responses with `code` say `message` to requests preferring
+    /// `language`, a tag such as `de` or `pt-BR`. A request for `de-AT`
+    /// gets the `de` message when there is no `de-AT` one.
+    pub fn register(&mut self, language: &str, code: u16, message: &str) {
+        self.catalogs
+            .entry(language.to_ascii_lowercase())
+            .or_default()
+            .insert(code, message.to_string());
+    }
+
+    /// The message for `code` in `language`, or in its primary language.
+    pub fn get(&self, language: &str, code: u16) -> Option<&str> {
+        let language = language.to_ascii_lowercase();
+        let primary = language.split('-').next().unwrap_or_default();
+        let message = [language.as_str(), primary]
+            .into_iter()
+            .find_map(|language| self.catalogs.get(language)?.get(&code));
+        message.map(String::as_str)
+    }
+
+    /// Replaces the `human-message` of `response` with the one for its code
+    /// in the language `request` prefers most that there is one in. It is
+    /// left as it is when that is [`DEFAULT_LANGUAGE`], or `*`, or when no
+    /// language the request accepts has a message for the code.
+    pub fn translate(&self, request: &JsontpRequest, response: &mut JsontpResponse) {
+        for language in accepted_languages(request) {
+            if language == "*" || language.split('-').next() == Some(DEFAULT_LANGUAGE) {
+                return;
+            }
+            if let Some(message) = self.get(&language, response.status.code) {
+                response.status.human_message = message.to_string();
+                return;
+            }
+        }
+    }
+}
+
+/// The languages `accept-language` lists, as a comma separated string or an
+/// array, in lower case and most preferred first, leaving out those refused
+/// with `;q=0`.
+pub fn accepted_languages(request: &JsontpRequest) -> Vec<String> {
+    let tags: Vec<&str> = match request.headers.get("accept-language") {
+        Some(Value::String(list)) => list.split(',').collect(),
+        Some(Value::Array(list)) => list.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+
+    let mut weighted: Vec<(String, f64)> = tags
+        .into_iter()
+        .filter_map(|tag| {
+            let mut parts = tag.split(';').map(str::trim);
+            let tag = parts.next()?.to_ascii_lowercase();
+            let q = parts
+                .find_map(|parameter| parameter.strip_prefix("q="))
+                .and_then(|q| q.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && q > 0.0).then_some((tag, q))
+        })
+        .collect();
+
+    // stable, so that tags of the same weight keep the order they came in
+    weighted.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    weighted.into_iter().map(|(tag, _)| tag).collect()
+}
+
+/// Translates the `human-message` of every response to a request that
+/// prefers another language, see [`Messages::translate`]. Responses to
+/// messages that could not be decoded are left in [`DEFAULT_LANGUAGE`].
+pub struct MessagesMiddleware {
+    pub messages: Messages,
+}
+
+impl Middleware for MessagesMiddleware {
+    fn on_response(
+        &self,
+        request: Option<&JsontpRequest>,
+        _context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        if let Some(request) = request {
+            self.messages.translate(request, response);
+        }
+    }
+}
+
+const GERMAN: &[(u16, &str)] = &[
+    (200, "Anfrage war erfolgreich"),
+    (201, "Ressource wurde erstellt"),
+    (204, "Anfrage war erfolgreich, ohne Inhalt"),
+    (206, "Teil der Ressource wurde geliefert"),
+    (304, "Ressource wurde nicht geändert"),
+    (400, "Anfrage ist fehlerhaft"),
+    (401, "Anmeldung ist erforderlich"),
+    (403, "Zugriff ist verboten"),
+    (404, "Ressource nicht gefunden"),
+    (405, "Methode ist nicht erlaubt"),
+    (409, "Anfrage steht im Konflikt mit der Ressource"),
+    (412, "Vorbedingung ist nicht erfüllt"),
+    (413, "Anfrage ist zu groß"),
+    (415, "Medientyp wird nicht unterstützt"),
+    (424, "Eine andere Anfrage ist fehlgeschlagen"),
+    (429, "Zu viele Anfragen"),
+    (431, "Kopfzeilen der Anfrage sind zu groß"),
+    (500, "Interner Serverfehler"),
+    (501, "Nicht implementiert"),
+    (503, "Dienst ist nicht verfügbar"),
+    (505, "Protokollversion wird nicht unterstützt"),
+];
+
+const FRENCH: &[(u16, &str)] = &[
+    (200, "La requête a réussi"),
+    (201, "La ressource a été créée"),
+    (204, "La requête a réussi, sans contenu"),
+    (206, "Une partie de la ressource a été envoyée"),
+    (304, "La ressource n'a pas été modifiée"),
+    (400, "La requête est invalide"),
+    (401, "Une authentification est nécessaire"),
+    (403, "L'accès est interdit"),
+    (404, "Ressource introuvable"),
+    (405, "La méthode n'est pas autorisée"),
+    (409, "La requête est en conflit avec la ressource"),
+    (412, "La précondition a échoué"),
+    (413, "La requête est trop volumineuse"),
+    (415, "Le type de média n'est pas pris en charge"),
+    (424, "Une autre requête a échoué"),
+    (429, "Trop de requêtes"),
+    (431, "Les en-têtes de la requête sont trop volumineux"),
+    (500, "Erreur interne du serveur"),
+    (501, "Non implémenté"),
+    (503, "Le service est indisponible"),
+    (505, "Version du protocole non prise en charge"),
+];
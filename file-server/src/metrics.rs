@@ -0,0 +1,175 @@
+use alloc::{collections::BTreeMap, string::String};
+#[cfg(target_has_atomic = "64")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+#[cfg(not(target_has_atomic = "64"))]
+use core::{cell::UnsafeCell, hint, sync::atomic::AtomicBool};
+
+/// A `u64` shared between threads: an `AtomicU64` on targets that have
+/// one, and a `u64` behind a spin lock on those that do not, so that counts
+/// do not wrap at 32 bits there.
+#[derive(Default)]
+pub struct Counter {
+    #[cfg(target_has_atomic = "64")]
+    value: AtomicU64,
+    #[cfg(not(target_has_atomic = "64"))]
+    locked: AtomicBool,
+    #[cfg(not(target_has_atomic = "64"))]
+    value: UnsafeCell<u64>,
+}
+
+// the value is only touched with `locked` held
+#[cfg(not(target_has_atomic = "64"))]
+unsafe impl Sync for Counter {}
+
+#[cfg(target_has_atomic = "64")]
+impl Counter {
+    pub const fn new(value: u64) -> Counter {
+        Counter {
+            value: AtomicU64::new(value),
+        }
+    }
+
+    pub fn load(&self) -> u64 {
+        self.value.load(Ordering::Acquire)
+    }
+
+    /// Adds `amount`, returning the value from before.
+    pub fn fetch_add(&self, amount: u64) -> u64 {
+        self.value.fetch_add(amount, Ordering::AcqRel)
+    }
+
+    /// Takes off `amount`, returning the value from before.
+    pub fn fetch_sub(&self, amount: u64) -> u64 {
+        self.value.fetch_sub(amount, Ordering::AcqRel)
+    }
+}
+
+#[cfg(not(target_has_atomic = "64"))]
+impl Counter {
+    pub const fn new(value: u64) -> Counter {
+        Counter {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(u64) -> u64) -> u64 {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        // SAFETY: the lock is held, so nothing else reads or writes the value
+        let before = unsafe {
+            let value = &mut *self.value.get();
+            let before = *value;
+            *value = f(before);
+            before
+        };
+        self.locked.store(false, Ordering::Release);
+        before
+    }
+
+    pub fn load(&self) -> u64 {
+        self.update(|value| value)
+    }
+
+    /// Adds `amount`, returning the value from before.
+    pub fn fetch_add(&self, amount: u64) -> u64 {
+        self.update(|value| value.wrapping_add(amount))
+    }
+
+    /// Takes off `amount`, returning the value from before.
+    pub fn fetch_sub(&self, amount: u64) -> u64 {
+        self.update(|value| value.wrapping_sub(amount))
+    }
+}
+
+/// Where a server with metrics turned on serves them.
+pub const METRICS_RESOURCE: &str = ".well-known/jsontp-metrics";
+
+/// Process-wide counters, readable at any time through [`Metrics::snapshot`].
+#[derive(Default)]
+pub struct Metrics {
+    pub connections_accepted: Counter,
+    pub connections_refused: Counter,
+    /// Connections accepted and not yet closed; with `requests-in-flight`,
+    /// shows how far a server has drained.
+    pub connections_open: Counter,
+    /// Requests being validated or handled right now.
+    pub requests_in_flight: Counter,
+    /// Connections that failed while being read from or written to.
+    pub connection_errors: Counter,
+    /// Connections dropped for sending too slowly or missing the deadline.
+    pub slow_clients_dropped: Counter,
+    pub oversized_messages: Counter,
+    /// Unsafe requests whose idempotency key had been seen before.
+    pub idempotency_hits: Counter,
+    /// Of those, the ones answered with the remembered response.
+    pub idempotency_replays: Counter,
+    pub idempotency_misses: Counter,
+    /// Bytes written to clients, counted as connections close.
+    pub bytes_served: Counter,
+    /// Requests turned down because their client had used up its bandwidth
+    /// quota.
+    pub bandwidth_quota_exceeded: Counter,
+    /// Requests whose handling panicked, answered with 500 rather than
+    /// taking other requests down with them.
+    pub handler_panics: Counter,
+}
+
+pub static METRICS: Metrics = Metrics {
+    connections_accepted: Counter::new(0),
+    connections_refused: Counter::new(0),
+    connections_open: Counter::new(0),
+    requests_in_flight: Counter::new(0),
+    connection_errors: Counter::new(0),
+    slow_clients_dropped: Counter::new(0),
+    oversized_messages: Counter::new(0),
+    idempotency_hits: Counter::new(0),
+    idempotency_replays: Counter::new(0),
+    idempotency_misses: Counter::new(0),
+    bytes_served: Counter::new(0),
+    bandwidth_quota_exceeded: Counter::new(0),
+    handler_panics: Counter::new(0),
+};
+
+impl Metrics {
+    pub fn increment(counter: &Counter) {
+        counter.fetch_add(1);
+    }
+
+    pub fn add(counter: &Counter, amount: u64) {
+        counter.fetch_add(amount);
+    }
+
+    /// Lowers a counter that goes up and down, such as `connections_open`.
+    pub fn decrement(counter: &Counter) {
+        counter.fetch_sub(1);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        let mut snapshot = BTreeMap::new();
+        for (name, counter) in [
+            ("connections-accepted", &self.connections_accepted),
+            ("connections-refused", &self.connections_refused),
+            ("connections-open", &self.connections_open),
+            ("requests-in-flight", &self.requests_in_flight),
+            ("connection-errors", &self.connection_errors),
+            ("slow-clients-dropped", &self.slow_clients_dropped),
+            ("oversized-messages", &self.oversized_messages),
+            ("idempotency-hits", &self.idempotency_hits),
+            ("idempotency-replays", &self.idempotency_replays),
+            ("idempotency-misses", &self.idempotency_misses),
+            ("bytes-served", &self.bytes_served),
+            ("bandwidth-quota-exceeded", &self.bandwidth_quota_exceeded),
+            ("handler-panics", &self.handler_panics),
+        ] {
+            snapshot.insert(String::from(name), counter.load());
+        }
+        snapshot
+    }
+}
@@ -0,0 +1,153 @@
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use core::{fmt, net::IpAddr};
+
+use serde_json::Value;
+
+use crate::{
+    protocol::{JsontpRequest, JsontpResponse, Utf8Policy},
+    streaming::RequestHead,
+};
+
+/// The key in [`Context::values`] under which authenticating middleware put
+/// the claims of the token a request was signed in with.
+pub const CLAIMS_VALUE: &str = "token-claims";
+
+/// Per-request state shared between middleware.
+#[derive(Debug, Default)]
+pub struct Context {
+    /// The number the server gave the connection the request came over, as
+    /// in its lifecycle events; 0 when the transport does not number them.
+    pub connection: u64,
+    /// The address the request came from, when the transport knows it.
+    pub peer: Option<IpAddr>,
+    /// The identity established by an authenticating middleware.
+    pub user: Option<String>,
+    pub roles: Vec<String>,
+    /// Scratch space for middleware to pass values from `on_request` to
+    /// `on_response`.
+    pub values: BTreeMap<String, Value>,
+    /// Set while the request is part of a transactional batch, whose
+    /// requests must all succeed or be undone.
+    pub in_transaction: bool,
+    /// What to do to undo the request if the transaction it is part of
+    /// fails.
+    pub rollbacks: Rollbacks,
+    /// The size in bytes of the message the request came in; for a batch,
+    /// of the whole batch.
+    pub bytes_read: usize,
+    /// When the server started reading the message.
+    #[cfg(feature = "std")]
+    pub received: Option<std::time::Instant>,
+    /// When the server stops waiting for the handler and answers for it, if
+    /// it ever does.
+    #[cfg(feature = "std")]
+    pub deadline: Option<std::time::Instant>,
+    /// The TLS session the request came over, for transports that terminate
+    /// TLS themselves; the ones in this crate do not.
+    pub tls: Option<TlsInfo>,
+    /// What the server does with content that is not UTF-8, for middleware
+    /// and handlers that turn bytes into a body.
+    pub utf8: Utf8Policy,
+    /// The wire format the request was decoded from.
+    #[cfg(feature = "std")]
+    pub wire_format: Option<crate::wire::WireFormat>,
+    /// The wire format the response will be encoded in, which the request
+    /// may have asked for with `wire-format`.
+    #[cfg(feature = "std")]
+    pub response_format: Option<crate::wire::WireFormat>,
+}
+
+/// What a TLS handshake settled on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Such as `TLSv1.3`.
+    pub version: String,
+    pub cipher: String,
+    /// The name the client asked for with SNI.
+    pub server_name: Option<String>,
+}
+
+impl Context {
+    /// How long ago the server started reading the request.
+    #[cfg(feature = "std")]
+    pub fn elapsed(&self) -> Option<std::time::Duration> {
+        self.received.map(|received| received.elapsed())
+    }
+
+    /// How long the handler has left before [`Context::deadline`], or zero
+    /// once it has passed.
+    #[cfg(feature = "std")]
+    pub fn remaining(&self) -> Option<std::time::Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(std::time::Instant::now()))
+    }
+
+    /// Registers a way to undo what handling the request changed. Only
+    /// transactional batches ever run these, so handlers can skip preparing
+    /// them when [`Context::in_transaction`] is not set.
+    pub fn on_rollback(&mut self, rollback: impl FnOnce() + Send + 'static) {
+        self.rollbacks.0.push(Box::new(rollback));
+    }
+}
+
+/// The undo actions registered for a request, see [`Context::on_rollback`].
+#[derive(Default)]
+pub struct Rollbacks(Vec<Box<dyn FnOnce() + Send>>);
+
+impl Rollbacks {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Runs every undo action, the last registered first.
+    pub fn run(self) {
+        for rollback in self.0.into_iter().rev() {
+            rollback();
+        }
+    }
+}
+
+impl fmt::Debug for Rollbacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rollbacks({})", self.0.len())
+    }
+}
+
+/// A hook into the request/response cycle of a server.
+///
+/// `on_request` runs after a request has been parsed and validated; returning
+/// an error short-circuits handling and the server answers with that status.
+/// `on_response` runs on every response right before it is encoded, including
+/// error responses, in which case `request` is `None` if the request could not
+/// be parsed.
+///
+/// `on_head` runs earlier still, as soon as the method, resource and headers
+/// of a request have arrived and before the body has been read, so requests
+/// that are bound to be refused can be turned away without receiving the
+/// rest. It is best effort: binary wire formats skip it, and it must not
+/// reject anything `on_request` would let through.
+///
+/// Servers call `on_request` in registration order and `on_response` in
+/// reverse, so the first middleware sees the request first and the response
+/// last.
+pub trait Middleware: Send + Sync {
+    fn on_head(&self, _head: &RequestHead, _context: &Context) -> Result<(), (String, u16)> {
+        Ok(())
+    }
+
+    fn on_request(
+        &self,
+        _request: &mut JsontpRequest,
+        _context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        Ok(())
+    }
+
+    fn on_response(
+        &self,
+        _request: Option<&JsontpRequest>,
+        _context: &mut Context,
+        _response: &mut JsontpResponse,
+    ) {
+    }
+}
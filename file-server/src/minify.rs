@@ -0,0 +1,449 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    middleware::Context,
+    protocol::{JsontpRequest, JsontpResponse},
+    transform::BodyTransform,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MinifyConfig {
+    /// The content types minified. Only `application/json` (and other
+    /// `+json` types), `text/html`, `text/css` and `text/javascript` have a
+    /// minifier; others listed are sent as they are.
+    pub types: Vec<String>,
+}
+
+impl Default for MinifyConfig {
+    fn default() -> Self {
+        MinifyConfig {
+            types: [
+                "application/json",
+                "text/html",
+                "text/css",
+                "text/javascript",
+            ]
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        }
+    }
+}
+
+impl MinifyConfig {
+    /// `text` minified, if `content_type` is one of `types` and has a
+    /// minifier.
+    pub fn minify(&self, content_type: &str, text: &str) -> Option<String> {
+        if !self.types.iter().any(|minified| minified == content_type) {
+            return None;
+        }
+        minify(content_type, text)
+    }
+}
+
+/// Minifies response bodies sent as they are whose `content-type` is one of
+/// `types`, for content the file server does not read itself.
+impl BodyTransform for MinifyConfig {
+    fn transform(
+        &self,
+        _request: Option<&JsontpRequest>,
+        _context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        if response.body.encoding != "identity" {
+            return;
+        }
+        let Some(content_type) = response.headers.get("content-type").and_then(Value::as_str)
+        else {
+            return;
+        };
+        let content_type = content_type.split(';').next().unwrap_or_default().trim();
+        if let Some(minified) = self.minify(content_type, &response.body.content) {
+            response.body.content = minified;
+        }
+    }
+}
+
+/// `text` minified as `content_type`, if there is a minifier for it.
+///
+/// The minifiers only take out what cannot matter: whitespace and comments
+/// outside of strings, and in HTML, whitespace between words and tags, but
+/// never inside `pre`, `textarea`, `script` or `style`. They do not rename
+/// or rewrite anything, so minified content behaves as the original does.
+pub fn minify(content_type: &str, text: &str) -> Option<String> {
+    match content_type {
+        "application/json" => Some(json(text)),
+        other if other.ends_with("+json") => Some(json(text)),
+        "text/html" => Some(html(text)),
+        "text/css" => Some(css(text)),
+        "text/javascript" | "application/javascript" => Some(js(text)),
+        _ => None,
+    }
+}
+
+/// JSON without the whitespace between its tokens.
+pub fn json(text: &str) -> String {
+    let mut minified = String::with_capacity(text.len());
+    let (mut in_string, mut escaped) = (false, false);
+
+    for c in text.chars() {
+        if in_string {
+            minified.push(c);
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+            minified.push(c);
+        } else if !matches!(c, ' ' | '\t' | '\n' | '\r') {
+            minified.push(c);
+        }
+    }
+    minified
+}
+
+/// Copies the string starting at `chars[i]`, quotes and all, giving where
+/// what follows it starts. An unterminated string runs to the end.
+fn copy_string(chars: &[char], mut i: usize, out: &mut String) -> usize {
+    let quote = chars[i];
+    out.push(quote);
+    i += 1;
+    while i < chars.len() {
+        let c = chars[i];
+        out.push(c);
+        i += 1;
+        if c == '\\' {
+            if let Some(&escaped) = chars.get(i) {
+                out.push(escaped);
+                i += 1;
+            }
+        } else if c == quote {
+            break;
+        } else if quote == '`' && c == '$' && chars.get(i) == Some(&'{') {
+            i = copy_substitution(chars, i, out);
+        }
+    }
+    i
+}
+
+/// Copies the `{...}` of a template literal's `${...}` as it is, strings
+/// and nested templates included.
+fn copy_substitution(chars: &[char], mut i: usize, out: &mut String) -> usize {
+    let mut depth = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\'' | '"' | '`' => {
+                i = copy_string(chars, i, out);
+                continue;
+            }
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    out.push('}');
+                    return i + 1;
+                }
+            }
+            _ => {}
+        }
+        out.push(chars[i]);
+        i += 1;
+    }
+    i
+}
+
+/// Skips the `/* ... */` comment starting at `chars[i]`, giving where what
+/// follows it starts and whether it spanned lines.
+fn skip_block_comment(chars: &[char], i: usize) -> (usize, bool) {
+    let mut newline = false;
+    let mut j = i + 2;
+    while j < chars.len() {
+        if chars[j] == '*' && chars.get(j + 1) == Some(&'/') {
+            return (j + 2, newline);
+        }
+        newline |= chars[j] == '\n';
+        j += 1;
+    }
+    (j, newline)
+}
+
+/// CSS without comments, and without whitespace next to the punctuation
+/// that does not need it or the last `;` of a block.
+pub fn css(text: &str) -> String {
+    const TIGHT: &[char] = &['{', '}', ';', ',', '>'];
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut minified = String::with_capacity(text.len());
+    let mut space = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i = skip_block_comment(&chars, i).0;
+            space = true;
+            continue;
+        }
+        if c.is_whitespace() {
+            space = true;
+            i += 1;
+            continue;
+        }
+
+        let last = minified.chars().last();
+        if space && last.is_some_and(|last| !TIGHT.contains(&last)) && !TIGHT.contains(&c) {
+            minified.push(' ');
+        }
+        space = false;
+
+        match c {
+            '"' | '\'' => i = copy_string(&chars, i, &mut minified),
+            _ => {
+                if c == '}' && last == Some(';') {
+                    minified.pop();
+                }
+                minified.push(c);
+                i += 1;
+            }
+        }
+    }
+    minified
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$' || !c.is_ascii()
+}
+
+/// JavaScript without comments or indentation, with runs of spaces made one
+/// where one is needed and none elsewhere. Line breaks are kept, one for a
+/// run of them, wherever one could end a statement, so that automatic
+/// semicolon insertion works as it did.
+///
+/// ```
+/// use jsontp::minify::js;
+///
+/// // a `/` after a word ending in `return` divides; after `return`, it
+/// // starts a regular expression
+/// assert_eq!(
+///     js("let x = noreturn / 2; let s = \"a/b // c\";\nfoo();"),
+///     "let x=noreturn/2;let s=\"a/b // c\";foo();"
+/// );
+/// assert_eq!(js("return /a\\/b/.test(s) // why"), "return/a\\/b/.test(s)");
+/// ```
+pub fn js(text: &str) -> String {
+    const TIGHT: &[char] = &['{', '}', '(', ')', '[', ']', ';', ',', ':', '='];
+    // after these, a `/` starts a regular expression rather than dividing
+    const BEFORE_REGEX: &[char] = &[
+        '(', ',', '=', ':', '[', '!', '&', '|', '?', '{', '}', ';', '+', '-', '*', '%', '<', '>',
+        '~', '^',
+    ];
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut minified = String::with_capacity(text.len());
+    // whitespace since the last token, and whether it broke a line
+    let mut gap: Option<bool> = None;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c.is_whitespace() {
+            gap = Some(gap == Some(true) || c == '\n');
+            i += 1;
+            continue;
+        }
+        if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            gap.get_or_insert(false);
+            continue;
+        }
+        if c == '/' && next == Some('*') {
+            let (end, newline) = skip_block_comment(&chars, i);
+            gap = Some(gap == Some(true) || newline);
+            i = end;
+            continue;
+        }
+
+        let last = minified.chars().last();
+        if let (Some(newline), Some(last)) = (gap.take(), last) {
+            if newline && !"{;,(".contains(last) && !"})],;".contains(c) {
+                minified.push('\n');
+            } else if !TIGHT.contains(&last) && !TIGHT.contains(&c) {
+                let needed = (is_word(last) && is_word(c))
+                    || (last == c && "+-/".contains(c))
+                    || (last.is_ascii_digit() && c == '.');
+                if needed {
+                    minified.push(' ');
+                }
+            }
+        }
+
+        let last = minified.chars().last();
+        match c {
+            '"' | '\'' | '`' => i = copy_string(&chars, i, &mut minified),
+            '/' if last.is_none_or(|last| BEFORE_REGEX.contains(&last) || last == '\n')
+                || ends_with_word(&minified, "return")
+                || ends_with_word(&minified, "typeof") =>
+            {
+                i = copy_regex(&chars, i, &mut minified);
+            }
+            _ => {
+                minified.push(c);
+                i += 1;
+            }
+        }
+    }
+    minified
+}
+
+/// Whether `text` ends with `word`, as a whole word rather than the end of a
+/// longer one.
+fn ends_with_word(text: &str, word: &str) -> bool {
+    text.strip_suffix(word)
+        .is_some_and(|before| !before.chars().last().is_some_and(is_word))
+}
+
+/// Copies the regular expression literal starting at `chars[i]` up to its
+/// closing `/`; its flags follow as an ordinary word.
+fn copy_regex(chars: &[char], mut i: usize, out: &mut String) -> usize {
+    out.push('/');
+    i += 1;
+    let mut in_class = false;
+    while i < chars.len() && chars[i] != '\n' {
+        let c = chars[i];
+        out.push(c);
+        i += 1;
+        match c {
+            '\\' => {
+                if let Some(&escaped) = chars.get(i) {
+                    out.push(escaped);
+                    i += 1;
+                }
+            }
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '/' if !in_class => break,
+            _ => {}
+        }
+    }
+    i
+}
+
+/// HTML without comments, other than conditional ones, and with every run
+/// of whitespace in text and tags made one space, or one line break if it
+/// had any. `pre`, `textarea`, `script` and `style` elements are left as
+/// they are.
+pub fn html(text: &str) -> String {
+    const RAW: &[&str] = &["pre", "textarea", "script", "style"];
+
+    let lower = text.to_ascii_lowercase();
+    let bytes = text.as_bytes();
+    let mut minified = String::with_capacity(text.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let rest = &lower[i..];
+        let is_tag = rest.starts_with('<')
+            && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!');
+
+        if is_tag && rest.starts_with("<!--") && !rest.starts_with("<!--[if") {
+            i = rest.find("-->").map_or(bytes.len(), |end| i + end + 3);
+            continue;
+        }
+
+        if is_tag {
+            let raw = RAW.iter().find(|name| {
+                rest[1..].starts_with(*name)
+                    && rest[1 + name.len()..]
+                        .starts_with(|c: char| c == '>' || c == '/' || c.is_ascii_whitespace())
+            });
+            let end = match raw {
+                Some(name) => rest
+                    .find(&format!("</{}", name))
+                    .and_then(|close| rest[close..].find('>').map(|end| i + close + end + 1))
+                    .unwrap_or(bytes.len()),
+                None => tag_end(bytes, i),
+            };
+            match raw {
+                Some(_) => minified.push_str(&text[i..end]),
+                None => collapse_tag(&text[i..end], &mut minified),
+            }
+            i = end;
+            continue;
+        }
+
+        if bytes[i].is_ascii_whitespace() {
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let mut newline = text[start..i].contains('\n');
+            // a comment taken out may leave two runs side by side
+            if minified.ends_with([' ', '\n']) {
+                newline |= minified.pop() == Some('\n');
+            }
+            minified.push(if newline { '\n' } else { ' ' });
+            continue;
+        }
+
+        let end = bytes[i + 1..]
+            .iter()
+            .position(|&b| b == b'<' || b.is_ascii_whitespace())
+            .map_or(bytes.len(), |end| i + 1 + end);
+        minified.push_str(&text[i..end]);
+        i = end;
+    }
+    minified
+}
+
+/// Where the tag starting at `bytes[i]` ends, past its `>`, skipping `>`
+/// in quoted attribute values.
+fn tag_end(bytes: &[u8], mut i: usize) -> usize {
+    let mut quote = None;
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (None, b'>') => return i + 1,
+            (None, b @ (b'"' | b'\'')) => quote = Some(b),
+            (Some(q), b) if b == q => quote = None,
+            _ => {}
+        }
+        i += 1;
+    }
+    i
+}
+
+/// Pushes `tag` with each run of whitespace outside attribute values made
+/// one space, and none before its closing `>`.
+fn collapse_tag(tag: &str, out: &mut String) {
+    let mut quote = None;
+    let mut space = false;
+    for c in tag.chars() {
+        match quote {
+            Some(q) => {
+                out.push(c);
+                if c == q {
+                    quote = None;
+                }
+            }
+            None if c.is_ascii_whitespace() => space = true,
+            None => {
+                if space && c != '>' {
+                    out.push(' ');
+                }
+                space = false;
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+                out.push(c);
+            }
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    sync::RwLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::Verifier;
+use rsa::{pkcs1v15, BigUint, RsaPublicKey};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::Sha256;
+
+use crate::{
+    middleware::{Context, Middleware, CLAIMS_VALUE},
+    protocol::JsontpRequest,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Where the identity provider publishes its signing keys.
+    pub jwks_url: String,
+    /// What `iss` must be; not checked when unset.
+    pub issuer: Option<String>,
+    /// What `aud` must be or contain; not checked when unset.
+    pub audience: Option<String>,
+    /// Seconds the keys are kept before being fetched again.
+    #[serde(default = "default_refresh")]
+    pub refresh: u64,
+    /// Seconds of clock difference allowed when checking `exp` and `nbf`.
+    #[serde(default = "default_leeway")]
+    pub leeway: u64,
+    /// The claim naming the user.
+    #[serde(default = "default_user_claim")]
+    pub user_claim: String,
+    /// The claim holding the user's roles, as an array or a space separated
+    /// string such as `scope`.
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+}
+
+fn default_refresh() -> u64 {
+    3600
+}
+
+fn default_leeway() -> u64 {
+    60
+}
+
+fn default_user_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_roles_claim() -> String {
+    "roles".to_string()
+}
+
+/// One key of a JSON Web Key Set. RSA keys (`RS256`) and Ed25519 keys
+/// (`EdDSA`) are understood; any other is ignored.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    pub alg: Option<String>,
+    /// RSA modulus and exponent.
+    pub n: Option<String>,
+    pub e: Option<String>,
+    /// The curve and public key of an OKP key.
+    pub crv: Option<String>,
+    pub x: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct KeySet {
+    pub keys: Vec<Jwk>,
+}
+
+impl KeySet {
+    fn find(&self, kid: Option<&str>) -> Option<&Jwk> {
+        match kid {
+            Some(kid) => self.keys.iter().find(|key| key.kid.as_deref() == Some(kid)),
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+    }
+}
+
+/// Gets a key set from wherever it is published.
+pub trait KeySource: Send + Sync {
+    fn fetch(&self) -> Result<KeySet, String>;
+}
+
+/// A key set published at a URL, as identity providers do at their
+/// `jwks_uri`.
+pub struct JwksUrl(pub String);
+
+impl KeySource for JwksUrl {
+    fn fetch(&self) -> Result<KeySet, String> {
+        ureq::get(&self.0)
+            .timeout(Duration::from_secs(10))
+            .call()
+            .map_err(|e| format!("could not fetch {}: {}", self.0, e))?
+            .into_json()
+            .map_err(|e| format!("{} is not a key set: {}", self.0, e))
+    }
+}
+
+/// Why a token was not accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JwtError {
+    Malformed,
+    UnsupportedAlgorithm(String),
+    UnknownKey,
+    BadSignature,
+    Expired,
+    NotYetValid,
+    WrongIssuer,
+    WrongAudience,
+}
+
+/// Checks JWTs against the keys of an identity provider, which are fetched
+/// when first needed, again every `refresh`, and early when a token names a
+/// key that is not known yet (at most once a minute).
+pub struct JwtValidator {
+    pub config: OidcConfig,
+    source: Box<dyn KeySource>,
+    /// The keys and when they were fetched.
+    keys: RwLock<Option<(Instant, KeySet)>>,
+}
+
+impl JwtValidator {
+    pub fn new(config: OidcConfig) -> JwtValidator {
+        let source = JwksUrl(config.jwks_url.clone());
+        JwtValidator::with_source(config, source)
+    }
+
+    pub fn with_source(config: OidcConfig, source: impl KeySource + 'static) -> JwtValidator {
+        JwtValidator {
+            config,
+            source: Box::new(source),
+            keys: RwLock::new(None),
+        }
+    }
+
+    /// The key called `kid`, fetching the key set again if it is stale or
+    /// does not have it.
+    fn key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let refresh = Duration::from_secs(self.config.refresh);
+        let cached = self.keys.read().unwrap().clone();
+
+        let due = match &cached {
+            None => true,
+            Some((fetched, _)) if fetched.elapsed() >= refresh => true,
+            Some((fetched, keys)) => {
+                keys.find(kid).is_none() && fetched.elapsed() >= Duration::from_secs(60)
+            }
+        };
+
+        if due {
+            match self.source.fetch() {
+                Ok(keys) => {
+                    let key = keys.find(kid).cloned();
+                    *self.keys.write().unwrap() = Some((Instant::now(), keys));
+                    return key;
+                }
+                // carry on with the keys there are until the provider is back
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+
+        cached.and_then(|(_, keys)| keys.find(kid).cloned())
+    }
+
+    /// The claims of `token` if it is signed by one of the provider's keys,
+    /// is currently valid, and is for the configured issuer and audience.
+    pub fn validate(&self, token: &str) -> Result<Map<String, Value>, JwtError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(JwtError::Malformed);
+        };
+
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| JwtError::Malformed)
+        };
+        let header: Map<String, Value> =
+            serde_json::from_slice(&decode(header)?).map_err(|_| JwtError::Malformed)?;
+        let claims: Map<String, Value> =
+            serde_json::from_slice(&decode(payload)?).map_err(|_| JwtError::Malformed)?;
+        let signature = decode(signature)?;
+
+        let algorithm = header
+            .get("alg")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let kid = header.get("kid").and_then(Value::as_str);
+        let key = self.key(kid).ok_or(JwtError::UnknownKey)?;
+        let signed = &token[..header_and_payload_len(token)];
+        verify(algorithm, &key, signed.as_bytes(), &signature)?;
+
+        self.check_claims(&claims)?;
+        Ok(claims)
+    }
+
+    fn check_claims(&self, claims: &Map<String, Value>) -> Result<(), JwtError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let leeway = self.config.leeway;
+        let time = |name| claims.get(name).and_then(Value::as_u64);
+
+        match time("exp") {
+            Some(exp) if exp + leeway > now => {}
+            _ => return Err(JwtError::Expired),
+        }
+        if time("nbf").is_some_and(|nbf| nbf > now + leeway) {
+            return Err(JwtError::NotYetValid);
+        }
+
+        if let Some(issuer) = &self.config.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err(JwtError::WrongIssuer);
+            }
+        }
+
+        if let Some(audience) = &self.config.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err(JwtError::WrongAudience);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The length of the `header.payload` part of a token, which is what is
+/// signed.
+fn header_and_payload_len(token: &str) -> usize {
+    token.rfind('.').unwrap_or(token.len())
+}
+
+fn verify(algorithm: &str, key: &Jwk, signed: &[u8], signature: &[u8]) -> Result<(), JwtError> {
+    if key.alg.as_deref().is_some_and(|alg| alg != algorithm) {
+        return Err(JwtError::UnknownKey);
+    }
+
+    let field = |value: &Option<String>| {
+        value
+            .as_deref()
+            .and_then(|value| URL_SAFE_NO_PAD.decode(value).ok())
+            .ok_or(JwtError::UnknownKey)
+    };
+
+    match (algorithm, key.kty.as_str()) {
+        ("RS256", "RSA") => {
+            let key = RsaPublicKey::new(
+                BigUint::from_bytes_be(&field(&key.n)?),
+                BigUint::from_bytes_be(&field(&key.e)?),
+            )
+            .map_err(|_| JwtError::UnknownKey)?;
+            let signature =
+                pkcs1v15::Signature::try_from(signature).map_err(|_| JwtError::BadSignature)?;
+
+            pkcs1v15::VerifyingKey::<Sha256>::new(key)
+                .verify(signed, &signature)
+                .map_err(|_| JwtError::BadSignature)
+        }
+        ("EdDSA", "OKP") if key.crv.as_deref() == Some("Ed25519") => {
+            let bytes: [u8; 32] = field(&key.x)?
+                .try_into()
+                .map_err(|_| JwtError::UnknownKey)?;
+            let key = ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+                .map_err(|_| JwtError::UnknownKey)?;
+            let signature = ed25519_dalek::Signature::from_slice(signature)
+                .map_err(|_| JwtError::BadSignature)?;
+
+            key.verify(signed, &signature)
+                .map_err(|_| JwtError::BadSignature)
+        }
+        (algorithm, _) => Err(JwtError::UnsupportedAlgorithm(algorithm.to_string())),
+    }
+}
+
+/// Signs in requests carrying `"authorization": "Bearer <jwt>"` with a token
+/// from an OpenID Connect or OAuth2 provider. The user and roles are taken
+/// from the configured claims, and every claim is put under
+/// [`CLAIMS_VALUE`] in the context's values. Tokens that fail validation
+/// are answered with 401; bearer tokens that are not JWTs are left to other
+/// middleware.
+pub struct OidcMiddleware {
+    pub validator: JwtValidator,
+}
+
+impl Middleware for OidcMiddleware {
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        let Some(authorization) = request.headers.get("authorization").and_then(Value::as_str)
+        else {
+            return Ok(());
+        };
+        let Some(token) = authorization
+            .strip_prefix("Bearer ")
+            .or_else(|| authorization.strip_prefix("bearer "))
+            .map(str::trim)
+            .filter(|token| token.matches('.').count() == 2)
+        else {
+            return Ok(());
+        };
+
+        let claims = self
+            .validator
+            .validate(token)
+            .map_err(|_| ("Unauthorized".to_string(), 401))?;
+
+        let config = &self.validator.config;
+        context.user = claims
+            .get(&config.user_claim)
+            .and_then(Value::as_str)
+            .map(ToString::to_string);
+        match claims.get(&config.roles_claim) {
+            Some(Value::Array(roles)) => context.roles.extend(
+                roles
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(ToString::to_string),
+            ),
+            Some(Value::String(roles)) => context
+                .roles
+                .extend(roles.split_whitespace().map(ToString::to_string)),
+            _ => {}
+        }
+        context
+            .values
+            .insert(CLAIMS_VALUE.to_string(), Value::Object(claims));
+        Ok(())
+    }
+}
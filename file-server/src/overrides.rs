@@ -0,0 +1,134 @@
+use serde::Deserialize;
+
+use crate::{middleware::Context, protocol::JsontpRequest, transport::Limits};
+
+/// Settings that differ from the server-wide ones for the resources starting
+/// with `prefix`. Where several overrides match a request, the ones with
+/// longer prefixes win, setting by setting.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RouteOverride {
+    /// `""` matches every resource.
+    pub prefix: String,
+    /// The methods the override is for; every method when empty.
+    pub methods: Vec<String>,
+    /// Instead of `limits.max-message-size`.
+    pub max_message_size: Option<usize>,
+    /// Instead of `limits.request-timeout`.
+    pub request_timeout: Option<u64>,
+    /// The body encodings accepted; requests with any other are answered
+    /// with 415.
+    pub encodings: Option<Vec<String>>,
+    /// Whether requests have to be authenticated by a middleware, such as
+    /// signature checking; those that are not are answered with 401.
+    pub auth: Option<bool>,
+    /// Instead of `limits.max-send-rate`.
+    pub max_send_rate: Option<u64>,
+}
+
+impl RouteOverride {
+    pub fn new(prefix: &str) -> RouteOverride {
+        RouteOverride {
+            prefix: prefix.to_string(),
+            ..RouteOverride::default()
+        }
+    }
+
+    pub fn method(mut self, method: &str) -> RouteOverride {
+        self.methods.push(method.to_string());
+        self
+    }
+
+    pub fn max_message_size(mut self, bytes: usize) -> RouteOverride {
+        self.max_message_size = Some(bytes);
+        self
+    }
+
+    pub fn request_timeout(mut self, seconds: u64) -> RouteOverride {
+        self.request_timeout = Some(seconds);
+        self
+    }
+
+    pub fn max_send_rate(mut self, bytes_per_second: u64) -> RouteOverride {
+        self.max_send_rate = Some(bytes_per_second);
+        self
+    }
+
+    pub fn encodings(mut self, encodings: &[&str]) -> RouteOverride {
+        self.encodings = Some(encodings.iter().map(ToString::to_string).collect());
+        self
+    }
+
+    pub fn auth(mut self, required: bool) -> RouteOverride {
+        self.auth = Some(required);
+        self
+    }
+
+    pub fn applies_to(&self, method: &str, resource: &str) -> bool {
+        resource.starts_with(&self.prefix)
+            && (self.methods.is_empty() || self.methods.iter().any(|m| m == method))
+    }
+
+    /// `limits` with this override's applied.
+    pub fn limits(&self, limits: &Limits) -> Limits {
+        Limits {
+            max_message_size: self.max_message_size.unwrap_or(limits.max_message_size),
+            request_timeout: self.request_timeout.unwrap_or(limits.request_timeout),
+            max_send_rate: self.max_send_rate.unwrap_or(limits.max_send_rate),
+            ..limits.clone()
+        }
+    }
+
+    /// Turns down a request that breaks the override, once the middleware
+    /// has had its say on who sent it.
+    pub fn check(
+        &self,
+        request: &JsontpRequest,
+        context: &Context,
+        limits: &Limits,
+    ) -> Result<(), (String, u16)> {
+        if context.bytes_read > self.limits(limits).max_message_size {
+            return Err(("Payload Too Large".to_string(), 413));
+        }
+        if let Some(encodings) = &self.encodings {
+            if !encodings.contains(&request.body.encoding) {
+                return Err(("Unsupported Media Type".to_string(), 415));
+            }
+        }
+        if self.auth == Some(true) && context.user.is_none() {
+            return Err(("Unauthorized".to_string(), 401));
+        }
+
+        Ok(())
+    }
+}
+
+/// The settings for a request: every override that applies to it, merged
+/// from the shortest prefix to the longest.
+pub fn resolve(overrides: &[RouteOverride], method: &str, resource: &str) -> RouteOverride {
+    let mut matching: Vec<&RouteOverride> = overrides
+        .iter()
+        .filter(|o| o.applies_to(method, resource))
+        .collect();
+    matching.sort_by_key(|o| o.prefix.len());
+
+    let mut resolved = RouteOverride::new(resource);
+    for o in matching {
+        resolved.max_message_size = o.max_message_size.or(resolved.max_message_size);
+        resolved.request_timeout = o.request_timeout.or(resolved.request_timeout);
+        resolved.encodings = o.encodings.clone().or(resolved.encodings);
+        resolved.auth = o.auth.or(resolved.auth);
+        resolved.max_send_rate = o.max_send_rate.or(resolved.max_send_rate);
+    }
+    resolved
+}
+
+/// The limits to read a message under before it is known which resource it
+/// is for: the most generous of `limits` and every override.
+pub fn read_limits(overrides: &[RouteOverride], limits: &Limits) -> Limits {
+    overrides.iter().fold(limits.clone(), |read, o| Limits {
+        max_message_size: read.max_message_size.max(o.max_message_size.unwrap_or(0)),
+        request_timeout: read.request_timeout.max(o.request_timeout.unwrap_or(0)),
+        ..read
+    })
+}
@@ -0,0 +1,109 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use serde_json::Value;
+
+use crate::headers::Headers;
+
+/// The request header naming where a page starts: the `next-cursor` of the
+/// page before it.
+pub const CURSOR_HEADER: &str = "cursor";
+/// The request header asking for at most this many items in a page.
+pub const LIMIT_HEADER: &str = "limit";
+/// The response header naming the cursor of the following page; absent on
+/// the last page.
+pub const NEXT_CURSOR_HEADER: &str = "next-cursor";
+
+/// Which page of a listing a request asks for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageRequest {
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl PageRequest {
+    pub fn from_headers(headers: &Headers) -> PageRequest {
+        PageRequest {
+            cursor: headers
+                .get(CURSOR_HEADER)
+                .and_then(Value::as_str)
+                .map(ToString::to_string),
+            limit: headers
+                .get(LIMIT_HEADER)
+                .and_then(Value::as_u64)
+                .map(|limit| limit as usize),
+        }
+    }
+
+    /// The page size to use: what was asked for, but between one and `max`.
+    pub fn limit(&self, max: usize) -> usize {
+        self.limit.unwrap_or(max).clamp(1, max.max(1))
+    }
+
+    /// Sets the headers asking for this page.
+    pub fn apply(&self, headers: &mut Headers) {
+        if let Some(cursor) = &self.cursor {
+            headers.insert(CURSOR_HEADER.to_string(), Value::String(cursor.clone()));
+        }
+        if let Some(limit) = self.limit {
+            headers.insert(LIMIT_HEADER.to_string(), Value::from(limit));
+        }
+    }
+}
+
+/// One page of a listing.
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Sets the `next-cursor` header of the response carrying this page.
+    pub fn apply(&self, headers: &mut Headers) {
+        if let Some(next) = &self.next {
+            headers.insert(NEXT_CURSOR_HEADER.to_string(), Value::String(next.clone()));
+        }
+    }
+}
+
+/// Cuts the page `request` asks for out of `items`, which must be in order of
+/// their `key`. Cursors are keys, so a cursor stays valid while items are
+/// added or removed, and a listing never skips or repeats an item that was
+/// there all along.
+pub fn paginate<T>(
+    items: impl IntoIterator<Item = T>,
+    key: impl Fn(&T) -> String,
+    request: &PageRequest,
+    max_limit: usize,
+) -> Page<T> {
+    let limit = request.limit(max_limit);
+    let mut page = Page {
+        items: Vec::new(),
+        next: None,
+    };
+
+    let after_cursor = items.into_iter().filter(|item| {
+        request
+            .cursor
+            .as_ref()
+            .is_none_or(|cursor| key(item) > *cursor)
+    });
+
+    for item in after_cursor {
+        if page.items.len() == limit {
+            page.next = page.items.last().map(&key);
+            break;
+        }
+        page.items.push(item);
+    }
+
+    page
+}
+
+/// The cursor for the page after the one in `headers`, if there is one.
+pub fn next_cursor(headers: &Headers) -> Option<&str> {
+    headers.get(NEXT_CURSOR_HEADER).and_then(Value::as_str)
+}
@@ -0,0 +1,142 @@
+use std::{env, ffi::CString, io, os::unix::fs::chroot};
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PrivilegesConfig {
+    /// The user, by name or number, to become once the port is bound.
+    pub user: Option<String>,
+    /// The group, by name or number, to become; the user's own group when
+    /// unset.
+    pub group: Option<String>,
+    /// Confine the process to the working directory, the document root,
+    /// before giving up root.
+    pub chroot: bool,
+}
+
+/// Gives up root as `config` says: into the working directory if `chroot`
+/// is set, then to `group` and `user`, leaving every other group. Done
+/// once the listener is bound and files that need root are open, it keeps
+/// a compromised server from doing what root could.
+///
+/// Becoming root again afterwards is checked to fail.
+pub fn drop_privileges(config: &PrivilegesConfig) -> io::Result<()> {
+    // looked up first, as the user database is outside any chroot
+    let user = config.user.as_deref().map(lookup_user).transpose()?;
+    let gid = match (&config.group, user) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some((_, Some(gid)))) => Some(gid),
+        (None, Some((uid, None))) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "user {} has no group of its own, so one has to be given",
+                    uid
+                ),
+            ))
+        }
+        (None, None) => None,
+    };
+
+    if config.chroot {
+        chroot(".")?;
+        env::set_current_dir("/")?;
+    }
+
+    // SAFETY: these calls take plain values, or a pointer to one group
+    if let Some(gid) = gid {
+        check(unsafe { libc::setgroups(1, &gid) })?;
+        check(unsafe { libc::setgid(gid) })?;
+    }
+    if let Some((uid, _)) = user {
+        check(unsafe { libc::setuid(uid) })?;
+
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "root privileges could be regained",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The uid and primary gid of `user`, which may be a uid the user database
+/// does not know, and so without a gid.
+fn lookup_user(user: &str) -> io::Result<(libc::uid_t, Option<libc::gid_t>)> {
+    let name = c_string(user)?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: all zeroes is a valid `passwd`, of null pointers and zeros
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+
+    // SAFETY: every pointer is to a live value of the right type, and
+    // `buffer` is as long as is said
+    let error = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if error != 0 {
+        return Err(io::Error::from_raw_os_error(error));
+    }
+    if !found.is_null() {
+        return Ok((entry.pw_uid, Some(entry.pw_gid)));
+    }
+
+    match user.parse() {
+        Ok(uid) => Ok((uid, None)),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("there is no user called {}", user),
+        )),
+    }
+}
+
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    let name = c_string(group)?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: as for `passwd`
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+
+    // SAFETY: as in `lookup_user`
+    let error = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if error != 0 {
+        return Err(io::Error::from_raw_os_error(error));
+    }
+    if !found.is_null() {
+        return Ok(entry.gr_gid);
+    }
+
+    group.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("there is no group called {}", group),
+        )
+    })
+}
+
+fn c_string(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name has a NUL"))
+}
@@ -0,0 +1,1028 @@
+use alloc::{
+    borrow::Cow,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use serde::{
+    de::{IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::{value::RawValue, Value};
+
+use crate::headers::Headers;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Body {
+    pub content: String,
+    pub encoding: String,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+/// The `encoding` of a body whose content is bytes that are not UTF-8 text,
+/// base64 encoded, as [`Utf8Policy::Binary`] sends them.
+pub const BINARY_ENCODING: &str = "base64";
+
+/// What to do with bytes that are to become the `content` of a body, read
+/// from a file or decompressed or decrypted, when they are not UTF-8.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Utf8Policy {
+    /// Turn them down: a server answers with `415`, a client fails.
+    #[default]
+    Reject,
+    /// Put U+FFFD in place of every sequence that is not UTF-8, which loses
+    /// what those bytes were.
+    Replace,
+    /// Send them as they are, base64 encoded, in a body in
+    /// [`BINARY_ENCODING`].
+    Binary,
+}
+
+/// Bytes turned down by [`Utf8Policy::Reject`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotUtf8 {
+    /// Where the first byte that is not UTF-8 is.
+    pub valid_up_to: usize,
+}
+
+impl core::fmt::Display for NotUtf8 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "content is not UTF-8 text, from byte {}",
+            self.valid_up_to
+        )
+    }
+}
+
+impl NotUtf8 {
+    /// What a server answers a body turned down for this with.
+    pub fn response(&self) -> (String, u16) {
+        (format!("Unsupported Media Type: {}", self), 415)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Body {
+    /// Makes `bytes` the content, as it is in `identity` when it is UTF-8 and
+    /// as `policy` says otherwise; fields of the encoding the body was in
+    /// before are for the caller to take out.
+    pub fn set_bytes(&mut self, bytes: Vec<u8>, policy: Utf8Policy) -> Result<(), NotUtf8> {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let bytes = match String::from_utf8(bytes) {
+            Ok(text) => {
+                self.content = text;
+                self.encoding = "identity".to_string();
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        match policy {
+            Utf8Policy::Reject => {
+                return Err(NotUtf8 {
+                    valid_up_to: bytes.utf8_error().valid_up_to(),
+                })
+            }
+            Utf8Policy::Replace => {
+                self.content = String::from_utf8_lossy(bytes.as_bytes()).into_owned();
+                self.encoding = "identity".to_string();
+            }
+            Utf8Policy::Binary => {
+                self.content = STANDARD.encode(bytes.as_bytes());
+                self.encoding = BINARY_ENCODING.to_string();
+            }
+        }
+        Ok(())
+    }
+
+    /// The bytes of an `identity` or [`BINARY_ENCODING`] body; `None` for
+    /// others, and for content that is not base64.
+    pub fn bytes(&self) -> Option<Cow<'_, [u8]>> {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        match self.encoding.as_str() {
+            "identity" | "" => Some(Cow::Borrowed(self.content.as_bytes())),
+            BINARY_ENCODING => STANDARD.decode(&self.content).ok().map(Cow::Owned),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsontpRequest {
+    pub jsontp: String,
+    #[serde(rename = "type")]
+    pub type_of_request: String,
+    pub method: String,
+    pub resource: String,
+    pub headers: Headers,
+    pub body: Body,
+    /// Fields this version of the protocol does not define, kept for
+    /// [`Validation::Lenient`] and written back when serialized; see
+    /// [`JsontpRequest::parse`].
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Status {
+    pub code: u16,
+    #[serde(rename = "formal-message")]
+    pub formal_message: String,
+    #[serde(rename = "human-message")]
+    pub human_message: String,
+    /// Whether sending the request again unchanged may well succeed; from
+    /// 1.1, see [`Version::retryable`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retryable: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsontpResponse {
+    pub jsontp: String,
+    #[serde(rename = "type")]
+    pub type_of_response: String,
+    pub status: Status,
+    pub resource: String,
+    pub headers: Headers,
+    pub body: Body,
+    /// The `id` of the request answered; from 1.1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+/// Several requests in one message, `type: "batch"`. They are handled one
+/// after the other, or all at once when `parallel` is set, and answered with
+/// a [`BatchResponse`] holding a response for each, in the same order.
+///
+/// A `transactional` batch is all or nothing: its requests are handled in
+/// order until one fails, and then whatever the earlier ones did is rolled
+/// back.
+///
+/// A batch with `ordered: false` does not care which of its requests is
+/// answered first. Each response is then sent on its own as a
+/// [`BatchPart`] as soon as it is ready, so that quick requests need not
+/// wait for slow ones.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchRequest {
+    pub jsontp: String,
+    #[serde(rename = "type")]
+    pub type_of_request: String,
+    #[serde(default)]
+    pub parallel: bool,
+    #[serde(default)]
+    pub transactional: bool,
+    #[serde(default = "ordered_by_default")]
+    pub ordered: bool,
+    pub requests: Vec<JsontpRequest>,
+}
+
+fn ordered_by_default() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchResponse {
+    pub jsontp: String,
+    #[serde(rename = "type")]
+    pub type_of_response: String,
+    pub responses: Vec<JsontpResponse>,
+}
+
+impl BatchRequest {
+    /// Checks the envelope; each request in it is validated on its own.
+    pub fn validate(&self, max_requests: usize) -> Result<(), (String, u16)> {
+        if Version::parse(&self.jsontp).is_none() {
+            return Err(("HTTP Version Not Supported".to_string(), 505));
+        }
+        if self.type_of_request != "batch" || self.requests.is_empty() {
+            return Err(("Bad Request".to_string(), 400));
+        }
+        // a transaction has to stop at the first failure, and may yet undo
+        // what was answered before it
+        if self.transactional && (self.parallel || !self.ordered) {
+            return Err(("Bad Request".to_string(), 400));
+        }
+        if self.requests.len() > max_requests {
+            return Err(("Payload Too Large".to_string(), 413));
+        }
+
+        Ok(())
+    }
+
+    /// [`JsontpRequest::check_field_order`] for every request of the batch
+    /// decoded from `message`, saying which one is out of order.
+    #[cfg(feature = "std")]
+    pub fn check_field_order(
+        &self,
+        message: &[u8],
+        format: crate::wire::WireFormat,
+        validation: Validation,
+    ) -> Result<(), (String, u16)> {
+        #[derive(Deserialize)]
+        struct Requests {
+            requests: Vec<FieldOrder>,
+        }
+
+        let Requests { requests } = match format.decode(message) {
+            Ok(requests) => requests,
+            Err(_) => return Ok(()),
+        };
+        for (index, (request, FieldOrder(fields))) in
+            self.requests.iter().zip(&requests).enumerate()
+        {
+            request
+                .check_fields(fields, validation)
+                .map_err(|(message, code)| (format!("{} in request {}", message, index), code))?;
+        }
+        Ok(())
+    }
+}
+
+impl BatchResponse {
+    pub fn new(responses: Vec<JsontpResponse>) -> BatchResponse {
+        BatchResponse {
+            jsontp: "1.0".to_string(),
+            type_of_response: "batch".to_string(),
+            responses,
+        }
+    }
+}
+
+/// The response to one request of an unordered batch, `type: "batch-part"`,
+/// sent as a message of its own. `index` is the place of the request in the
+/// batch; a batch of `n` requests is answered with `n` parts, in whatever
+/// order they were ready.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchPart {
+    pub jsontp: String,
+    #[serde(rename = "type")]
+    pub type_of_response: String,
+    pub index: usize,
+    pub response: JsontpResponse,
+}
+
+impl BatchPart {
+    pub fn new(index: usize, response: JsontpResponse) -> BatchPart {
+        BatchPart {
+            jsontp: "1.0".to_string(),
+            type_of_response: "batch-part".to_string(),
+            index,
+            response,
+        }
+    }
+}
+
+/// A version of the protocol, as named by the `jsontp` field of a message.
+///
+/// 1.1 adds two optional fields to 1.0: an `id` a request may carry for the
+/// response to echo, so that answers can be matched to requests however
+/// they arrive, and `retryable` in the status of a response, saying whether
+/// the request is worth sending again as it is. A 1.0 peer gets neither:
+/// servers answer in the version of the request, and
+/// [`Version::adapt_response`] takes out what that version lacks.
+///
+/// ```
+/// use jsontp::protocol::{Version, JsontpRequest};
+/// use jsontp::server::status_response;
+///
+/// let request = |jsontp: &str| -> JsontpRequest {
+///     serde_json::from_value(serde_json::json!({
+///         "jsontp": jsontp, "type": "request", "method": "GET", "resource": "a",
+///         "id": "7", "headers": {}, "body": {"content": "{}", "encoding": "identity"},
+///     }))
+///     .unwrap()
+/// };
+///
+/// for (jsontp, id, retryable) in [("1.0", None, None), ("1.1", Some("7"), Some(true))] {
+///     let request = request(jsontp);
+///     let version = Version::parse(&request.jsontp).unwrap();
+///
+///     let mut response = status_response(503, "Service Unavailable", "Try again");
+///     version.adapt_response(Some(&request), &mut response);
+///     assert_eq!(response.jsontp, jsontp);
+///     assert_eq!(response.id.as_deref(), id);
+///     assert_eq!(response.status.retryable, retryable);
+/// }
+/// assert_eq!(Version::parse("1.1.3"), Some(Version::V1_1));
+/// assert_eq!(Version::parse("2.0"), None);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    V1_0,
+    V1_1,
+}
+
+impl Version {
+    /// The newest version this crate speaks.
+    pub const LATEST: Version = Version::V1_1;
+
+    /// The version `jsontp` names, which may have a patch number after it,
+    /// as `1.0.2`; `None` for versions not spoken.
+    pub fn parse(jsontp: &str) -> Option<Version> {
+        let mut parts = jsontp.splitn(3, '.');
+        match (parts.next(), parts.next()) {
+            (Some("1"), Some("0")) => Some(Version::V1_0),
+            (Some("1"), Some("1")) => Some(Version::V1_1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Version::V1_0 => "1.0",
+            Version::V1_1 => "1.1",
+        }
+    }
+
+    /// The fields a request or response of this version may have besides
+    /// those every version has.
+    pub fn fields(self) -> &'static [&'static str] {
+        match self {
+            Version::V1_0 => &[],
+            Version::V1_1 => &["id"],
+        }
+    }
+
+    /// Whether a request answered with `code` may succeed if sent again
+    /// unchanged: for timeouts, rate limits and a server that is down or
+    /// overloaded, but not for a request that is wrong or was carried out.
+    pub fn retryable(code: u16) -> bool {
+        matches!(code, 408 | 425 | 429 | 502 | 503 | 504)
+    }
+
+    /// Makes `response` one of this version, answering `request`: 1.1 fields
+    /// are filled in or, for 1.0, taken out.
+    pub fn adapt_response(self, request: Option<&JsontpRequest>, response: &mut JsontpResponse) {
+        response.jsontp = self.as_str().to_string();
+        match self {
+            Version::V1_0 => {
+                response.id = None;
+                response.status.retryable = None;
+            }
+            Version::V1_1 => {
+                response.id = request.and_then(JsontpRequest::id).map(str::to_string);
+                response.status.retryable = Some(Version::retryable(response.status.code));
+            }
+        }
+    }
+
+    /// Makes `request` one of this version, taking out the fields of later
+    /// versions, so that a peer speaking only this one accepts it.
+    pub fn adapt_request(self, request: &mut JsontpRequest) {
+        request.jsontp = self.as_str().to_string();
+        for version in [Version::V1_1] {
+            if version > self {
+                for field in version.fields() {
+                    request.other.remove(*field);
+                }
+            }
+        }
+    }
+}
+
+/// The header a request can set to `strict` or `lenient` to be validated
+/// differently from what the server does by default.
+pub const VALIDATION_HEADER: &str = "validation";
+
+/// What a known header's value has to be. Each type also takes the strings
+/// or numbers that stand for one of its values, which
+/// [`JsontpRequest::normalize_headers`] turns into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    /// A string; numbers become strings.
+    String,
+    /// A comma-separated string, or an array of strings, which is joined
+    /// into one.
+    List,
+    /// A non-negative integer, or a string of digits.
+    Integer,
+    /// `true` or `false`, or the strings `"true"` and `"false"`.
+    Boolean,
+    /// An object of strings, or a `name=value; name=value` string, which is
+    /// split into one.
+    Pairs,
+    /// Any JSON, for headers with a structure of their own.
+    Any,
+}
+
+impl HeaderType {
+    /// `value` as this type, or `None` if it does not stand for one.
+    pub fn normalize(&self, value: &Value) -> Option<Value> {
+        match (self, value) {
+            (HeaderType::Any, value) => Some(value.clone()),
+            (HeaderType::String | HeaderType::List, Value::String(_)) => Some(value.clone()),
+            (HeaderType::String, Value::Number(number)) => Some(Value::String(number.to_string())),
+            (HeaderType::List, Value::Array(items)) => {
+                let items: Option<Vec<&str>> = items.iter().map(Value::as_str).collect();
+                Some(Value::String(items?.join(", ")))
+            }
+            (HeaderType::Integer, Value::Number(number)) => number.as_u64().map(Value::from),
+            (HeaderType::Integer, Value::String(digits)) => {
+                digits.parse::<u64>().ok().map(Value::from)
+            }
+            (HeaderType::Boolean, Value::Bool(_)) => Some(value.clone()),
+            (HeaderType::Boolean, Value::String(text)) => match text.as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            (HeaderType::Pairs, Value::Object(pairs)) => {
+                pairs.values().all(Value::is_string).then(|| value.clone())
+            }
+            (HeaderType::Pairs, Value::String(pairs)) => Some(Value::Object(
+                pairs
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .map(|(name, value)| (name.to_string(), Value::String(value.to_string())))
+                    .collect(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// What a value of this type is, as in "`limit` must be a non-negative
+    /// integer".
+    pub fn expected(&self) -> &'static str {
+        match self {
+            HeaderType::String => "a string",
+            HeaderType::List => "a string or an array of strings",
+            HeaderType::Integer => "a non-negative integer",
+            HeaderType::Boolean => "true or false",
+            HeaderType::Pairs => "a string or an object of strings",
+            HeaderType::Any => "any JSON",
+        }
+    }
+}
+
+/// The headers this server understands, and what their values have to be.
+pub const KNOWN_HEADERS: &[(&str, HeaderType)] = &[
+    ("content-type", HeaderType::String),
+    ("host", HeaderType::String),
+    ("accept", HeaderType::List),
+    ("accept-encoding", HeaderType::List),
+    ("accept-language", HeaderType::List),
+    ("authorization", HeaderType::String),
+    ("cookies", HeaderType::Pairs),
+    ("if-modified-since", HeaderType::String),
+    ("if-unmodified-since", HeaderType::String),
+    ("if-match", HeaderType::List),
+    ("if-none-match", HeaderType::List),
+    ("expect", HeaderType::String),
+    ("origin", HeaderType::String),
+    ("access-control-request-method", HeaderType::String),
+    ("access-control-request-headers", HeaderType::List),
+    ("signature", HeaderType::Any),
+    ("csrf-token", HeaderType::String),
+    ("wire-format", HeaderType::String),
+    ("upload-id", HeaderType::String),
+    ("upload-offset", HeaderType::Integer),
+    ("upload-complete", HeaderType::Boolean),
+    ("destination", HeaderType::String),
+    ("overwrite", HeaderType::Boolean),
+    ("depth", HeaderType::String),
+    ("cursor", HeaderType::String),
+    ("limit", HeaderType::Integer),
+    ("idempotency-key", HeaderType::String),
+    ("compression-context", HeaderType::String),
+    ("priority", HeaderType::Integer),
+    ("extensions", HeaderType::List),
+    (VALIDATION_HEADER, HeaderType::String),
+];
+
+/// The type of a known header, in any case.
+pub fn header_type(name: &str) -> Option<HeaderType> {
+    KNOWN_HEADERS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|(_, header_type)| *header_type)
+}
+
+/// Turns down a known header whose value is not of its type.
+fn check_header(name: &str, value: &Value) -> Result<(), (String, u16)> {
+    match header_type(name) {
+        Some(header_type) if header_type.normalize(value).is_none() => Err((
+            format!("Bad Request: `{}` must be {}", name, header_type.expected()),
+            400,
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The fields of a body besides `content` and `encoding` this server
+/// understands.
+pub const KNOWN_BODY_FIELDS: &[&str] = &["encryption", "dictionary", "context", "sequence"];
+
+/// Headers an application accepts besides [`KNOWN_HEADERS`]. Requests
+/// carrying them pass strict validation, and the headers reach the handler
+/// with the name and value they were sent with, whatever JSON the value is.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CustomHeaders {
+    /// Whole names, such as `x-request-id`.
+    pub names: Vec<String>,
+    /// Vendor prefixes, such as `x-` or `acme-`; any header starting with
+    /// one is accepted.
+    pub prefixes: Vec<String>,
+}
+
+impl CustomHeaders {
+    pub fn new() -> CustomHeaders {
+        CustomHeaders::default()
+    }
+
+    pub fn name(mut self, name: &str) -> CustomHeaders {
+        self.names.push(name.to_lowercase());
+        self
+    }
+
+    pub fn prefix(mut self, prefix: &str) -> CustomHeaders {
+        self.prefixes.push(prefix.to_lowercase());
+        self
+    }
+
+    /// Whether `key`, in any case, is one of the names or starts with one
+    /// of the prefixes.
+    pub fn permits(&self, key: &str) -> bool {
+        let key = key.to_lowercase();
+        self.names.iter().any(|name| name.to_lowercase() == key)
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| key.starts_with(&prefix.to_lowercase()))
+    }
+}
+
+/// What to do with a request carrying headers or fields this server does
+/// not know.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Validation {
+    /// Turn it down with 400, naming the first one.
+    #[default]
+    Strict,
+    /// Accept it, and pass them on to the handler as they are.
+    Lenient,
+}
+
+impl Validation {
+    pub fn from_name(name: &str) -> Option<Validation> {
+        match name {
+            "strict" => Some(Validation::Strict),
+            "lenient" => Some(Validation::Lenient),
+            _ => None,
+        }
+    }
+
+    /// The policy for a request whose `validation` header is `header`:
+    /// `None` if it has none, `Some(None)` if it is not a string.
+    fn requested(
+        header: Option<Option<&str>>,
+        default: Validation,
+    ) -> Result<Validation, (String, u16)> {
+        match header {
+            None => Ok(default),
+            Some(name) => name.and_then(Validation::from_name).ok_or_else(|| {
+                (
+                    "Bad Request: validation must be \"strict\" or \"lenient\"".to_string(),
+                    400,
+                )
+            }),
+        }
+    }
+}
+
+impl JsontpRequest {
+    /// The `id` a 1.1 request carries for its response to echo.
+    pub fn id(&self) -> Option<&str> {
+        self.other.get("id").and_then(Value::as_str)
+    }
+
+    /// Parses a JSON request, treating top-level fields as `validation`
+    /// says for the version the request names. Strict turns the request down
+    /// when it has a field that version does not define, or has its fields
+    /// out of the order the version writes them in: [`REQUEST_FIELDS`], then
+    /// those of [`Version::fields`]. Lenient takes them in any order and
+    /// keeps the unknown ones in `other`, which serializing writes back
+    /// after the known ones, so that they round-trip.
+    ///
+    /// ```
+    /// use jsontp::protocol::{JsontpRequest, Validation};
+    ///
+    /// let message = br#"{"jsontp": "1.0", "type": "request", "method": "GET",
+    ///     "resource": "a", "headers": {}, "body": {"content": "{}",
+    ///     "encoding": "identity"}, "trace": [1, 2]}"#;
+    /// assert_eq!(JsontpRequest::parse(message, Validation::Strict).unwrap_err().1, 400);
+    ///
+    /// let request = JsontpRequest::parse(message, Validation::Lenient).unwrap();
+    /// let written = serde_json::to_vec(&request).unwrap();
+    /// let again = JsontpRequest::parse(&written, Validation::Lenient).unwrap();
+    /// assert_eq!(again.other["trace"], serde_json::json!([1, 2]));
+    ///
+    /// let reordered = br#"{"type": "request", "jsontp": "1.0", "method": "GET",
+    ///     "resource": "a", "headers": {}, "body": {"content": "{}",
+    ///     "encoding": "identity"}}"#;
+    /// assert!(JsontpRequest::parse(reordered, Validation::Strict).is_err());
+    /// assert!(JsontpRequest::parse(reordered, Validation::Lenient).is_ok());
+    /// ```
+    pub fn parse(message: &[u8], validation: Validation) -> Result<JsontpRequest, (String, u16)> {
+        let request: JsontpRequest = serde_json::from_slice(message)
+            .map_err(|error| (format!("Bad Request: {}", error), 400))?;
+        if validation == Validation::Lenient {
+            return Ok(request);
+        }
+
+        let Some(version) = Version::parse(&request.jsontp) else {
+            return Err(("HTTP Version Not Supported".to_string(), 505));
+        };
+        let FieldOrder(fields) = serde_json::from_slice(message)
+            .map_err(|error| (format!("Bad Request: {}", error), 400))?;
+
+        let order = field_order(version);
+        if let Some(field) = fields.iter().find(|field| !order.contains(&field.as_str())) {
+            return Err((format!("Bad Request: unknown field `{}`", field), 400));
+        }
+        check_field_order(&fields, &order)?;
+
+        Ok(request)
+    }
+
+    /// Checks that the top-level fields of `message`, which the request was
+    /// decoded from in `format`, come in the order its version writes them
+    /// in, when `validation` is strict and the request's `validation` header
+    /// does not say otherwise. Fields the version does not define, and
+    /// versions that are not supported, are left to
+    /// [`JsontpRequest::validate`]. The server checks every request it
+    /// decodes with this.
+    ///
+    /// ```
+    /// use jsontp::protocol::{JsontpRequest, Validation};
+    /// use jsontp::wire::WireFormat;
+    ///
+    /// let message = br#"{"type": "request", "jsontp": "1.0", "method": "GET",
+    ///     "resource": "a", "headers": {}, "body": {"content": "{}",
+    ///     "encoding": "identity"}}"#;
+    /// let request: JsontpRequest = serde_json::from_slice(message).unwrap();
+    /// let strict = request.check_field_order(message, WireFormat::Json, Validation::Strict);
+    /// assert_eq!(strict.unwrap_err().1, 400);
+    /// assert!(request
+    ///     .check_field_order(message, WireFormat::Json, Validation::Lenient)
+    ///     .is_ok());
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn check_field_order(
+        &self,
+        message: &[u8],
+        format: crate::wire::WireFormat,
+        validation: Validation,
+    ) -> Result<(), (String, u16)> {
+        let FieldOrder(fields) = match format.decode(message) {
+            Ok(fields) => fields,
+            Err(_) => return Ok(()),
+        };
+        self.check_fields(&fields, validation)
+    }
+
+    /// [`JsontpRequest::check_field_order`] for `fields`, the top-level
+    /// fields of the request in the order they came in.
+    #[cfg(feature = "std")]
+    fn check_fields(&self, fields: &[String], validation: Validation) -> Result<(), (String, u16)> {
+        let validation = Validation::requested(
+            self.headers.get(VALIDATION_HEADER).map(Value::as_str),
+            validation,
+        );
+        if validation != Ok(Validation::Strict) {
+            return Ok(());
+        }
+        match Version::parse(&self.jsontp) {
+            Some(version) => check_field_order(fields, &field_order(version)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the request, treating unknown headers and fields as
+    /// `validation` says unless its `validation` header says otherwise.
+    /// Headers `custom` permits are not unknown. Known headers have to be of
+    /// their [`HeaderType`] either way.
+    pub fn validate(
+        &self,
+        validation: Validation,
+        custom: &CustomHeaders,
+    ) -> Result<(), (String, u16)> {
+        let validation = Validation::requested(
+            self.headers.get(VALIDATION_HEADER).map(Value::as_str),
+            validation,
+        )?;
+
+        validate(
+            &self.jsontp,
+            &self.type_of_request,
+            &self.method,
+            &self.resource,
+            &self.body.content,
+            &self.body.encoding,
+            self.headers.keys(),
+            self.other.keys().map(String::as_str),
+            self.body.other.keys().map(String::as_str),
+            validation,
+            custom,
+        )?;
+
+        for (name, value) in &self.headers {
+            check_header(name, value)?;
+
+            let repeatable = matches!(header_type(name), None | Some(HeaderType::List));
+            if !repeatable && self.headers.get_all(name).nth(1).is_some() {
+                return Err((
+                    format!("Bad Request: `{}` can only be given once", name),
+                    400,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turns the values of known headers into the type they have to be:
+    /// `"42"` into `42` for an integer, `["gzip", "br"]` into `"gzip, br"`
+    /// for a list, and so on, and a list given more than once into one
+    /// listing every value. The server does this once the middleware has
+    /// accepted a request, so handlers only see the canonical types.
+    pub fn normalize_headers(&mut self) {
+        for (name, value) in self.headers.iter_mut() {
+            if let Some(normalized) = header_type(name).and_then(|t| t.normalize(value)) {
+                *value = normalized;
+            }
+        }
+
+        let lists: Vec<String> = self
+            .headers
+            .keys()
+            .filter(|name| header_type(name) == Some(HeaderType::List))
+            .map(ToString::to_string)
+            .collect();
+        for name in lists {
+            if self.headers.get_all(&name).nth(1).is_none() {
+                continue;
+            }
+            let values: Vec<&str> = self
+                .headers
+                .get_all(&name)
+                .filter_map(Value::as_str)
+                .collect();
+            let joined = Value::String(values.join(", "));
+            self.headers.insert(name, joined);
+        }
+    }
+}
+
+/// A [`Body`] borrowing its strings from the buffer it was parsed from
+/// wherever they contain no escapes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BodyRef<'a> {
+    #[serde(borrow)]
+    pub content: Cow<'a, str>,
+    #[serde(borrow)]
+    pub encoding: Cow<'a, str>,
+    #[serde(flatten, borrow)]
+    pub other: BTreeMap<Cow<'a, str>, Value>,
+}
+
+/// A [`JsontpRequest`] that borrows from the buffer it was parsed from
+/// instead of allocating a `String` for every field. Header values are kept
+/// as unparsed JSON.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsontpRequestRef<'a> {
+    #[serde(borrow)]
+    pub jsontp: Cow<'a, str>,
+    #[serde(rename = "type", borrow)]
+    pub type_of_request: Cow<'a, str>,
+    #[serde(borrow)]
+    pub method: Cow<'a, str>,
+    #[serde(borrow)]
+    pub resource: Cow<'a, str>,
+    #[serde(borrow)]
+    pub headers: BTreeMap<Cow<'a, str>, &'a RawValue>,
+    #[serde(borrow)]
+    pub body: BodyRef<'a>,
+    #[serde(flatten, borrow)]
+    pub other: BTreeMap<Cow<'a, str>, Value>,
+}
+
+impl<'a> JsontpRequestRef<'a> {
+    /// Parses a JSON request without copying the strings out of `data`.
+    pub fn from_slice(data: &'a [u8]) -> serde_json::Result<JsontpRequestRef<'a>> {
+        serde_json::from_slice(data)
+    }
+
+    pub fn validate(
+        &self,
+        validation: Validation,
+        custom: &CustomHeaders,
+    ) -> Result<(), (String, u16)> {
+        let header = self
+            .headers
+            .get(VALIDATION_HEADER)
+            .map(|value| serde_json::from_str::<String>(value.get()).ok());
+        let validation =
+            Validation::requested(header.as_ref().map(|name| name.as_deref()), validation)?;
+
+        validate(
+            &self.jsontp,
+            &self.type_of_request,
+            &self.method,
+            &self.resource,
+            &self.body.content,
+            &self.body.encoding,
+            self.headers.keys().map(|key| key.as_ref()),
+            self.other.keys().map(|key| key.as_ref()),
+            self.body.other.keys().map(|key| key.as_ref()),
+            validation,
+            custom,
+        )?;
+
+        self.headers.iter().try_for_each(|(name, value)| {
+            if header_type(name).is_none() {
+                return Ok(());
+            }
+            let value = serde_json::from_str(value.get()).unwrap_or(Value::Null);
+            check_header(name, &value)
+        })
+    }
+
+    pub fn into_owned(self) -> JsontpRequest {
+        JsontpRequest {
+            jsontp: self.jsontp.into_owned(),
+            type_of_request: self.type_of_request.into_owned(),
+            method: self.method.into_owned(),
+            resource: self.resource.into_owned(),
+            headers: self
+                .headers
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = serde_json::from_str(value.get()).unwrap_or(Value::Null);
+                    (key.into_owned(), value)
+                })
+                .collect(),
+            body: Body {
+                content: self.body.content.into_owned(),
+                encoding: self.body.encoding.into_owned(),
+                other: self
+                    .body
+                    .other
+                    .into_iter()
+                    .map(|(key, value)| (key.into_owned(), value))
+                    .collect(),
+            },
+            other: self
+                .other
+                .into_iter()
+                .map(|(key, value)| (key.into_owned(), value))
+                .collect(),
+        }
+    }
+}
+
+/// Whether requests may have `method`.
+pub fn is_known_method(method: &str) -> bool {
+    matches!(
+        method,
+        "GET" | "POST" | "PUT" | "DELETE" | "OPTIONS" | "MKCOL" | "COPY" | "MOVE"
+    )
+}
+
+/// The fields every version of a request has, in the order they are written.
+pub const REQUEST_FIELDS: &[&str] = &["jsontp", "type", "method", "resource", "headers", "body"];
+
+/// The top-level fields of a request of `version`, in the order they are
+/// written.
+fn field_order(version: Version) -> Vec<&'static str> {
+    REQUEST_FIELDS
+        .iter()
+        .chain(version.fields())
+        .copied()
+        .collect()
+}
+
+/// Checks that those of `fields` that are in `order` come in that order.
+fn check_field_order(fields: &[String], order: &[&str]) -> Result<(), (String, u16)> {
+    let mut last = None;
+    for field in fields {
+        let Some(place) = order.iter().position(|known| known == field) else {
+            continue;
+        };
+        if last.is_some_and(|last| place < last) {
+            return Err((
+                format!(
+                    "Bad Request: `{}` is out of order, fields go {}",
+                    field,
+                    order.join(", ")
+                ),
+                400,
+            ));
+        }
+        last = Some(place);
+    }
+    Ok(())
+}
+
+/// The top-level field names of an object, in the order they come in.
+struct FieldOrder(Vec<String>);
+
+impl<'de> Deserialize<'de> for FieldOrder {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Fields;
+
+        impl<'de> Visitor<'de> for Fields {
+            type Value = FieldOrder;
+
+            fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                formatter.write_str("an object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<FieldOrder, A::Error> {
+                let mut fields = Vec::new();
+                while let Some(field) = map.next_key::<String>()? {
+                    map.next_value::<IgnoredAny>()?;
+                    fields.push(field);
+                }
+                Ok(FieldOrder(fields))
+            }
+        }
+
+        deserializer.deserialize_map(Fields)
+    }
+}
+
+/// Whether bodies may be in `encoding`.
+pub fn is_known_encoding(encoding: &str) -> bool {
+    match encoding {
+        "gzip" | "deflate" | "deflate-stream" | "br" | "identity" | BINARY_ENCODING => true,
+        #[cfg(feature = "zstd")]
+        "zstd" => true,
+        _ => false,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn validate<'k>(
+    jsontp: &str,
+    type_of_request: &str,
+    method: &str,
+    resource: &str,
+    content: &str,
+    encoding: &str,
+    header_keys: impl Iterator<Item = &'k str>,
+    mut field_keys: impl Iterator<Item = &'k str>,
+    body_field_keys: impl Iterator<Item = &'k str>,
+    validation: Validation,
+    custom: &CustomHeaders,
+) -> Result<(), (String, u16)> {
+    let Some(version) = Version::parse(jsontp) else {
+        return Err(("HTTP Version Not Supported".to_string(), 505));
+    };
+    if type_of_request != "request" {
+        return Err(("Bad Request".to_string(), 400));
+    }
+    if resource.is_empty() {
+        return Err(("Bad Request".to_string(), 400));
+    }
+    if content.is_empty() {
+        return Err(("Bad Request".to_string(), 400));
+    }
+    if encoding.is_empty() {
+        return Err(("Bad Request".to_string(), 400));
+    }
+    if method.is_empty() {
+        return Err(("Bad Request".to_string(), 400));
+    }
+
+    if !is_known_encoding(encoding) || !is_known_method(method) {
+        return Err(("Bad Request".to_string(), 400));
+    }
+
+    if validation == Validation::Lenient {
+        return Ok(());
+    }
+
+    for key in header_keys {
+        if header_type(key).is_none() && !custom.permits(key) {
+            return Err((format!("Bad Request: unknown header `{}`", key), 400));
+        }
+    }
+    if let Some(key) = field_keys.find(|key| !version.fields().contains(key)) {
+        return Err((format!("Bad Request: unknown field `{}`", key), 400));
+    }
+    for key in body_field_keys {
+        if !KNOWN_BODY_FIELDS.contains(&key) {
+            return Err((format!("Bad Request: unknown field `body.{}`", key), 400));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::{
+    collections::BTreeMap,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    metrics::Counter,
+    protocol::{JsontpRequest, JsontpResponse},
+};
+
+/// The header [`ResponseHeaders::request_id`] puts on responses.
+pub const REQUEST_ID_HEADER: &str = "request-id";
+
+/// Runs on every response a server sends, before the middleware see it, so
+/// that what hooks add is signed or encrypted with the rest, and also on the
+/// ones sent without middleware: messages over
+/// `max-message-size`, heads turned down before the body arrived, batches
+/// turned down whole and requests whose handler panicked. `request` is
+/// `None` for those and for messages that could not be decoded. Each
+/// response of a batch is one of its own. Any
+/// `Fn(Option<&JsontpRequest>, &mut JsontpResponse)` closure is a hook.
+pub trait ResponseHook: Send + Sync {
+    fn on_response(&self, request: Option<&JsontpRequest>, response: &mut JsontpResponse);
+}
+
+impl<F> ResponseHook for F
+where
+    F: Fn(Option<&JsontpRequest>, &mut JsontpResponse) + Send + Sync,
+{
+    fn on_response(&self, request: Option<&JsontpRequest>, response: &mut JsontpResponse) {
+        self(request, response)
+    }
+}
+
+/// Headers put on every response, such as `server` or `cache-control`, as
+/// a [`ResponseHook`]. Headers a response has already, from its handler, are
+/// left as they are.
+///
+/// ```
+/// use jsontp::response_hooks::{ResponseHeaders, ResponseHook};
+/// use jsontp::server::status_response;
+///
+/// let hook = ResponseHeaders {
+///     headers: [("server".to_string(), "jsontp".to_string())].into(),
+///     request_id: true,
+/// };
+/// let mut first = status_response(413, "Payload Too Large", "Too large");
+/// let mut second = status_response(413, "Payload Too Large", "Too large");
+/// hook.on_response(None, &mut first);
+/// hook.on_response(None, &mut second);
+/// assert_eq!(first.headers.get("server"), second.headers.get("server"));
+/// assert_ne!(first.headers.get("request-id"), second.headers.get("request-id"));
+/// ```
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ResponseHeaders {
+    pub headers: BTreeMap<String, String>,
+    /// Whether every response gets a `request-id` unique to the process, to
+    /// find it by in logs.
+    pub request_id: bool,
+}
+
+impl ResponseHook for ResponseHeaders {
+    fn on_response(&self, _request: Option<&JsontpRequest>, response: &mut JsontpResponse) {
+        for (name, value) in &self.headers {
+            if !response.headers.contains_key(name) {
+                response
+                    .headers
+                    .insert(name.clone(), Value::String(value.clone()));
+            }
+        }
+
+        if self.request_id && !response.headers.contains_key(REQUEST_ID_HEADER) {
+            response
+                .headers
+                .insert(REQUEST_ID_HEADER, Value::String(next_request_id()));
+        }
+    }
+}
+
+static NEXT_REQUEST: Counter = Counter::new(1);
+
+/// An id no other response of this process has, and, as it starts with
+/// when the process first made one, most likely none of another.
+pub fn next_request_id() -> String {
+    static STARTED: OnceLock<u64> = OnceLock::new();
+    let started = STARTED.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as u64)
+    });
+    format!("{:x}-{:x}", started, NEXT_REQUEST.fetch_add(1))
+}
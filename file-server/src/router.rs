@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    middleware::Context,
+    protocol::{JsontpRequest, JsontpResponse},
+    schema::{ApiSchema, Route},
+    server::{error_response, Handler},
+};
+
+/// The values of the `{name}` parameters in the resource of a request.
+pub type Parameters = BTreeMap<String, String>;
+
+/// A handler for one route. `#[jsontp::route]` implements this for the
+/// functions it is put on; anything else can implement it by hand.
+pub trait Endpoint: Send + Sync {
+    fn route(&self) -> Route;
+    fn call(
+        &self,
+        request: &JsontpRequest,
+        context: &mut Context,
+        parameters: &Parameters,
+    ) -> JsontpResponse;
+}
+
+/// The answer to a request whose resource has a parameter that does not
+/// parse as the type its route takes.
+pub fn invalid_parameter(request: &JsontpRequest, name: &str) -> JsontpResponse {
+    let mut response = error_response(request, "Bad Request".to_string(), 400);
+    response.status.human_message = format!("Invalid {}", name);
+    response
+}
+
+struct Closure<F> {
+    route: Route,
+    f: F,
+}
+
+impl<F> Endpoint for Closure<F>
+where
+    F: Fn(&JsontpRequest, &mut Context, &Parameters) -> JsontpResponse + Send + Sync,
+{
+    fn route(&self) -> Route {
+        self.route.clone()
+    }
+
+    fn call(
+        &self,
+        request: &JsontpRequest,
+        context: &mut Context,
+        parameters: &Parameters,
+    ) -> JsontpResponse {
+        (self.f)(request, context, parameters)
+    }
+}
+
+/// A [`Handler`] that passes each request to the first endpoint whose route
+/// matches its method and resource. Requests for a resource no route matches
+/// are answered with 404, and those matching only with another method with
+/// 405.
+pub struct Router {
+    title: String,
+    endpoints: Vec<(Route, Box<dyn Endpoint>)>,
+}
+
+impl Router {
+    pub fn new(title: &str) -> Router {
+        Router {
+            title: title.to_string(),
+            endpoints: Vec::new(),
+        }
+    }
+
+    pub fn mount(mut self, endpoint: impl Endpoint + 'static) -> Router {
+        self.endpoints.push((endpoint.route(), Box::new(endpoint)));
+        self
+    }
+
+    /// Mounts a closure for `route`.
+    pub fn route(
+        self,
+        route: Route,
+        f: impl Fn(&JsontpRequest, &mut Context, &Parameters) -> JsontpResponse + Send + Sync + 'static,
+    ) -> Router {
+        self.mount(Closure { route, f })
+    }
+
+    /// The routes mounted so far, for [`Server::schema`](crate::server::Server::schema).
+    pub fn schema(&self) -> ApiSchema {
+        let mut schema = ApiSchema::new(&self.title);
+        schema.routes = self
+            .endpoints
+            .iter()
+            .map(|(route, _)| route.clone())
+            .collect();
+        schema
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &JsontpRequest, context: &mut Context) -> JsontpResponse {
+        let mut resource_exists = false;
+
+        for (route, endpoint) in &self.endpoints {
+            let Some(parameters) = route.matches(&request.resource) else {
+                continue;
+            };
+            if route.method != request.method {
+                resource_exists = true;
+                continue;
+            }
+            return endpoint.call(request, context, &parameters);
+        }
+
+        if resource_exists {
+            error_response(request, "Method Not Allowed".to_string(), 405)
+        } else {
+            error_response(request, "Not Found".to_string(), 404)
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The resource a server with a schema answers `GET` requests for with it.
+pub const SCHEMA_RESOURCE: &str = ".well-known/jsontp-schema";
+
+/// A machine-readable description of what a server answers, for clients and
+/// tooling to discover its API with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ApiSchema {
+    pub jsontp: String,
+    pub title: String,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+impl ApiSchema {
+    pub fn new(title: &str) -> ApiSchema {
+        ApiSchema {
+            jsontp: "1.0".to_string(),
+            title: title.to_string(),
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn route(mut self, route: Route) -> ApiSchema {
+        self.routes.push(route);
+        self
+    }
+
+    /// The route a request is for, and the values of the parameters in its
+    /// resource.
+    pub fn find(&self, method: &str, resource: &str) -> Option<(&Route, BTreeMap<String, String>)> {
+        self.routes.iter().find_map(|route| {
+            if route.method != method {
+                return None;
+            }
+            route
+                .matches(resource)
+                .map(|parameters| (route, parameters))
+        })
+    }
+}
+
+/// A header a route reads.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HeaderSchema {
+    pub name: String,
+    #[serde(default)]
+    pub required: bool,
+    /// A JSON Schema for the value.
+    pub schema: Value,
+}
+
+/// One method on one kind of resource.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Route {
+    /// What the route does, in kebab-case, such as `read-file`; generated
+    /// clients name their methods after it.
+    pub name: String,
+    pub method: String,
+    /// The resource, with `{name}` standing for a part that varies. A
+    /// parameter at the end written `{name*}` takes the rest of the resource,
+    /// slashes and all.
+    pub resource: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub summary: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<HeaderSchema>,
+    /// A JSON Schema for the body content, when it is JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<Value>,
+    /// A JSON Schema for the content of successful responses, when it is
+    /// JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<Value>,
+    /// The status codes the route answers with, and what each means.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub statuses: BTreeMap<u16, String>,
+}
+
+impl Route {
+    pub fn new(name: &str, method: &str, resource: &str) -> Route {
+        Route {
+            name: name.to_string(),
+            method: method.to_string(),
+            resource: resource.to_string(),
+            summary: String::new(),
+            headers: Vec::new(),
+            request: None,
+            response: None,
+            statuses: BTreeMap::new(),
+        }
+    }
+
+    pub fn summary(mut self, summary: &str) -> Route {
+        self.summary = summary.to_string();
+        self
+    }
+
+    pub fn header(mut self, name: &str, required: bool, schema: Value) -> Route {
+        self.headers.push(HeaderSchema {
+            name: name.to_string(),
+            required,
+            schema,
+        });
+        self
+    }
+
+    pub fn request(mut self, schema: Value) -> Route {
+        self.request = Some(schema);
+        self
+    }
+
+    pub fn response(mut self, schema: Value) -> Route {
+        self.response = Some(schema);
+        self
+    }
+
+    pub fn status(mut self, code: u16, meaning: &str) -> Route {
+        self.statuses.insert(code, meaning.to_string());
+        self
+    }
+
+    /// The names of the parameters in the resource, in order.
+    pub fn parameters(&self) -> Vec<&str> {
+        self.resource
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| name.trim_end_matches('*'))
+            .collect()
+    }
+
+    /// The parameters of `resource` if it is one this route is about.
+    pub fn matches(&self, resource: &str) -> Option<BTreeMap<String, String>> {
+        let mut parameters = BTreeMap::new();
+        let mut segments = resource.split('/');
+
+        for pattern in self.resource.split('/') {
+            let parameter = pattern
+                .strip_prefix('{')
+                .and_then(|pattern| pattern.strip_suffix('}'));
+
+            match parameter {
+                Some(name) if name.ends_with('*') => {
+                    let rest: Vec<&str> = segments.by_ref().collect();
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    parameters.insert(name.trim_end_matches('*').to_string(), rest.join("/"));
+                }
+                Some(name) => {
+                    let segment = segments.next().filter(|segment| !segment.is_empty())?;
+                    parameters.insert(name.to_string(), segment.to_string());
+                }
+                None => {
+                    if segments.next()? != pattern {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        segments.next().is_none().then_some(parameters)
+    }
+}
@@ -0,0 +1,189 @@
+use std::{fs, io, path::Path, time::UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::pagination::{paginate, PageRequest};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SearchConfig {
+    /// The resource that answers searches instead of being served as a file.
+    pub resource: String,
+    /// The most results returned in one page, whatever the client asks for.
+    pub max_limit: usize,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        SearchConfig {
+            resource: "search".to_string(),
+            max_limit: 100,
+        }
+    }
+}
+
+/// What to look for, sent as the JSON content of the search request. Every
+/// filter is optional; times are seconds since the Unix epoch. Which page of
+/// results is wanted goes in the usual pagination headers.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SearchQuery {
+    /// A glob matched against file names, with `*` and `?`.
+    pub name: Option<String>,
+    /// Only search this directory.
+    pub under: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<u64>,
+    pub modified_before: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchResult {
+    pub path: String,
+    pub size: u64,
+    pub modified: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchPage {
+    pub results: Vec<SearchResult>,
+    /// Where the next page starts; absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for any single one.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was, and how much of the name it has taken so far
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, taken)) => {
+                    p = star + 1;
+                    n = taken + 1;
+                    backtrack = Some((star, taken + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl SearchQuery {
+    fn matches(&self, name: &str, size: u64, modified: u64) -> bool {
+        self.name
+            .as_ref()
+            .is_none_or(|pattern| glob_matches(pattern, name))
+            && self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+            && self.modified_after.is_none_or(|after| modified > after)
+            && self.modified_before.is_none_or(|before| modified < before)
+    }
+}
+
+/// Collects the files under `directory` in path order. Hidden entries, such
+/// as partial uploads, are skipped.
+pub(crate) fn walk(
+    directory: &Path,
+    prefix: &str,
+    files: &mut Vec<(String, fs::Metadata)>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let path = format!("{}{}", prefix, name);
+        let metadata = entry.metadata()?;
+
+        if metadata.is_dir() {
+            walk(&entry.path(), &format!("{}/", path), files)?;
+        } else {
+            files.push((path, metadata));
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the files under `root` matching `query`, one page at a time. Paths
+/// in the results are relative to `root`, and results come in path order so a
+/// cursor stays valid while files are added or removed. Only files whose
+/// path `visible` accepts are found, so that a search does not tell of files
+/// the client could not get.
+pub fn search(
+    root: &Path,
+    query: &SearchQuery,
+    page: &PageRequest,
+    max_limit: usize,
+    visible: impl Fn(&str) -> bool,
+) -> io::Result<SearchPage> {
+    let (directory, prefix) = match query.under.as_deref().map(|under| under.trim_matches('/')) {
+        Some(under) if !under.is_empty() => (root.join(under), format!("{}/", under)),
+        _ => (root.to_path_buf(), String::new()),
+    };
+
+    if prefix.split('/').any(|component| component == "..") {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+
+    let mut files = Vec::new();
+    walk(&directory, &prefix, &mut files)?;
+    // the walk goes directory by directory, which is not quite string order
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let matching = files
+        .into_iter()
+        .filter(|(path, _)| visible(path))
+        .filter_map(|(path, metadata)| {
+            let name = path.rsplit('/').next().unwrap_or(&path);
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since_epoch| since_epoch.as_secs());
+
+            query
+                .matches(name, metadata.len(), modified)
+                .then_some(SearchResult {
+                    size: metadata.len(),
+                    path,
+                    modified,
+                })
+        });
+
+    let page = paginate(matching, |result| result.path.clone(), page, max_limit);
+
+    Ok(SearchPage {
+        results: page.items,
+        next: page.next,
+    })
+}
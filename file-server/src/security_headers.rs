@@ -0,0 +1,71 @@
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    middleware::{Context, Middleware},
+    protocol::{JsontpRequest, JsontpResponse},
+};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SecurityHeaders {
+    /// Headers added to every response. Defaults to turning off content
+    /// sniffing and framing.
+    pub headers: BTreeMap<String, String>,
+    /// When set, responses sent over TLS also get a
+    /// `strict-transport-security` header with this `max-age`.
+    pub hsts_max_age: Option<u64>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        let mut headers = BTreeMap::new();
+        headers.insert("x-content-type-options".to_string(), "nosniff".to_string());
+        headers.insert("x-frame-options".to_string(), "DENY".to_string());
+
+        SecurityHeaders {
+            headers,
+            hsts_max_age: None,
+        }
+    }
+}
+
+/// Adds [`SecurityHeaders`] to every response. Headers a handler has already
+/// set are left untouched.
+pub struct SecurityHeadersMiddleware {
+    pub headers: SecurityHeaders,
+    /// Whether the transport is encrypted; HSTS is only sent when it is.
+    pub tls: bool,
+}
+
+impl Middleware for SecurityHeadersMiddleware {
+    fn on_response(
+        &self,
+        _request: Option<&JsontpRequest>,
+        _context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        for (name, value) in &self.headers.headers {
+            if !response.headers.contains_key(name) {
+                response
+                    .headers
+                    .insert(name.clone(), Value::String(value.clone()));
+            }
+        }
+
+        if let (true, Some(max_age)) = (self.tls, self.headers.hsts_max_age) {
+            if !response.headers.contains_key("strict-transport-security") {
+                response.headers.insert(
+                    "strict-transport-security",
+                    Value::String(format!("max-age={}", max_age)),
+                );
+            }
+        }
+    }
+}
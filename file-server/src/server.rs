@@ -0,0 +1,1094 @@
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet},
+    fmt, iter,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, RwLock,
+    },
+    thread,
+    time::Instant,
+};
+
+use serde_json::Value;
+
+use crate::{
+    events::{self, Event, Subscriber},
+    extensions::{self, EXTENSIONS_HEADER},
+    header_table::{HeaderTable, HEADER_TABLE_RESOURCE},
+    headers::Headers,
+    idempotency::{
+        fingerprint, is_unsafe, Claim, Idempotency, Released, StoredResponse,
+        IDEMPOTENCY_KEY_HEADER,
+    },
+    ip_filter::IpFilter,
+    metrics::{Metrics, METRICS, METRICS_RESOURCE},
+    middleware::{Context, Middleware, TlsInfo},
+    overrides::{self, RouteOverride},
+    protocol::{
+        BatchPart, BatchRequest, BatchResponse, Body, CustomHeaders, JsontpRequest, JsontpResponse,
+        Status, Utf8Policy, Validation, Version,
+    },
+    response_hooks::ResponseHook,
+    schema::{ApiSchema, SCHEMA_RESOURCE},
+    streaming::RequestHead,
+    tenants::TENANT_VALUE,
+    transport::{
+        read_message, write_message, write_paced, BufferPool, Flush, Limits, ReadError, TcpOptions,
+        TokenBucket,
+    },
+    wire::{DecodeError, WireFormat},
+};
+
+/// Produces the response to a request that has passed validation and every
+/// middleware. Any `Fn(&JsontpRequest, &mut Context) -> JsontpResponse`
+/// closure is a handler.
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &JsontpRequest, context: &mut Context) -> JsontpResponse;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&JsontpRequest, &mut Context) -> JsontpResponse + Send + Sync,
+{
+    fn handle(&self, request: &JsontpRequest, context: &mut Context) -> JsontpResponse {
+        self(request, context)
+    }
+}
+
+/// The response to a request that was turned down, echoing the request back.
+pub fn error_response(request: &JsontpRequest, message: String, code: u16) -> JsontpResponse {
+    JsontpResponse {
+        jsontp: "1.0".to_string(),
+        type_of_response: "response".to_string(),
+        status: Status {
+            code,
+            formal_message: message.clone(),
+            human_message: message,
+            retryable: None,
+        },
+        resource: request.resource.clone(),
+        headers: request.headers.clone(),
+        body: request.body.clone(),
+        id: None,
+    }
+}
+
+/// The answer to a message that could not be decoded, saying why.
+pub fn decode_error_response(error: &DecodeError) -> JsontpResponse {
+    let mut response = status_response(
+        400,
+        "Bad Request",
+        &format!("Request was not a valid JSONTP request: {}", error),
+    );
+    response.headers.insert(
+        "content-type".to_string(),
+        Value::String("application/json".to_string()),
+    );
+    response.body.content = serde_json::json!({ "error": error }).to_string();
+    response.body.encoding = "identity".to_string();
+    response
+}
+
+/// A response that is not about any particular request.
+pub fn status_response(code: u16, formal_message: &str, human_message: &str) -> JsontpResponse {
+    JsontpResponse {
+        jsontp: "1.0".to_string(),
+        type_of_response: "response".to_string(),
+        status: Status {
+            code,
+            formal_message: formal_message.to_string(),
+            human_message: human_message.to_string(),
+            retryable: None,
+        },
+        resource: "".to_string(),
+        headers: Headers::new(),
+        body: Body {
+            content: "".to_string(),
+            encoding: "".to_string(),
+            other: BTreeMap::new(),
+        },
+        id: None,
+    }
+}
+
+/// What a transport knows about a message it has read, for the [`Context`]
+/// of the requests in it.
+#[derive(Debug, Clone)]
+pub struct Arrival {
+    /// The number [`Server::opened`] gave the connection, or 0.
+    pub connection: u64,
+    pub peer: IpAddr,
+    /// When the transport started reading the message.
+    pub received: Instant,
+    /// When the transport stops waiting for the response, if it does.
+    pub deadline: Option<Instant>,
+    pub tls: Option<TlsInfo>,
+}
+
+impl Arrival {
+    /// A message from `peer` that started arriving just now.
+    pub fn new(peer: IpAddr) -> Arrival {
+        Arrival {
+            connection: 0,
+            peer,
+            received: Instant::now(),
+            deadline: None,
+            tls: None,
+        }
+    }
+
+    fn context(&self, bytes_read: usize, format: WireFormat) -> Context {
+        Context {
+            connection: self.connection,
+            peer: Some(self.peer),
+            bytes_read,
+            received: Some(self.received),
+            deadline: self.deadline,
+            tls: self.tls.clone(),
+            wire_format: Some(format),
+            response_format: Some(format),
+            ..Context::default()
+        }
+    }
+}
+
+/// Counts a request in `requests-in-flight` until dropped, so that one whose
+/// handling panics is counted out too.
+struct InFlight;
+
+impl InFlight {
+    fn enter() -> InFlight {
+        Metrics::increment(&METRICS.requests_in_flight);
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        Metrics::decrement(&METRICS.requests_in_flight);
+    }
+}
+
+/// Everything needed to turn bytes from a client into bytes for it,
+/// independent of how connections are driven.
+pub struct Server {
+    pub handler: Box<dyn Handler>,
+    pub middleware: Vec<Box<dyn Middleware>>,
+    pub limits: Limits,
+    pub tcp: TcpOptions,
+    pub ip_filter: Arc<RwLock<IpFilter>>,
+    /// Replays the response to an unsafe request whose `idempotency-key` was
+    /// seen before, instead of handling it again.
+    pub idempotency: Option<Idempotency>,
+    /// Served at [`SCHEMA_RESOURCE`] instead of passing requests for it to
+    /// the handler.
+    pub schema: Option<ApiSchema>,
+    /// What to do with requests carrying headers or fields the server does
+    /// not know, unless they ask for something else.
+    pub validation: Validation,
+    /// Headers the application accepts besides the ones the server knows.
+    pub custom_headers: CustomHeaders,
+    /// Settings for some resources that differ from the server-wide ones.
+    pub overrides: Vec<RouteOverride>,
+    /// Buffers responses are encoded into, reused from one to the next.
+    pub buffers: BufferPool,
+    /// Told about connections being accepted, requests starting and ending,
+    /// and connections failing or closing.
+    pub subscribers: Vec<Box<dyn Subscriber>>,
+    /// Whether [`METRICS`] are served at [`METRICS_RESOURCE`].
+    pub metrics: bool,
+    /// Further sections of the metrics resource, by name, each computed when
+    /// it is asked for.
+    pub reports: BTreeMap<String, Box<dyn Fn() -> Value + Send + Sync>>,
+    /// Numbers for header names, served at [`HEADER_TABLE_RESOURCE`] and
+    /// accepted in place of the names when set.
+    pub header_table: Option<HeaderTable>,
+    /// Whether connections being handled, refused or failing are logged to
+    /// standard output as plain lines, as they are unless a
+    /// [`Subscriber`] logs them some other way.
+    pub plain_logs: bool,
+    /// The protocol extensions the server supports, listed in the
+    /// `extensions` header of every response to `OPTIONS`. A request
+    /// requiring others is answered with `501`.
+    pub extensions: BTreeSet<String>,
+    /// What to do with content that is not UTF-8, handed to the middleware
+    /// and the handler as [`Context::utf8`].
+    pub utf8: Utf8Policy,
+    /// Run on every response, in order, before the middleware, so that a
+    /// signature covers what they add; see [`ResponseHook`] for the
+    /// responses the middleware never see.
+    pub response_hooks: Vec<Box<dyn ResponseHook>>,
+}
+
+impl Server {
+    pub fn new(handler: impl Handler + 'static) -> Server {
+        Server {
+            handler: Box::new(handler),
+            middleware: Vec::new(),
+            limits: Limits::default(),
+            tcp: TcpOptions::default(),
+            ip_filter: Arc::new(RwLock::new(IpFilter::default())),
+            idempotency: None,
+            schema: None,
+            validation: Validation::default(),
+            custom_headers: CustomHeaders::new(),
+            overrides: Vec::new(),
+            buffers: BufferPool::new(),
+            subscribers: Vec::new(),
+            metrics: false,
+            reports: BTreeMap::new(),
+            header_table: None,
+            plain_logs: true,
+            extensions: BTreeSet::new(),
+            utf8: Utf8Policy::default(),
+            response_hooks: Vec::new(),
+        }
+    }
+
+    /// Logs a line about a connection, if [`plain_logs`](Server::plain_logs)
+    /// are on.
+    pub fn log(&self, line: fmt::Arguments) {
+        if self.plain_logs {
+            println!("{}", line);
+        }
+    }
+
+    /// Decides whether a freshly accepted connection may be served, counting
+    /// it either way.
+    pub fn accepts(&self, peer: &SocketAddr) -> bool {
+        if !self.ip_filter.read().unwrap().permits(&peer.ip()) {
+            Metrics::increment(&METRICS.connections_refused);
+            self.log(format_args!("Refused connection from {}", peer));
+            return false;
+        }
+
+        Metrics::increment(&METRICS.connections_accepted);
+        true
+    }
+
+    /// The bytes to answer a message that went over `max-message-size` with.
+    pub fn oversized_response(&self) -> Vec<u8> {
+        Metrics::increment(&METRICS.oversized_messages);
+
+        let mut response = status_response(
+            413,
+            "Payload Too Large",
+            "Request was larger than the server accepts",
+        );
+        self.run_response_hooks(None, &mut response);
+        serde_json::to_vec(&response).unwrap()
+    }
+
+    /// Hands `response` to every [`Server::response_hooks`] in turn.
+    pub fn run_response_hooks(
+        &self,
+        request: Option<&JsontpRequest>,
+        response: &mut JsontpResponse,
+    ) {
+        for hook in &self.response_hooks {
+            hook.on_response(request, response);
+        }
+    }
+
+    /// Runs the middleware's early checks on the head of a request that is
+    /// still arriving, and gives the limits the rest of it is read under.
+    pub fn check_head(&self, head: &RequestHead, peer: IpAddr) -> Result<Limits, (String, u16)> {
+        let context = Context {
+            peer: Some(peer),
+            ..Context::default()
+        };
+
+        self.middleware
+            .iter()
+            .try_for_each(|middleware| middleware.on_head(head, &context))?;
+
+        let method = head.method.as_deref().unwrap_or_default();
+        let resource = head.resource.as_deref().unwrap_or_default();
+        Ok(overrides::resolve(&self.overrides, method, resource).limits(&self.limits))
+    }
+
+    /// The limits messages are read under until it is known which resource
+    /// they are for.
+    pub fn read_limits(&self) -> Limits {
+        overrides::read_limits(&self.overrides, &self.limits)
+    }
+
+    /// The bytes to answer a request turned down by [`Server::check_head`]
+    /// with.
+    pub fn rejected_response(&self, message: &str, code: u16) -> Vec<u8> {
+        let mut response = status_response(code, message, message);
+        self.run_response_hooks(None, &mut response);
+        serde_json::to_vec(&response).unwrap()
+    }
+
+    /// Decodes one message, runs it through validation, the middleware and
+    /// the handler, and encodes the response.
+    pub fn handle_message(&self, message: &[u8], arrival: &Arrival) -> Vec<u8> {
+        let mut output = Vec::new();
+        self.handle_message_into(message, arrival, &mut output);
+        output
+    }
+
+    /// Like [`Server::handle_message`], but appends the response to `output`,
+    /// so that a buffer from [`Server::buffers`] can be used again.
+    pub fn handle_message_into(&self, message: &[u8], arrival: &Arrival, output: &mut Vec<u8>) {
+        self.handle_message_with(message, arrival, output, &mut |_| {});
+    }
+
+    /// Like [`Server::handle_message_into`], but when a message is answered
+    /// with several, as an unordered batch is, `flush` is handed `output`
+    /// after each of them so that it can be sent before the rest are ready.
+    /// Whatever `flush` leaves in `output` stays there.
+    pub fn handle_message_with(
+        &self,
+        message: &[u8],
+        arrival: &Arrival,
+        output: &mut Vec<u8>,
+        flush: &mut dyn FnMut(&mut Vec<u8>),
+    ) {
+        let format = WireFormat::sniff(message);
+        let request: Result<JsontpRequest, DecodeError> = format.decode(message);
+
+        if request.is_err() {
+            if let Ok(batch) = format.decode::<BatchRequest>(message) {
+                let context = || arrival.context(message.len(), format);
+                return self.handle_batch(batch, message, format, context, output, flush);
+            }
+        }
+
+        // serde takes the fields in any order, strict validation does not
+        let request = request.and_then(|request| {
+            match request.check_field_order(message, format, self.validation) {
+                Ok(()) => Ok(request),
+                Err((error, _)) => Err(DecodeError {
+                    category: "data",
+                    message: error.trim_start_matches("Bad Request: ").to_string(),
+                    line: None,
+                    column: None,
+                    offset: None,
+                }),
+            }
+        });
+
+        let response_format = request
+            .as_ref()
+            .ok()
+            .and_then(|request| request.headers.get("wire-format"))
+            .and_then(Value::as_str)
+            .and_then(WireFormat::from_name)
+            .unwrap_or(format);
+
+        let mut context = Context {
+            response_format: Some(response_format),
+            ..arrival.context(message.len(), format)
+        };
+        let mut response = self.respond(request, &mut context, Some(response_format));
+
+        if response_format.encode_into(&response, output).is_err() {
+            response
+                .headers
+                .insert("wire-format".to_string(), Value::String("json".to_string()));
+            serde_json::to_writer(output, &response).unwrap();
+        }
+    }
+
+    /// Handles every request of a batch as if it had arrived on its own, and
+    /// encodes their responses together in `format` onto `output`, or one by
+    /// one for an unordered batch.
+    fn handle_batch(
+        &self,
+        batch: BatchRequest,
+        message: &[u8],
+        format: WireFormat,
+        arrival: impl Fn() -> Context + Sync,
+        output: &mut Vec<u8>,
+        flush: &mut dyn FnMut(&mut Vec<u8>),
+    ) {
+        let valid = batch
+            .validate(self.limits.max_batch_size)
+            .and_then(|_| batch.check_field_order(message, format, self.validation));
+        if let Err((message, code)) = valid {
+            let mut response = status_response(code, &message, &message);
+            self.run_response_hooks(None, &mut response);
+            if format.encode_into(&response, output).is_err() {
+                serde_json::to_writer(output, &response).unwrap();
+            }
+            return;
+        }
+
+        let batch_version = Version::parse(&batch.jsontp).unwrap_or(Version::V1_0);
+
+        // unordered batches are never transactional
+        if !batch.ordered {
+            return self.handle_unordered(batch, format, arrival, output, flush);
+        }
+
+        let context = || Context {
+            in_transaction: batch.transactional,
+            ..arrival()
+        };
+
+        let responses = if batch.transactional {
+            self.handle_transaction(batch.requests, context)
+        } else if batch.parallel {
+            thread::scope(|scope| {
+                let handles: Vec<_> = batch
+                    .requests
+                    .into_iter()
+                    .map(|request| {
+                        scope.spawn(move || self.respond(Ok(request), &mut context(), None))
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap_or_else(|_| self.panicked_response()))
+                    .collect()
+            })
+        } else {
+            batch
+                .requests
+                .into_iter()
+                .map(|request| self.respond(Ok(request), &mut context(), None))
+                .collect()
+        };
+
+        let mut response = BatchResponse::new(responses);
+        response.jsontp = batch_version.as_str().to_string();
+        if format.encode_into(&response, output).is_err() {
+            serde_json::to_writer(output, &response).unwrap();
+        }
+    }
+
+    /// Answers each request of an unordered batch with a [`BatchPart`] as
+    /// soon as it is ready, handing `output` to `flush` after each.
+    fn handle_unordered(
+        &self,
+        batch: BatchRequest,
+        format: WireFormat,
+        context: impl Fn() -> Context + Sync,
+        output: &mut Vec<u8>,
+        flush: &mut dyn FnMut(&mut Vec<u8>),
+    ) {
+        let version = Version::parse(&batch.jsontp).unwrap_or(Version::V1_0);
+        let mut send = |index: usize, response: JsontpResponse| {
+            let mut part = BatchPart::new(index, response);
+            part.jsontp = version.as_str().to_string();
+            if format.encode_into(&part, output).is_err() {
+                serde_json::to_writer(&mut *output, &part).unwrap();
+            }
+            flush(output);
+        };
+
+        if !batch.parallel {
+            for (index, request) in batch.requests.into_iter().enumerate() {
+                send(index, self.respond(Ok(request), &mut context(), None));
+            }
+            return;
+        }
+
+        let count = batch.requests.len();
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            let handles: Vec<_> = batch
+                .requests
+                .into_iter()
+                .enumerate()
+                .map(|(index, request)| {
+                    let sender = sender.clone();
+                    let context = &context;
+                    scope.spawn(move || {
+                        let response = self.respond(Ok(request), &mut context(), None);
+                        let _ = sender.send((index, response));
+                    })
+                })
+                .collect();
+            drop(sender);
+
+            let mut answered = vec![false; count];
+            for (index, response) in receiver {
+                answered[index] = true;
+                send(index, response);
+            }
+
+            // a request whose handler panicked sent nothing
+            for handle in handles {
+                let _ = handle.join();
+            }
+            for index in (0..count).filter(|&index| !answered[index]) {
+                send(index, self.panicked_response());
+            }
+        });
+    }
+
+    /// The answer to a request of a batch that panicked outside its handler,
+    /// in a middleware say.
+    fn panicked_response(&self) -> JsontpResponse {
+        Metrics::increment(&METRICS.handler_panics);
+        let mut response = status_response(500, "Internal Server Error", "Request failed");
+        self.run_response_hooks(None, &mut response);
+        response
+    }
+
+    /// Handles `requests` in order until one fails. If one does, the rollbacks
+    /// of those before it are run, newest first, and every other request is
+    /// answered with `424 Failed Dependency`.
+    fn handle_transaction(
+        &self,
+        requests: Vec<JsontpRequest>,
+        context: impl Fn() -> Context,
+    ) -> Vec<JsontpResponse> {
+        let mut responses = Vec::new();
+        let mut rollbacks = Vec::new();
+        let mut failed = None;
+
+        for (index, request) in requests.iter().enumerate() {
+            let mut context = context();
+            let response = self.respond(Ok(request.clone()), &mut context, None);
+
+            let succeeded = response.status.code < 400;
+            responses.push(response);
+            rollbacks.push(context.rollbacks);
+
+            if !succeeded {
+                failed = Some(index);
+                break;
+            }
+        }
+
+        let Some(failed) = failed else {
+            return responses;
+        };
+
+        for rollbacks in rollbacks.into_iter().rev() {
+            rollbacks.run();
+        }
+
+        requests
+            .iter()
+            .zip(
+                responses
+                    .into_iter()
+                    .map(Some)
+                    .chain(iter::repeat_with(|| None)),
+            )
+            .enumerate()
+            .map(|(index, (request, response))| match response {
+                Some(response) if index == failed => response,
+                Some(_) => error_response(
+                    request,
+                    "Failed Dependency: rolled back as another request failed".to_string(),
+                    424,
+                ),
+                None => error_response(
+                    request,
+                    "Failed Dependency: not run as an earlier request failed".to_string(),
+                    424,
+                ),
+            })
+            .collect()
+    }
+
+    /// Runs the handler, answering with 500 if it panics, so that the panic
+    /// fails this request alone and the middleware still see its response.
+    fn handle_isolated(&self, request: &JsontpRequest, context: &mut Context) -> JsontpResponse {
+        panic::catch_unwind(AssertUnwindSafe(|| self.handle_once(request, context))).unwrap_or_else(
+            |_| {
+                Metrics::increment(&METRICS.handler_panics);
+                self.log(format_args!(
+                    "Handler panicked on {} {}",
+                    request.method, request.resource
+                ));
+                status_response(500, "Internal Server Error", "Request failed")
+            },
+        )
+    }
+
+    /// Calls the handler, unless the request is a retry of one answered
+    /// already, in which case that answer is replayed. Keys are kept apart per
+    /// tenant and user, and reusing one for a different request is refused,
+    /// as is a retry arriving while the first request is still being handled.
+    fn handle_once(&self, request: &JsontpRequest, context: &mut Context) -> JsontpResponse {
+        let key = request
+            .headers
+            .get(IDEMPOTENCY_KEY_HEADER)
+            .and_then(Value::as_str)
+            .filter(|_| is_unsafe(&request.method));
+
+        let (Some(idempotency), Some(key)) = (&self.idempotency, key) else {
+            return self.handler.handle(request, context);
+        };
+        if !idempotency.applies_to(&request.resource) {
+            return self.handler.handle(request, context);
+        }
+        let store = &idempotency.store;
+
+        // tenants sign their own users in, so the same name may be two users
+        let tenant = context.values.get(TENANT_VALUE).and_then(Value::as_str);
+        let key = format!(
+            "{}\0{}\0{}",
+            tenant.unwrap_or(""),
+            context.user.as_deref().unwrap_or(""),
+            key
+        );
+        let fingerprint = fingerprint(request);
+
+        match store.claim(&key) {
+            Claim::Stored(stored) => {
+                Metrics::increment(&METRICS.idempotency_hits);
+                if stored.fingerprint != fingerprint {
+                    return error_response(
+                        request,
+                        "Unprocessable Entity: idempotency key was used for another request"
+                            .to_string(),
+                        422,
+                    );
+                }
+                Metrics::increment(&METRICS.idempotency_replays);
+                return stored.response;
+            }
+            Claim::Pending => {
+                Metrics::increment(&METRICS.idempotency_hits);
+                return error_response(
+                    request,
+                    "Conflict: a request with this idempotency key is still being handled"
+                        .to_string(),
+                    409,
+                );
+            }
+            Claim::Claimed => Metrics::increment(&METRICS.idempotency_misses),
+        }
+
+        // given up however the handler ends, panics included
+        let _claim = Released {
+            store: store.as_ref(),
+            key: &key,
+        };
+        let response = self.handler.handle(request, context);
+
+        // failures on the server's side may well go away, so they are not
+        // kept, and a retry of the request carries it out again
+        if response.status.code < 500 {
+            store.put(
+                &key,
+                StoredResponse {
+                    fingerprint,
+                    response: response.clone(),
+                },
+            );
+
+            // nor is the answer to a request whose transaction is undone
+            if context.in_transaction {
+                let store = Arc::clone(store);
+                let key = key.clone();
+                context.on_rollback(move || store.remove(&key));
+            }
+        }
+
+        response
+    }
+
+    /// Turns down a request requiring extensions the server does not
+    /// support.
+    fn check_extensions(&self, request: &JsontpRequest) -> Result<(), (String, u16)> {
+        let missing = extensions::missing(
+            &extensions::from_headers(&request.headers),
+            &self.extensions,
+        );
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err((
+                format!("Extensions not supported: {}", missing.join(", ")),
+                501,
+            )),
+        }
+    }
+
+    /// The schema, if the request asks for it and there is one.
+    fn describe(&self, request: &JsontpRequest) -> Option<JsontpResponse> {
+        let schema = self.schema.as_ref()?;
+        if request.method != "GET" || request.resource != SCHEMA_RESOURCE {
+            return None;
+        }
+
+        let mut response = status_response(200, "OK", "Request was successful");
+        response.resource = request.resource.clone();
+        response.headers.insert(
+            "content-type".to_string(),
+            Value::String("application/json".to_string()),
+        );
+        response.body.content = serde_json::to_string(schema).unwrap();
+        response.body.encoding = "identity".to_string();
+        Some(response)
+    }
+
+    /// The counters and reports, if the request asks for them and they are
+    /// served.
+    fn report(&self, request: &JsontpRequest) -> Option<JsontpResponse> {
+        if !self.metrics || request.method != "GET" || request.resource != METRICS_RESOURCE {
+            return None;
+        }
+
+        let mut metrics = serde_json::Map::new();
+        for (name, value) in METRICS.snapshot() {
+            metrics.insert(name, Value::from(value));
+        }
+        for (name, report) in &self.reports {
+            metrics.insert(name.clone(), report());
+        }
+
+        let mut response = status_response(200, "OK", "Request was successful");
+        response.resource = request.resource.clone();
+        response.headers.insert(
+            "content-type".to_string(),
+            Value::String("application/json".to_string()),
+        );
+        response.body.content = Value::Object(metrics).to_string();
+        response.body.encoding = "identity".to_string();
+        Some(response)
+    }
+
+    /// The header table, if the request asks for it and there is one.
+    fn header_names(&self, request: &JsontpRequest) -> Option<JsontpResponse> {
+        let table = self.header_table.as_ref()?;
+        if request.method != "GET" || request.resource != HEADER_TABLE_RESOURCE {
+            return None;
+        }
+
+        let mut response = status_response(200, "OK", "Request was successful");
+        response.resource = request.resource.clone();
+        response.headers.insert(
+            "content-type".to_string(),
+            Value::String("application/json".to_string()),
+        );
+        response.body.content = table.to_json().to_string();
+        response.body.encoding = "identity".to_string();
+        Some(response)
+    }
+
+    /// Runs a decoded request through validation, the middleware and the
+    /// handler; a message that could not be decoded is answered with 400 and
+    /// `{"error": {"category": ..., "message": ..., "line": ..., "column":
+    /// ...}}` as its content. The `wire-format` header is set when `format`
+    /// is given, before the middleware sees the response.
+    fn respond(
+        &self,
+        request: Result<JsontpRequest, DecodeError>,
+        context: &mut Context,
+        format: Option<WireFormat>,
+    ) -> JsontpResponse {
+        let started = Instant::now();
+        // counted out again even if handling panics
+        let in_flight = InFlight::enter();
+        context.utf8 = self.utf8;
+
+        // whether the request numbered its headers, so the response should
+        let mut interned = false;
+        let (request, mut response) = match request {
+            Ok(mut request) => {
+                let expanded = self
+                    .limits
+                    .header_limits()
+                    .check(&request.headers)
+                    .and_then(|_| match &self.header_table {
+                        Some(table) => table.expand(&mut request.headers),
+                        None => Ok(false),
+                    });
+                interned = expanded == Ok(true);
+
+                self.emit(Event::RequestStart {
+                    connection: context.connection,
+                    request: &request,
+                });
+
+                let accepted = expanded
+                    .and_then(|_| request.validate(self.validation, &self.custom_headers))
+                    .and_then(|_| self.check_extensions(&request))
+                    .and_then(|_| {
+                        self.middleware
+                            .iter()
+                            .try_for_each(|middleware| middleware.on_request(&mut request, context))
+                    })
+                    .and_then(|_| {
+                        overrides::resolve(&self.overrides, &request.method, &request.resource)
+                            .check(&request, context, &self.limits)
+                    });
+
+                let response = match accepted {
+                    Ok(_) => {
+                        request.normalize_headers();
+                        self.describe(&request)
+                            .or_else(|| self.report(&request))
+                            .or_else(|| self.header_names(&request))
+                            .unwrap_or_else(|| self.handle_isolated(&request, context))
+                    }
+                    Err((message, code)) => error_response(&request, message, code),
+                };
+
+                (Some(request), response)
+            }
+            Err(error) => (None, decode_error_response(&error)),
+        };
+
+        if let Some(format) = format {
+            response.headers.insert(
+                "wire-format".to_string(),
+                Value::String(format.name().to_string()),
+            );
+        }
+
+        if request
+            .as_ref()
+            .is_some_and(|request| request.method == "OPTIONS")
+        {
+            response.headers.insert(
+                EXTENSIONS_HEADER.to_string(),
+                extensions::to_header(&self.extensions),
+            );
+        }
+
+        // the hooks go first, so that the headers they add are there when
+        // the middleware sign and encrypt the response
+        self.run_response_hooks(request.as_ref(), &mut response);
+
+        // answered in the version of the request, or 1.0 when it names none
+        // spoken, so that an older peer is not sent what it does not know.
+        // Each middleware sees the response in that version, adapted again
+        // for what the ones before it changed, so that what the last of them
+        // signs is what is sent
+        let version = request
+            .as_ref()
+            .and_then(|request| Version::parse(&request.jsontp))
+            .unwrap_or(Version::V1_0);
+        for middleware in self.middleware.iter().rev() {
+            version.adapt_response(request.as_ref(), &mut response);
+            middleware.on_response(request.as_ref(), context, &mut response);
+        }
+        version.adapt_response(request.as_ref(), &mut response);
+        if let (true, Some(table)) = (interned, &self.header_table) {
+            table.intern(&mut response.headers);
+        }
+
+        drop(in_flight);
+        self.emit(Event::RequestEnd {
+            connection: context.connection,
+            request: request.as_ref(),
+            response: &response,
+            elapsed: started.elapsed(),
+        });
+
+        response
+    }
+
+    /// Serves a single connection to completion on the current thread.
+    pub fn handle_connection(&self, mut stream: TcpStream) {
+        let Ok(peer) = stream.peer_addr() else {
+            return;
+        };
+        self.log(format_args!("Handling connection from {}", peer));
+
+        let opened = Instant::now();
+        let connection = self.opened(peer);
+        // panics while handling are answered in `serve`; this keeps one
+        // while reading or writing from leaving the connection counted open
+        let (bytes_read, bytes_written) = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.serve(&mut stream, peer, connection)
+        }))
+        .unwrap_or_else(|_| {
+            self.errored(connection, peer, "serving the connection panicked");
+            (0, 0)
+        });
+        self.closed(connection, peer, bytes_read, bytes_written, opened);
+    }
+
+    /// Reads one message from `stream` and answers it, giving the number of
+    /// bytes read and written.
+    fn serve(&self, stream: &mut TcpStream, peer: SocketAddr, connection: u64) -> (usize, usize) {
+        // the limits of the resource, once its head has been read
+        let limits = Cell::new(None);
+        let check_head = |head: &RequestHead| {
+            let checked =
+                panic::catch_unwind(AssertUnwindSafe(|| self.check_head(head, peer.ip())))
+                    .unwrap_or_else(|_| {
+                        Metrics::increment(&METRICS.handler_panics);
+                        Err(("Internal Server Error".to_string(), 500))
+                    })?;
+            limits.set(Some(checked.clone()));
+            Ok(checked)
+        };
+
+        let arrival = Arrival {
+            connection,
+            ..Arrival::new(peer.ip())
+        };
+        let message = match read_message(stream, &self.read_limits(), check_head) {
+            Ok(data) => data,
+            Err(ReadError::TooSlow) => {
+                Metrics::increment(&METRICS.slow_clients_dropped);
+                self.log(format_args!("Dropped slow connection from {}", peer));
+                self.errored(connection, peer, "the client was too slow");
+                return (0, 0);
+            }
+            Err(ReadError::TooLarge) => {
+                let response = self.oversized_response();
+                return (0, self.answer(stream, peer, connection, &response, None));
+            }
+            Err(ReadError::Rejected(message, code)) => {
+                let response = self.rejected_response(&message, code);
+                return (0, self.answer(stream, peer, connection, &response, None));
+            }
+            Err(ReadError::Io(e)) => {
+                self.log(format_args!("Failed to read from {}: {}", peer, e));
+                self.errored(connection, peer, &e.to_string());
+                return (0, 0);
+            }
+        };
+
+        let bytes_read = message.len();
+        let mut pacer = limits.take().unwrap_or_else(|| self.limits.clone()).pacer();
+        let mut bytes_written = 0;
+        let mut failed = false;
+        let mut send = |output: &mut Vec<u8>| {
+            if !failed && !output.is_empty() {
+                let written = self.answer(stream, peer, connection, output, pacer.as_mut());
+                failed = written == 0;
+                bytes_written += written;
+            }
+            output.clear();
+        };
+
+        // the parts of an unordered batch are sent as soon as each is ready
+        // and a request whose handling panics outside its handler, in a
+        // middleware say, is answered with 500 like the event loop does
+        let mut response = self.buffers.take();
+        let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.handle_message_with(&message, &arrival, &mut response, &mut send)
+        }));
+        if handled.is_err() {
+            Metrics::increment(&METRICS.handler_panics);
+            self.log(format_args!("Handling a request from {} panicked", peer));
+            response.clear();
+            response.extend(self.rejected_response("Internal Server Error", 500));
+        }
+        send(&mut response);
+        self.buffers.give(response);
+        self.buffers.give(message);
+
+        #[cfg(feature = "arena")]
+        crate::arena::reset();
+
+        if bytes_written > 0 {
+            self.log(format_args!("handled connection from {}", peer));
+        }
+        (bytes_read, bytes_written)
+    }
+
+    /// Writes all of `response`, paced by `pacer` if given, giving its
+    /// length, or 0 if that failed.
+    fn answer(
+        &self,
+        stream: &mut TcpStream,
+        peer: SocketAddr,
+        connection: u64,
+        response: &[u8],
+        pacer: Option<&mut TokenBucket>,
+    ) -> usize {
+        let written = match pacer {
+            Some(pacer) => write_paced(stream, response, pacer, Flush::EveryMessage),
+            None => write_message(stream, &[response], Flush::EveryMessage),
+        };
+
+        match written {
+            Ok(()) => response.len(),
+            Err(e) => {
+                self.log(format_args!("Failed to write to {}: {}", peer, e));
+                self.errored(connection, peer, &e.to_string());
+                0
+            }
+        }
+    }
+
+    /// Passes `event` to every subscriber.
+    pub fn emit(&self, event: Event) {
+        for subscriber in &self.subscribers {
+            subscriber.on_event(&event);
+        }
+    }
+
+    /// Numbers a connection that has just been accepted and announces it.
+    /// Transports call this before reading from a connection, and
+    /// [`Server::closed`] once they are done with it.
+    pub fn opened(&self, peer: SocketAddr) -> u64 {
+        let connection = events::next_connection();
+        Metrics::increment(&METRICS.connections_open);
+        self.emit(Event::Accepted { connection, peer });
+        connection
+    }
+
+    /// Announces that serving a connection went wrong.
+    pub fn errored(&self, connection: u64, peer: SocketAddr, error: &str) {
+        Metrics::increment(&METRICS.connection_errors);
+        self.emit(Event::Errored {
+            connection,
+            peer,
+            error,
+        });
+    }
+
+    /// Announces that a connection numbered by [`Server::opened`] has been
+    /// closed.
+    pub fn closed(
+        &self,
+        connection: u64,
+        peer: SocketAddr,
+        bytes_read: usize,
+        bytes_written: usize,
+        opened: Instant,
+    ) {
+        Metrics::decrement(&METRICS.connections_open);
+        Metrics::add(&METRICS.bytes_served, bytes_written as u64);
+        self.emit(Event::Closed {
+            connection,
+            peer,
+            bytes_read,
+            bytes_written,
+            open_for: opened.elapsed(),
+        });
+    }
+
+    /// Accepts connections forever, serving each on its own thread.
+    pub fn run(self: Arc<Self>, listener: TcpListener) {
+        self.run_until(&listener, &AtomicBool::new(false));
+    }
+
+    /// Accepts connections until `stopping` is set, serving each on its own
+    /// thread. Only a connection wakes a waiting accept, so whoever sets
+    /// `stopping` has to connect to the listener afterwards, as
+    /// [`JsontpServer::stop`](crate::embedded::JsontpServer::stop) does.
+    /// Connections already accepted are served to the end.
+    pub fn run_until(self: &Arc<Self>, listener: &TcpListener, stopping: &AtomicBool) {
+        for stream in listener.incoming() {
+            if stopping.load(Ordering::Acquire) {
+                return;
+            }
+            let Ok(stream) = stream else {
+                continue;
+            };
+
+            match stream.peer_addr() {
+                Ok(peer) if self.accepts(&peer) => {}
+                _ => continue,
+            }
+
+            if let Err(e) = self.tcp.apply(&stream) {
+                self.log(format_args!("Could not set socket options: {}", e));
+            }
+
+            let server = self.clone();
+            thread::spawn(move || server.handle_connection(stream));
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signer, Verifier};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::{
+    canonical::to_canonical_string,
+    headers::Headers,
+    middleware::{Context, Middleware},
+    protocol::{JsontpRequest, JsontpResponse},
+    transform::BodyTransform,
+};
+
+pub const SIGNATURE_HEADER: &str = "signature";
+
+/// How many seconds a signature is accepted for after it was made, unless a
+/// [`SignatureMiddleware`] says otherwise.
+pub const DEFAULT_MAX_AGE: u64 = 300;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub enum SigningKey {
+    HmacSha256(Vec<u8>),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+pub enum VerifyingKey {
+    HmacSha256(Vec<u8>),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl SigningKey {
+    fn algorithm(&self) -> &'static str {
+        match self {
+            SigningKey::HmacSha256(_) => "hmac-sha256",
+            SigningKey::Ed25519(_) => "ed25519",
+        }
+    }
+}
+
+/// Looks up the key a peer claims to have signed with. This is the hook
+/// applications use to plug in their own key storage.
+pub trait KeyResolver: Send + Sync {
+    fn resolve(&self, key_id: &str) -> Option<&VerifyingKey>;
+}
+
+impl KeyResolver for BTreeMap<String, VerifyingKey> {
+    fn resolve(&self, key_id: &str) -> Option<&VerifyingKey> {
+        self.get(key_id)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing,
+    Malformed,
+    UnknownKey,
+    AlgorithmMismatch,
+    Invalid,
+    /// The signature was made longer ago than allowed, or claims to be made
+    /// further in the future, so it may be a replay.
+    Stale,
+}
+
+/// A message that can be signed: a request or a response.
+pub trait Signed: Serialize {
+    fn headers(&self) -> &Headers;
+    fn headers_mut(&mut self) -> &mut Headers;
+}
+
+impl Signed for JsontpRequest {
+    fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+}
+
+impl Signed for JsontpResponse {
+    fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+}
+
+/// The bytes that get signed: the canonical JSON of the whole message but
+/// its signature, so its version, type, method and resource or status,
+/// headers and body, along with `created`, the Unix time in seconds it was
+/// signed at. The headers are signed as `[name, value]` pairs in the order
+/// they came in, so that every entry of a repeated name is covered, not only
+/// the one a JSON object would keep.
+pub fn signing_payload(message: &impl Signed, created: u64) -> String {
+    let headers: Vec<Value> = message
+        .headers()
+        .iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case(SIGNATURE_HEADER))
+        .map(|(name, value)| json!([name, value]))
+        .collect();
+
+    let mut message = serde_json::to_value(message).unwrap_or_default();
+    message["headers"] = Value::Array(headers);
+
+    to_canonical_string(&json!({ "message": message, "created": created })).unwrap_or_default()
+}
+
+/// Computes a signature over `message`, made at `created` (Unix time in
+/// seconds), and stores it in the `signature` header.
+pub fn sign(message: &mut impl Signed, key_id: &str, key: &SigningKey, created: u64) {
+    let payload = signing_payload(message, created);
+
+    let signature = match key {
+        SigningKey::HmacSha256(secret) => {
+            let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts any key length");
+            mac.update(payload.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+        SigningKey::Ed25519(key) => key.sign(payload.as_bytes()).to_bytes().to_vec(),
+    };
+
+    message.headers_mut().insert(
+        SIGNATURE_HEADER.to_string(),
+        json!({
+            "algorithm": key.algorithm(),
+            "key-id": key_id,
+            "created": created,
+            "value": STANDARD.encode(signature),
+        }),
+    );
+}
+
+/// Checks the `signature` header against `message`, using `resolver` to find
+/// the key named by its `key-id`. At `now` (Unix time in seconds), the
+/// signature must have been made no more than `max_age` seconds ago, or as
+/// far ahead, for clocks that are not quite in step; older ones may be
+/// replays of a message captured earlier.
+///
+/// ```
+/// use std::collections::BTreeMap;
+///
+/// use jsontp::protocol::JsontpRequest;
+/// use jsontp::signing::{SignatureError, SigningKey, VerifyingKey};
+///
+/// let mut request: JsontpRequest = serde_json::from_value(serde_json::json!({
+///     "jsontp": "1.0", "type": "request", "method": "GET", "resource": "public/a",
+///     "headers": {}, "body": {"content": "{}", "encoding": "identity"},
+/// }))
+/// .unwrap();
+/// request.sign("k", &SigningKey::HmacSha256(b"secret".to_vec()), 1_000);
+///
+/// let mut keys = BTreeMap::new();
+/// keys.insert("k".to_string(), VerifyingKey::HmacSha256(b"secret".to_vec()));
+/// assert_eq!(request.verify_signature(&keys, 1_010, 300), Ok(()));
+/// assert_eq!(request.verify_signature(&keys, 2_000, 300), Err(SignatureError::Stale));
+///
+/// let mut repeated = request.clone();
+/// repeated.headers.append("accept", serde_json::json!("evil/inject"));
+/// assert_eq!(repeated.verify_signature(&keys, 1_010, 300), Err(SignatureError::Invalid));
+///
+/// request.method = "DELETE".to_string();
+/// assert_eq!(request.verify_signature(&keys, 1_010, 300), Err(SignatureError::Invalid));
+/// ```
+pub fn verify(
+    message: &impl Signed,
+    resolver: &dyn KeyResolver,
+    now: u64,
+    max_age: u64,
+) -> Result<(), SignatureError> {
+    let signature = message
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .ok_or(SignatureError::Missing)?;
+
+    let field = |name: &str| {
+        signature
+            .get(name)
+            .and_then(Value::as_str)
+            .ok_or(SignatureError::Malformed)
+    };
+    let algorithm = field("algorithm")?;
+    let key_id = field("key-id")?;
+    let value = STANDARD
+        .decode(field("value")?)
+        .map_err(|_| SignatureError::Malformed)?;
+
+    let created = signature
+        .get("created")
+        .and_then(Value::as_u64)
+        .ok_or(SignatureError::Malformed)?;
+
+    let key = resolver.resolve(key_id).ok_or(SignatureError::UnknownKey)?;
+    let payload = signing_payload(message, created);
+
+    match (algorithm, key) {
+        ("hmac-sha256", VerifyingKey::HmacSha256(secret)) => {
+            let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts any key length");
+            mac.update(payload.as_bytes());
+            mac.verify_slice(&value)
+                .map_err(|_| SignatureError::Invalid)
+        }
+        ("ed25519", VerifyingKey::Ed25519(key)) => {
+            let signature = ed25519_dalek::Signature::from_slice(&value)
+                .map_err(|_| SignatureError::Malformed)?;
+            key.verify(payload.as_bytes(), &signature)
+                .map_err(|_| SignatureError::Invalid)
+        }
+        ("hmac-sha256" | "ed25519", _) => Err(SignatureError::AlgorithmMismatch),
+        _ => Err(SignatureError::Malformed),
+    }?;
+
+    // checked once the signature holds, so that a forged time is not taken
+    // for a stale one
+    if now.abs_diff(created) > max_age {
+        return Err(SignatureError::Stale);
+    }
+    Ok(())
+}
+
+/// The Unix time in seconds, as [`SignatureMiddleware::clock`] needs it.
+#[cfg(feature = "std")]
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl JsontpRequest {
+    pub fn sign(&mut self, key_id: &str, key: &SigningKey, created: u64) {
+        sign(self, key_id, key, created);
+    }
+
+    pub fn verify_signature(
+        &self,
+        resolver: &dyn KeyResolver,
+        now: u64,
+        max_age: u64,
+    ) -> Result<(), SignatureError> {
+        verify(self, resolver, now, max_age)
+    }
+}
+
+impl JsontpResponse {
+    pub fn sign(&mut self, key_id: &str, key: &SigningKey, created: u64) {
+        sign(self, key_id, key, created);
+    }
+
+    pub fn verify_signature(
+        &self,
+        resolver: &dyn KeyResolver,
+        now: u64,
+        max_age: u64,
+    ) -> Result<(), SignatureError> {
+        verify(self, resolver, now, max_age)
+    }
+}
+
+/// Rejects requests whose signature is missing, does not verify or is older
+/// than `max_age` seconds, and signs outgoing responses when given a key to
+/// do so. A verified request is authenticated as the `key-id` it was signed
+/// with.
+///
+/// With `preflights` set, CORS preflights, `OPTIONS` requests with `origin`
+/// and `access-control-request-method`, are let through unsigned, as
+/// browsers send them on their own. Register it before any middleware that
+/// adds headers to responses, so that it signs last and covers them.
+pub struct SignatureMiddleware {
+    pub resolver: Box<dyn KeyResolver>,
+    pub response_key: Option<(String, SigningKey)>,
+    pub max_age: u64,
+    /// Whether preflights are let through unsigned. Only set it when a
+    /// [`CorsMiddleware`](crate::cors::CorsMiddleware) after this one
+    /// answers them, or they reach the handler unauthenticated.
+    pub preflights: bool,
+    /// The Unix time in seconds; [`unix_time`] where there is `std`.
+    pub clock: fn() -> u64,
+}
+
+#[cfg(feature = "std")]
+impl SignatureMiddleware {
+    /// Checks requests with `resolver`, signs responses with
+    /// `response_key` if given, and accepts signatures up to
+    /// [`DEFAULT_MAX_AGE`] old.
+    pub fn new(
+        resolver: Box<dyn KeyResolver>,
+        response_key: Option<(String, SigningKey)>,
+    ) -> SignatureMiddleware {
+        SignatureMiddleware {
+            resolver,
+            response_key,
+            max_age: DEFAULT_MAX_AGE,
+            preflights: false,
+            clock: unix_time,
+        }
+    }
+}
+
+impl Middleware for SignatureMiddleware {
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        let preflight = self.preflights
+            && request.method == "OPTIONS"
+            && request.headers.contains_key("origin")
+            && request
+                .headers
+                .contains_key("access-control-request-method");
+        if preflight && !request.headers.contains_key(SIGNATURE_HEADER) {
+            return Ok(());
+        }
+
+        match request.verify_signature(self.resolver.as_ref(), (self.clock)(), self.max_age) {
+            Ok(()) => {
+                context.user = request
+                    .headers
+                    .get(SIGNATURE_HEADER)
+                    .and_then(|signature| signature.get("key-id"))
+                    .and_then(Value::as_str)
+                    .map(ToString::to_string);
+                Ok(())
+            }
+            Err(SignatureError::Missing) => Err(("Unauthorized".to_string(), 401)),
+            Err(_) => Err(("Forbidden".to_string(), 403)),
+        }
+    }
+
+    fn on_response(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        self.transform(request, context, response)
+    }
+}
+
+impl BodyTransform for SignatureMiddleware {
+    fn transform(
+        &self,
+        _request: Option<&JsontpRequest>,
+        _context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        if let Some((key_id, key)) = &self.response_key {
+            response.sign(key_id, key, (self.clock)());
+        }
+    }
+}
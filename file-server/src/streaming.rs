@@ -0,0 +1,255 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+
+use serde_json::Value;
+
+use crate::headers::Headers;
+
+/// The parts of a request that decide whether it will be served at all,
+/// available before the body has arrived.
+#[derive(Debug, Default, Clone)]
+pub struct RequestHead {
+    pub method: Option<String>,
+    pub resource: Option<String>,
+    pub headers: Option<Headers>,
+}
+
+impl RequestHead {
+    pub fn is_complete(&self) -> bool {
+        self.method.is_some() && self.resource.is_some() && self.headers.is_some()
+    }
+}
+
+/// How large the `headers` of a request may be, counted while they arrive
+/// so that an endless header section is cut off early. Sizes are in bytes
+/// of JSON, name and value together for a single header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderLimits {
+    pub max_count: usize,
+    pub max_size: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        HeaderLimits {
+            max_count: 100,
+            max_size: 8 * 1024,
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Turns down `headers` that go over a limit, as the scanner would have
+    /// had they been read as JSON.
+    pub fn check(&self, headers: &Headers) -> Result<(), (String, u16)> {
+        if headers.len() > self.max_count {
+            return Err(self.too_many());
+        }
+
+        let mut bytes = 0;
+        for (name, value) in headers.iter() {
+            let size = name.len() + serde_json::to_string(value).map_or(0, |value| value.len());
+            if size > self.max_size {
+                return Err(self.too_large(name));
+            }
+            bytes += size;
+            if bytes > self.max_bytes {
+                return Err(self.too_long());
+            }
+        }
+        Ok(())
+    }
+
+    fn too_many(&self) -> (String, u16) {
+        too_large(format!("more than {} headers", self.max_count))
+    }
+
+    fn too_large(&self, name: &str) -> (String, u16) {
+        too_large(format!("header `{}` is over {} bytes", name, self.max_size))
+    }
+
+    fn too_long(&self) -> (String, u16) {
+        too_large(format!("headers are over {} bytes", self.max_bytes))
+    }
+}
+
+fn too_large(reason: String) -> (String, u16) {
+    (format!("Request Header Fields Too Large: {}", reason), 431)
+}
+
+/// Scans a JSON request as it arrives and picks out the top-level `method`,
+/// `resource` and `headers` members as soon as each one is complete.
+///
+/// Feed it the whole buffer received so far on every call; it remembers how
+/// far it got and only looks at the new bytes. With limits, the `headers`
+/// are measured as they arrive and reading can stop as soon as they go
+/// over.
+#[derive(Debug, Default)]
+pub struct HeadScanner {
+    limits: Option<HeaderLimits>,
+    /// Where the `headers` object and the header in it being read started,
+    /// and how many headers came before it, while they are being read.
+    headers: Option<(usize, usize, usize)>,
+    position: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    string_start: usize,
+    expecting_key: bool,
+    key: Option<String>,
+    value_start: Option<usize>,
+    head: RequestHead,
+}
+
+impl HeadScanner {
+    pub fn new() -> HeadScanner {
+        HeadScanner::default()
+    }
+
+    /// A scanner that turns down a head whose headers go over `limits`.
+    pub fn with_limits(limits: HeaderLimits) -> HeadScanner {
+        HeadScanner {
+            limits: Some(limits),
+            ..HeadScanner::default()
+        }
+    }
+
+    pub fn head(&self) -> &RequestHead {
+        &self.head
+    }
+
+    /// Scans the bytes of `data` not seen yet and returns what is known of
+    /// the head, or `431` once the headers have gone over the limits.
+    pub fn feed(&mut self, data: &[u8]) -> Result<&RequestHead, (String, u16)> {
+        while self.position < data.len() {
+            let byte = data[self.position];
+            self.measure(data)?;
+
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                    if self.depth == 1 && self.expecting_key {
+                        self.key = core::str::from_utf8(&data[self.string_start..self.position])
+                            .ok()
+                            .map(ToString::to_string);
+                        self.expecting_key = false;
+                    }
+                }
+            } else {
+                match byte {
+                    b'"' => {
+                        self.in_string = true;
+                        self.string_start = self.position + 1;
+                    }
+                    b'{' | b'[' => {
+                        self.depth += 1;
+                        if self.depth == 1 {
+                            self.expecting_key = true;
+                        }
+                        if self.depth == 2 && byte == b'{' && self.key.as_deref() == Some("headers")
+                        {
+                            self.headers = Some((self.position, self.position + 1, 0));
+                        }
+                    }
+                    b'}' | b']' => {
+                        if self.depth == 1 {
+                            self.finish_value(data);
+                        }
+                        if self.depth == 2 {
+                            self.end_header(data)?;
+                            self.headers = None;
+                        }
+                        self.depth = self.depth.saturating_sub(1);
+                    }
+                    b':' if self.depth == 1 => self.value_start = Some(self.position + 1),
+                    b',' if self.depth == 1 => {
+                        self.finish_value(data);
+                        self.expecting_key = true;
+                    }
+                    b',' if self.depth == 2 => self.end_header(data)?,
+                    _ => {}
+                }
+            }
+
+            self.position += 1;
+        }
+
+        Ok(&self.head)
+    }
+
+    /// Checks the headers being read against the limits, up to the byte at
+    /// the current position.
+    fn measure(&self, data: &[u8]) -> Result<(), (String, u16)> {
+        let (Some(limits), Some((start, header_start, _))) = (&self.limits, self.headers) else {
+            return Ok(());
+        };
+
+        if self.position - start > limits.max_bytes {
+            return Err(limits.too_long());
+        }
+        if self.position - header_start > limits.max_size {
+            let name = header_name(&data[header_start..self.position]);
+            return Err(limits.too_large(&name));
+        }
+        Ok(())
+    }
+
+    /// Counts the header that ends at the current position, if there was
+    /// one, and moves on to the next.
+    fn end_header(&mut self, data: &[u8]) -> Result<(), (String, u16)> {
+        let Some((start, header_start, count)) = self.headers else {
+            return Ok(());
+        };
+
+        let header = &data[header_start..self.position];
+        let count = match header.iter().all(u8::is_ascii_whitespace) {
+            true => count,
+            false => count + 1,
+        };
+        if let Some(limits) = self.limits.filter(|limits| count > limits.max_count) {
+            return Err(limits.too_many());
+        }
+
+        self.headers = Some((start, self.position + 1, count));
+        Ok(())
+    }
+
+    fn finish_value(&mut self, data: &[u8]) {
+        let (Some(key), Some(start)) = (self.key.take(), self.value_start.take()) else {
+            return;
+        };
+
+        let value = &data[start..self.position];
+
+        // read as `Headers` rather than a JSON object, which would keep only
+        // the last entry of a repeated name, so that checks of the head see
+        // the same headers as the middleware later do
+        if key == "headers" {
+            if let Ok(headers) = serde_json::from_slice::<Headers>(value) {
+                self.head.headers = Some(headers);
+            }
+            return;
+        }
+
+        match (key.as_str(), serde_json::from_slice::<Value>(value)) {
+            ("method", Ok(Value::String(method))) => self.head.method = Some(method),
+            ("resource", Ok(Value::String(resource))) => self.head.resource = Some(resource),
+            _ => {}
+        }
+    }
+}
+
+/// The name of the header `member` is the start of, as far as it has come.
+fn header_name(member: &[u8]) -> String {
+    let member = String::from_utf8_lossy(member);
+    let name = member.trim_start().trim_start_matches('"');
+    name.split('"').next().unwrap_or_default().to_string()
+}
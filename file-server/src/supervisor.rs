@@ -0,0 +1,62 @@
+use std::{
+    env,
+    process::{Child, Command},
+    thread,
+    time::Duration,
+};
+
+/// Set in the environment of worker processes, to the worker's index.
+pub const WORKER_ENV: &str = "JSONTP_WORKER";
+
+pub fn is_worker() -> bool {
+    env::var_os(WORKER_ENV).is_some()
+}
+
+fn spawn_worker(id: usize) -> Option<Child> {
+    let exe = env::current_exe().ok()?;
+
+    match Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(WORKER_ENV, id.to_string())
+        .spawn()
+    {
+        Ok(child) => {
+            println!("Started worker {} (pid {})", id, child.id());
+            Some(child)
+        }
+        Err(e) => {
+            eprintln!("could not start worker {}: {}", id, e);
+            None
+        }
+    }
+}
+
+/// Runs `workers` copies of the current executable, with the same arguments,
+/// and restarts any that exit. The workers are expected to bind their
+/// listener with `SO_REUSEPORT` so the kernel spreads connections across
+/// them. Never returns.
+pub fn supervise(workers: usize) -> ! {
+    let mut children: Vec<Option<Child>> = (0..workers).map(spawn_worker).collect();
+
+    loop {
+        thread::sleep(Duration::from_secs(1));
+
+        for (id, child) in children.iter_mut().enumerate() {
+            let exited = match child {
+                Some(running) => match running.try_wait() {
+                    Ok(Some(status)) => {
+                        eprintln!("worker {} exited with {}", id, status);
+                        true
+                    }
+                    Ok(None) => false,
+                    Err(_) => true,
+                },
+                None => true,
+            };
+
+            if exited {
+                *child = spawn_worker(id);
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    env, io,
+    net::TcpListener,
+    os::{fd::FromRawFd, unix::net::UnixDatagram},
+    process,
+};
+
+use socket2::{Socket, Type};
+
+/// The first file descriptor systemd passes.
+const LISTEN_FDS_START: i32 = 3;
+
+/// The listening sockets systemd passed to this process, when it was
+/// started through a `.socket` unit, so that systemd can bind privileged
+/// ports and hold connections while the server restarts. Empty when it was
+/// started any other way.
+///
+/// The `LISTEN_*` variables are taken out of the environment, so that
+/// processes this one starts do not take the sockets for theirs.
+pub fn listeners() -> io::Result<Vec<TcpListener>> {
+    let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == process::id().to_string());
+    let count = env::var("LISTEN_FDS").ok();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    let Some(count) = count.filter(|_| for_us) else {
+        return Ok(Vec::new());
+    };
+    let count: i32 = count.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("LISTEN_FDS is not a number: {}", count),
+        )
+    })?;
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passes the descriptors from 3 on open and for
+            // this process to own, which LISTEN_PID has confirmed, and they
+            // are taken out of the environment so nothing takes them twice
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            if socket.r#type()? != Type::STREAM || socket.local_addr()?.as_socket().is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("file descriptor {} is not a TCP socket", fd),
+                ));
+            }
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+/// Tells systemd how the service is doing, as `sd_notify` does: `READY=1`
+/// once it serves, `STOPPING=1` as it stops, `STATUS=...` for `systemctl
+/// status` to show. Gives whether anyone was listening, which is only the
+/// case under a unit with `Type=notify`.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_encoded_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract notify sockets are only supported on Linux",
+            ))
+        }
+        None => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    future::Future,
+    hash::{Hash, Hasher},
+    pin::{pin, Pin},
+    sync::{Arc, Condvar, Mutex},
+    task::{Context as TaskContext, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    headers::Headers,
+    middleware::Context,
+    protocol::{JsontpRequest, JsontpResponse},
+    server::Handler,
+};
+
+/// Wakes a thread parked in [`block_on`].
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` to completion on this thread, parking it while the future
+/// waits.
+///
+/// This is not an async runtime: nothing here drives sockets or timers, so
+/// futures that rely on one (tokio's, say) must be given it by other means.
+/// Blocking work goes through [`spawn_blocking`].
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = TaskContext::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+struct Shared<T> {
+    output: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// The future of a closure running on another thread, from [`spawn_blocking`].
+pub struct Blocking<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, context: &mut TaskContext) -> Poll<T> {
+        let mut shared = self.shared.lock().unwrap();
+
+        match shared.output.take() {
+            Some(Ok(output)) => Poll::Ready(output),
+            // the panic belongs to whoever awaits the result
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => {
+                shared.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static> Blocking<T> {
+    /// A future of what `f` returns, and the job that runs `f` and hands
+    /// its result to the future.
+    fn new(f: impl FnOnce() -> T + Send + 'static) -> (Blocking<T>, impl FnOnce() + Send) {
+        let shared = Arc::new(Mutex::new(Shared {
+            output: None,
+            waker: None,
+        }));
+
+        let finished = shared.clone();
+        let job = move || {
+            let output = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+
+            let mut finished = finished.lock().unwrap();
+            finished.output = Some(output);
+            if let Some(waker) = finished.waker.take() {
+                waker.wake();
+            }
+        };
+
+        (Blocking { shared }, job)
+    }
+}
+
+/// Runs `f` on a thread of its own, for work that blocks or takes long enough
+/// to hold up other futures, and returns a future of its result.
+pub fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Blocking<T> {
+    let (blocking, job) = Blocking::new(f);
+    thread::spawn(job);
+    blocking
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PoolConfig {
+    pub threads: usize,
+    /// The most jobs waiting for a thread; more are turned away.
+    pub queue: usize,
+    /// Seconds a handler may take, waiting included, before its request is
+    /// answered with 503.
+    pub timeout: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            threads: 8,
+            queue: 256,
+            timeout: 30,
+        }
+    }
+}
+
+/// Returned when a [`BlockingPool`] has as many jobs waiting as it allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolFull;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The request header saying how urgent a request is, from 0, the most
+/// urgent, to 7, as with HTTP's `priority: u=...`.
+pub const PRIORITY_HEADER: &str = "priority";
+
+/// The urgency of requests that do not give one.
+pub const DEFAULT_URGENCY: u8 = 3;
+
+/// The urgency `headers` ask for; one out of range counts as not given.
+pub fn urgency(headers: &Headers) -> u8 {
+    let urgency = match headers.get(PRIORITY_HEADER) {
+        Some(Value::Number(urgency)) => urgency.as_u64(),
+        Some(Value::String(urgency)) => urgency.trim().parse().ok(),
+        _ => None,
+    };
+    urgency
+        .filter(|&urgency| urgency <= 7)
+        .map_or(DEFAULT_URGENCY, |urgency| urgency as u8)
+}
+
+/// Divisible by every weight, so that each job's cost is a whole number.
+const COST: u64 = 840;
+
+/// Jobs in weighted fair queueing order: each is tagged with the virtual
+/// time its flow would finish it if every flow were served at the rate of
+/// its weight, and the earliest tag runs first. An urgent job gets ahead of
+/// bulk ones without starving them, and a flow sending many jobs only
+/// delays its own.
+#[derive(Default)]
+struct Schedule {
+    queued: BTreeMap<(u64, u64), Job>,
+    /// The tag of the job that started last.
+    virtual_time: u64,
+    /// The tag of each flow's latest job, while it is ahead of
+    /// `virtual_time`.
+    flows: HashMap<u64, u64>,
+    submitted: u64,
+    closed: bool,
+}
+
+struct Queue {
+    schedule: Mutex<Schedule>,
+    ready: Condvar,
+    limit: usize,
+}
+
+/// A fixed set of threads for blocking work, such as handlers that touch the
+/// filesystem, so that it does not stall an event loop. Jobs queue up until a
+/// thread is free, up to a limit, and are taken in weighted fair order
+/// across flows, see [`BlockingPool::execute_as`].
+pub struct BlockingPool {
+    queue: Arc<Queue>,
+    timeout: Duration,
+}
+
+impl BlockingPool {
+    pub fn new(config: &PoolConfig) -> BlockingPool {
+        let queue = Arc::new(Queue {
+            schedule: Mutex::new(Schedule::default()),
+            ready: Condvar::new(),
+            limit: config.queue,
+        });
+
+        for _ in 0..config.threads.max(1) {
+            let queue = queue.clone();
+            thread::spawn(move || work(&queue));
+        }
+
+        BlockingPool {
+            queue,
+            timeout: Duration::from_secs(config.timeout),
+        }
+    }
+
+    /// How long the work of a request may take before it is given up on.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Queues `job` to run on one of the threads.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) -> Result<(), PoolFull> {
+        self.execute_as(job, &(), DEFAULT_URGENCY)
+    }
+
+    /// Queues `job` for `flow`, such as the address of the client it is
+    /// for, at `urgency` from 0 to 7. Each urgency of a flow queues apart,
+    /// and they get threads in proportion to their weight, 8 for urgency 0
+    /// down to 1 for urgency 7, so that a client's urgent requests are not
+    /// stuck behind its bulk ones.
+    pub fn execute_as(
+        &self,
+        job: impl FnOnce() + Send + 'static,
+        flow: &impl Hash,
+        urgency: u8,
+    ) -> Result<(), PoolFull> {
+        let mut hasher = DefaultHasher::new();
+        (flow, urgency).hash(&mut hasher);
+        let flow = hasher.finish();
+        let weight = 8 - u64::from(urgency.min(7));
+
+        let mut schedule = self.queue.schedule.lock().unwrap();
+        if schedule.queued.len() >= self.queue.limit {
+            return Err(PoolFull);
+        }
+
+        let virtual_time = schedule.virtual_time;
+        if schedule.flows.len() > self.queue.limit {
+            schedule.flows.retain(|_, finish| *finish > virtual_time);
+        }
+        let start = schedule
+            .flows
+            .get(&flow)
+            .map_or(virtual_time, |&finish| finish.max(virtual_time));
+        let finish = start + COST / weight;
+        schedule.flows.insert(flow, finish);
+
+        let sequence = schedule.submitted;
+        schedule.submitted += 1;
+        schedule.queued.insert((finish, sequence), Box::new(job));
+        drop(schedule);
+
+        self.queue.ready.notify_one();
+        Ok(())
+    }
+
+    /// Like [`spawn_blocking`], but on one of the threads of the pool.
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<Blocking<T>, PoolFull> {
+        let (blocking, job) = Blocking::new(f);
+        self.execute(job)?;
+        Ok(blocking)
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        self.queue.schedule.lock().unwrap().closed = true;
+        self.queue.ready.notify_all();
+    }
+}
+
+fn work(queue: &Queue) {
+    loop {
+        // the lock is only held while waiting, never while a job runs
+        let mut schedule = queue.schedule.lock().unwrap();
+        let job = loop {
+            if let Some(((finish, _), job)) = schedule.queued.pop_first() {
+                schedule.virtual_time = schedule.virtual_time.max(finish);
+                break job;
+            }
+            if schedule.closed {
+                return;
+            }
+            schedule = queue.ready.wait(schedule).unwrap();
+        };
+        drop(schedule);
+
+        // a panicking job must not take the thread with it
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+    }
+}
+
+/// Makes a [`Handler`] of an `async fn(&JsontpRequest, &mut Context) ->
+/// JsontpResponse`, or an async closure of the same shape. Each call is driven
+/// by [`block_on`] on the thread that would have called a plain handler.
+pub struct Async<F>(pub F);
+
+impl<F> Handler for Async<F>
+where
+    F: AsyncFn(&JsontpRequest, &mut Context) -> JsontpResponse + Send + Sync,
+{
+    fn handle(&self, request: &JsontpRequest, context: &mut Context) -> JsontpResponse {
+        block_on((self.0)(request, context))
+    }
+}
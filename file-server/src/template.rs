@@ -0,0 +1,388 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use serde_json::Value;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    NotFound(String),
+    /// The template could not be parsed; `offset` is in bytes from its start.
+    Syntax {
+        name: String,
+        offset: usize,
+        message: String,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::NotFound(name) => write!(f, "no template named {}", name),
+            TemplateError::Syntax {
+                name,
+                offset,
+                message,
+            } => write!(f, "{} at byte {}: {}", name, offset, message),
+            TemplateError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Each,
+    If,
+    Unless,
+}
+
+impl Block {
+    fn name(self) -> &'static str {
+        match self {
+            Block::Each => "each",
+            Block::If => "if",
+            Block::Unless => "unless",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Value {
+        path: String,
+        escape: bool,
+    },
+    Block {
+        block: Block,
+        path: String,
+        body: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+/// A parsed template in a small subset of Handlebars:
+///
+/// - `{{path}}` inserts a value, HTML-escaped, and `{{{path}}}` inserts it as
+///   it is. Paths are dotted (`user.name`); `this` is the current value, and
+///   names not found in it are looked up in the enclosing ones.
+/// - `{{#each path}}...{{/each}}` repeats for every element of an array or
+///   value of an object, with `@index` and `@key` naming where it is.
+/// - `{{#if path}}...{{else}}...{{/if}}` and `{{#unless path}}...{{/unless}}`
+///   test whether a value is there and not `false`, zero, empty or null.
+/// - `{{! comment }}` is left out.
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let mut parser = Parser {
+            name,
+            source,
+            position: 0,
+        };
+        let (nodes, end) = parser.nodes()?;
+
+        match end {
+            None => Ok(Template { nodes }),
+            Some(tag) => Err(parser.error(format!("unexpected {{{{{}}}}}", tag))),
+        }
+    }
+
+    pub fn render(&self, data: &Value) -> String {
+        let mut output = String::new();
+        render(&self.nodes, &[Scope::new(data)], &mut output);
+        output
+    }
+}
+
+struct Parser<'a> {
+    name: &'a str,
+    source: &'a str,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: String) -> TemplateError {
+        TemplateError::Syntax {
+            name: self.name.to_string(),
+            offset: self.position,
+            message,
+        }
+    }
+
+    /// Parses nodes up to the end of the template or a closing or `else` tag,
+    /// which is returned.
+    fn nodes(&mut self) -> Result<(Vec<Node>, Option<String>), TemplateError> {
+        let mut nodes = Vec::new();
+
+        loop {
+            let rest = &self.source[self.position..];
+            let Some(start) = rest.find("{{") else {
+                if !rest.is_empty() {
+                    nodes.push(Node::Text(rest.to_string()));
+                }
+                self.position = self.source.len();
+                return Ok((nodes, None));
+            };
+
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+            self.position += start;
+
+            let raw = rest[start..].starts_with("{{{");
+            let (open, close) = if raw { ("{{{", "}}}") } else { ("{{", "}}") };
+            let tag_start = self.position + open.len();
+            let Some(length) = self.source[tag_start..].find(close) else {
+                return Err(self.error(format!("{} is never closed", open)));
+            };
+            let tag = self.source[tag_start..tag_start + length].trim();
+            self.position = tag_start + length + close.len();
+
+            if raw {
+                nodes.push(Node::Value {
+                    path: tag.to_string(),
+                    escape: false,
+                });
+            } else if tag.starts_with('!') {
+                // comments leave nothing behind
+            } else if let Some(opening) = tag.strip_prefix('#') {
+                nodes.push(self.block(opening)?);
+            } else if tag.starts_with('/') || tag == "else" {
+                return Ok((nodes, Some(tag.to_string())));
+            } else {
+                nodes.push(Node::Value {
+                    path: tag.to_string(),
+                    escape: true,
+                });
+            }
+        }
+    }
+
+    fn block(&mut self, opening: &str) -> Result<Node, TemplateError> {
+        let (kind, path) = opening.split_once(' ').unwrap_or((opening, ""));
+        let block = match kind {
+            "each" => Block::Each,
+            "if" => Block::If,
+            "unless" => Block::Unless,
+            _ => return Err(self.error(format!("unknown block {}", kind))),
+        };
+        let path = path.trim();
+        if path.is_empty() {
+            return Err(self.error(format!("{} needs a value", kind)));
+        }
+
+        let closing = format!("/{}", block.name());
+        let (body, mut end) = self.nodes()?;
+        let mut otherwise = Vec::new();
+        if end.as_deref() == Some("else") {
+            (otherwise, end) = self.nodes()?;
+        }
+
+        if end.as_deref() != Some(closing.as_str()) {
+            return Err(self.error(format!("{} is never closed", block.name())));
+        }
+
+        Ok(Node::Block {
+            block,
+            path: path.to_string(),
+            body,
+            otherwise,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Scope<'a> {
+    value: &'a Value,
+    index: Option<usize>,
+    key: Option<&'a str>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(value: &'a Value) -> Scope<'a> {
+        Scope {
+            value,
+            index: None,
+            key: None,
+        }
+    }
+}
+
+fn lookup<'a>(scopes: &[Scope<'a>], path: &str) -> Option<Cow<'a, Value>> {
+    let innermost = scopes.last()?;
+    match path {
+        "this" | "." => return Some(Cow::Borrowed(innermost.value)),
+        "@index" => return innermost.index.map(|index| Cow::Owned(Value::from(index))),
+        "@key" => return innermost.key.map(|key| Cow::Owned(Value::from(key))),
+        _ => {}
+    }
+
+    let path = path.strip_prefix("this.").unwrap_or(path);
+    scopes.iter().rev().find_map(|scope| {
+        path.split('.')
+            .try_fold(scope.value, |value, name| value.get(name))
+            .map(Cow::Borrowed)
+    })
+}
+
+fn is_truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+        Some(Value::Number(number)) => number.as_f64() != Some(0.0),
+        Some(Value::String(string)) => !string.is_empty(),
+        Some(Value::Array(array)) => !array.is_empty(),
+        Some(Value::Object(object)) => !object.is_empty(),
+        Some(Value::Bool(true)) => true,
+    }
+}
+
+/// Escapes the characters that mean something in HTML text and attributes.
+pub fn escape_html(text: &str, output: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            _ => output.push(c),
+        }
+    }
+}
+
+fn render(nodes: &[Node], scopes: &[Scope], output: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Value { path, escape } => {
+                let value = lookup(scopes, path);
+                let text = match value.as_deref() {
+                    None | Some(Value::Null) => continue,
+                    Some(Value::String(string)) => Cow::Borrowed(string.as_str()),
+                    Some(value) => Cow::Owned(value.to_string()),
+                };
+                if *escape {
+                    escape_html(&text, output);
+                } else {
+                    output.push_str(&text);
+                }
+            }
+            Node::Block {
+                block: Block::Each,
+                path,
+                body,
+                otherwise,
+            } => {
+                let value = lookup(scopes, path);
+                let items: Vec<(Option<&str>, &Value)> = match value.as_deref() {
+                    Some(Value::Array(array)) => array.iter().map(|item| (None, item)).collect(),
+                    Some(Value::Object(object)) => object
+                        .iter()
+                        .map(|(key, item)| (Some(key.as_str()), item))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+
+                if items.is_empty() {
+                    render(otherwise, scopes, output);
+                }
+
+                for (index, (key, item)) in items.into_iter().enumerate() {
+                    let mut inner = scopes.to_vec();
+                    inner.push(Scope {
+                        value: item,
+                        index: Some(index),
+                        key,
+                    });
+                    render(body, &inner, output);
+                }
+            }
+            Node::Block {
+                block,
+                path,
+                body,
+                otherwise,
+            } => {
+                let truthy = is_truthy(lookup(scopes, path).as_deref());
+                let branch = if truthy == (*block == Block::If) {
+                    body
+                } else {
+                    otherwise
+                };
+                render(branch, scopes, output);
+            }
+        }
+    }
+}
+
+/// The templates in a directory, parsed once and kept. With `reload` set, as
+/// during development, a template is parsed again whenever its file changes.
+#[derive(Debug)]
+pub struct Templates {
+    directory: PathBuf,
+    reload: bool,
+    cache: RwLock<BTreeMap<String, (SystemTime, Arc<Template>)>>,
+}
+
+impl Templates {
+    pub fn new(directory: impl Into<PathBuf>, reload: bool) -> Templates {
+        Templates {
+            directory: directory.into(),
+            reload,
+            cache: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// The template in the file `name`, relative to the directory.
+    pub fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(TemplateError::NotFound(name.to_string()));
+        }
+        let path = self.directory.join(relative);
+
+        if let Some((modified, template)) = self.cache.read().unwrap().get(name) {
+            let unchanged = !self.reload
+                || fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|current| current == *modified);
+            if unchanged {
+                return Ok(template.clone());
+            }
+        }
+
+        let not_found = |e: io::Error| match e.kind() {
+            io::ErrorKind::NotFound => TemplateError::NotFound(name.to_string()),
+            _ => TemplateError::Io(e),
+        };
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(not_found)?;
+        let source = fs::read_to_string(&path).map_err(not_found)?;
+        let template = Arc::new(Template::parse(name, &source)?);
+
+        self.cache
+            .write()
+            .unwrap()
+            .insert(name.to_string(), (modified, template.clone()));
+
+        Ok(template)
+    }
+
+    pub fn render(&self, name: &str, data: &Value) -> Result<String, TemplateError> {
+        Ok(self.get(name)?.render(data))
+    }
+}
@@ -0,0 +1,174 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    acl::AclRule,
+    bandwidth::BandwidthConfig,
+    collection,
+    middleware::{Context, Middleware},
+    protocol::{JsontpRequest, JsontpResponse},
+    streaming::RequestHead,
+    throttle::ThrottleConfig,
+};
+
+/// The key in [`Context::values`] under which [`TenantMiddleware`] puts the
+/// name of the tenant a request is for.
+pub const TENANT_VALUE: &str = "tenant";
+
+/// One of several sites served from the same process, each with files,
+/// users, limits and logs of its own.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    /// Requests whose `host` header is one of these are for this tenant. A
+    /// port in the header is ignored.
+    pub hosts: Vec<String>,
+    /// Requests for resources under this one are for this tenant, which
+    /// sees them without it: with a prefix of `acme`, `acme/index.html` is
+    /// `index.html` in `root`. When `hosts` is set too, a request must match
+    /// both.
+    pub prefix: Option<String>,
+    /// The directory the tenant's resources are in, rather than the working
+    /// directory.
+    pub root: PathBuf,
+    #[cfg(feature = "users")]
+    pub users_file: Option<PathBuf>,
+    #[cfg(feature = "tokens")]
+    pub tokens: Option<crate::tokens::TokenConfig>,
+    #[cfg(feature = "oidc")]
+    pub oidc: Option<crate::oidc::OidcConfig>,
+    pub acl: Vec<AclRule>,
+    pub throttle: Option<ThrottleConfig>,
+    pub bandwidth: Option<BandwidthConfig>,
+    #[cfg(feature = "audit")]
+    pub audit: Option<crate::audit::AuditConfig>,
+}
+
+impl TenantConfig {
+    /// Whether a request for `resource`, with `host` as its `host` header,
+    /// is for this tenant.
+    pub fn matches(&self, host: Option<&str>, resource: &str) -> bool {
+        if self.hosts.is_empty() && self.prefix.is_none() {
+            return false;
+        }
+
+        let host_matches = self.hosts.is_empty()
+            || host.is_some_and(|host| {
+                let host = strip_port(host);
+                self.hosts
+                    .iter()
+                    .any(|tenant_host| tenant_host.eq_ignore_ascii_case(host))
+            });
+        host_matches && self.strip_prefix(resource).is_some()
+    }
+
+    /// `resource` as the tenant sees it, without its prefix.
+    pub fn relative<'a>(&self, resource: &'a str) -> &'a str {
+        self.strip_prefix(resource).unwrap_or(resource)
+    }
+
+    /// Where in `root` `resource` is, or `None` if it would be outside it.
+    pub fn path(&self, resource: &str) -> Option<PathBuf> {
+        collection::resolve(&self.root, self.relative(resource))
+    }
+
+    fn strip_prefix<'a>(&self, resource: &'a str) -> Option<&'a str> {
+        let resource = resource.trim_start_matches('/');
+        let Some(prefix) = &self.prefix else {
+            return Some(resource);
+        };
+
+        let prefix = prefix.trim_matches('/');
+        match resource.strip_prefix(prefix)? {
+            "" => Some("."),
+            rest => rest.strip_prefix('/'),
+        }
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        // an IPv6 address has colons of its own, and brackets when a port follows
+        Some((name, port))
+            if port.bytes().all(|b| b.is_ascii_digit())
+                && (name.starts_with('[') || !name.contains(':')) =>
+        {
+            name
+        }
+        _ => host,
+    }
+}
+
+fn host(headers: &crate::headers::Headers) -> Option<&str> {
+    headers.get("host").and_then(Value::as_str)
+}
+
+/// Runs the middleware of the tenant each request is for, such as its
+/// authentication and limits, and `default` for requests that are for no
+/// tenant, naming the tenant in [`TENANT_VALUE`] for the handler.
+pub struct TenantMiddleware {
+    pub tenants: Vec<(TenantConfig, Vec<Box<dyn Middleware>>)>,
+    pub default: Vec<Box<dyn Middleware>>,
+}
+
+impl TenantMiddleware {
+    fn chain(&self, host: Option<&str>, resource: &str) -> (Option<&str>, &[Box<dyn Middleware>]) {
+        match self
+            .tenants
+            .iter()
+            .find(|(tenant, _)| tenant.matches(host, resource))
+        {
+            Some((tenant, middleware)) => (Some(&tenant.name), middleware),
+            None => (None, &self.default),
+        }
+    }
+}
+
+impl Middleware for TenantMiddleware {
+    fn on_head(&self, head: &RequestHead, context: &Context) -> Result<(), (String, u16)> {
+        let (Some(resource), Some(headers)) = (&head.resource, &head.headers) else {
+            return Ok(());
+        };
+
+        let (_, chain) = self.chain(host(headers), resource);
+        chain
+            .iter()
+            .try_for_each(|middleware| middleware.on_head(head, context))
+    }
+
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        let (tenant, chain) = self.chain(host(&request.headers), &request.resource);
+        if let Some(tenant) = tenant {
+            context
+                .values
+                .insert(TENANT_VALUE.to_string(), Value::String(tenant.to_string()));
+        }
+
+        chain
+            .iter()
+            .try_for_each(|middleware| middleware.on_request(request, context))
+    }
+
+    fn on_response(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        let chain = match request {
+            Some(request) => self.chain(host(&request.headers), &request.resource).1,
+            None => &self.default,
+        };
+
+        for middleware in chain.iter().rev() {
+            middleware.on_response(request, context, response);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    middleware::{Context, Middleware},
+    protocol::{JsontpRequest, JsontpResponse},
+};
+
+/// Where the throttle leaves what it decided for `on_response`.
+const QUOTA_VALUE: &str = "throttle-quota";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// Seconds a quota lasts before it starts again.
+    pub window: u64,
+    /// Requests per window for signed-in users without a quota of their
+    /// own.
+    pub requests: u64,
+    /// Requests per window for each address whose requests are not signed
+    /// in; `requests` when unset.
+    pub anonymous: Option<u64>,
+    /// Quotas of particular users, by name.
+    pub users: BTreeMap<String, u64>,
+    /// Quotas of users with a role; the most generous of a user's roles
+    /// applies, unless the user has a quota by name.
+    pub roles: BTreeMap<String, u64>,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            window: 60,
+            requests: 60,
+            anonymous: None,
+            users: BTreeMap::new(),
+            roles: BTreeMap::new(),
+        }
+    }
+}
+
+/// Who a request counts against, `user:<name>` or `ip:<address>`, and their
+/// quota: a signed-in user's own in `users`, else the most generous of their
+/// roles', else `default`; for anyone else `anonymous`, else `default`.
+pub(crate) fn quota_for(
+    context: &Context,
+    default: u64,
+    anonymous: Option<u64>,
+    users: &BTreeMap<String, u64>,
+    roles: &BTreeMap<String, u64>,
+) -> Option<(String, u64)> {
+    let Some(user) = &context.user else {
+        let peer = context.peer?;
+        return Some((format!("ip:{}", peer), anonymous.unwrap_or(default)));
+    };
+
+    let quota = users.get(user).copied().unwrap_or_else(|| {
+        context
+            .roles
+            .iter()
+            .filter_map(|role| roles.get(role).copied())
+            .max()
+            .unwrap_or(default)
+    });
+    Some((format!("user:{}", user), quota))
+}
+
+/// Limits how many requests each client makes per window: signed-in users
+/// by name, whatever address they come from, and everyone else by address.
+/// Every response says where its client stands in `quota-limit`,
+/// `quota-remaining` and `quota-reset` (seconds until the window starts
+/// again), and requests over the quota are answered with 429.
+///
+/// Register it after any authenticating middleware, so that it sees who
+/// requests are from.
+pub struct ThrottleMiddleware {
+    pub config: ThrottleConfig,
+    /// When each client's window started and how many requests it has made
+    /// in it.
+    windows: Mutex<HashMap<String, (Instant, u64)>>,
+}
+
+impl ThrottleMiddleware {
+    /// Beyond this many clients, ones whose window has passed are forgotten.
+    const TRACKED: usize = 10_000;
+
+    pub fn new(config: ThrottleConfig) -> ThrottleMiddleware {
+        ThrottleMiddleware {
+            config,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request from `client`, giving how many it has left and how
+    /// long until its window starts again, or `None` if it had none left.
+    fn take(&self, client: String, quota: u64) -> (Option<u64>, Duration) {
+        let window = Duration::from_secs(self.config.window);
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+
+        if windows.len() >= Self::TRACKED {
+            windows.retain(|_, (started, _)| now.duration_since(*started) < window);
+        }
+
+        let (started, used) = windows.entry(client).or_insert((now, 0));
+        if now.duration_since(*started) >= window {
+            *started = now;
+            *used = 0;
+        }
+        let reset = window.saturating_sub(now.duration_since(*started));
+
+        if *used >= quota {
+            return (None, reset);
+        }
+        *used += 1;
+        (Some(quota - *used), reset)
+    }
+}
+
+impl Middleware for ThrottleMiddleware {
+    fn on_request(
+        &self,
+        _request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        let config = &self.config;
+        let Some((client, quota)) = quota_for(
+            context,
+            config.requests,
+            config.anonymous,
+            &config.users,
+            &config.roles,
+        ) else {
+            return Ok(());
+        };
+
+        let (remaining, reset) = self.take(client, quota);
+        context.values.insert(
+            QUOTA_VALUE.to_string(),
+            json!({
+                "quota-limit": quota,
+                "quota-remaining": remaining.unwrap_or(0),
+                "quota-reset": reset.as_secs_f64().ceil() as u64,
+            }),
+        );
+
+        match remaining {
+            Some(_) => Ok(()),
+            None => Err(("Too Many Requests".to_string(), 429)),
+        }
+    }
+
+    fn on_response(
+        &self,
+        _request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        let Some(Value::Object(quota)) = context.values.get(QUOTA_VALUE) else {
+            return;
+        };
+
+        for (name, value) in quota {
+            response.headers.insert(name.clone(), value.clone());
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::{
+    middleware::{Context, Middleware, CLAIMS_VALUE},
+    protocol::{JsontpRequest, JsontpResponse},
+    router::{Endpoint, Parameters},
+    schema::Route,
+    server::status_response,
+    users::Users,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TokenConfig {
+    pub secret: String,
+    /// Seconds a token is valid for after it is issued.
+    #[serde(default = "default_lifetime")]
+    pub lifetime: u64,
+    /// The resource credentials are exchanged for a token at, with `POST`.
+    #[serde(default = "default_resource")]
+    pub resource: String,
+}
+
+fn default_lifetime() -> u64 {
+    3600
+}
+
+fn default_resource() -> String {
+    "token".to_string()
+}
+
+/// What a token says about whoever holds it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Claims {
+    /// The user the token was issued to.
+    pub sub: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// When it was issued, in seconds since the Unix epoch.
+    pub iat: u64,
+    /// When it stops being accepted, in seconds since the Unix epoch.
+    pub exp: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Issues and checks tokens of the form `claims.mac`: the claims as
+/// base64url JSON and an HMAC-SHA256 of them. Nothing is stored server side,
+/// so a token stays valid until it expires.
+pub struct TokenSigner {
+    secret: Vec<u8>,
+}
+
+impl TokenSigner {
+    pub fn new(secret: &[u8]) -> TokenSigner {
+        TokenSigner {
+            secret: secret.to_vec(),
+        }
+    }
+
+    fn mac(&self, claims: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("hmac accepts any key length");
+        mac.update(claims.as_bytes());
+        mac
+    }
+
+    pub fn issue(&self, claims: &Claims) -> String {
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+        let mac = URL_SAFE_NO_PAD.encode(self.mac(&claims).finalize().into_bytes());
+        format!("{}.{}", claims, mac)
+    }
+
+    /// The claims of `token` if it was issued with this key and has not
+    /// expired.
+    pub fn verify(&self, token: &str) -> Option<Claims> {
+        let (claims, mac) = token.split_once('.')?;
+        let mac = URL_SAFE_NO_PAD.decode(mac).ok()?;
+        self.mac(claims).verify_slice(&mac).ok()?;
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+        (claims.exp > now()).then_some(claims)
+    }
+}
+
+/// What a client sends to get a token, as the JSON content of the request.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Credentials {
+    name: String,
+    password: String,
+}
+
+/// Exchanges the name and password of one of [`Users`] for a token, at
+/// `resource`. The content of the request is
+/// `{"name": ..., "password": ...}`, and that of the response
+/// `{"token": ..., "token-type": "Bearer", "expires-at": ...}`.
+pub struct TokenService {
+    pub signer: TokenSigner,
+    pub users: Users,
+    /// Seconds a token is valid for.
+    pub lifetime: u64,
+    pub resource: String,
+}
+
+impl TokenService {
+    pub fn new(config: &TokenConfig, users: Users) -> TokenService {
+        TokenService {
+            signer: TokenSigner::new(config.secret.as_bytes()),
+            users,
+            lifetime: config.lifetime,
+            resource: config.resource.clone(),
+        }
+    }
+
+    /// Answers a request for a token. Unlike most error responses, those
+    /// given here do not echo the request, which holds a password.
+    pub fn login(&self, request: &JsontpRequest) -> JsontpResponse {
+        let refuse = |code, formal_message, human_message| {
+            let mut response = status_response(code, formal_message, human_message);
+            response.resource = request.resource.clone();
+            response
+        };
+
+        if request.method != "POST" {
+            return refuse(405, "Method Not Allowed", "Tokens are issued with POST");
+        }
+
+        let Ok(credentials) = serde_json::from_str::<Credentials>(&request.body.content) else {
+            return refuse(
+                400,
+                "Bad Request",
+                "Send {\"name\": ..., \"password\": ...} to get a token",
+            );
+        };
+        let Some(user) = self.users.verify(&credentials.name, &credentials.password) else {
+            return refuse(401, "Unauthorized", "Wrong name or password");
+        };
+
+        let issued = now();
+        let claims = Claims {
+            sub: user.name.clone(),
+            roles: user.roles.clone(),
+            iat: issued,
+            exp: issued + self.lifetime,
+        };
+
+        let mut response = status_response(200, "OK", "Request was successful");
+        response.resource = request.resource.clone();
+        response.headers.insert(
+            "content-type".to_string(),
+            Value::String("application/json".to_string()),
+        );
+        response.body.content = json!({
+            "token": self.signer.issue(&claims),
+            "token-type": "Bearer",
+            "expires-at": claims.exp,
+        })
+        .to_string();
+        response
+    }
+}
+
+/// The schema of a token resource at `resource`.
+pub fn token_route(resource: &str) -> Route {
+    Route::new("token", "POST", resource)
+        .summary("Exchange a name and password for a bearer token")
+        .request(json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "password": { "type": "string" }
+            },
+            "required": ["name", "password"]
+        }))
+        .response(json!({
+            "type": "object",
+            "properties": {
+                "token": { "type": "string" },
+                "token-type": { "type": "string" },
+                "expires-at": { "type": "integer" }
+            },
+            "required": ["token", "token-type", "expires-at"]
+        }))
+        .status(200, "OK")
+        .status(400, "Credentials missing")
+        .status(401, "Wrong name or password")
+}
+
+impl Endpoint for TokenService {
+    fn route(&self) -> Route {
+        token_route(&self.resource)
+    }
+
+    fn call(
+        &self,
+        request: &JsontpRequest,
+        _context: &mut Context,
+        _parameters: &Parameters,
+    ) -> JsontpResponse {
+        self.login(request)
+    }
+}
+
+/// Signs in requests carrying `"authorization": "Bearer <token>"` with a
+/// token from [`TokenSigner`]: the user and roles go in the [`Context`], and
+/// all the claims under [`CLAIMS_VALUE`] in its values. Tokens that are
+/// forged or expired are answered with 401; requests without one are left
+/// anonymous.
+pub struct BearerMiddleware {
+    pub signer: TokenSigner,
+}
+
+impl Middleware for BearerMiddleware {
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        let Some(authorization) = request.headers.get("authorization").and_then(Value::as_str)
+        else {
+            return Ok(());
+        };
+        // JWTs, with three parts, are for other middleware
+        let Some(token) = authorization
+            .strip_prefix("Bearer ")
+            .or_else(|| authorization.strip_prefix("bearer "))
+            .map(str::trim)
+            .filter(|token| token.matches('.').count() == 1)
+        else {
+            return Ok(());
+        };
+
+        let claims = self
+            .signer
+            .verify(token)
+            .ok_or_else(|| ("Unauthorized".to_string(), 401))?;
+
+        context.user = Some(claims.sub.clone());
+        context.roles.extend(claims.roles.iter().cloned());
+        context.values.insert(
+            CLAIMS_VALUE.to_string(),
+            serde_json::to_value(&claims).unwrap(),
+        );
+        Ok(())
+    }
+}
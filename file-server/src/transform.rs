@@ -0,0 +1,110 @@
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    middleware::{Context, Middleware},
+    protocol::{JsontpRequest, JsontpResponse},
+    streaming::RequestHead,
+};
+
+/// A step response bodies go through on their way out, such as minifying,
+/// compressing, encrypting or signing them, or an application's own, such
+/// as watermarking or filling in a template. `request` is `None` when the
+/// request could not be parsed.
+///
+/// Any `Fn(Option<&JsontpRequest>, &mut Context, &mut JsontpResponse)` is a
+/// transform.
+pub trait BodyTransform: Send + Sync {
+    fn transform(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    );
+}
+
+impl<F> BodyTransform for F
+where
+    F: Fn(Option<&JsontpRequest>, &mut Context, &mut JsontpResponse) + Send + Sync,
+{
+    fn transform(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        self(request, context, response)
+    }
+}
+
+impl<T: BodyTransform + ?Sized> BodyTransform for Arc<T> {
+    fn transform(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        (**self).transform(request, context, response)
+    }
+}
+
+/// Body transforms run on every response in the order they were added, each
+/// seeing the body as the one before left it, so that custom steps can go
+/// anywhere among the built-in ones:
+///
+/// ```ignore
+/// let compression = Arc::new(CompressionMiddleware::new(config));
+/// server.middleware.push(Box::new(RequestsOnly(compression.clone())));
+/// server.middleware.push(Box::new(
+///     Transforms::default()
+///         .then(watermark)
+///         .then(compression)
+///         .then(signatures),
+/// ));
+/// ```
+///
+/// As a middleware, the whole pipeline runs where it is registered.
+#[derive(Default)]
+pub struct Transforms {
+    pub stages: Vec<Box<dyn BodyTransform>>,
+}
+
+impl Transforms {
+    /// Adds `stage` after the ones already added.
+    pub fn then(mut self, stage: impl BodyTransform + 'static) -> Transforms {
+        self.stages.push(Box::new(stage));
+        self
+    }
+}
+
+impl Middleware for Transforms {
+    fn on_response(
+        &self,
+        request: Option<&JsontpRequest>,
+        context: &mut Context,
+        response: &mut JsontpResponse,
+    ) {
+        for stage in &self.stages {
+            stage.transform(request, context, response);
+        }
+    }
+}
+
+/// Runs the request hooks of a middleware and not its response hook, for one
+/// whose responses are transformed by [`Transforms`] instead, such as
+/// encryption, which decrypts requests as a middleware and encrypts
+/// responses as a transform.
+pub struct RequestsOnly<M>(pub Arc<M>);
+
+impl<M: Middleware> Middleware for RequestsOnly<M> {
+    fn on_head(&self, head: &RequestHead, context: &Context) -> Result<(), (String, u16)> {
+        self.0.on_head(head, context)
+    }
+
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        self.0.on_request(request, context)
+    }
+}
@@ -0,0 +1,525 @@
+use std::{
+    io::{self, IoSlice, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+use crate::{
+    streaming::{HeadScanner, HeaderLimits, RequestHead},
+    wire::{MessageState, WireFormat},
+};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Limits {
+    /// The largest message, in bytes, the server will read.
+    pub max_message_size: usize,
+    /// Seconds a client has to send a complete message.
+    pub request_timeout: u64,
+    /// Bytes per second a client must sustain once the first second has
+    /// passed. Zero disables the check.
+    pub min_transfer_rate: u64,
+    /// The most requests one batch may carry.
+    pub max_batch_size: usize,
+    /// Bytes per second a response is sent at, at most. Zero leaves sending
+    /// to go as fast as the connection allows.
+    pub max_send_rate: u64,
+    /// Bytes that may go out at once before [`Limits::max_send_rate`] starts
+    /// holding a response back.
+    pub send_burst: u64,
+    /// The most headers a request may have.
+    pub max_header_count: usize,
+    /// The most bytes of JSON one header, name and value, may take.
+    pub max_header_size: usize,
+    /// The most bytes of JSON the headers of a request may take together.
+    pub max_header_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_message_size: 1024 * 1024,
+            request_timeout: 10,
+            min_transfer_rate: 256,
+            max_batch_size: 100,
+            max_send_rate: 0,
+            send_burst: 64 * 1024,
+            max_header_count: HeaderLimits::default().max_count,
+            max_header_size: HeaderLimits::default().max_size,
+            max_header_bytes: HeaderLimits::default().max_bytes,
+        }
+    }
+}
+
+impl Limits {
+    /// Whether a client that started sending at `started` and has sent
+    /// `received` bytes so far has missed the deadline or the minimum rate.
+    pub fn too_slow(&self, started: Instant, received: usize, now: Instant) -> bool {
+        let elapsed = now.duration_since(started);
+
+        if elapsed >= Duration::from_secs(self.request_timeout) {
+            return true;
+        }
+
+        self.min_transfer_rate > 0
+            && elapsed > Duration::from_secs(1)
+            && (received as f64 / elapsed.as_secs_f64()) < self.min_transfer_rate as f64
+    }
+
+    /// The limits on the headers of a request.
+    pub fn header_limits(&self) -> HeaderLimits {
+        HeaderLimits {
+            max_count: self.max_header_count,
+            max_size: self.max_header_size,
+            max_bytes: self.max_header_bytes,
+        }
+    }
+
+    /// What paces responses sent under these limits, if anything does.
+    pub fn pacer(&self) -> Option<TokenBucket> {
+        (self.max_send_rate > 0).then(|| TokenBucket::new(self.max_send_rate, self.send_burst))
+    }
+}
+
+/// Paces writes to `rate` bytes per second on average, letting up to `burst`
+/// bytes go at once after a pause.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    /// Bytes that may be sent right now.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that starts out full.
+    pub fn new(rate: u64, burst: u64) -> TokenBucket {
+        // anything smaller would never let a byte through
+        let burst = burst.max(1);
+        TokenBucket {
+            rate: rate.max(1),
+            burst,
+            tokens: burst as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.refilled = now;
+    }
+
+    /// How many bytes may be sent now, which may be none.
+    pub fn available(&mut self, now: Instant) -> usize {
+        self.refill(now);
+        self.tokens as usize
+    }
+
+    /// Counts `bytes` as sent.
+    pub fn spend(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+
+    /// How long after `now` until `wanted` bytes, or a full bucket if that
+    /// is less, may be sent.
+    pub fn wait(&self, wanted: usize, now: Instant) -> Duration {
+        let wanted = (wanted as f64).min(self.burst as f64);
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        let tokens = self.tokens + elapsed * self.rate as f64;
+
+        Duration::from_secs_f64(((wanted - tokens) / self.rate as f64).max(0.0))
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// The client missed the deadline or fell below the minimum rate.
+    TooSlow,
+    TooLarge,
+    /// The head of the request was turned down before the body arrived, by
+    /// the server or for headers over the limits.
+    Rejected(String, u16),
+    Io(io::Error),
+}
+
+/// Reads one message from `stream`, stopping as soon as it is complete, is
+/// clearly invalid, or the client closes its side.
+///
+/// For JSON messages `check_head` is called once the method, resource and
+/// headers have arrived; if it returns an error reading stops there, and
+/// otherwise the rest of the message is read under the limits it returns.
+pub fn read_message<F>(
+    stream: &mut TcpStream,
+    limits: &Limits,
+    check_head: F,
+) -> Result<Vec<u8>, ReadError>
+where
+    F: Fn(&RequestHead) -> Result<Limits, (String, u16)>,
+{
+    let started = Instant::now();
+    let mut limits = limits.clone();
+    let mut data = Vec::new();
+    let mut buffer = [0; 8192];
+    let mut scanner = Some(HeadScanner::with_limits(limits.header_limits()));
+
+    loop {
+        let now = Instant::now();
+        if limits.too_slow(started, data.len(), now) {
+            return Err(ReadError::TooSlow);
+        }
+        let deadline = started + Duration::from_secs(limits.request_timeout);
+
+        // wake up at least once a second so the rate is re-checked even if
+        // the client goes quiet
+        let timeout = (deadline - now).min(Duration::from_secs(1));
+        stream
+            .set_read_timeout(Some(timeout))
+            .map_err(ReadError::Io)?;
+
+        let bytes_read = match stream.read(&mut buffer) {
+            Ok(bytes_read) => bytes_read,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ReadError::Io(e)),
+        };
+
+        if bytes_read == 0 {
+            return Ok(data);
+        }
+
+        data.extend_from_slice(&buffer[..bytes_read]);
+
+        if data.len() > limits.max_message_size {
+            return Err(ReadError::TooLarge);
+        }
+
+        let format = WireFormat::sniff(&data);
+
+        if let (WireFormat::Json, Some(head_scanner)) = (format, &mut scanner) {
+            let head = head_scanner
+                .feed(&data)
+                .map_err(|(message, code)| ReadError::Rejected(message, code))?;
+            if head.is_complete() {
+                limits = check_head(head_scanner.head())
+                    .map_err(|(message, code)| ReadError::Rejected(message, code))?;
+                scanner = None;
+                if data.len() > limits.max_message_size {
+                    return Err(ReadError::TooLarge);
+                }
+            }
+        }
+
+        match format.message_state(&data) {
+            MessageState::Incomplete => {}
+            MessageState::Complete | MessageState::Invalid => return Ok(data),
+        }
+    }
+}
+
+/// Whether [`write_message`] flushes the writer once a message is written.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Flush {
+    /// After every message, so that none is left sitting in a buffer.
+    #[default]
+    EveryMessage,
+    /// Left to whoever owns the writer, which can then gather several
+    /// messages into one buffer.
+    Never,
+}
+
+/// How long [`write_message`] waits on a writer that keeps answering
+/// `WouldBlock` without taking anything, before giving up.
+const WRITE_STALL: Duration = Duration::from_secs(30);
+
+/// Writes `parts` one after another, handing them to the writer together
+/// through `write_vectored` so that, say, a prefix and the message after it
+/// leave in one system call. Short writes are carried on from where they
+/// stopped, and writes that are interrupted or would block are tried again,
+/// so either every byte is handed over or an error is returned.
+///
+/// ```
+/// use std::io::{self, Write};
+///
+/// use jsontp::transport::{write_message, Flush};
+///
+/// /// Takes a byte at a time, turning every other write away.
+/// struct Reluctant {
+///     written: Vec<u8>,
+///     calls: usize,
+/// }
+///
+/// impl Write for Reluctant {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+///         self.calls += 1;
+///         match self.calls % 4 {
+///             1 => Err(io::ErrorKind::WouldBlock.into()),
+///             3 => Err(io::ErrorKind::Interrupted.into()),
+///             _ => {
+///                 self.written.push(buf[0]);
+///                 Ok(1)
+///             }
+///         }
+///     }
+///
+///     fn flush(&mut self) -> io::Result<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// let mut writer = Reluctant { written: Vec::new(), calls: 0 };
+/// let message = br#"{"jsontp":"1.0","type":"response"}"#;
+/// write_message(&mut writer, &[b"\n", message, b"\n"], Flush::EveryMessage).unwrap();
+///
+/// assert_eq!(writer.written, [&b"\n"[..], message, b"\n"].concat());
+/// ```
+pub fn write_message<W: Write>(writer: &mut W, parts: &[&[u8]], flush: Flush) -> io::Result<()> {
+    let mut slices: Vec<IoSlice> = parts
+        .iter()
+        .filter(|part| !part.is_empty())
+        .map(|part| IoSlice::new(part))
+        .collect();
+    let mut remaining = &mut slices[..];
+    let mut blocked_since = None;
+
+    while !remaining.is_empty() {
+        match writer.write_vectored(remaining) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(bytes_written) => {
+                IoSlice::advance_slices(&mut remaining, bytes_written);
+                blocked_since = None;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if blocked_since.get_or_insert_with(Instant::now).elapsed() >= WRITE_STALL {
+                    return Err(e);
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    match flush {
+        Flush::EveryMessage => writer.flush(),
+        Flush::Never => Ok(()),
+    }
+}
+
+/// Writes `data` no faster than `pacer` allows, sleeping between chunks, and
+/// flushes once all of it is handed over.
+pub fn write_paced<W: Write>(
+    writer: &mut W,
+    data: &[u8],
+    pacer: &mut TokenBucket,
+    flush: Flush,
+) -> io::Result<()> {
+    let mut written = 0;
+
+    while written < data.len() {
+        let now = Instant::now();
+        let wanted = (data.len() - written).min(PACED_CHUNK);
+        let allowed = pacer.available(now).min(wanted);
+        if allowed == 0 {
+            thread::sleep(pacer.wait(wanted, now));
+            continue;
+        }
+
+        write_message(writer, &[&data[written..written + allowed]], Flush::Never)?;
+        pacer.spend(allowed);
+        written += allowed;
+    }
+
+    write_message(writer, &[], flush)
+}
+
+/// The most [`write_paced`] writes at once, so that a large bucket does not
+/// turn into one burst.
+pub const PACED_CHUNK: usize = 16 * 1024;
+
+/// Socket options for listeners and connections, shared by the server and
+/// the client. Unset options keep the operating system's defaults.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TcpOptions {
+    pub nodelay: bool,
+    /// Seconds a connection may sit idle before keepalive probes are sent.
+    pub keepalive: Option<u64>,
+    /// Seconds between keepalive probes.
+    pub keepalive_interval: Option<u64>,
+    /// Unanswered probes before the connection is dropped.
+    pub keepalive_retries: Option<u32>,
+    /// Defaults to on for unix, like `std::net::TcpListener::bind`, so the
+    /// server can restart while old connections are in `TIME_WAIT`.
+    pub reuse_address: bool,
+    /// Lets several sockets bind the same port; only available on unix.
+    pub reuse_port: bool,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub backlog: Option<i32>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        TcpOptions {
+            nodelay: false,
+            keepalive: None,
+            keepalive_interval: None,
+            keepalive_retries: None,
+            reuse_address: cfg!(unix),
+            reuse_port: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            backlog: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    fn socket_for(&self, address: &str) -> io::Result<(Socket, socket2::SockAddr)> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to use"))?;
+
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        self.apply_to_socket(&socket)?;
+
+        Ok((socket, address.into()))
+    }
+
+    fn apply_to_socket(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        socket.set_nodelay(self.nodelay)?;
+
+        if let Some(time) = self.keepalive {
+            #[allow(unused_mut)]
+            let mut keepalive = TcpKeepalive::new().with_time(Duration::from_secs(time));
+
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "freebsd",
+                target_os = "windows"
+            ))]
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(Duration::from_secs(interval));
+            }
+
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "freebsd"
+            ))]
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+
+        Ok(())
+    }
+
+    /// Binds a listener on `address` with these options applied.
+    pub fn bind(&self, address: &str) -> io::Result<TcpListener> {
+        let (socket, address) = self.socket_for(address)?;
+
+        socket.set_reuse_address(self.reuse_address)?;
+
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(self.reuse_port)?;
+        #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+        if self.reuse_port {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "reuse-port is not supported on this platform",
+            ));
+        }
+
+        socket.bind(&address)?;
+        socket.listen(self.backlog.unwrap_or(128))?;
+
+        Ok(socket.into())
+    }
+
+    /// Connects to `address` with these options applied.
+    pub fn connect(&self, address: &str) -> io::Result<TcpStream> {
+        let (socket, address) = self.socket_for(address)?;
+        socket.connect(&address)?;
+
+        Ok(socket.into())
+    }
+
+    /// Applies the per-connection options to an accepted stream.
+    pub fn apply<'s, S>(&self, stream: &'s S) -> io::Result<()>
+    where
+        SockRef<'s>: From<&'s S>,
+    {
+        self.apply_to_socket(&SockRef::from(stream))
+    }
+}
+
+/// Byte buffers kept between connections, so that reading a request or
+/// encoding a response reuses memory an earlier one grew rather than
+/// allocating afresh each time.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// The most buffers kept at once.
+    const KEEP: usize = 64;
+    /// Buffers that grew beyond this, for some unusually large message, are
+    /// freed rather than kept.
+    const LARGEST: usize = 1 << 20;
+
+    pub fn new() -> BufferPool {
+        BufferPool::default()
+    }
+
+    /// An empty buffer, with room left over from whatever it last held.
+    pub fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Hands `buffer` back once what it holds has been sent.
+    pub fn give(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > Self::LARGEST {
+            return;
+        }
+        buffer.clear();
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < Self::KEEP {
+            buffers.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::metrics::Counter;
+
+/// How hard writes to the document root try to survive a crash.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Durability {
+    /// Leave flushing to the operating system.
+    None,
+    /// Sync file contents before they are renamed into place, so a crash
+    /// leaves either the old file or the whole new one.
+    #[default]
+    Data,
+    /// Also sync the directory after the rename, and every upload chunk
+    /// before its offset is reported back.
+    Full,
+}
+
+impl Durability {
+    fn sync_file(&self, file: &File) -> io::Result<()> {
+        match self {
+            Durability::None => Ok(()),
+            Durability::Data | Durability::Full => file.sync_all(),
+        }
+    }
+
+    fn sync_directory(&self, path: &Path) -> io::Result<()> {
+        if *self != Durability::Full {
+            return Ok(());
+        }
+
+        // directories can only be opened and synced like this on unix
+        #[cfg(unix)]
+        {
+            let directory = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            File::open(directory)?.sync_all()?;
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+
+        Ok(())
+    }
+}
+
+/// Replaces `destination` with `data` so that concurrent readers see either
+/// the old contents or the new, never a mix: the data goes to a temporary file
+/// in the same directory first, which is then renamed over the destination.
+pub fn write_atomically(destination: &Path, data: &[u8], durability: Durability) -> io::Result<()> {
+    static COUNTER: Counter = Counter::new(0);
+
+    let name = destination
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(io::ErrorKind::InvalidInput)?;
+    let temporary = destination.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1)
+    ));
+
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temporary)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            durability.sync_file(&file)
+        })
+        .and_then(|_| fs::rename(&temporary, destination));
+
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written?;
+
+    durability.sync_directory(destination)
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// Upload ids may only contain ASCII letters, digits, `-` and `_`.
+    InvalidId,
+    /// The chunk does not start where the upload left off, which is
+    /// `expected` bytes in.
+    WrongOffset {
+        expected: u64,
+    },
+    /// The chunk would take the upload past `max_size` bytes.
+    TooLarge {
+        max_size: u64,
+    },
+    Io(io::Error),
+}
+
+impl From<io::Error> for UploadError {
+    fn from(error: io::Error) -> Self {
+        UploadError::Io(error)
+    }
+}
+
+/// An upload to `destination` that arrives in chunks, possibly over several
+/// connections.
+///
+/// The bytes received so far are kept in a hidden file next to the
+/// destination, so an interrupted client can ask for [`Upload::offset`] and
+/// carry on from there. [`Upload::complete`] renames it into place, which
+/// readers see as a single change since both are in the same directory.
+///
+/// Nothing here stops two chunks of the same upload being appended at once,
+/// or one being appended while the upload is completed; callers serialise
+/// them, as the file server does by holding its write lock throughout.
+#[derive(Debug)]
+pub struct Upload {
+    destination: PathBuf,
+    partial: PathBuf,
+    max_size: u64,
+}
+
+impl Upload {
+    pub fn new(destination: &Path, id: &str) -> Result<Upload, UploadError> {
+        let valid_id = !id.is_empty()
+            && id.len() <= 128
+            && id
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+        let name = destination.file_name().and_then(|name| name.to_str());
+
+        let (true, Some(name)) = (valid_id, name) else {
+            return Err(UploadError::InvalidId);
+        };
+
+        Ok(Upload {
+            destination: destination.to_path_buf(),
+            partial: destination.with_file_name(format!(".{}.{}.upload", name, id)),
+            max_size: u64::MAX,
+        })
+    }
+
+    /// Turns down chunks that would take the upload past `bytes`, which is
+    /// unlimited otherwise.
+    pub fn max_size(mut self, bytes: u64) -> Upload {
+        self.max_size = bytes;
+        self
+    }
+
+    /// How many bytes of the upload the server has; zero for an upload that
+    /// has not started.
+    pub fn offset(&self) -> io::Result<u64> {
+        match fs::metadata(&self.partial) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds `data` at `offset`, which must be where the upload left off, and
+    /// returns the new offset.
+    pub fn append(
+        &self,
+        offset: u64,
+        mut data: &[u8],
+        durability: Durability,
+    ) -> Result<u64, UploadError> {
+        self.append_from(offset, &mut data, durability)
+    }
+
+    /// Like [`Upload::append`], but copies the chunk from `reader` a piece
+    /// at a time, as when it is decompressed on the way. If reading fails
+    /// part way, or gives more than is left of [`Upload::max_size`], whatever
+    /// was written of the chunk is taken off again; `reader` is read no
+    /// further than one byte past what is left.
+    pub fn append_from(
+        &self,
+        offset: u64,
+        reader: &mut impl Read,
+        durability: Durability,
+    ) -> Result<u64, UploadError> {
+        let expected = self.offset()?;
+        if offset != expected {
+            return Err(UploadError::WrongOffset { expected });
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.partial)?;
+        let room = self.max_size.saturating_sub(expected);
+        let written = match io::copy(&mut reader.take(room.saturating_add(1)), &mut file) {
+            Ok(written) if written > room => {
+                file.set_len(expected)?;
+                return Err(UploadError::TooLarge {
+                    max_size: self.max_size,
+                });
+            }
+            Ok(written) => written,
+            Err(e) => {
+                file.set_len(expected)?;
+                return Err(e.into());
+            }
+        };
+        if durability == Durability::Full {
+            file.sync_all()?;
+        }
+
+        Ok(expected + written)
+    }
+
+    /// Moves the finished upload to its destination.
+    pub fn complete(self, durability: Durability) -> io::Result<()> {
+        durability.sync_file(&File::open(&self.partial)?)?;
+        fs::rename(&self.partial, &self.destination)?;
+        durability.sync_directory(&self.destination)
+    }
+
+    /// Throws away what has been received so far.
+    pub fn abort(self) -> io::Result<()> {
+        match fs::remove_file(&self.partial) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
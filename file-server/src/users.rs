@@ -0,0 +1,192 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::Value;
+
+use crate::{
+    middleware::{Context, Middleware},
+    protocol::JsontpRequest,
+};
+
+/// Someone who can sign in, as one line of a users file:
+/// `name:$argon2id$...:role,role`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    /// An argon2 hash in PHC string format.
+    pub hash: String,
+    pub roles: Vec<String>,
+}
+
+/// The users of a server, kept in a file much like htpasswd's: one user per
+/// line, with blank lines and lines starting with `#` ignored.
+#[derive(Debug, Clone, Default)]
+pub struct Users {
+    users: BTreeMap<String, User>,
+}
+
+impl Users {
+    pub fn new() -> Users {
+        Users::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Users, String> {
+        let mut users = Users::new();
+
+        for (number, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line)) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.splitn(3, ':');
+            let (Some(name), Some(hash)) = (fields.next(), fields.next()) else {
+                return Err(format!("line {}: expected `name:hash:roles`", number));
+            };
+            if name.is_empty() {
+                return Err(format!("line {}: the name is empty", number));
+            }
+            PasswordHash::new(hash)
+                .map_err(|e| format!("line {}: invalid hash for {}: {}", number, name, e))?;
+
+            let roles = fields
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|role| !role.is_empty())
+                .map(ToString::to_string)
+                .collect();
+
+            users.users.insert(
+                name.to_string(),
+                User {
+                    name: name.to_string(),
+                    hash: hash.to_string(),
+                    roles,
+                },
+            );
+        }
+
+        Ok(users)
+    }
+
+    /// Reads a users file; one that does not exist yet has no users.
+    pub fn load(path: &Path) -> Result<Users, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Users::parse(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Users::new()),
+            Err(e) => Err(format!("could not read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Writes the users to `path`, replacing it in one step so a server
+    /// reading it never sees half a file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        for user in self.users.values() {
+            text.push_str(&format!(
+                "{}:{}:{}\n",
+                user.name,
+                user.hash,
+                user.roles.join(",")
+            ));
+        }
+
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, text)?;
+        fs::rename(&temporary, path)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    /// Adds a user, or gives an existing one a new password and roles.
+    pub fn set(&mut self, name: &str, password: &str, roles: &[String]) -> Result<(), String> {
+        if name.is_empty() || name.contains(':') || name.contains(char::is_whitespace) {
+            return Err(format!("`{}` cannot be used as a name", name));
+        }
+
+        self.users.insert(
+            name.to_string(),
+            User {
+                name: name.to_string(),
+                hash: hash_password(password)?,
+                roles: roles.to_vec(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<User> {
+        self.users.remove(name)
+    }
+
+    /// The user called `name`, if `password` is theirs.
+    pub fn verify(&self, name: &str, password: &str) -> Option<&User> {
+        let user = self.users.get(name)?;
+        let hash = PasswordHash::new(&user.hash).ok()?;
+
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .ok()
+            .map(|_| user)
+    }
+}
+
+/// Hashes `password` with argon2id and a fresh salt.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Signs requests in with HTTP-style Basic credentials,
+/// `"authorization": "Basic base64(name:password)"`, checked against
+/// [`Users`]. A signed-in request gets the user's name and roles in its
+/// [`Context`], for the ACL and handlers; wrong credentials are answered with
+/// 401, and requests without any are left anonymous.
+pub struct BasicAuthMiddleware {
+    pub users: Users,
+}
+
+impl Middleware for BasicAuthMiddleware {
+    fn on_request(
+        &self,
+        request: &mut JsontpRequest,
+        context: &mut Context,
+    ) -> Result<(), (String, u16)> {
+        let Some(authorization) = request.headers.get("authorization").and_then(Value::as_str)
+        else {
+            return Ok(());
+        };
+        // other schemes are for other middleware
+        let Some(encoded) = authorization
+            .strip_prefix("Basic ")
+            .or_else(|| authorization.strip_prefix("basic "))
+        else {
+            return Ok(());
+        };
+
+        let unauthorized = || ("Unauthorized".to_string(), 401);
+        let decoded = STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .ok_or_else(unauthorized)?;
+        let (name, password) = decoded.split_once(':').ok_or_else(unauthorized)?;
+        let user = self.users.verify(name, password).ok_or_else(unauthorized)?;
+
+        context.user = Some(user.name.clone());
+        context.roles.extend(user.roles.iter().cloned());
+        Ok(())
+    }
+}
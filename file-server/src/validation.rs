@@ -0,0 +1,233 @@
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    headers::Headers,
+    protocol::{JsontpRequest, JsontpResponse},
+    server::error_response,
+};
+
+#[cfg(feature = "derive")]
+pub use jsontp_macros::JsontpBody;
+
+/// A field of a request body that broke one of its rules.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    /// The name of the field in the JSON; empty when the problem is with the
+    /// body as a whole.
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: String) -> FieldError {
+        FieldError {
+            field: field.to_string(),
+            message,
+        }
+    }
+}
+
+/// Why a request body was not accepted: 400 if it is not the JSON expected,
+/// 422 if it is but some fields are not valid, in which case every one of
+/// them is listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BodyError {
+    pub code: u16,
+    pub errors: Vec<FieldError>,
+}
+
+impl BodyError {
+    /// The response telling the client, with `{"errors": [{"field": ...,
+    /// "message": ...}]}` as its JSON content.
+    pub fn response(&self, request: &JsontpRequest) -> JsontpResponse {
+        let message = match self.code {
+            422 => "Unprocessable Entity",
+            _ => "Bad Request",
+        };
+
+        let mut response = error_response(request, message.to_string(), self.code);
+        response.headers = Headers::from([(
+            "content-type".to_string(),
+            Value::String("application/json".to_string()),
+        )]);
+        response.body.content =
+            serde_json::to_string(&serde_json::json!({ "errors": self.errors })).unwrap();
+        response.body.encoding = "identity".to_string();
+        response
+    }
+}
+
+/// A request body with rules for its fields, usually implemented with
+/// `#[derive(JsontpBody)]` and `#[validate(...)]` attributes.
+pub trait JsontpBody {
+    /// Adds an error to `errors` for every rule that is broken.
+    fn validate(&self, errors: &mut Vec<FieldError>);
+
+    /// Parses the JSON content of `request` and checks every rule.
+    fn from_request(request: &JsontpRequest) -> Result<Self, BodyError>
+    where
+        Self: DeserializeOwned + Sized,
+    {
+        let body: Self = serde_json::from_str(&request.body.content).map_err(|e| BodyError {
+            code: 400,
+            errors: vec![FieldError::new("", e.to_string())],
+        })?;
+
+        let mut errors = Vec::new();
+        body.validate(&mut errors);
+
+        if errors.is_empty() {
+            Ok(body)
+        } else {
+            Err(BodyError { code: 422, errors })
+        }
+    }
+}
+
+/// Values with a length; `None` for an absent optional value, which is not
+/// checked.
+pub trait Length {
+    fn length(&self) -> Option<usize>;
+}
+
+impl Length for str {
+    fn length(&self) -> Option<usize> {
+        Some(self.chars().count())
+    }
+}
+
+impl Length for String {
+    fn length(&self) -> Option<usize> {
+        self.as_str().length()
+    }
+}
+
+impl<T> Length for Vec<T> {
+    fn length(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl<T: Length> Length for Option<T> {
+    fn length(&self) -> Option<usize> {
+        self.as_ref()?.length()
+    }
+}
+
+/// Numbers, as far as range checks are concerned.
+pub trait Number {
+    fn number(&self) -> Option<f64>;
+}
+
+macro_rules! number {
+    ($($type:ty),*) => {
+        $(impl Number for $type {
+            fn number(&self) -> Option<f64> {
+                Some(*self as f64)
+            }
+        })*
+    };
+}
+
+number!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64);
+
+impl<T: Number> Number for Option<T> {
+    fn number(&self) -> Option<f64> {
+        self.as_ref()?.number()
+    }
+}
+
+/// Strings, as far as pattern checks are concerned.
+pub trait Text {
+    fn text(&self) -> Option<&str>;
+}
+
+impl Text for String {
+    fn text(&self) -> Option<&str> {
+        Some(self)
+    }
+}
+
+impl<T: Text> Text for Option<T> {
+    fn text(&self) -> Option<&str> {
+        self.as_ref()?.text()
+    }
+}
+
+fn bounds_message<T: std::fmt::Display>(min: Option<T>, max: Option<T>) -> String {
+    match (min, max) {
+        (Some(min), Some(max)) => format!("must be between {} and {}", min, max),
+        (Some(min), None) => format!("must be at least {}", min),
+        (None, Some(max)) => format!("must be at most {}", max),
+        (None, None) => String::new(),
+    }
+}
+
+pub fn check_length(
+    field: &str,
+    value: &(impl Length + ?Sized),
+    min: Option<usize>,
+    max: Option<usize>,
+    errors: &mut Vec<FieldError>,
+) {
+    let Some(length) = value.length() else {
+        return;
+    };
+
+    if min.is_some_and(|min| length < min) || max.is_some_and(|max| length > max) {
+        errors.push(FieldError::new(
+            field,
+            format!("length {}", bounds_message(min, max)),
+        ));
+    }
+}
+
+pub fn check_range(
+    field: &str,
+    value: &impl Number,
+    min: Option<f64>,
+    max: Option<f64>,
+    errors: &mut Vec<FieldError>,
+) {
+    let Some(number) = value.number() else {
+        return;
+    };
+
+    if min.is_some_and(|min| number < min) || max.is_some_and(|max| number > max) {
+        errors.push(FieldError::new(field, bounds_message(min, max)));
+    }
+}
+
+/// A compiled regular expression for [`check_pattern`].
+#[cfg(feature = "derive")]
+#[derive(Debug)]
+pub struct Pattern(regex_lite::Regex);
+
+#[cfg(feature = "derive")]
+impl Pattern {
+    /// Panics if `pattern` is not a valid regular expression; the derive
+    /// macro checks its patterns when it expands.
+    pub fn new(pattern: &str) -> Pattern {
+        Pattern(regex_lite::Regex::new(pattern).expect("invalid pattern"))
+    }
+}
+
+#[cfg(feature = "derive")]
+pub fn check_pattern(
+    field: &str,
+    value: &impl Text,
+    pattern: &Pattern,
+    errors: &mut Vec<FieldError>,
+) {
+    let Some(text) = value.text() else {
+        return;
+    };
+
+    if !pattern.0.is_match(text) {
+        errors.push(FieldError::new(
+            field,
+            format!("must match {}", pattern.0.as_str()),
+        ));
+    }
+}
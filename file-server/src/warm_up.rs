@@ -0,0 +1,115 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    headers::Headers,
+    protocol::{Body, JsontpRequest, JsontpResponse},
+    search::{glob_matches, walk},
+    server::{Arrival, Server},
+};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WarmUpConfig {
+    /// Resources to request at startup, relative to the working directory.
+    /// One with `*` or `?` in it stands for every file matching, where `*`
+    /// matches `/` too.
+    pub resources: Vec<String>,
+}
+
+/// The resources `config` names, patterns expanded against the files under
+/// `root`.
+pub fn resources(config: &WarmUpConfig, root: &Path) -> Vec<String> {
+    let mut files = Vec::new();
+    if config.resources.iter().any(|resource| is_pattern(resource)) {
+        if let Err(e) = walk(root, "", &mut files) {
+            eprintln!("could not list the files to warm up: {}", e);
+        }
+    }
+
+    let mut resources = Vec::new();
+    for resource in &config.resources {
+        if is_pattern(resource) {
+            resources.extend(
+                files
+                    .iter()
+                    .map(|(name, _)| name)
+                    .filter(|name| glob_matches(resource, name))
+                    .cloned(),
+            );
+        } else {
+            resources.push(resource.clone());
+        }
+    }
+    resources.dedup();
+    resources
+}
+
+/// Has `server` answer a `GET` of each of `resources` before any client
+/// asks, as a client on this machine would, so that the metadata cache and
+/// the compressed bodies are in place for the first requests after a
+/// deploy. Each resource is requested once as it is and once with each of
+/// `encodings` in `accept-encoding`. Gives how many resources were answered
+/// with 200.
+pub fn warm_up(server: &Server, resources: &[String], encodings: &[String]) -> usize {
+    let arrival = Arrival::new(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let accepts: Vec<_> = std::iter::once(None)
+        .chain(encodings.iter().map(|encoding| Some(encoding.as_str())))
+        .collect();
+
+    resources
+        .iter()
+        .filter(|resource| {
+            accepts
+                .iter()
+                .all(|accept| fetch(server, &arrival, resource, *accept))
+        })
+        .count()
+}
+
+/// Whether a `GET` of `resource` is answered with 200.
+fn fetch(server: &Server, arrival: &Arrival, resource: &str, accept: Option<&str>) -> bool {
+    let mut headers = Headers::new();
+    if let Some(accept) = accept {
+        headers.insert("accept-encoding".to_string(), Value::from(accept));
+    }
+    let request = JsontpRequest {
+        jsontp: "1.0".to_string(),
+        type_of_request: "request".to_string(),
+        method: "GET".to_string(),
+        resource: resource.to_string(),
+        headers,
+        // the protocol wants some content in every request
+        body: Body {
+            content: "{}".to_string(),
+            encoding: "identity".to_string(),
+            other: Default::default(),
+        },
+        other: Default::default(),
+    };
+
+    let answer = server.handle_message(&serde_json::to_vec(&request).unwrap(), arrival);
+    match serde_json::from_slice::<JsontpResponse>(&answer) {
+        Ok(response) if response.status.code == 200 => true,
+        Ok(response) => {
+            eprintln!(
+                "could not warm up {}: {}",
+                resource, response.status.formal_message
+            );
+            false
+        }
+        Err(e) => {
+            eprintln!("could not warm up {}: {}", resource, e);
+            false
+        }
+    }
+}
+
+fn is_pattern(resource: &str) -> bool {
+    resource.contains(['*', '?'])
+}
@@ -0,0 +1,35 @@
+use std::path::{self, Path};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// Calls `on_change` with the absolute path of everything that changes under
+/// `root`, and with `None` when events were lost and anything may have
+/// changed. Watching stops when the returned watcher is dropped.
+pub fn watch(
+    root: &Path,
+    on_change: impl Fn(Option<&Path>) + Send + 'static,
+) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            on_change(None);
+            return;
+        };
+
+        if event.need_rescan() {
+            on_change(None);
+            return;
+        }
+
+        if let EventKind::Access(_) = event.kind {
+            return;
+        }
+
+        for path in &event.paths {
+            on_change(Some(path));
+        }
+    })?;
+
+    watcher.watch(&path::absolute(root)?, RecursiveMode::Recursive)?;
+
+    Ok(watcher)
+}
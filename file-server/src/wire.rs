@@ -0,0 +1,259 @@
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
+
+use std::fmt;
+
+use crate::canonical::to_canonical_string;
+
+/// Why a message could not be decoded, and where in it, for telling the
+/// client what is wrong with what it sent.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    /// `syntax` for a message that is not well-formed, `data` for one that
+    /// is but does not have the fields and types expected, `eof` for one cut
+    /// short.
+    pub category: &'static str,
+    pub message: String,
+    /// The 1-based line and column of JSON messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    /// The byte offset in binary messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+}
+
+impl DecodeError {
+    fn new(category: &'static str, message: String) -> DecodeError {
+        DecodeError {
+            category,
+            message,
+            line: None,
+            column: None,
+            offset: None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(error: serde_json::Error) -> Self {
+        let category = match error.classify() {
+            serde_json::error::Category::Syntax => "syntax",
+            serde_json::error::Category::Data => "data",
+            serde_json::error::Category::Eof => "eof",
+            serde_json::error::Category::Io => "io",
+        };
+        if error.line() == 0 {
+            return DecodeError::new(category, error.to_string());
+        }
+
+        // the position is given separately, so leave it out of the message
+        let position = format!(" at line {} column {}", error.line(), error.column());
+        let message = error.to_string();
+        DecodeError {
+            line: Some(error.line()),
+            column: Some(error.column()),
+            ..DecodeError::new(
+                category,
+                message
+                    .strip_suffix(&position)
+                    .unwrap_or(&message)
+                    .to_string(),
+            )
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        match (self.line, self.column, self.offset) {
+            (Some(line), Some(column), _) => write!(f, " at line {} column {}", line, column),
+            (_, _, Some(offset)) => write!(f, " at offset {}", offset),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageState {
+    Complete,
+    /// More bytes are needed before the message can be decoded.
+    Incomplete,
+    Invalid,
+}
+
+/// The encoding used to put a message on the wire. The data model is always
+/// the JSON one; binary formats only change how it is serialized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    /// JSON in the form produced by [`crate::canonical::to_canonical_string`].
+    CanonicalJson,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl WireFormat {
+    /// Looks up a format by the name used in the `wire-format` header.
+    /// Returns `None` for unknown formats and for formats not compiled in.
+    pub fn from_name(name: &str) -> Option<WireFormat> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(WireFormat::Json),
+            "canonical-json" => Some(WireFormat::CanonicalJson),
+            #[cfg(feature = "cbor")]
+            "cbor" => Some(WireFormat::Cbor),
+            #[cfg(feature = "msgpack")]
+            "msgpack" | "messagepack" => Some(WireFormat::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "json",
+            WireFormat::CanonicalJson => "canonical-json",
+            #[cfg(feature = "cbor")]
+            WireFormat::Cbor => "cbor",
+            #[cfg(feature = "msgpack")]
+            WireFormat::MessagePack => "msgpack",
+        }
+    }
+
+    /// Guesses the format of an incoming message from its first byte.
+    ///
+    /// Every message is a map, so the leading byte is unambiguous: `{` for
+    /// JSON, major type 5 for CBOR and a map marker for MessagePack.
+    /// Anything unrecognised is treated as JSON.
+    pub fn sniff(data: &[u8]) -> WireFormat {
+        match data.first() {
+            #[cfg(feature = "cbor")]
+            Some(0xa0..=0xbf) => WireFormat::Cbor,
+            #[cfg(feature = "msgpack")]
+            Some(0x80..=0x8f | 0xde | 0xdf) => WireFormat::MessagePack,
+            _ => WireFormat::Json,
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, DecodeError> {
+        match self {
+            #[cfg(feature = "simd-json")]
+            WireFormat::Json | WireFormat::CanonicalJson => {
+                // simd-json parses in place, and picks the best implementation
+                // for the CPU at runtime. Anything it refuses is handed to
+                // serde_json, which also gives the better error message.
+                #[cfg(feature = "arena")]
+                let parsed = crate::arena::with_arena(|arena| {
+                    simd_json::serde::from_slice(arena.alloc_slice_copy(data))
+                });
+                #[cfg(not(feature = "arena"))]
+                let parsed = simd_json::serde::from_slice(&mut data.to_vec());
+
+                parsed
+                    .or_else(|_| serde_json::from_slice(data))
+                    .map_err(DecodeError::from)
+            }
+            #[cfg(not(feature = "simd-json"))]
+            WireFormat::Json | WireFormat::CanonicalJson => {
+                serde_json::from_slice(data).map_err(DecodeError::from)
+            }
+            #[cfg(feature = "cbor")]
+            WireFormat::Cbor => ciborium::from_reader(data).map_err(|e| {
+                let (category, offset) = match &e {
+                    ciborium::de::Error::Syntax(offset) => ("syntax", Some(*offset)),
+                    ciborium::de::Error::Semantic(offset, _) => ("data", *offset),
+                    ciborium::de::Error::Io(_) => ("eof", None),
+                    ciborium::de::Error::RecursionLimitExceeded => ("syntax", None),
+                };
+                DecodeError {
+                    offset,
+                    ..DecodeError::new(category, e.to_string())
+                }
+            }),
+            #[cfg(feature = "msgpack")]
+            WireFormat::MessagePack => rmp_serde::from_slice(data).map_err(|e| {
+                let category = match &e {
+                    rmp_serde::decode::Error::InvalidMarkerRead(_)
+                    | rmp_serde::decode::Error::InvalidDataRead(_) => "eof",
+                    rmp_serde::decode::Error::Syntax(_) => "syntax",
+                    _ => "data",
+                };
+                DecodeError::new(category, e.to_string())
+            }),
+        }
+    }
+
+    /// Checks whether `data` holds a whole message yet, without building it.
+    pub fn message_state(&self, data: &[u8]) -> MessageState {
+        let eof = |complete: bool, eof: bool| match (complete, eof) {
+            (true, _) => MessageState::Complete,
+            (false, true) => MessageState::Incomplete,
+            (false, false) => MessageState::Invalid,
+        };
+
+        match self {
+            WireFormat::Json | WireFormat::CanonicalJson => {
+                match serde_json::from_slice::<IgnoredAny>(data) {
+                    Ok(_) => MessageState::Complete,
+                    Err(e) => eof(false, e.is_eof()),
+                }
+            }
+            #[cfg(feature = "cbor")]
+            WireFormat::Cbor => match ciborium::from_reader::<IgnoredAny, _>(data) {
+                Ok(_) => MessageState::Complete,
+                Err(ciborium::de::Error::Io(e)) => {
+                    eof(false, e.kind() == std::io::ErrorKind::UnexpectedEof)
+                }
+                Err(_) => MessageState::Invalid,
+            },
+            #[cfg(feature = "msgpack")]
+            WireFormat::MessagePack => match rmp_serde::from_slice::<IgnoredAny>(data) {
+                Ok(_) => MessageState::Complete,
+                Err(
+                    rmp_serde::decode::Error::InvalidMarkerRead(e)
+                    | rmp_serde::decode::Error::InvalidDataRead(e),
+                ) => eof(false, e.kind() == std::io::ErrorKind::UnexpectedEof),
+                Err(_) => MessageState::Invalid,
+            },
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        let mut buffer = Vec::new();
+        self.encode_into(value, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Appends `value` to `buffer`, which is left as it was if encoding
+    /// fails. Reusing a buffer spares the allocations of growing a new one
+    /// for every message.
+    pub fn encode_into<T: Serialize>(&self, value: &T, buffer: &mut Vec<u8>) -> Result<(), String> {
+        let start = buffer.len();
+        let encoded = match self {
+            WireFormat::Json => {
+                serde_json::to_writer(&mut *buffer, value).map_err(|e| e.to_string())
+            }
+            WireFormat::CanonicalJson => to_canonical_string(value)
+                .map(|text| buffer.extend_from_slice(text.as_bytes()))
+                .map_err(|e| e.to_string()),
+            #[cfg(feature = "cbor")]
+            WireFormat::Cbor => {
+                ciborium::into_writer(value, &mut *buffer).map_err(|e| e.to_string())
+            }
+            #[cfg(feature = "msgpack")]
+            WireFormat::MessagePack => {
+                rmp_serde::encode::write_named(&mut *buffer, value).map_err(|e| e.to_string())
+            }
+        };
+
+        if encoded.is_err() {
+            buffer.truncate(start);
+        }
+        encoded
+    }
+}